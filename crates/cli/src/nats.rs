@@ -1,16 +1,158 @@
+use std::path::PathBuf;
+
 use anyhow::Context as _;
 use tokio::sync::mpsc;
 
 pub const DEFAULT_URL: &str = "nats://127.0.0.1:4222";
 
+/// NATS.io connection options
+#[derive(clap::Args, Clone, Debug)]
+pub struct ConnectOptions {
+    /// NATS.io address to use
+    #[arg(short = 'n', long = "nats", default_value = DEFAULT_URL)]
+    pub url: String,
+
+    /// Path to NATS.io credentials file
+    #[arg(
+        long = "nats-creds",
+        env = "NATS_CREDS",
+        conflicts_with_all = ["nkey", "token", "user"]
+    )]
+    pub creds: Option<PathBuf>,
+
+    /// NATS.io nkey seed
+    #[arg(
+        long = "nats-nkey",
+        env = "NATS_NKEY",
+        hide_env_values = true,
+        conflicts_with_all = ["token", "user"]
+    )]
+    pub nkey: Option<String>,
+
+    /// NATS.io authentication token
+    #[arg(
+        long = "nats-token",
+        env = "NATS_TOKEN",
+        hide_env_values = true,
+        conflicts_with = "user"
+    )]
+    pub token: Option<String>,
+
+    /// NATS.io user name
+    #[arg(long = "nats-user", requires = "password")]
+    pub user: Option<String>,
+
+    /// NATS.io user password
+    #[arg(
+        long = "nats-password",
+        env = "NATS_PASSWORD",
+        hide_env_values = true,
+        requires = "user"
+    )]
+    pub password: Option<String>,
+
+    /// Path to PEM-encoded TLS client certificate
+    #[arg(long = "nats-tls-cert", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Path to PEM-encoded TLS client private key
+    #[arg(long = "nats-tls-key", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Path to PEM-encoded TLS CA certificate used to verify the server
+    #[arg(long = "nats-tls-ca")]
+    pub tls_ca: Option<PathBuf>,
+
+    /// NATS.io connection name
+    #[arg(long = "nats-name")]
+    pub name: Option<String>,
+
+    /// NATS.io inbox prefix to use for replies
+    ///
+    /// Defaults to `_INBOX`
+    #[arg(long = "nats-inbox-prefix")]
+    pub inbox_prefix: Option<String>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            url: DEFAULT_URL.to_string(),
+            creds: None,
+            nkey: None,
+            token: None,
+            user: None,
+            password: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_ca: None,
+            name: None,
+            inbox_prefix: None,
+        }
+    }
+}
+
+impl ConnectOptions {
+    /// Constructs [`async_nats::ConnectOptions`] from these options
+    pub async fn to_async_nats(&self) -> anyhow::Result<async_nats::ConnectOptions> {
+        let mut opts = async_nats::ConnectOptions::new();
+        if let Some(creds) = &self.creds {
+            opts = opts.credentials_file(creds).await.with_context(|| {
+                format!(
+                    "failed to load NATS.io credentials from `{}`",
+                    creds.display()
+                )
+            })?;
+        }
+        if let Some(nkey) = &self.nkey {
+            opts = opts.nkey(nkey.clone());
+        }
+        if let Some(token) = &self.token {
+            opts = opts.token(token.clone());
+        }
+        if let (Some(user), Some(password)) = (&self.user, &self.password) {
+            opts = opts.user_and_password(user.clone(), password.clone());
+        }
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            opts = opts
+                .add_client_certificate(cert.clone(), key.clone())
+                .require_tls(true);
+        }
+        if let Some(ca) = &self.tls_ca {
+            opts = opts.add_root_certificates(ca.clone()).require_tls(true);
+        }
+        if let Some(name) = &self.name {
+            opts = opts.name(name.as_str());
+        }
+        if let Some(prefix) = &self.inbox_prefix {
+            opts = opts.custom_inbox_prefix(prefix.as_str());
+        }
+        Ok(opts)
+    }
+
+    /// Connect to NATS.io server using these options, see [`connect_with_options`]
+    pub async fn connect(&self) -> anyhow::Result<async_nats::Client> {
+        let opts = self.to_async_nats().await?;
+        connect_with_options(self.url.as_str(), opts).await
+    }
+}
+
 /// Connect to NATS.io server and ensure that the connection is fully established before
 /// returning the resulting [`async_nats::Client`]
 pub async fn connect(addrs: impl async_nats::ToServerAddrs) -> anyhow::Result<async_nats::Client> {
+    connect_with_options(addrs, async_nats::ConnectOptions::new()).await
+}
+
+/// Connect to NATS.io server using `opts` and ensure that the connection is fully established
+/// before returning the resulting [`async_nats::Client`]
+pub async fn connect_with_options(
+    addrs: impl async_nats::ToServerAddrs,
+    opts: async_nats::ConnectOptions,
+) -> anyhow::Result<async_nats::Client> {
     let (conn_tx, mut conn_rx) = mpsc::channel(1);
     let client = async_nats::connect_with_options(
         addrs,
-        async_nats::ConnectOptions::new()
-            .retry_on_initial_connect()
+        opts.retry_on_initial_connect()
             .event_callback(move |event| {
                 let conn_tx = conn_tx.clone();
                 async move {
@@ -1,15 +1,104 @@
 use tracing_subscriber::layer::SubscriberExt as _;
 use tracing_subscriber::util::SubscriberInitExt as _;
 
+/// Environment variable enabling the OTLP span exporter, if set
+#[cfg(feature = "otlp")]
+pub const OTLP_ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
 #[must_use]
 pub fn env_filter() -> tracing_subscriber::EnvFilter {
     tracing_subscriber::EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"))
 }
 
+/// Flushes and shuts down the span exporter installed by [`init_with_otlp`].
+///
+/// Use [`Guard::shutdown`] to handle failures, the guard otherwise shuts down the exporter on
+/// drop and reports failures as `tracing` events.
+#[must_use = "spans are not exported once the guard is dropped"]
+#[derive(Debug, Default)]
+pub struct Guard {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Guard {
+    /// Flushes pending spans and shuts down the span exporter, if any
+    pub fn shutdown(mut self) -> anyhow::Result<()> {
+        self.shutdown_exporter()
+    }
+
+    fn shutdown_exporter(&mut self) -> anyhow::Result<()> {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider.take() {
+            use anyhow::Context as _;
+
+            provider
+                .shutdown()
+                .context("failed to shut down OTLP span exporter")?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Err(err) = self.shutdown_exporter() {
+            tracing::warn!(?err, "failed to shut down span exporter");
+        }
+    }
+}
+
+/// Constructs an OTLP span exporter over HTTP, configured by the standard `OTEL_*`
+/// environment variables, if [`OTLP_ENDPOINT_ENV`] is set
+#[cfg(feature = "otlp")]
+fn otlp_provider() -> anyhow::Result<Option<opentelemetry_sdk::trace::SdkTracerProvider>> {
+    use anyhow::Context as _;
+
+    if std::env::var_os(OTLP_ENDPOINT_ENV).is_none() {
+        return Ok(None);
+    }
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+        .context("failed to build OTLP span exporter")?;
+    Ok(Some(
+        opentelemetry_sdk::trace::SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .build(),
+    ))
+}
+
 pub fn init() {
     tracing_subscriber::registry()
         .with(tracing_subscriber::fmt::layer().compact().without_time())
         .with(env_filter())
         .init();
 }
+
+/// Installs the global tracing subscriber like [`init`].
+///
+/// With the `otlp` feature enabled, spans are additionally exported over OTLP if
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` is set. The returned [`Guard`] must be held until exit.
+pub fn init_with_otlp() -> anyhow::Result<Guard> {
+    #[cfg(feature = "otlp")]
+    {
+        use opentelemetry::trace::TracerProvider as _;
+
+        let provider = otlp_provider()?;
+        tracing_subscriber::registry()
+            .with(tracing_subscriber::fmt::layer().compact().without_time())
+            .with(provider.as_ref().map(|provider| {
+                tracing_opentelemetry::layer().with_tracer(provider.tracer("wrpc"))
+            }))
+            .with(env_filter())
+            .init();
+        Ok(Guard { provider })
+    }
+    #[cfg(not(feature = "otlp"))]
+    {
+        init();
+        Ok(Guard::default())
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use async_nats::{Message, Subject};
+use bytes::BytesMut;
+use clap::Parser;
+use futures::StreamExt as _;
+use tracing::{instrument, warn};
+use wit_parser::{Function, Resolve};
+use wrpc_introspect::rpc_func_name;
+
+mod value;
+
+pub use value::{format_error, Formatter};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(flatten)]
+    nats: wrpc_cli::nats::ConnectOptions,
+
+    /// Path to WIT package used to decode values
+    #[arg(short, long)]
+    wit: Option<PathBuf>,
+
+    /// Prefix to inspect.
+    ///
+    /// Invocations are decoded only if their subjects use the default subject layout. Instance
+    /// names of subjects published using `EscapedLayout` cannot be recovered, so the values of
+    /// such invocations are printed as raw bytes.
+    prefix: String,
+}
+
+/// Functions defined in a WIT package indexed by wRPC instance and function name.
+///
+/// Instance names are not escaped, so functions invoked using
+/// [`EscapedLayout`](wrpc_transport_nats::EscapedLayout) subjects are not found.
+#[derive(Default)]
+pub struct Functions {
+    resolve: Resolve,
+    functions: HashMap<(String, String), Function>,
+}
+
+impl Functions {
+    pub fn new(resolve: Resolve) -> Self {
+        let mut functions = HashMap::default();
+        for (id, iface) in &resolve.interfaces {
+            let Some(instance) = resolve.id_of(id) else {
+                continue;
+            };
+            for func in iface.functions.values() {
+                functions.insert(
+                    (instance.clone(), rpc_func_name(func).to_string()),
+                    func.clone(),
+                );
+            }
+        }
+        for (_, world) in &resolve.worlds {
+            let instance = if let Some(package) = world.package {
+                resolve.id_of_name(package, &world.name)
+            } else {
+                world.name.clone()
+            };
+            for item in world.imports.values().chain(world.exports.values()) {
+                if let wit_parser::WorldItem::Function(func) = item {
+                    functions.insert(
+                        (instance.clone(), rpc_func_name(func).to_string()),
+                        func.clone(),
+                    );
+                }
+            }
+        }
+        Self { resolve, functions }
+    }
+
+    #[must_use]
+    pub fn get(&self, instance: &str, func: &str) -> Option<&Function> {
+        self.functions
+            .get(&(instance.to_string(), func.to_string()))
+    }
+
+    #[must_use]
+    pub fn resolve(&self) -> &Resolve {
+        &self.resolve
+    }
+}
+
+/// Direction of a value transmission within an invocation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Params,
+    Results,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Params => "params",
+            Self::Results => "results",
+        }
+    }
+}
+
+struct Invocation {
+    id: u64,
+    instance: String,
+    func: String,
+    client_rx: String,
+    server_rx: Option<String>,
+    params: BytesMut,
+    params_done: bool,
+    results: BytesMut,
+    results_done: bool,
+}
+
+impl Invocation {
+    fn buffer(&mut self, dir: Direction) -> (&mut BytesMut, &mut bool) {
+        match dir {
+            Direction::Params => (&mut self.params, &mut self.params_done),
+            Direction::Results => (&mut self.results, &mut self.results_done),
+        }
+    }
+}
+
+/// Correlates NATS messages into wRPC invocations and prints them
+pub struct Sniffer {
+    invocation_prefix: String,
+    functions: Option<Functions>,
+    next_id: u64,
+    invocations: HashMap<u64, Invocation>,
+    inboxes: HashMap<String, u64>,
+}
+
+impl Sniffer {
+    pub fn new(prefix: &str, functions: Option<Functions>) -> Self {
+        Self {
+            invocation_prefix: wrpc_transport_nats::invocation_subject(prefix, "", ""),
+            functions,
+            next_id: 0,
+            invocations: HashMap::default(),
+            inboxes: HashMap::default(),
+        }
+    }
+
+    fn format(&self, inv: &Invocation, dir: Direction, buf: &[u8]) -> String {
+        let Some(functions) = &self.functions else {
+            return format!("{buf:02x?}");
+        };
+        let Some(func) = functions.get(&inv.instance, &inv.func) else {
+            return format!("{buf:02x?} (function not found in WIT)");
+        };
+        let fmt = Formatter::new(functions.resolve());
+        let res = match dir {
+            Direction::Params => fmt.format_values(func.params.iter().map(|(_, ty)| ty), buf),
+            Direction::Results => fmt.format_values(func.results.iter_types(), buf),
+        };
+        match res {
+            Ok(Some(s)) => s,
+            Ok(None) => format!("{buf:02x?} (incomplete)"),
+            Err(err) => format!("{buf:02x?} (failed to decode: {err:#})"),
+        }
+    }
+
+    /// Appends `payload` to the root value buffer of the invocation in direction `dir`,
+    /// printing the values once the stream is finished
+    fn handle_root(&mut self, id: u64, dir: Direction, payload: &[u8]) {
+        let Some(inv) = self.invocations.get_mut(&id) else {
+            return;
+        };
+        let (buf, done) = inv.buffer(dir);
+        if payload.is_empty() {
+            *done = true;
+        } else {
+            buf.extend_from_slice(payload);
+        }
+        if !*done {
+            return;
+        }
+        let buf = buf.split().freeze();
+        let s = self.format(&self.invocations[&id], dir, &buf);
+        println!("#{id} {}: {s}", dir.as_str());
+        let Some(inv) = self.invocations.get(&id) else {
+            return;
+        };
+        if inv.params_done && inv.results_done {
+            self.finish(id);
+        }
+    }
+
+    fn finish(&mut self, id: u64) {
+        if let Some(inv) = self.invocations.remove(&id) {
+            self.inboxes.remove(&inv.client_rx);
+            if let Some(server_rx) = inv.server_rx {
+                self.inboxes.remove(&server_rx);
+            }
+        }
+    }
+
+    /// Handles a message published on an invocation subject
+    fn handle_invocation(&mut self, subject: &str, reply: Option<Subject>, payload: &[u8]) {
+        let (instance, func) = subject.rsplit_once('.').unwrap_or(("", subject));
+        let Some(reply) = reply else {
+            warn!(subject, "invocation without a reply subject");
+            return;
+        };
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        println!("#{id} invoke `{instance}.{func}` (reply: {reply})");
+        self.inboxes.insert(reply.to_string(), id);
+        self.invocations.insert(
+            id,
+            Invocation {
+                id,
+                instance: instance.to_string(),
+                func: func.to_string(),
+                client_rx: reply.to_string(),
+                server_rx: None,
+                params: BytesMut::default(),
+                params_done: false,
+                results: BytesMut::default(),
+                results_done: false,
+            },
+        );
+        if !payload.is_empty() {
+            self.handle_root(id, Direction::Params, payload);
+        }
+    }
+
+    /// Handles a message published on an inbox subject
+    fn handle_inbox(&mut self, subject: &str, reply: Option<Subject>, payload: &[u8]) {
+        // Inboxes are of form `{inbox_prefix}.{nuid}`, longest matching inbox wins
+        let Some((inbox, id)) = self
+            .inboxes
+            .iter()
+            .filter(|(inbox, _)| {
+                subject == inbox.as_str()
+                    || subject
+                        .strip_prefix(inbox.as_str())
+                        .is_some_and(|s| s.starts_with('.'))
+            })
+            .max_by_key(|(inbox, _)| inbox.len())
+            .map(|(inbox, id)| (inbox.clone(), *id))
+        else {
+            return;
+        };
+        let Some(inv) = self.invocations.get_mut(&id) else {
+            return;
+        };
+        let rest = &subject[inbox.len()..];
+        if rest.is_empty() {
+            let Some(server_rx) = reply else {
+                println!("#{id} handshake response without a reply subject");
+                return;
+            };
+            println!("#{id} accepted (reply: {server_rx})");
+            inv.server_rx = Some(server_rx.to_string());
+            self.inboxes.insert(server_rx.to_string(), id);
+            return;
+        }
+        let rest = &rest[1..];
+        let (kind, path) = rest.split_once('.').unwrap_or((rest, ""));
+        let dir = match kind {
+            "params" => Direction::Params,
+            "results" => Direction::Results,
+            "error" => {
+                match format_error(payload) {
+                    Ok(err) => println!("#{} error: {err}", inv.id),
+                    Err(err) => println!("#{} error: {payload:02x?} ({err:#})", inv.id),
+                }
+                self.finish(id);
+                return;
+            }
+            _ => {
+                println!("#{id} {rest}: {payload:02x?}");
+                return;
+            }
+        };
+        if path.is_empty() {
+            self.handle_root(id, dir, payload);
+        } else {
+            println!("#{id} {}[{path}]: {payload:02x?}", dir.as_str());
+        }
+    }
+
+    /// Handles a single NATS message
+    pub fn handle_message(
+        &mut self,
+        Message {
+            subject,
+            reply,
+            payload,
+            ..
+        }: Message,
+    ) {
+        if let Some(name) = subject.strip_prefix(self.invocation_prefix.as_str()) {
+            let name = name.to_string();
+            self.handle_invocation(&name, reply, &payload);
+        } else {
+            self.handle_inbox(&subject, reply, &payload);
+        }
+    }
+}
+
+#[instrument(level = "trace", ret)]
+pub async fn run() -> anyhow::Result<()> {
+    let _tracing = wrpc_cli::tracing::init_with_otlp().context("failed to initialize tracing")?;
+
+    let Args { nats, wit, prefix } = Args::parse();
+    let functions = if let Some(wit) = wit {
+        let mut resolve = Resolve::default();
+        resolve
+            .push_path(&wit)
+            .with_context(|| format!("failed to parse WIT at `{}`", wit.display()))?;
+        Some(Functions::new(resolve))
+    } else {
+        None
+    };
+    // wRPC peers are expected to use the same inbox prefix as the sniffer
+    let inbox_prefix = nats.inbox_prefix.as_deref().unwrap_or("_INBOX").to_string();
+    let nats = nats.connect().await.context("failed to connect to NATS")?;
+
+    let invocations = nats
+        .subscribe(format!(
+            "{}>",
+            wrpc_transport_nats::invocation_subject(&prefix, "", "")
+        ))
+        .await
+        .context("failed to subscribe on invocation subjects")?;
+    let inboxes = nats
+        .subscribe(format!("{inbox_prefix}.>"))
+        .await
+        .context("failed to subscribe on inbox subjects")?;
+    let mut sniffer = Sniffer::new(&prefix, functions);
+    let mut msgs = futures::stream::select(invocations, inboxes);
+    while let Some(msg) = msgs.next().await {
+        sniffer.handle_message(msg);
+    }
+    Ok(())
+}
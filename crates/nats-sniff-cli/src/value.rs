@@ -0,0 +1,328 @@
+use core::fmt::Write as _;
+use core::str;
+
+use anyhow::{bail, ensure, Context as _};
+use wit_parser::{Case, Field, Handle, Resolve, Result_, Stream, Type, TypeDefKind};
+
+fn read_u8(r: &mut &[u8]) -> std::io::Result<u8> {
+    let Some((b, rest)) = r.split_first() else {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    };
+    *r = rest;
+    Ok(*b)
+}
+
+fn read_bytes<'a>(r: &mut &'a [u8], n: usize) -> std::io::Result<&'a [u8]> {
+    if r.len() < n {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    let (buf, rest) = r.split_at(n);
+    *r = rest;
+    Ok(buf)
+}
+
+fn read_unsigned(r: &mut &[u8]) -> std::io::Result<u64> {
+    leb128::read::unsigned(r).map_err(|err| match err {
+        leb128::read::Error::IoError(err) => err,
+        leb128::read::Error::Overflow => {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "LEB128 value overflow")
+        }
+    })
+}
+
+fn read_signed(r: &mut &[u8]) -> std::io::Result<i64> {
+    leb128::read::signed(r).map_err(|err| match err {
+        leb128::read::Error::IoError(err) => err,
+        leb128::read::Error::Overflow => {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "LEB128 value overflow")
+        }
+    })
+}
+
+fn read_len(r: &mut &[u8]) -> std::io::Result<usize> {
+    let n = read_unsigned(r)?;
+    n.try_into()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
+fn read_char(r: &mut &[u8]) -> std::io::Result<char> {
+    let b = read_u8(r)?;
+    let n = match b {
+        0x00..=0x7f => 0,
+        0xc0..=0xdf => 1,
+        0xe0..=0xef => 2,
+        0xf0..=0xf7 => 3,
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "invalid UTF-8 leading byte",
+            ))
+        }
+    };
+    let mut buf = [b, 0, 0, 0];
+    buf[1..=n].copy_from_slice(read_bytes(r, n)?);
+    let s = str::from_utf8(&buf[..=n])
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    s.chars()
+        .next()
+        .ok_or_else(|| std::io::ErrorKind::InvalidData.into())
+}
+
+fn read_string<'a>(r: &mut &'a [u8]) -> std::io::Result<&'a str> {
+    let n = read_len(r)?;
+    let buf = read_bytes(r, n)?;
+    str::from_utf8(buf).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
+fn invalid_discriminant(kind: &str, discriminant: usize) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("unknown {kind} discriminant `{discriminant}`"),
+    )
+}
+
+/// Formats values of WIT types encoded in `buf` in a human-readable form
+pub struct Formatter<'a> {
+    resolve: &'a Resolve,
+    out: String,
+}
+
+impl<'a> Formatter<'a> {
+    pub fn new(resolve: &'a Resolve) -> Self {
+        Self {
+            resolve,
+            out: String::default(),
+        }
+    }
+
+    fn write_value(&mut self, r: &mut &[u8], ty: &Type) -> std::io::Result<()> {
+        match ty {
+            Type::Bool => match read_u8(r)? {
+                0 => self.out.push_str("false"),
+                1 => self.out.push_str("true"),
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "invalid bool value",
+                    ))
+                }
+            },
+            Type::U8 => {
+                let v = read_u8(r)?;
+                _ = write!(self.out, "{v}");
+            }
+            Type::S8 => {
+                let v = read_u8(r)?;
+                _ = write!(self.out, "{}", v as i8);
+            }
+            Type::U16 | Type::U32 | Type::U64 => {
+                let v = read_unsigned(r)?;
+                _ = write!(self.out, "{v}");
+            }
+            Type::S16 | Type::S32 | Type::S64 => {
+                let v = read_signed(r)?;
+                _ = write!(self.out, "{v}");
+            }
+            Type::F32 => {
+                let buf = read_bytes(r, 4)?;
+                let v = f32::from_le_bytes(buf.try_into().expect("invalid f32 length"));
+                _ = write!(self.out, "{v}");
+            }
+            Type::F64 => {
+                let buf = read_bytes(r, 8)?;
+                let v = f64::from_le_bytes(buf.try_into().expect("invalid f64 length"));
+                _ = write!(self.out, "{v}");
+            }
+            Type::Char => {
+                let v = read_char(r)?;
+                _ = write!(self.out, "{v:?}");
+            }
+            Type::String => {
+                let v = read_string(r)?;
+                _ = write!(self.out, "{v:?}");
+            }
+            Type::Id(id) => {
+                let resolve = self.resolve;
+                match &resolve.types[*id].kind {
+                    TypeDefKind::Record(ty) => {
+                        self.out.push('{');
+                        for (i, Field { name, ty, .. }) in ty.fields.iter().enumerate() {
+                            if i > 0 {
+                                self.out.push_str(", ");
+                            }
+                            _ = write!(self.out, "{name}: ");
+                            self.write_value(r, ty)?;
+                        }
+                        self.out.push('}');
+                    }
+                    TypeDefKind::Tuple(ty) => {
+                        self.out.push('(');
+                        for (i, ty) in ty.types.iter().enumerate() {
+                            if i > 0 {
+                                self.out.push_str(", ");
+                            }
+                            self.write_value(r, ty)?;
+                        }
+                        self.out.push(')');
+                    }
+                    TypeDefKind::Variant(ty) => {
+                        let discriminant = read_len(r)?;
+                        let Case { name, ty, .. } = ty
+                            .cases
+                            .get(discriminant)
+                            .ok_or_else(|| invalid_discriminant("variant", discriminant))?;
+                        self.out.push_str(name);
+                        if let Some(ty) = ty {
+                            self.out.push('(');
+                            self.write_value(r, ty)?;
+                            self.out.push(')');
+                        }
+                    }
+                    TypeDefKind::Enum(ty) => {
+                        let discriminant = read_len(r)?;
+                        let case = ty
+                            .cases
+                            .get(discriminant)
+                            .ok_or_else(|| invalid_discriminant("enum", discriminant))?;
+                        self.out.push_str(&case.name);
+                    }
+                    TypeDefKind::Flags(ty) => {
+                        let n = ty.flags.len();
+                        let buf = read_bytes(r, n.div_ceil(8))?;
+                        self.out.push('{');
+                        let mut first = true;
+                        for (i, flag) in ty.flags.iter().enumerate() {
+                            if buf[i / 8] & (1 << (i % 8)) != 0 {
+                                if !first {
+                                    self.out.push_str(", ");
+                                }
+                                first = false;
+                                self.out.push_str(&flag.name);
+                            }
+                        }
+                        self.out.push('}');
+                    }
+                    TypeDefKind::Option(ty) => match read_u8(r)? {
+                        0 => self.out.push_str("none"),
+                        1 => {
+                            self.out.push_str("some(");
+                            self.write_value(r, ty)?;
+                            self.out.push(')');
+                        }
+                        discriminant => {
+                            return Err(invalid_discriminant("option", discriminant.into()))
+                        }
+                    },
+                    TypeDefKind::Result(Result_ { ok, err }) => {
+                        let (name, ty) = match read_u8(r)? {
+                            0 => ("ok", ok),
+                            1 => ("err", err),
+                            discriminant => {
+                                return Err(invalid_discriminant("result", discriminant.into()))
+                            }
+                        };
+                        self.out.push_str(name);
+                        if let Some(ty) = ty {
+                            self.out.push('(');
+                            self.write_value(r, ty)?;
+                            self.out.push(')');
+                        }
+                    }
+                    TypeDefKind::List(Type::U8) => {
+                        let n = read_len(r)?;
+                        let buf = read_bytes(r, n)?;
+                        _ = write!(self.out, "{buf:02x?}");
+                    }
+                    TypeDefKind::List(ty) => {
+                        let n = read_len(r)?;
+                        self.out.push('[');
+                        for i in 0..n {
+                            if i > 0 {
+                                self.out.push_str(", ");
+                            }
+                            self.write_value(r, ty)?;
+                        }
+                        self.out.push(']');
+                    }
+                    TypeDefKind::Future(ty) => match read_u8(r)? {
+                        0 => self.out.push_str("future(<pending>)"),
+                        1 => {
+                            self.out.push_str("future(");
+                            if let Some(ty) = ty {
+                                self.write_value(r, ty)?;
+                            }
+                            self.out.push(')');
+                        }
+                        discriminant => {
+                            return Err(invalid_discriminant("future", discriminant.into()))
+                        }
+                    },
+                    TypeDefKind::Stream(Stream { element, .. }) => {
+                        let n = read_len(r)?;
+                        if n == 0 {
+                            self.out.push_str("stream(<pending>)");
+                        } else {
+                            self.out.push_str("stream[");
+                            for i in 0..n {
+                                if i > 0 {
+                                    self.out.push_str(", ");
+                                }
+                                if let Some(ty) = element {
+                                    self.write_value(r, ty)?;
+                                }
+                            }
+                            self.out.push(']');
+                        }
+                    }
+                    TypeDefKind::Handle(Handle::Own(..) | Handle::Borrow(..)) => {
+                        let n = read_len(r)?;
+                        let buf = read_bytes(r, n)?;
+                        _ = write!(self.out, "resource({buf:02x?})");
+                    }
+                    TypeDefKind::Type(ty) => self.write_value(r, ty)?,
+                    TypeDefKind::Resource | TypeDefKind::Unknown => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "unsupported type",
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Formats a tuple of values of types `types` encoded in `buf`.
+    ///
+    /// Returns `None` if `buf` does not yet contain complete values.
+    pub fn format_values<'b>(
+        mut self,
+        types: impl IntoIterator<Item = &'b Type>,
+        mut buf: &[u8],
+    ) -> anyhow::Result<Option<String>> {
+        self.out.push('(');
+        for (i, ty) in types.into_iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            match self.write_value(&mut buf, ty) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => bail!(err),
+            }
+        }
+        self.out.push(')');
+        ensure!(
+            buf.is_empty(),
+            "{} trailing bytes after values: {buf:02x?}",
+            buf.len()
+        );
+        Ok(Some(self.out))
+    }
+}
+
+/// Formats an error string encoded in `buf`
+pub fn format_error(mut buf: &[u8]) -> anyhow::Result<String> {
+    let s = read_string(&mut buf).context("failed to decode error string")?;
+    Ok(s.to_string())
+}
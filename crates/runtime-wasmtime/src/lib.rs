@@ -466,9 +466,11 @@ where
                                                             "failed to encode input stream chunk",
                                                         )?;
                                                     w.write_all(&chunk).await?;
+                                                    w.flush().await?;
                                                 }
                                                 Err(StreamError::Closed) => {
                                                     w.write_all(&[0x00]).await?;
+                                                    w.flush().await?;
                                                 }
                                                 Err(err) => return Err(err.into()),
                                             }
@@ -492,9 +494,11 @@ where
                                                             "failed to encode input stream chunk",
                                                         )?;
                                                     w.write_all(&chunk).await?;
+                                                    w.flush().await?;
                                                 }
                                                 Err(StreamError::Closed) => {
                                                     w.write_all(&[0x00]).await?;
+                                                    w.flush().await?;
                                                 }
                                                 Err(err) => return Err(err.into()),
                                             }
@@ -552,8 +556,11 @@ async fn read_flags(n: usize, r: &mut (impl AsyncRead + Unpin)) -> std::io::Resu
 }
 
 /// Read encoded value of type [`Type`] from an [`AsyncRead`] into a [`Val`]
+///
+/// This is not part of the public API and is only exposed for fuzzing.
+#[doc(hidden)]
 #[instrument(level = "trace", skip_all, fields(ty, path))]
-async fn read_value<T, R>(
+pub async fn read_value<T, R>(
     store: &mut impl AsContextMut<Data = T>,
     r: &mut Pin<&mut R>,
     val: &mut Val,
@@ -633,6 +640,17 @@ where
         }
         Type::List(ty) => {
             let n = r.read_u32_leb128().await?;
+            let max = wrpc_transport::Limits::current().max_list_len;
+            if n > max {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    wrpc_transport::Error::LimitExceeded {
+                        limit: "list length",
+                        len: n.into(),
+                        max: max.into(),
+                    },
+                ));
+            }
             let n = n.try_into().unwrap_or(usize::MAX);
             let mut vs = Vec::with_capacity(n);
             let ty = ty.ty();
@@ -8,6 +8,8 @@ use std::process::Command;
 use wit_bindgen_core::Files;
 use wit_parser::{Resolve, WorldId};
 
+pub mod round_trip;
+
 /// Returns a suitable directory to place output for tests within.
 ///
 /// This tries to pick a location in the `target` directory that can be
@@ -0,0 +1,253 @@
+//! Property-based checks of value encoding round trips.
+//!
+//! Values are generated directly in their canonical wRPC encoding, which implementations
+//! under test are expected to decode and re-encode byte for byte.
+
+use core::fmt::Write as _;
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context as _};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::strategy::{Union, ValueTree as _};
+use proptest::test_runner::{Config, TestCaseError, TestRunner};
+use wit_parser::{PackageId, Resolve, Type, TypeDefKind};
+
+/// Maximum number of elements in generated `list` values
+const MAX_LIST_LEN: usize = 4;
+
+/// Maximum number of characters in generated `string` values
+const MAX_STRING_LEN: usize = 16;
+
+/// Number of values checked by [`check`]
+const CASES: u32 = 256;
+
+fn leb128_unsigned(mut v: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(10);
+    loop {
+        let b = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            buf.push(b);
+            return buf;
+        }
+        buf.push(b | 0x80);
+    }
+}
+
+fn leb128_signed(mut v: i64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(10);
+    loop {
+        let b = (v & 0x7f) as u8;
+        v >>= 7;
+        if (v == 0 && b & 0x40 == 0) || (v == -1 && b & 0x40 != 0) {
+            buf.push(b);
+            return buf;
+        }
+        buf.push(b | 0x80);
+    }
+}
+
+fn concat(values: Vec<BoxedStrategy<Vec<u8>>>) -> BoxedStrategy<Vec<u8>> {
+    values.prop_map(|bufs| bufs.concat()).boxed()
+}
+
+fn prefixed(prefix: Vec<u8>, value: Option<BoxedStrategy<Vec<u8>>>) -> BoxedStrategy<Vec<u8>> {
+    if let Some(value) = value {
+        value
+            .prop_map(move |buf| [prefix.as_slice(), &buf].concat())
+            .boxed()
+    } else {
+        Just(prefix).boxed()
+    }
+}
+
+/// Returns a strategy generating canonical encodings of arbitrary values of type `ty`.
+///
+/// Floating point NaN values are never generated, since implementations are not required to
+/// preserve their payloads.
+///
+/// # Panics
+///
+/// Panics if `ty` contains resources, futures or streams, which have no synchronous encoding
+#[must_use]
+pub fn value(resolve: &Resolve, ty: &Type) -> BoxedStrategy<Vec<u8>> {
+    match ty {
+        Type::Bool => any::<bool>().prop_map(|v| vec![v.into()]).boxed(),
+        Type::U8 => any::<u8>().prop_map(|v| vec![v]).boxed(),
+        Type::U16 => any::<u16>().prop_map(|v| leb128_unsigned(v.into())).boxed(),
+        Type::U32 => any::<u32>().prop_map(|v| leb128_unsigned(v.into())).boxed(),
+        Type::U64 => any::<u64>().prop_map(leb128_unsigned).boxed(),
+        Type::S8 => any::<i8>().prop_map(|v| v.to_le_bytes().to_vec()).boxed(),
+        Type::S16 => any::<i16>().prop_map(|v| leb128_signed(v.into())).boxed(),
+        Type::S32 => any::<i32>().prop_map(|v| leb128_signed(v.into())).boxed(),
+        Type::S64 => any::<i64>().prop_map(leb128_signed).boxed(),
+        Type::F32 => (prop::num::f32::POSITIVE
+            | prop::num::f32::NEGATIVE
+            | prop::num::f32::NORMAL
+            | prop::num::f32::SUBNORMAL
+            | prop::num::f32::ZERO
+            | prop::num::f32::INFINITE)
+            .prop_map(|v| v.to_le_bytes().to_vec())
+            .boxed(),
+        Type::F64 => (prop::num::f64::POSITIVE
+            | prop::num::f64::NEGATIVE
+            | prop::num::f64::NORMAL
+            | prop::num::f64::SUBNORMAL
+            | prop::num::f64::ZERO
+            | prop::num::f64::INFINITE)
+            .prop_map(|v| v.to_le_bytes().to_vec())
+            .boxed(),
+        Type::Char => any::<char>()
+            .prop_map(|v| v.to_string().into_bytes())
+            .boxed(),
+        Type::String => vec(any::<char>(), 0..=MAX_STRING_LEN)
+            .prop_map(|s| {
+                let s = String::from_iter(s);
+                let mut buf = leb128_unsigned(s.len() as u64);
+                buf.extend_from_slice(s.as_bytes());
+                buf
+            })
+            .boxed(),
+        Type::Id(id) => match &resolve.types[*id].kind {
+            TypeDefKind::Record(ty) => concat(
+                ty.fields
+                    .iter()
+                    .map(|field| value(resolve, &field.ty))
+                    .collect(),
+            ),
+            TypeDefKind::Tuple(ty) => {
+                concat(ty.types.iter().map(|ty| value(resolve, ty)).collect())
+            }
+            TypeDefKind::Flags(ty) => {
+                let n = ty.flags.len();
+                vec(any::<bool>(), n)
+                    .prop_map(move |flags| {
+                        let mut buf = vec![0; n.div_ceil(8)];
+                        for (i, set) in flags.into_iter().enumerate() {
+                            if set {
+                                buf[i / 8] |= 1 << (i % 8);
+                            }
+                        }
+                        buf
+                    })
+                    .boxed()
+            }
+            TypeDefKind::Enum(ty) => (0..ty.cases.len() as u64).prop_map(leb128_unsigned).boxed(),
+            TypeDefKind::Variant(ty) => Union::new(ty.cases.iter().enumerate().map(|(i, case)| {
+                prefixed(
+                    leb128_unsigned(i as u64),
+                    case.ty.as_ref().map(|ty| value(resolve, ty)),
+                )
+            }))
+            .boxed(),
+            TypeDefKind::Option(ty) => Union::new([
+                Just(vec![0]).boxed(),
+                prefixed(vec![1], Some(value(resolve, ty))),
+            ])
+            .boxed(),
+            TypeDefKind::Result(ty) => Union::new([
+                prefixed(vec![0], ty.ok.as_ref().map(|ty| value(resolve, ty))),
+                prefixed(vec![1], ty.err.as_ref().map(|ty| value(resolve, ty))),
+            ])
+            .boxed(),
+            TypeDefKind::List(ty) => vec(value(resolve, ty), 0..=MAX_LIST_LEN)
+                .prop_map(|vs| {
+                    let mut buf = leb128_unsigned(vs.len() as u64);
+                    for v in vs {
+                        buf.extend(v);
+                    }
+                    buf
+                })
+                .boxed(),
+            TypeDefKind::Type(ty) => value(resolve, ty),
+            TypeDefKind::Resource
+            | TypeDefKind::Handle(..)
+            | TypeDefKind::Future(..)
+            | TypeDefKind::Stream(..) => panic!(
+                "values of type `{:?}` cannot be generated",
+                resolve.types[*id].kind
+            ),
+            TypeDefKind::Unknown => unreachable!(),
+        },
+    }
+}
+
+/// Parses the WIT package at `path`
+pub fn parse(path: impl AsRef<Path>) -> anyhow::Result<(Resolve, PackageId)> {
+    let path = path.as_ref();
+    let mut resolve = Resolve::default();
+    let (pkg, _) = resolve
+        .push_path(path)
+        .with_context(|| format!("failed to parse WIT at `{}`", path.display()))?;
+    Ok((resolve, pkg))
+}
+
+/// Looks up type `name` defined in `interface` of package `pkg`
+pub fn interface_type(
+    resolve: &Resolve,
+    pkg: PackageId,
+    interface: &str,
+    name: &str,
+) -> anyhow::Result<Type> {
+    let id = resolve.packages[pkg]
+        .interfaces
+        .get(interface)
+        .with_context(|| format!("interface `{interface}` not found"))?;
+    let id = resolve.interfaces[*id]
+        .types
+        .get(name)
+        .with_context(|| format!("type `{name}` not found in interface `{interface}`"))?;
+    Ok(Type::Id(*id))
+}
+
+/// Checks that `round_trip` re-encodes canonical encodings of arbitrary values of type `ty`
+/// byte for byte.
+///
+/// `round_trip` is expected to decode a single value from its argument and return its encoding.
+pub fn check(
+    resolve: &Resolve,
+    ty: &Type,
+    round_trip: impl Fn(&[u8]) -> anyhow::Result<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let mut runner = TestRunner::new(Config {
+        cases: CASES,
+        failure_persistence: None,
+        ..Config::default()
+    });
+    runner
+        .run(&value(resolve, ty), |buf| {
+            let out = round_trip(&buf).map_err(|err| TestCaseError::fail(format!("{err:#}")))?;
+            prop_assert_eq!(out, buf);
+            Ok(())
+        })
+        .map_err(|err| anyhow!("{err}"))
+}
+
+/// Writes `n` canonical encodings of arbitrary values of type `ty` to `path`, one hex-encoded
+/// value per line
+pub fn write_corpus(
+    path: impl AsRef<Path>,
+    resolve: &Resolve,
+    ty: &Type,
+    n: usize,
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let value = value(resolve, ty);
+    let mut runner = TestRunner::deterministic();
+    let mut corpus = String::new();
+    for _ in 0..n {
+        let buf = value
+            .new_tree(&mut runner)
+            .map_err(|err| anyhow!("failed to generate value: {}", err.message()))?
+            .current();
+        for b in buf {
+            write!(corpus, "{b:02x}").expect("failed to write to string");
+        }
+        corpus.push('\n');
+    }
+    fs::write(path, corpus)
+        .with_context(|| format!("failed to write corpus to `{}`", path.display()))
+}
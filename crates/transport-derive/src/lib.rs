@@ -0,0 +1,649 @@
+//! Derive macros for `wrpc_transport::Encode` and `wrpc_transport::Decode`.
+//!
+//! Structs are encoded like WIT records, i.e. fields are encoded in declaration order.
+//! Enums are encoded like WIT variants, i.e. the case index followed by the payload, if any.
+//! Enums without payloads are encoded like WIT enums.
+//!
+//! Cases with more than a single field are encoded as a tuple payload.
+//!
+//! Use `#[wrpc(crate = "path")]` to refer to `wrpc_transport` crate by a different path.
+
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, LitStr, Path, Result, Type};
+
+/// Derives `wrpc_transport::Encode` for owned and borrowed values of a struct or enum
+#[proc_macro_derive(Encode, attributes(wrpc))]
+pub fn derive_encode(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    encode(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Derives `wrpc_transport::Decode` for a struct or enum
+#[proc_macro_derive(Decode, attributes(wrpc))]
+pub fn derive_decode(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    decode(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Returns the path to `wrpc_transport` crate
+fn crate_path(input: &DeriveInput) -> Result<Path> {
+    let mut path = syn::parse_quote!(::wrpc_transport);
+    for attr in &input.attrs {
+        if !attr.path().is_ident("wrpc") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("crate") {
+                let s: LitStr = meta.value()?.parse()?;
+                path = s.parse()?;
+                Ok(())
+            } else {
+                Err(meta.error("unsupported `wrpc` attribute"))
+            }
+        })?;
+    }
+    Ok(path)
+}
+
+fn ensure_supported(input: &DeriveInput) -> Result<()> {
+    if input.generics.params.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::new_spanned(
+            &input.generics,
+            "generic types are not supported",
+        ))
+    }
+}
+
+/// Value bindings of a set of fields
+struct Bindings<'a> {
+    fields: &'a Fields,
+    types: Vec<&'a Type>,
+    values: Vec<Ident>,
+}
+
+impl<'a> Bindings<'a> {
+    fn new(fields: &'a Fields) -> Self {
+        Self {
+            fields,
+            types: fields.iter().map(|field| &field.ty).collect(),
+            values: (0..fields.len()).map(|i| format_ident!("v_{i}")).collect(),
+        }
+    }
+
+    /// Returns the pattern (or constructor) binding fields to `values`
+    fn pattern(&self) -> TokenStream {
+        let values = &self.values;
+        match self.fields {
+            Fields::Named(fields) => {
+                let names = fields.named.iter().map(|field| &field.ident);
+                quote!({ #(#names: #values),* })
+            }
+            Fields::Unnamed(..) => quote!((#(#values),*)),
+            Fields::Unit => quote!(),
+        }
+    }
+
+    /// Returns the payload type of a variant case with these fields, if any
+    fn payload(&self, lifetime: Option<&syn::Lifetime>) -> Option<TokenStream> {
+        let types = self
+            .types
+            .iter()
+            .map(|ty| lifetime.map_or_else(|| quote!(#ty), |lt| quote!(&#lt #ty)));
+        match self.types.len() {
+            0 => None,
+            1 => types.into_iter().next(),
+            _ => Some(quote!((#(#types,)*))),
+        }
+    }
+
+    /// Returns the payload value of a variant case with these fields
+    fn payload_value(&self) -> TokenStream {
+        let values = &self.values;
+        match values.as_slice() {
+            [value] => quote!(#value),
+            _ => quote!((#(#values,)*)),
+        }
+    }
+}
+
+fn encode(input: &DeriveInput) -> Result<TokenStream> {
+    ensure_supported(input)?;
+    let krate = crate_path(input)?;
+    let name = &input.ident;
+    let vis = &input.vis;
+    let lt = syn::Lifetime::new("'__wrpc", Span::call_site());
+    let index = quote!(Send + Sync + #krate::Index<W> + 'static);
+    let io_error = quote!(::std::io::Error);
+    let bytes = quote!(#krate::__private::bytes);
+    let codec = quote!(#krate::__private::tokio_util::codec);
+    match &input.data {
+        Data::Struct(data) => {
+            let bindings = Bindings::new(&data.fields);
+            let pattern = bindings.pattern();
+            let Bindings { types, values, .. } = bindings;
+            let encoders: Vec<_> = (0..types.len()).map(|i| format_ident!("e_{i}")).collect();
+            let n = types.len();
+            Ok(quote! {
+                const _: () = {
+                    #[doc(hidden)]
+                    #vis struct __WrpcEncoder<W>
+                    where
+                        W: #index,
+                        #(#types: #krate::Encode<W>,)*
+                    {
+                        #(#encoders: <#types as #krate::Encode<W>>::Encoder,)*
+                        _ty: ::core::marker::PhantomData<W>,
+                    }
+
+                    #[automatically_derived]
+                    impl<W> ::core::default::Default for __WrpcEncoder<W>
+                    where
+                        W: #index,
+                        #(#types: #krate::Encode<W>,)*
+                    {
+                        fn default() -> Self {
+                            Self {
+                                #(#encoders: ::core::default::Default::default(),)*
+                                _ty: ::core::marker::PhantomData,
+                            }
+                        }
+                    }
+
+                    #[automatically_derived]
+                    impl<W> #krate::Deferred<W> for __WrpcEncoder<W>
+                    where
+                        W: #index,
+                        #(#types: #krate::Encode<W>,)*
+                    {
+                        fn take_deferred(&mut self) -> ::core::option::Option<#krate::DeferredFn<W>> {
+                            let deferred: [::core::option::Option<#krate::DeferredFn<W>>; #n] = [
+                                #(#krate::Deferred::<W>::take_deferred(&mut self.#encoders),)*
+                            ];
+                            if deferred.iter().any(::core::option::Option::is_some) {
+                                ::core::option::Option::Some(::std::boxed::Box::new(|w, path| {
+                                    ::std::boxed::Box::pin(#krate::handle_deferred(w, deferred, path))
+                                }))
+                            } else {
+                                ::core::option::Option::None
+                            }
+                        }
+                    }
+
+                    #[automatically_derived]
+                    impl<W> #codec::Encoder<#name> for __WrpcEncoder<W>
+                    where
+                        W: #index,
+                        #(
+                            #types: #krate::Encode<W>,
+                            #io_error: ::core::convert::From<
+                                <<#types as #krate::Encode<W>>::Encoder as #codec::Encoder<#types>>::Error,
+                            >,
+                        )*
+                    {
+                        type Error = #io_error;
+
+                        #[allow(unused_variables)]
+                        fn encode(&mut self, item: #name, dst: &mut #bytes::BytesMut) -> ::std::io::Result<()> {
+                            let #name #pattern = item;
+                            #(#codec::Encoder::<#types>::encode(&mut self.#encoders, #values, dst)?;)*
+                            ::core::result::Result::Ok(())
+                        }
+                    }
+
+                    #[automatically_derived]
+                    impl<#lt, W> #codec::Encoder<&#lt #name> for __WrpcEncoder<W>
+                    where
+                        W: #index,
+                        #(
+                            #types: #krate::Encode<W>,
+                            <#types as #krate::Encode<W>>::Encoder: #codec::Encoder<&#lt #types>,
+                            #io_error: ::core::convert::From<
+                                <<#types as #krate::Encode<W>>::Encoder as #codec::Encoder<&#lt #types>>::Error,
+                            >,
+                        )*
+                    {
+                        type Error = #io_error;
+
+                        #[allow(unused_variables)]
+                        fn encode(&mut self, item: &#lt #name, dst: &mut #bytes::BytesMut) -> ::std::io::Result<()> {
+                            let #name #pattern = item;
+                            #(#codec::Encoder::<&#lt #types>::encode(&mut self.#encoders, #values, dst)?;)*
+                            ::core::result::Result::Ok(())
+                        }
+                    }
+
+                    #[automatically_derived]
+                    impl<W> #krate::Encode<W> for #name
+                    where
+                        W: #index,
+                        #(
+                            #types: #krate::Encode<W>,
+                            #io_error: ::core::convert::From<
+                                <<#types as #krate::Encode<W>>::Encoder as #codec::Encoder<#types>>::Error,
+                            >,
+                        )*
+                    {
+                        type Encoder = __WrpcEncoder<W>;
+                    }
+
+                    #[automatically_derived]
+                    impl<#lt, W> #krate::Encode<W> for &#lt #name
+                    where
+                        W: #index,
+                        #(
+                            #types: #krate::Encode<W>,
+                            <#types as #krate::Encode<W>>::Encoder: #codec::Encoder<&#lt #types>,
+                            #io_error: ::core::convert::From<
+                                <<#types as #krate::Encode<W>>::Encoder as #codec::Encoder<&#lt #types>>::Error,
+                            >,
+                        )*
+                    {
+                        type Encoder = __WrpcEncoder<W>;
+                    }
+                };
+            })
+        }
+        Data::Enum(data) => {
+            let cases: Vec<_> = data
+                .variants
+                .iter()
+                .map(|variant| (&variant.ident, Bindings::new(&variant.fields)))
+                .collect();
+            let payloads: Vec<_> = cases
+                .iter()
+                .filter_map(|(_, bindings)| bindings.payload(None))
+                .collect();
+            let ref_payloads: Vec<_> = cases
+                .iter()
+                .filter_map(|(_, bindings)| bindings.payload(Some(&lt)))
+                .collect();
+            let mut owned_arms = vec![];
+            let mut ref_arms = vec![];
+            for (i, (case, bindings)) in cases.iter().enumerate() {
+                let i = u32::try_from(i).map_err(|_| Error::new_spanned(case, "too many cases"))?;
+                let pattern = bindings.pattern();
+                let value = bindings.payload_value();
+                for (arms, payload) in [
+                    (&mut owned_arms, bindings.payload(None)),
+                    (&mut ref_arms, bindings.payload(Some(&lt))),
+                ] {
+                    arms.push(if let Some(payload) = payload {
+                        quote! {
+                            #name::#case #pattern => {
+                                #codec::Encoder::<u32>::encode(&mut #krate::__private::wasm_tokio::Leb128Encoder, #i, dst)?;
+                                self.0 = <#payload as #krate::Encode<W>>::encode(
+                                    #value,
+                                    &mut ::core::default::Default::default(),
+                                    dst,
+                                )?;
+                                ::core::result::Result::Ok(())
+                            }
+                        }
+                    } else {
+                        quote! {
+                            #name::#case #pattern => #codec::Encoder::<u32>::encode(
+                                &mut #krate::__private::wasm_tokio::Leb128Encoder,
+                                #i,
+                                dst,
+                            ),
+                        }
+                    });
+                }
+            }
+            Ok(quote! {
+                const _: () = {
+                    #[doc(hidden)]
+                    #vis struct __WrpcEncoder<W>(::core::option::Option<#krate::DeferredFn<W>>);
+
+                    #[automatically_derived]
+                    impl<W> ::core::default::Default for __WrpcEncoder<W> {
+                        fn default() -> Self {
+                            Self(::core::option::Option::None)
+                        }
+                    }
+
+                    #[automatically_derived]
+                    impl<W> #krate::Deferred<W> for __WrpcEncoder<W> {
+                        fn take_deferred(&mut self) -> ::core::option::Option<#krate::DeferredFn<W>> {
+                            self.0.take()
+                        }
+                    }
+
+                    #[automatically_derived]
+                    impl<W> #codec::Encoder<#name> for __WrpcEncoder<W>
+                    where
+                        W: #index,
+                        #(
+                            #payloads: #krate::Encode<W>,
+                            #io_error: ::core::convert::From<
+                                <<#payloads as #krate::Encode<W>>::Encoder as #codec::Encoder<#payloads>>::Error,
+                            >,
+                        )*
+                    {
+                        type Error = #io_error;
+
+                        fn encode(&mut self, item: #name, dst: &mut #bytes::BytesMut) -> ::std::io::Result<()> {
+                            match item {
+                                #(#owned_arms)*
+                            }
+                        }
+                    }
+
+                    #[automatically_derived]
+                    impl<#lt, W> #codec::Encoder<&#lt #name> for __WrpcEncoder<W>
+                    where
+                        W: #index,
+                        #(
+                            #ref_payloads: #krate::Encode<W>,
+                            #io_error: ::core::convert::From<
+                                <<#ref_payloads as #krate::Encode<W>>::Encoder as #codec::Encoder<#ref_payloads>>::Error,
+                            >,
+                        )*
+                    {
+                        type Error = #io_error;
+
+                        fn encode(&mut self, item: &#lt #name, dst: &mut #bytes::BytesMut) -> ::std::io::Result<()> {
+                            match item {
+                                #(#ref_arms)*
+                            }
+                        }
+                    }
+
+                    #[automatically_derived]
+                    impl<W> #krate::Encode<W> for #name
+                    where
+                        W: #index,
+                        #(
+                            #payloads: #krate::Encode<W>,
+                            #io_error: ::core::convert::From<
+                                <<#payloads as #krate::Encode<W>>::Encoder as #codec::Encoder<#payloads>>::Error,
+                            >,
+                        )*
+                    {
+                        type Encoder = __WrpcEncoder<W>;
+                    }
+
+                    #[automatically_derived]
+                    impl<#lt, W> #krate::Encode<W> for &#lt #name
+                    where
+                        W: #index,
+                        #(
+                            #ref_payloads: #krate::Encode<W>,
+                            #io_error: ::core::convert::From<
+                                <<#ref_payloads as #krate::Encode<W>>::Encoder as #codec::Encoder<#ref_payloads>>::Error,
+                            >,
+                        )*
+                    {
+                        type Encoder = __WrpcEncoder<W>;
+                    }
+                };
+            })
+        }
+        Data::Union(data) => Err(Error::new(
+            data.union_token.span,
+            "unions are not supported",
+        )),
+    }
+}
+
+fn decode(input: &DeriveInput) -> Result<TokenStream> {
+    ensure_supported(input)?;
+    let krate = crate_path(input)?;
+    let name = &input.ident;
+    let vis = &input.vis;
+    let index = quote!(Send + Sync + #krate::Index<R> + 'static);
+    let io_error = quote!(::std::io::Error);
+    let bytes = quote!(#krate::__private::bytes);
+    let codec = quote!(#krate::__private::tokio_util::codec);
+    match &input.data {
+        Data::Struct(data) => {
+            let bindings = Bindings::new(&data.fields);
+            let pattern = bindings.pattern();
+            let Bindings { types, values, .. } = bindings;
+            let decoders: Vec<_> = (0..types.len()).map(|i| format_ident!("d_{i}")).collect();
+            let n = types.len();
+            Ok(quote! {
+                const _: () = {
+                    #[doc(hidden)]
+                    #vis struct __WrpcDecoder<R>
+                    where
+                        R: #index,
+                        #(#types: #krate::Decode<R>,)*
+                    {
+                        #(#decoders: <#types as #krate::Decode<R>>::Decoder,)*
+                        #(#values: ::core::option::Option<#types>,)*
+                        _ty: ::core::marker::PhantomData<R>,
+                    }
+
+                    #[automatically_derived]
+                    impl<R> ::core::default::Default for __WrpcDecoder<R>
+                    where
+                        R: #index,
+                        #(#types: #krate::Decode<R>,)*
+                    {
+                        fn default() -> Self {
+                            Self {
+                                #(#decoders: ::core::default::Default::default(),)*
+                                #(#values: ::core::option::Option::None,)*
+                                _ty: ::core::marker::PhantomData,
+                            }
+                        }
+                    }
+
+                    #[automatically_derived]
+                    impl<R> #krate::Deferred<R> for __WrpcDecoder<R>
+                    where
+                        R: #index,
+                        #(#types: #krate::Decode<R>,)*
+                    {
+                        fn take_deferred(&mut self) -> ::core::option::Option<#krate::DeferredFn<R>> {
+                            let deferred: [::core::option::Option<#krate::DeferredFn<R>>; #n] = [
+                                #(#krate::Deferred::<R>::take_deferred(&mut self.#decoders),)*
+                            ];
+                            if deferred.iter().any(::core::option::Option::is_some) {
+                                ::core::option::Option::Some(::std::boxed::Box::new(|r, path| {
+                                    ::std::boxed::Box::pin(#krate::handle_deferred(r, deferred, path))
+                                }))
+                            } else {
+                                ::core::option::Option::None
+                            }
+                        }
+                    }
+
+                    #[automatically_derived]
+                    impl<R> #codec::Decoder for __WrpcDecoder<R>
+                    where
+                        R: #index,
+                        #(
+                            #types: #krate::Decode<R>,
+                            #io_error: ::core::convert::From<
+                                <<#types as #krate::Decode<R>>::Decoder as #codec::Decoder>::Error,
+                            >,
+                        )*
+                    {
+                        type Item = #name;
+                        type Error = #io_error;
+
+                        #[allow(unused_variables)]
+                        fn decode(
+                            &mut self,
+                            src: &mut #bytes::BytesMut,
+                        ) -> ::std::io::Result<::core::option::Option<Self::Item>> {
+                            #(
+                                if self.#values.is_none() {
+                                    let ::core::option::Option::Some(v) = #codec::Decoder::decode(&mut self.#decoders, src)? else {
+                                        return ::core::result::Result::Ok(::core::option::Option::None);
+                                    };
+                                    self.#values = ::core::option::Option::Some(v);
+                                }
+                            )*
+                            #(let #values = self.#values.take().unwrap();)*
+                            ::core::result::Result::Ok(::core::option::Option::Some(#name #pattern))
+                        }
+                    }
+
+                    #[automatically_derived]
+                    impl<R> #krate::Decode<R> for #name
+                    where
+                        R: #index,
+                        #(
+                            #types: #krate::Decode<R>,
+                            #io_error: ::core::convert::From<
+                                <<#types as #krate::Decode<R>>::Decoder as #codec::Decoder>::Error,
+                            >,
+                        )*
+                    {
+                        type Decoder = __WrpcDecoder<R>;
+                        type ListDecoder = #krate::ListDecoder<Self::Decoder, R>;
+                    }
+                };
+            })
+        }
+        Data::Enum(data) => {
+            let mut payloads = vec![];
+            let mut decoders = vec![];
+            let mut arms = vec![];
+            for (i, variant) in data.variants.iter().enumerate() {
+                let i = u32::try_from(i)
+                    .map_err(|_| Error::new_spanned(&variant.ident, "too many cases"))?;
+                let case = &variant.ident;
+                let bindings = Bindings::new(&variant.fields);
+                let pattern = bindings.pattern();
+                if let Some(payload) = bindings.payload(None) {
+                    let decoder = format_ident!("d_{i}");
+                    let value = bindings.payload_value();
+                    arms.push(quote! {
+                        #i => {
+                            let dec = self.#decoder.get_or_insert_with(::core::default::Default::default);
+                            let ::core::option::Option::Some(#value) = #codec::Decoder::decode(dec, src)? else {
+                                self.disc = ::core::option::Option::Some(disc);
+                                return ::core::result::Result::Ok(::core::option::Option::None);
+                            };
+                            ::core::result::Result::Ok(::core::option::Option::Some(#name::#case #pattern))
+                        }
+                    });
+                    payloads.push(payload);
+                    decoders.push(decoder);
+                } else {
+                    arms.push(quote! {
+                        #i => ::core::result::Result::Ok(::core::option::Option::Some(#name::#case #pattern)),
+                    });
+                }
+            }
+            Ok(quote! {
+                const _: () = {
+                    #[doc(hidden)]
+                    #vis struct __WrpcDecoder<R>
+                    where
+                        R: #index,
+                        #(#payloads: #krate::Decode<R>,)*
+                    {
+                        disc: ::core::option::Option<u32>,
+                        #(#decoders: ::core::option::Option<<#payloads as #krate::Decode<R>>::Decoder>,)*
+                        _ty: ::core::marker::PhantomData<R>,
+                    }
+
+                    #[automatically_derived]
+                    impl<R> ::core::default::Default for __WrpcDecoder<R>
+                    where
+                        R: #index,
+                        #(#payloads: #krate::Decode<R>,)*
+                    {
+                        fn default() -> Self {
+                            Self {
+                                disc: ::core::option::Option::None,
+                                #(#decoders: ::core::option::Option::None,)*
+                                _ty: ::core::marker::PhantomData,
+                            }
+                        }
+                    }
+
+                    #[automatically_derived]
+                    impl<R> #krate::Deferred<R> for __WrpcDecoder<R>
+                    where
+                        R: #index,
+                        #(#payloads: #krate::Decode<R>,)*
+                    {
+                        fn take_deferred(&mut self) -> ::core::option::Option<#krate::DeferredFn<R>> {
+                            #(
+                                if let ::core::option::Option::Some(f) = self
+                                    .#decoders
+                                    .as_mut()
+                                    .and_then(#krate::Deferred::<R>::take_deferred)
+                                {
+                                    return ::core::option::Option::Some(f);
+                                }
+                            )*
+                            ::core::option::Option::None
+                        }
+                    }
+
+                    #[automatically_derived]
+                    impl<R> #codec::Decoder for __WrpcDecoder<R>
+                    where
+                        R: #index,
+                        #(
+                            #payloads: #krate::Decode<R>,
+                            #io_error: ::core::convert::From<
+                                <<#payloads as #krate::Decode<R>>::Decoder as #codec::Decoder>::Error,
+                            >,
+                        )*
+                    {
+                        type Item = #name;
+                        type Error = #io_error;
+
+                        fn decode(
+                            &mut self,
+                            src: &mut #bytes::BytesMut,
+                        ) -> ::std::io::Result<::core::option::Option<Self::Item>> {
+                            let disc = if let ::core::option::Option::Some(disc) = self.disc.take() {
+                                disc
+                            } else {
+                                let ::core::option::Option::Some(disc) = #codec::Decoder::decode(
+                                    &mut #krate::__private::wasm_tokio::Leb128DecoderU32,
+                                    src,
+                                )? else {
+                                    return ::core::result::Result::Ok(::core::option::Option::None);
+                                };
+                                disc
+                            };
+                            match disc {
+                                #(#arms)*
+                                _ => ::core::result::Result::Err(::std::io::Error::new(
+                                    ::std::io::ErrorKind::InvalidInput,
+                                    ::std::format!("unknown variant discriminant `{disc}`"),
+                                )),
+                            }
+                        }
+                    }
+
+                    #[automatically_derived]
+                    impl<R> #krate::Decode<R> for #name
+                    where
+                        R: #index,
+                        #(
+                            #payloads: #krate::Decode<R>,
+                            #io_error: ::core::convert::From<
+                                <<#payloads as #krate::Decode<R>>::Decoder as #codec::Decoder>::Error,
+                            >,
+                        )*
+                    {
+                        type Decoder = __WrpcDecoder<R>;
+                        type ListDecoder = #krate::ListDecoder<Self::Decoder, R>;
+                    }
+                };
+            })
+        }
+        Data::Union(data) => Err(Error::new(
+            data.union_token.span,
+            "unions are not supported",
+        )),
+    }
+}
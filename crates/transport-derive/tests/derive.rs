@@ -0,0 +1,341 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context as _};
+use bytes::BytesMut;
+use futures::{stream, Stream, StreamExt as _};
+use tokio::io::{duplex, AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::try_join;
+use tokio_util::codec::{Decoder as _, Encoder as _};
+use wrpc_transport::{Decode, Deferred as _, Encode, Index};
+
+struct NoopStream;
+
+impl Index<Self> for NoopStream {
+    fn index(&self, path: &[usize]) -> anyhow::Result<Self> {
+        panic!("index should not be called with path {path:?}")
+    }
+}
+
+impl AsyncRead for NoopStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for NoopStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// In-memory connection, which multiplexes byte streams by path
+#[derive(Clone, Default)]
+struct Mux(Arc<Mutex<HashMap<Vec<usize>, DuplexStream>>>);
+
+impl Mux {
+    /// Returns one end of the stream at `path`, the first call creates the stream
+    fn stream(&self, path: Vec<usize>) -> MuxStream {
+        let mut streams = self.0.lock().unwrap();
+        let io = streams.remove(&path).unwrap_or_else(|| {
+            let (a, b) = duplex(1024);
+            streams.insert(path.clone(), b);
+            a
+        });
+        MuxStream {
+            mux: self.clone(),
+            path,
+            io,
+        }
+    }
+}
+
+struct MuxStream {
+    mux: Mux,
+    path: Vec<usize>,
+    io: DuplexStream,
+}
+
+impl Index<Self> for MuxStream {
+    fn index(&self, path: &[usize]) -> anyhow::Result<Self> {
+        Ok(self.mux.stream([self.path.as_slice(), path].concat()))
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+struct Record {
+    id: u32,
+    name: String,
+    tags: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Encode, Decode)]
+pub struct Tuple(pub u8, pub bool);
+
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+struct Unit;
+
+#[derive(Debug, PartialEq, Encode, Decode)]
+enum Variant {
+    Empty,
+    Id(u32),
+    Pair(u8, String),
+    Named { record: Record, unit: Unit },
+}
+
+#[derive(Debug, PartialEq, Encode, Decode)]
+enum Enum {
+    A,
+    B,
+    C,
+}
+
+#[derive(Encode, Decode)]
+struct Async {
+    id: u32,
+    items: Pin<Box<dyn Stream<Item = u32> + Send + Sync>>,
+    done: Pin<Box<dyn Future<Output = std::io::Result<bool>> + Send + Sync>>,
+}
+
+fn encode<T: Encode<NoopStream>>(v: T) -> anyhow::Result<BytesMut>
+where
+    anyhow::Error: From<<T::Encoder as tokio_util::codec::Encoder<T>>::Error>,
+{
+    let mut buf = BytesMut::default();
+    let mut enc = T::Encoder::default();
+    enc.encode(v, &mut buf)?;
+    if enc.take_deferred().is_some() {
+        bail!("no deferred write should have been returned");
+    }
+    Ok(buf)
+}
+
+fn decode<T: Decode<NoopStream>>(mut buf: BytesMut) -> anyhow::Result<T>
+where
+    anyhow::Error: From<<T::Decoder as tokio_util::codec::Decoder>::Error>,
+{
+    let mut dec = T::Decoder::default();
+    let v = dec.decode(&mut buf)?.context("value incomplete")?;
+    if dec.take_deferred().is_some() {
+        bail!("no deferred read should have been returned");
+    }
+    assert!(buf.is_empty());
+    Ok(v)
+}
+
+#[test]
+fn record() -> anyhow::Result<()> {
+    let v = Record {
+        id: 42,
+        name: "foo".into(),
+        tags: vec!["bar".into(), "baz".into()],
+    };
+    let buf = encode(&v)?;
+    assert_eq!(
+        buf,
+        encode((42u32, "foo", vec!["bar".to_string(), "baz".to_string()]))?
+    );
+    assert_eq!(buf, encode(v.clone())?);
+    assert_eq!(decode::<Record>(buf)?, v);
+
+    let buf = encode(Tuple(0x42, true))?;
+    assert_eq!(buf.as_ref(), b"\x42\x01");
+    assert_eq!(decode::<Tuple>(buf)?, Tuple(0x42, true));
+
+    let buf = encode(Unit)?;
+    assert!(buf.is_empty());
+    assert_eq!(decode::<Unit>(buf)?, Unit);
+    Ok(())
+}
+
+#[test]
+fn variant() -> anyhow::Result<()> {
+    assert_eq!(encode(Variant::Empty)?.as_ref(), b"\x00");
+    let v = Variant::Id(42);
+    let buf = encode(&v)?;
+    assert_eq!(buf.as_ref(), b"\x01\x2a");
+    assert_eq!(decode::<Variant>(buf)?, v);
+    assert_eq!(
+        encode(Variant::Pair(0x42, "foo".into()))?.as_ref(),
+        b"\x02\x42\x03foo"
+    );
+    assert_eq!(
+        encode(&Variant::Named {
+            record: Record {
+                id: 1,
+                name: "bar".into(),
+                tags: vec![],
+            },
+            unit: Unit,
+        })?
+        .as_ref(),
+        b"\x03\x01\x03bar\x00"
+    );
+    assert_eq!(encode(Enum::C)?.as_ref(), b"\x02");
+
+    let vs = vec![
+        Variant::Named {
+            record: Record {
+                id: 1,
+                name: "bar".into(),
+                tags: vec!["baz".into()],
+            },
+            unit: Unit,
+        },
+        Variant::Id(42),
+        Variant::Empty,
+        Variant::Pair(0x42, "foo".into()),
+    ];
+    let buf = encode(&vs)?;
+    assert_eq!(decode::<Vec<Variant>>(buf)?, vs);
+    assert_eq!(
+        decode::<Vec<Enum>>(encode(vec![Enum::B, Enum::A])?)?,
+        [Enum::B, Enum::A]
+    );
+
+    let mut dec = <Variant as Decode<NoopStream>>::Decoder::default();
+    let err = dec
+        .decode(&mut BytesMut::from(&b"\x04"[..]))
+        .expect_err("decoding unknown discriminant should fail");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[tokio::test]
+async fn deferred() -> anyhow::Result<()> {
+    let mut buf = BytesMut::default();
+    let mut enc = <Async as Encode<NoopStream>>::Encoder::default();
+    enc.encode(
+        Async {
+            id: 42,
+            items: Box::pin(stream::iter([1, 2])),
+            done: Box::pin(async { Ok(true) }),
+        },
+        &mut buf,
+    )?;
+    assert_eq!(buf.as_ref(), b"\x2a\x00\x00");
+    assert!(enc.take_deferred().is_some());
+
+    let mut dec = <Async as Decode<NoopStream>>::Decoder::default();
+    let Async { id, items, done } = dec
+        .decode(&mut BytesMut::from(&b"\x2a\x02\x01\x02\x01\x01"[..]))?
+        .context("value incomplete")?;
+    assert!(dec.take_deferred().is_none());
+    assert_eq!(id, 42);
+    assert_eq!(items.collect::<Vec<_>>().await, [1, 2]);
+    assert!(done.await?);
+
+    let Async { id, .. } = dec
+        .decode(&mut BytesMut::from(&b"\x2a\x00\x00"[..]))?
+        .context("value incomplete")?;
+    assert_eq!(id, 42);
+    assert!(dec.take_deferred().is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn deferred_round_trip() -> anyhow::Result<()> {
+    let mux = Mux::default();
+    let outgoing = mux.stream(vec![]);
+    let incoming = mux.stream(vec![]);
+
+    let mut buf = BytesMut::default();
+    let mut enc = <Async as Encode<MuxStream>>::Encoder::default();
+    enc.encode(
+        Async {
+            id: 42,
+            items: Box::pin(stream::iter([1, 2, 3])),
+            done: Box::pin(async { Ok(true) }),
+        },
+        &mut buf,
+    )?;
+    let tx = enc.take_deferred().context("deferred write missing")?;
+
+    let mut dec = <Async as Decode<MuxStream>>::Decoder::default();
+    let Async { id, items, done } = dec.decode(&mut buf)?.context("value incomplete")?;
+    assert!(buf.is_empty());
+    let rx = dec.take_deferred().context("deferred read missing")?;
+    assert_eq!(id, 42);
+
+    let (items, done, (), ()) = try_join!(
+        async { Ok(items.collect::<Vec<_>>().await) },
+        done,
+        tx(outgoing.into(), Vec::with_capacity(8)),
+        rx(incoming.into(), Vec::with_capacity(8)),
+    )?;
+    assert_eq!(items, [1, 2, 3]);
+    assert!(done);
+    Ok(())
+}
+
+#[tokio::test]
+async fn deferred_closed() -> anyhow::Result<()> {
+    let mux = Mux::default();
+    let incoming = mux.stream(vec![]);
+
+    let mut dec = <Async as Decode<MuxStream>>::Decoder::default();
+    let Async { id, items, done } = dec
+        .decode(&mut BytesMut::from(&b"\x2a\x02\x01\x02\x00"[..]))?
+        .context("value incomplete")?;
+    let rx = dec.take_deferred().context("deferred read missing")?;
+    assert_eq!(id, 42);
+    assert_eq!(items.collect::<Vec<_>>().await, [1, 2]);
+
+    // close the nested future stream before the value is sent
+    drop(mux.stream(vec![2]));
+    let err = rx(incoming.into(), Vec::with_capacity(8))
+        .await
+        .expect_err("deferred read should have failed");
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    let err = done.await.expect_err("future should have failed");
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    Ok(())
+}
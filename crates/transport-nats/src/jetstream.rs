@@ -0,0 +1,350 @@
+//! Durable invocations of functions without results backed by NATS JetStream.
+//!
+//! Invocations are published to a JetStream stream and served from a durable consumer, which
+//! redelivers invocations, which were not acknowledged by the handler.
+
+use core::time::Duration;
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, ensure, Context as _};
+use async_nats::jetstream::consumer::{pull, AckPolicy};
+use async_nats::jetstream::stream::RetentionPolicy;
+use async_nats::jetstream::{self, AckKind};
+use async_nats::{HeaderMap, ServerInfo};
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt as _};
+use tokio_util::codec::{Decoder as _, Encoder as _};
+use tracing::{instrument, trace};
+use wrpc_transport::{Deferred as _, TupleDecode, TupleEncode};
+
+use crate::{headers_len, invocation_subject, InvocationContext, ParamWriter, Reader};
+
+/// Returns the subject durable invocations of `func` from `instance` are published on
+#[must_use]
+#[inline]
+pub fn durable_subject(prefix: &str, instance: &str, func: &str) -> String {
+    invocation_subject(&durable_prefix(prefix), instance, func)
+}
+
+fn durable_prefix(prefix: &str) -> String {
+    if prefix.is_empty() {
+        "durable".to_string()
+    } else {
+        format!("{prefix}.durable")
+    }
+}
+
+/// Returns a durable consumer name, which is unique for `func` from `instance` in a stream.
+///
+/// Characters other than ASCII alphanumerics are escaped as `_` followed by the hex-encoded
+/// UTF-8 bytes, so distinct `instance` and `func` pairs never map to the same name.
+fn consumer_name(instance: &str, func: &str) -> String {
+    fn escape(s: &str, name: &mut String) {
+        for b in s.bytes() {
+            if b.is_ascii_alphanumeric() {
+                name.push(char::from(b));
+            } else {
+                name.push_str(&format!("_{b:02x}"));
+            }
+        }
+    }
+    let mut name = String::with_capacity(instance.len() + func.len() + 1);
+    escape(instance, &mut name);
+    name.push('-');
+    escape(func, &mut name);
+    name
+}
+
+/// A durable invocation received from the stream.
+///
+/// Unless acknowledged using [`Invocation::ack`], the invocation is redelivered after the
+/// acknowledgement wait time of the consumer.
+pub struct Invocation(jetstream::Message);
+
+impl Invocation {
+    /// Returns the invocation context
+    #[must_use]
+    pub fn context(&self) -> InvocationContext {
+        self.0
+            .headers
+            .clone()
+            .map(InvocationContext::from)
+            .unwrap_or_default()
+    }
+
+    /// Returns the encoded invocation parameters
+    #[must_use]
+    pub fn params(&self) -> &Bytes {
+        &self.0.payload
+    }
+
+    /// Returns the number of times this invocation has been delivered
+    pub fn delivered(&self) -> anyhow::Result<i64> {
+        let info = self
+            .0
+            .info()
+            .map_err(|err| anyhow!(err).context("failed to parse message info"))?;
+        Ok(info.delivered)
+    }
+
+    /// Acknowledges the invocation, removing it from the stream
+    #[instrument(level = "trace", skip(self))]
+    pub async fn ack(&self) -> anyhow::Result<()> {
+        self.0
+            .ack()
+            .await
+            .map_err(|err| anyhow!(err).context("failed to acknowledge invocation"))
+    }
+
+    /// Negatively acknowledges the invocation, requesting redelivery after `delay`
+    #[instrument(level = "trace", skip(self))]
+    pub async fn nak(&self, delay: Option<Duration>) -> anyhow::Result<()> {
+        self.0
+            .ack_with(AckKind::Nak(delay))
+            .await
+            .map_err(|err| anyhow!(err).context("failed to negatively acknowledge invocation"))
+    }
+}
+
+/// Client for durable invocations.
+///
+/// Invocations of all functions are published to a single JetStream stream with work queue
+/// retention, which captures the [durable subjects](durable_subject) under the prefix of the
+/// client. Each served function is consumed by a durable pull consumer with explicit
+/// acknowledgement, which is named after the instance and function, therefore invocations are
+/// distributed among all servers of a function and removed from the stream once acknowledged.
+#[derive(Clone, Debug)]
+pub struct Client {
+    nats: async_nats::Client,
+    js: jetstream::Context,
+    prefix: Arc<str>,
+    stream: Arc<str>,
+    ack_wait: Option<Duration>,
+    max_deliver: Option<i64>,
+}
+
+impl Client {
+    /// Constructs a new durable invocation client and creates the JetStream stream named
+    /// `stream` capturing all durable invocations under `prefix`, if it does not exist yet
+    #[instrument(level = "trace", skip(nats, prefix, stream))]
+    pub async fn new(
+        nats: async_nats::Client,
+        prefix: impl Into<Arc<str>>,
+        stream: impl Into<Arc<str>>,
+    ) -> anyhow::Result<Self> {
+        let prefix = prefix.into();
+        let stream = stream.into();
+        let js = jetstream::new(nats.clone());
+        js.get_or_create_stream(jetstream::stream::Config {
+            name: stream.to_string(),
+            subjects: vec![format!("{}>", durable_subject(&prefix, "", ""))],
+            retention: RetentionPolicy::WorkQueue,
+            ..Default::default()
+        })
+        .await
+        .context("failed to create stream")?;
+        Ok(Self {
+            nats,
+            js,
+            prefix,
+            stream,
+            ack_wait: None,
+            max_deliver: None,
+        })
+    }
+
+    /// Redeliver invocations, which are not acknowledged within `ack_wait`
+    #[must_use]
+    pub fn with_ack_wait(self, ack_wait: Duration) -> Self {
+        Self {
+            ack_wait: Some(ack_wait),
+            ..self
+        }
+    }
+
+    /// Deliver each invocation at most `max_deliver` times
+    #[must_use]
+    pub fn with_max_deliver(self, max_deliver: i64) -> Self {
+        Self {
+            max_deliver: Some(max_deliver),
+            ..self
+        }
+    }
+
+    /// Durably invoke function `func` on instance `instance`.
+    ///
+    /// Returns once the invocation is persisted in the stream. Invocations, which exceed the
+    /// maximum payload size of the NATS server, fail with
+    /// [`PayloadTooLarge`](wrpc_transport::Error::PayloadTooLarge).
+    #[instrument(level = "trace", skip(self, cx, params), fields(params = format!("{params:02x?}")))]
+    pub async fn invoke(
+        &self,
+        cx: InvocationContext,
+        instance: &str,
+        func: &str,
+        params: Bytes,
+    ) -> anyhow::Result<()> {
+        ensure!(
+            cx.compression.is_none(),
+            "compression is not supported by durable invocations"
+        );
+        let subject = durable_subject(&self.prefix, instance, func);
+        trace!(?subject, "publishing invocation");
+        let headers = HeaderMap::from(cx);
+        let ServerInfo { max_payload, .. } = self.nats.server_info();
+        let len = if headers.iter().next().is_some() {
+            headers_len(&headers)
+        } else {
+            0
+        }
+        .saturating_add(params.len());
+        if len > max_payload {
+            return Err(wrpc_transport::Error::PayloadTooLarge {
+                len,
+                max: max_payload,
+            }
+            .into());
+        }
+        let ack = if headers.iter().next().is_some() {
+            self.js.publish_with_headers(subject, headers, params).await
+        } else {
+            self.js.publish(subject, params).await
+        }
+        .context("failed to publish invocation")?;
+        ack.await.context("failed to persist invocation")?;
+        Ok(())
+    }
+
+    /// Durably invoke function `func` on instance `instance` using typed `Params`.
+    ///
+    /// Asynchronous parameter values are not supported.
+    #[instrument(level = "trace", skip(self, cx, params))]
+    pub async fn invoke_values<Params>(
+        &self,
+        cx: InvocationContext,
+        instance: &str,
+        func: &str,
+        params: Params,
+    ) -> Result<(), wrpc_transport::Error>
+    where
+        Params: TupleEncode<ParamWriter>,
+        <Params::Encoder as tokio_util::codec::Encoder<Params>>::Error:
+            std::error::Error + Send + Sync + 'static,
+    {
+        let mut buf = BytesMut::default();
+        let mut enc = Params::Encoder::default();
+        trace!("encoding parameters");
+        enc.encode(params, &mut buf)
+            .map_err(wrpc_transport::Error::encode)?;
+        if enc.take_deferred().is_some() {
+            return Err(wrpc_transport::Error::Transport(anyhow!(
+                "asynchronous parameters are not supported by durable invocations"
+            )));
+        }
+        self.invoke(cx, instance, func, buf.freeze()).await?;
+        Ok(())
+    }
+
+    /// Serve durable invocations of function `func` from instance `instance`
+    #[instrument(level = "trace", skip(self))]
+    pub async fn serve(
+        &self,
+        instance: &str,
+        func: &str,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<Invocation>> + 'static> {
+        let stream = self
+            .js
+            .get_stream(self.stream.as_ref())
+            .await
+            .context("failed to get stream")?;
+        let name = consumer_name(instance, func);
+        let mut config = pull::Config {
+            durable_name: Some(name.clone()),
+            filter_subject: durable_subject(&self.prefix, instance, func),
+            ack_policy: AckPolicy::Explicit,
+            ..Default::default()
+        };
+        if let Some(ack_wait) = self.ack_wait {
+            config.ack_wait = ack_wait;
+        }
+        if let Some(max_deliver) = self.max_deliver {
+            config.max_deliver = max_deliver;
+        }
+        let consumer = stream
+            .get_or_create_consumer(&name, config)
+            .await
+            .context("failed to create consumer")?;
+        let messages = consumer
+            .messages()
+            .await
+            .context("failed to consume invocations")?;
+        Ok(messages.map(|msg| msg.map(Invocation).context("failed to receive invocation")))
+    }
+
+    /// Serve durable invocations of function `func` from instance `instance` using typed
+    /// `Params`.
+    ///
+    /// Invocations, which fail to decode, are terminated and never redelivered.
+    #[instrument(level = "trace", skip(self))]
+    pub async fn serve_values<Params>(
+        &self,
+        instance: &str,
+        func: &str,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<(Invocation, Params)>> + 'static>
+    where
+        Params: TupleDecode<Reader> + 'static,
+        <Params::Decoder as tokio_util::codec::Decoder>::Error:
+            std::error::Error + Send + Sync + 'static,
+    {
+        let invocations = self.serve(instance, func).await?;
+        Ok(invocations.then(|inv| async move {
+            let inv = inv?;
+            match decode_params::<Params>(inv.params()) {
+                Ok(params) => Ok((inv, params)),
+                Err(err) => {
+                    inv.0
+                        .ack_with(AckKind::Term)
+                        .await
+                        .map_err(|err| anyhow!(err).context("failed to terminate invocation"))?;
+                    Err(err)
+                }
+            }
+        }))
+    }
+}
+
+fn decode_params<Params>(params: &Bytes) -> anyhow::Result<Params>
+where
+    Params: TupleDecode<Reader>,
+    <Params::Decoder as tokio_util::codec::Decoder>::Error:
+        std::error::Error + Send + Sync + 'static,
+{
+    let mut buf = BytesMut::from(params.as_ref());
+    let mut dec = Params::Decoder::default();
+    let Some(params) = dec
+        .decode_eof(&mut buf)
+        .context("failed to decode parameters")?
+    else {
+        bail!("incomplete parameters")
+    };
+    if dec.take_deferred().is_some() {
+        bail!("asynchronous parameters are not supported by durable invocations")
+    }
+    Ok(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consumer_names() {
+        assert_eq!(
+            consumer_name("wrpc-test:integration/durable", "run"),
+            "wrpc_2dtest_3aintegration_2fdurable-run"
+        );
+        assert_ne!(consumer_name("a.b", "c"), consumer_name("a_b", "c"));
+        assert_ne!(consumer_name("a-b", "c"), consumer_name("a", "b-c"));
+    }
+}
@@ -1,28 +1,341 @@
 #![allow(clippy::type_complexity)]
 
+pub mod jetstream;
+
+mod scatter;
+
 use core::future::Future;
 use core::iter::zip;
 use core::pin::{pin, Pin};
-use core::task::{ready, Context, Poll};
-use core::{mem, str};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{ready, Context, Poll, Waker};
+use core::time::Duration;
+use core::{fmt, mem, str};
 
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use anyhow::{anyhow, ensure, Context as _};
+use anyhow::{anyhow, bail, ensure, Context as _};
 use async_nats::client::Publisher;
-use async_nats::{HeaderMap, Message, ServerInfo, StatusCode, Subject, Subscriber};
-use bytes::{Buf as _, Bytes};
+use async_nats::header::{IntoHeaderName, IntoHeaderValue};
+use async_nats::{HeaderMap, Message, ServerInfo, StatusCode, Subject};
+use bytes::{Buf as _, BufMut as _, Bytes, BytesMut};
 use futures::future::try_join_all;
 use futures::sink::SinkExt as _;
 use futures::{Stream, StreamExt};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tokio::sync::oneshot;
+use tokio::sync::{oneshot, Notify};
+use tokio::time::{sleep, sleep_until, Instant, Sleep};
 use tokio::try_join;
+use tokio_util::codec::{Decoder as _, Encoder as _};
 use tracing::{instrument, trace, warn};
-use wrpc_transport::Index as _;
+use wasm_tokio::{CoreNameDecoder, CoreNameEncoder};
+use wrpc_transport::compress::{Compression, CompressionContext, CompressionTransport};
+use wrpc_transport::limits::Scope;
+use wrpc_transport::otel::TraceContext;
+use wrpc_transport::{Index as _, Limits, Reject as _};
 
 pub const PROTOCOL: &str = "wrpc.0.0.1";
 
+/// Header carrying the invocation deadline as milliseconds since Unix epoch
+pub const DEADLINE_HEADER: &str = "wrpc-deadline";
+
+/// Header marking a message as a cancellation of the invocation by the peer
+pub const CANCEL_HEADER: &str = "wrpc-cancel";
+
+/// Header enabling flow control for all streams of the invocation, the value is the window
+/// size in messages, which may be sent before being acknowledged by the receiver
+pub const FLOW_CONTROL_HEADER: &str = "wrpc-flow-control";
+
+/// W3C trace context `traceparent` header
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// W3C trace context `tracestate` header
+pub const TRACESTATE_HEADER: &str = "tracestate";
+
+/// Header carrying the identity of the caller
+pub const CALLER_HEADER: &str = "wrpc-caller";
+
+/// Header carrying the version of the content encoded in the invocation
+pub const CONTENT_VERSION_HEADER: &str = "wrpc-content-version";
+
+/// Header marking an invocation as scatter-gather, see [`Client::scatter`]
+pub const SCATTER_HEADER: &str = "wrpc-scatter";
+
+/// Header carrying the compression algorithm applied to all streams of the invocation,
+/// see [`wrpc_transport::compress`]
+pub const COMPRESSION_HEADER: &str = "wrpc-compression";
+
+/// Length of the sequence number prefixed to payloads of flow-controlled streams
+const SEQUENCE_LEN: usize = 8;
+
+/// Sets the invocation deadline in `headers`
+fn set_deadline(headers: &mut HeaderMap, deadline: SystemTime) {
+    let ms = deadline
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    headers.insert(DEADLINE_HEADER, ms.to_string().as_str());
+}
+
+/// Returns the invocation deadline set in `headers`, if any
+#[must_use]
+fn deadline(headers: &HeaderMap) -> Option<SystemTime> {
+    let ms = headers.get(DEADLINE_HEADER)?.as_str().parse().ok()?;
+    UNIX_EPOCH.checked_add(Duration::from_millis(ms))
+}
+
+/// Enables flow control with a window of `window` messages for the invocation in `headers`
+fn set_flow_control(headers: &mut HeaderMap, window: u32) {
+    headers.insert(FLOW_CONTROL_HEADER, window.to_string().as_str());
+}
+
+/// Returns the flow control window set in `headers`, if any
+#[must_use]
+fn flow_control(headers: &HeaderMap) -> Option<u32> {
+    headers
+        .get(FLOW_CONTROL_HEADER)?
+        .as_str()
+        .parse()
+        .ok()
+        .filter(|window| *window > 0)
+}
+
+/// Sets the compression algorithm of the invocation in `headers`
+fn set_compression(headers: &mut HeaderMap, compression: Compression) {
+    headers.insert(COMPRESSION_HEADER, compression.as_str());
+}
+
+/// Returns the compression algorithm set in `headers`, if any
+#[must_use]
+fn compression(headers: &HeaderMap) -> Option<Compression> {
+    headers.get(COMPRESSION_HEADER)?.as_str().parse().ok()
+}
+
+/// NATS invocation context, transmitted to the server as message headers
+#[derive(Clone, Debug, Default)]
+pub struct InvocationContext {
+    /// Invocation deadline
+    pub deadline: Option<SystemTime>,
+    /// W3C trace context `traceparent`
+    pub traceparent: Option<String>,
+    /// W3C trace context `tracestate`
+    pub tracestate: Option<String>,
+    /// Identity of the caller
+    pub caller: Option<String>,
+    /// Version of the content encoded in the invocation
+    pub content_version: Option<String>,
+    /// Flow control window in messages, see [`FLOW_CONTROL_HEADER`]
+    pub flow_control: Option<u32>,
+    /// Compression algorithm, see [`COMPRESSION_HEADER`]
+    pub compression: Option<Compression>,
+    /// Custom headers
+    pub headers: HeaderMap,
+}
+
+impl InvocationContext {
+    /// Sets the invocation deadline
+    #[must_use]
+    pub fn with_deadline(self, deadline: SystemTime) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..self
+        }
+    }
+
+    /// Sets the invocation deadline `timeout` from now
+    #[must_use]
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            deadline: SystemTime::now().checked_add(timeout),
+            ..self
+        }
+    }
+
+    /// Sets the W3C trace context
+    #[must_use]
+    pub fn with_trace_context(
+        self,
+        traceparent: impl Into<String>,
+        tracestate: Option<String>,
+    ) -> Self {
+        Self {
+            traceparent: Some(traceparent.into()),
+            tracestate,
+            ..self
+        }
+    }
+
+    /// Sets the identity of the caller
+    #[must_use]
+    pub fn with_caller(self, caller: impl Into<String>) -> Self {
+        Self {
+            caller: Some(caller.into()),
+            ..self
+        }
+    }
+
+    /// Sets the version of the content encoded in the invocation
+    #[must_use]
+    pub fn with_content_version(self, version: impl Into<String>) -> Self {
+        Self {
+            content_version: Some(version.into()),
+            ..self
+        }
+    }
+
+    /// Enables flow control with a window of `window` messages
+    #[must_use]
+    pub fn with_flow_control(self, window: u32) -> Self {
+        Self {
+            flow_control: Some(window),
+            ..self
+        }
+    }
+
+    /// Sets the compression algorithm applied to all streams of the invocation.
+    ///
+    /// Streams are only compressed by clients wrapped in `wrpc_transport::compress::Compress`,
+    /// invocations using a compression algorithm fail otherwise. Servers not wrapped in
+    /// `Compress` reject such invocations.
+    #[must_use]
+    pub fn with_compression(self, compression: Compression) -> Self {
+        Self {
+            compression: Some(compression),
+            ..self
+        }
+    }
+
+    /// Sets a custom header, well-known wRPC headers take precedence over custom ones
+    #[must_use]
+    pub fn with_header(mut self, name: impl IntoHeaderName, value: impl IntoHeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+}
+
+impl From<InvocationContext> for HeaderMap {
+    fn from(
+        InvocationContext {
+            deadline,
+            traceparent,
+            tracestate,
+            caller,
+            content_version,
+            flow_control,
+            compression,
+            mut headers,
+        }: InvocationContext,
+    ) -> Self {
+        if let Some(deadline) = deadline {
+            set_deadline(&mut headers, deadline);
+        }
+        if let Some(traceparent) = traceparent {
+            headers.insert(TRACEPARENT_HEADER, traceparent.as_str());
+        }
+        if let Some(tracestate) = tracestate {
+            headers.insert(TRACESTATE_HEADER, tracestate.as_str());
+        }
+        if let Some(caller) = caller {
+            headers.insert(CALLER_HEADER, caller.as_str());
+        }
+        if let Some(version) = content_version {
+            headers.insert(CONTENT_VERSION_HEADER, version.as_str());
+        }
+        if let Some(window) = flow_control {
+            set_flow_control(&mut headers, window);
+        }
+        if let Some(compression) = compression {
+            set_compression(&mut headers, compression);
+        }
+        headers
+    }
+}
+
+impl From<HeaderMap> for InvocationContext {
+    fn from(headers: HeaderMap) -> Self {
+        let header = |name: &str| headers.get(name).map(|value| value.as_str().to_string());
+        let mut custom = HeaderMap::new();
+        for (name, values) in headers.iter() {
+            let name_bytes: &[u8] = name.as_ref();
+            if [
+                DEADLINE_HEADER,
+                TRACEPARENT_HEADER,
+                TRACESTATE_HEADER,
+                CALLER_HEADER,
+                CONTENT_VERSION_HEADER,
+                FLOW_CONTROL_HEADER,
+                COMPRESSION_HEADER,
+            ]
+            .iter()
+            .any(|known| known.as_bytes() == name_bytes)
+            {
+                continue;
+            }
+            for value in values {
+                custom.append(name.clone(), value.clone());
+            }
+        }
+        Self {
+            deadline: deadline(&headers),
+            traceparent: header(TRACEPARENT_HEADER),
+            tracestate: header(TRACESTATE_HEADER),
+            caller: header(CALLER_HEADER),
+            content_version: header(CONTENT_VERSION_HEADER),
+            flow_control: flow_control(&headers),
+            compression: compression(&headers),
+            headers: custom,
+        }
+    }
+}
+
+impl CompressionContext for InvocationContext {
+    fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+}
+
+impl TraceContext for InvocationContext {
+    fn trace_context(&self) -> Option<(&str, Option<&str>)> {
+        let traceparent = self.traceparent.as_deref()?;
+        Some((traceparent, self.tracestate.as_deref()))
+    }
+
+    fn set_trace_context(&mut self, traceparent: String, tracestate: Option<String>) {
+        self.traceparent = Some(traceparent);
+        self.tracestate = tracestate;
+    }
+}
+
+/// Returns the encoded size of `headers` in a NATS message
+fn headers_len(headers: &HeaderMap) -> usize {
+    // based on https://github.com/nats-io/nats.rs/blob/0942c473ce56163fdd1fbc62762f8164e3afa7bf/async-nats/src/header.rs#L215-L224
+    let mut len = b"NATS/1.0\r\n".len() + b"\r\n".len();
+    for (k, vs) in headers.iter() {
+        let k: &[u8] = k.as_ref();
+        for v in vs {
+            len = len
+                .saturating_add(k.len())
+                .saturating_add(b": ".len())
+                .saturating_add(v.as_str().len())
+                .saturating_add(b"\r\n".len());
+        }
+    }
+    len
+}
+
+fn deadline_instant(deadline: SystemTime) -> Instant {
+    let timeout = deadline
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+    Instant::now() + timeout
+}
+
+fn deadline_exceeded_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, "invocation deadline exceeded")
+}
+
 #[must_use]
 #[inline]
 pub fn param_subject(prefix: &str) -> String {
@@ -35,6 +348,18 @@ pub fn result_subject(prefix: &str) -> String {
     format!("{prefix}.results")
 }
 
+#[must_use]
+#[inline]
+pub fn error_subject(prefix: &str) -> String {
+    format!("{prefix}.error")
+}
+
+#[must_use]
+#[inline]
+pub fn ack_subject(prefix: &str) -> String {
+    format!("{prefix}.ack")
+}
+
 #[must_use]
 #[inline]
 pub fn index_path(prefix: &str, path: &[usize]) -> String {
@@ -90,14 +415,413 @@ pub fn invocation_subject(prefix: &str, instance: &str, func: &str) -> String {
     s
 }
 
+/// Maps wRPC invocation targets to NATS subjects
+pub trait SubjectLayout: fmt::Debug + Send + Sync {
+    /// Returns the subject invocations of `func` from `instance` are published on
+    fn invocation_subject(&self, prefix: &str, instance: &str, func: &str) -> String {
+        invocation_subject(prefix, instance, func)
+    }
+}
+
+/// Default subject layout `{prefix}.wrpc.0.0.1.{instance}.{func}`, see [`invocation_subject`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultLayout;
+
+impl SubjectLayout for DefaultLayout {}
+
+/// Subject layout, which replaces `:`, `/`, `@` and `.` in instance names by `_`, such that
+/// each instance maps to a single subject token, e.g. `wasi:http/handler@0.2.0` is mapped to
+/// `wasi_http_handler_0_2_0`.
+///
+/// Note, that distinct instance names may map to the same subject token.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EscapedLayout;
+
+impl SubjectLayout for EscapedLayout {
+    fn invocation_subject(&self, prefix: &str, instance: &str, func: &str) -> String {
+        let instance = instance.replace([':', '/', '@', '.'], "_");
+        invocation_subject(prefix, &instance, func)
+    }
+}
+
+/// Returns a new unique inbox subject, using `prefix` instead of the client inbox prefix if set
+fn new_inbox(nats: &async_nats::Client, prefix: Option<&str>) -> String {
+    let inbox = nats.new_inbox();
+    let Some(prefix) = prefix else {
+        return inbox;
+    };
+    let id = inbox.rsplit_once('.').map_or(inbox.as_str(), |(_, id)| id);
+    format!("{prefix}.{id}")
+}
+
+/// NATS subscription accounted for in the live subscription gauge of the [`Client`], which
+/// created it. The subscription is unsubscribed from once dropped.
+#[derive(Debug)]
+pub struct Subscriber {
+    sub: async_nats::Subscriber,
+    live: Arc<AtomicUsize>,
+}
+
+impl Subscriber {
+    fn new(sub: async_nats::Subscriber, live: &Arc<AtomicUsize>) -> Self {
+        live.fetch_add(1, Ordering::Relaxed);
+        Self {
+            sub,
+            live: Arc::clone(live),
+        }
+    }
+
+    async fn subscribe(
+        nats: &async_nats::Client,
+        live: &Arc<AtomicUsize>,
+        subject: Subject,
+    ) -> anyhow::Result<Self> {
+        let sub = nats.subscribe(subject).await?;
+        Ok(Self::new(sub, live))
+    }
+}
+
+impl Stream for Subscriber {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.sub.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.live.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 fn corrupted_memory_error() -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::Other, "corrupted memory state")
 }
 
+/// Root stream subject of the peer, which is only known once the invocation handshake completes
+#[derive(Debug, Default)]
+pub struct Peer {
+    subject: OnceLock<Subject>,
+    notify: Notify,
+}
+
+impl Peer {
+    fn get(&self) -> Option<&Subject> {
+        self.subject.get()
+    }
+
+    /// Sets the subject, waking all tasks waiting for it
+    fn set(&self, subject: Subject) {
+        if self.subject.set(subject).is_ok() {
+            self.notify.notify_waiters();
+        }
+    }
+
+    /// Waits until the subject is known
+    async fn wait(&self) -> &Subject {
+        loop {
+            let mut notified = pin!(self.notify.notified());
+            notified.as_mut().enable();
+            if let Some(subject) = self.subject.get() {
+                return subject;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl From<Subject> for Peer {
+    fn from(subject: Subject) -> Self {
+        Self {
+            subject: OnceLock::from(subject),
+            notify: Notify::default(),
+        }
+    }
+}
+
+/// Signals cancellation of the invocation to the peer on drop, unless disarmed
+#[derive(Debug)]
+struct Canceller {
+    nats: Arc<async_nats::Client>,
+    tx: Arc<Peer>,
+    armed: bool,
+}
+
+impl Canceller {
+    fn new(nats: Arc<async_nats::Client>, tx: Arc<Peer>) -> Self {
+        Self {
+            nats,
+            tx,
+            armed: true,
+        }
+    }
+}
+
+impl Drop for Canceller {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let Some(tx) = self.tx.get() else {
+            return;
+        };
+        let Ok(rt) = tokio::runtime::Handle::try_current() else {
+            warn!(
+                ?tx,
+                "no runtime available, cannot signal invocation cancellation"
+            );
+            return;
+        };
+        let nats = Arc::clone(&self.nats);
+        let tx = tx.clone();
+        trace!(?tx, "signaling invocation cancellation");
+        rt.spawn(async move {
+            let mut headers = HeaderMap::new();
+            headers.insert(CANCEL_HEADER, "1");
+            if let Err(err) = nats
+                .publish_with_headers(tx, headers, Bytes::default())
+                .await
+            {
+                warn!(?err, "failed to signal invocation cancellation");
+            }
+        });
+    }
+}
+
+/// Tracks acknowledgements received from the peer for flow-controlled streams of an invocation
+#[derive(Debug)]
+struct Acks {
+    sub: Subscriber,
+    /// Subject prefix of acknowledgements, the remainder of the subject is the stream key
+    prefix: String,
+    acked: HashMap<Box<str>, u64>,
+    wakers: HashMap<Box<str>, Waker>,
+    done: bool,
+}
+
+impl Acks {
+    fn new(sub: Subscriber, rx: &str) -> Self {
+        Self {
+            sub,
+            prefix: format!("{}.", ack_subject(rx)),
+            acked: HashMap::default(),
+            wakers: HashMap::default(),
+            done: false,
+        }
+    }
+
+    /// Wakes all writers waiting for credit, one of them will poll the subscription next
+    fn wake_all(&mut self) {
+        for (_, waker) in self.wakers.drain() {
+            waker.wake();
+        }
+    }
+
+    /// Polls until message `seq` of stream `key` fits in the `window` of unacknowledged messages
+    #[instrument(level = "trace", skip(self, cx))]
+    fn poll_credit(
+        &mut self,
+        cx: &mut Context<'_>,
+        key: &str,
+        seq: u64,
+        window: u64,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            let acked = self.acked.get(key).copied().unwrap_or_default();
+            if seq < acked.saturating_add(window) {
+                self.wake_all();
+                return Poll::Ready(Ok(()));
+            }
+            if self.done {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "acknowledgement subscription finished",
+                )));
+            }
+            match self.sub.poll_next_unpin(cx) {
+                Poll::Ready(Some(Message {
+                    subject, payload, ..
+                })) => {
+                    let Some(msg_key) = subject.strip_prefix(self.prefix.as_str()) else {
+                        warn!(?subject, "received acknowledgement on unexpected subject");
+                        continue;
+                    };
+                    let Some(n) = str::from_utf8(&payload)
+                        .ok()
+                        .and_then(|n| n.parse::<u64>().ok())
+                    else {
+                        warn!(?subject, ?payload, "failed to parse acknowledgement");
+                        continue;
+                    };
+                    trace!(key = msg_key, n, "received acknowledgement");
+                    let acked = self.acked.entry(msg_key.into()).or_default();
+                    *acked = n.max(*acked);
+                    if let Some(waker) = self.wakers.remove(msg_key) {
+                        waker.wake();
+                    }
+                }
+                Poll::Ready(None) => {
+                    trace!("acknowledgement subscription finished");
+                    self.done = true;
+                    self.wake_all();
+                }
+                Poll::Pending => {
+                    trace!(acked, "waiting for credit");
+                    self.wakers.insert(key.into(), cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+/// Sending half of a flow-controlled stream
+#[derive(Clone, Debug)]
+struct FlowSender {
+    acks: Arc<std::sync::Mutex<Acks>>,
+    key: String,
+    window: u64,
+    seq: u64,
+}
+
+impl FlowSender {
+    fn new(acks: Arc<std::sync::Mutex<Acks>>, key: &str, window: u32) -> Self {
+        Self {
+            acks,
+            key: key.to_string(),
+            window: window.into(),
+            seq: 0,
+        }
+    }
+
+    fn index(&self, path: &[usize]) -> Self {
+        Self {
+            acks: Arc::clone(&self.acks),
+            key: index_path(&self.key, path),
+            window: self.window,
+            seq: 0,
+        }
+    }
+
+    fn poll_credit(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut acks = self
+            .acks
+            .lock()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))?;
+        acks.poll_credit(cx, &self.key, self.seq, self.window)
+    }
+}
+
+/// Receiving half of a flow-controlled stream, which detects gaps in the sequence of
+/// received messages and grants credit to the peer
+struct FlowReceiver {
+    nats: Arc<async_nats::Client>,
+    /// Root stream subject of the peer, acknowledgements are sent to the peer's inbox
+    peer: Arc<Peer>,
+    kind: &'static str,
+    key: String,
+    window: u64,
+    next: u64,
+    unacked: u64,
+}
+
+impl FlowReceiver {
+    fn new(
+        nats: Arc<async_nats::Client>,
+        peer: Arc<Peer>,
+        kind: &'static str,
+        window: u32,
+    ) -> Self {
+        Self {
+            nats,
+            peer,
+            kind,
+            key: kind.to_string(),
+            window: window.into(),
+            next: 0,
+            unacked: 0,
+        }
+    }
+
+    /// Constructs a receiver for the stream at absolute `path`
+    fn index(&self, path: &[usize]) -> Self {
+        Self {
+            nats: Arc::clone(&self.nats),
+            peer: Arc::clone(&self.peer),
+            kind: self.kind,
+            key: index_path(self.kind, path),
+            window: self.window,
+            next: 0,
+            unacked: 0,
+        }
+    }
+
+    /// Strips the sequence number from `payload`, acknowledging the message if necessary
+    fn receive(&mut self, payload: &mut Bytes) -> std::io::Result<()> {
+        if payload.len() < SEQUENCE_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "flow-controlled message is missing a sequence number",
+            ));
+        }
+        let seq = payload.get_u64();
+        if seq != self.next {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "gap in stream detected, expected message {}, got {seq}",
+                    self.next
+                ),
+            ));
+        }
+        self.next = seq.saturating_add(1);
+        self.unacked = self.unacked.saturating_add(1);
+        if payload.is_empty() || self.unacked >= (self.window / 2).max(1) {
+            self.ack();
+        }
+        Ok(())
+    }
+
+    /// Acknowledges all messages received so far. If the peer inbox is not known yet, the
+    /// acknowledgement is sent once the invocation handshake completes.
+    fn ack(&mut self) {
+        let Ok(rt) = tokio::runtime::Handle::try_current() else {
+            warn!("no runtime available, cannot acknowledge messages");
+            return;
+        };
+        let nats = Arc::clone(&self.nats);
+        let peer = Arc::clone(&self.peer);
+        let key = self.key.clone();
+        let n = self.next;
+        if peer.get().is_none() {
+            trace!(n, "peer inbox not known yet, postponing acknowledgement");
+        }
+        rt.spawn(async move {
+            let Some((inbox, _)) = peer.wait().await.as_str().rsplit_once('.') else {
+                warn!("peer subject is not a valid stream subject, cannot acknowledge messages");
+                return;
+            };
+            let tx = Subject::from(format!("{}.{key}", ack_subject(inbox)));
+            trace!(?tx, n, "acknowledging messages");
+            if let Err(err) = nats.publish(tx, Bytes::from(n.to_string())).await {
+                warn!(?err, "failed to acknowledge messages");
+            }
+        });
+        self.unacked = 0;
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Client {
     nats: Arc<async_nats::Client>,
     prefix: Arc<str>,
+    queue_group: Option<Arc<str>>,
+    handshake_timeout: Option<Duration>,
+    inbox_prefix: Option<Arc<str>>,
+    layout: Arc<dyn SubjectLayout>,
+    subscriptions: Arc<AtomicUsize>,
+    compressed: bool,
 }
 
 impl Client {
@@ -105,12 +829,80 @@ impl Client {
         Self {
             nats: nats.into(),
             prefix: prefix.into(),
+            queue_group: None,
+            handshake_timeout: None,
+            inbox_prefix: None,
+            layout: Arc::new(DefaultLayout),
+            subscriptions: Arc::default(),
+            compressed: false,
+        }
+    }
+
+    /// Returns the number of NATS subscriptions created by this client and its clones, which
+    /// are currently live
+    #[must_use]
+    pub fn live_subscriptions(&self) -> usize {
+        self.subscriptions.load(Ordering::Relaxed)
+    }
+
+    /// Use `prefix` for inbox subjects of invocations instead of the inbox prefix of the
+    /// NATS client.
+    ///
+    /// Both clients and servers subscribe on subjects under their inbox, e.g. `{prefix}.>`
+    /// permissions are sufficient.
+    #[must_use]
+    pub fn with_inbox_prefix(self, prefix: impl Into<Arc<str>>) -> Self {
+        Self {
+            inbox_prefix: Some(prefix.into()),
+            ..self
+        }
+    }
+
+    /// Map invocation targets to subjects using `layout`, by default [`DefaultLayout`] is used
+    #[must_use]
+    pub fn with_subject_layout(self, layout: impl SubjectLayout + 'static) -> Self {
+        Self {
+            layout: Arc::new(layout),
+            ..self
+        }
+    }
+
+    /// Fail invocations, which are not accepted by a server within `timeout`.
+    ///
+    /// By default, invocations wait for a server to accept them indefinitely, unless NATS
+    /// reports that there are no servers subscribed to the invocation subject.
+    #[must_use]
+    pub fn with_handshake_timeout(self, timeout: Duration) -> Self {
+        Self {
+            handshake_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Serve invocations as a member of NATS queue group `group`.
+    ///
+    /// Each invocation is delivered to a single member of the group, only that member
+    /// responds to the handshake.
+    #[must_use]
+    pub fn with_queue_group(self, group: impl Into<Arc<str>>) -> Self {
+        Self {
+            queue_group: Some(group.into()),
+            ..self
+        }
+    }
+}
+
+impl CompressionTransport for Client {
+    fn with_compression_enabled(self) -> Self {
+        Self {
+            compressed: true,
+            ..self
         }
     }
 }
 
 #[derive(Debug)]
-pub struct ByteSubscription(Subscriber);
+pub struct ByteSubscription(async_nats::Subscriber);
 
 impl Stream for ByteSubscription {
     type Item = std::io::Result<Bytes>;
@@ -125,23 +917,223 @@ impl Stream for ByteSubscription {
     }
 }
 
+/// Demultiplexes messages received on a wildcard subscription by the concrete path parsed
+/// from the message subject.
+///
+/// Messages for paths, which are not currently read, are buffered. Wildcard indices are
+/// bounded by [`Limits::max_list_len`] and buffered messages are accounted against
+/// [`Limits::max_invocation_size`] when received.
+struct Demux {
+    sub: Subscriber,
+    /// Subscribed path, the trailing subject tokens of messages correspond to its elements
+    pattern: Box<[Option<usize>]>,
+    queues: HashMap<Box<[usize]>, VecDeque<Message>>,
+    /// Paths claimed by live readers
+    claimed: HashSet<Box<[usize]>>,
+    /// Paths, whose readers were released, messages for these are discarded
+    released: HashSet<Box<[usize]>>,
+    wakers: HashMap<Box<[usize]>, Waker>,
+    done: bool,
+}
+
+impl Demux {
+    fn new(sub: Subscriber, pattern: &[Option<usize>]) -> Self {
+        Self {
+            sub,
+            pattern: pattern.into(),
+            queues: HashMap::default(),
+            claimed: HashSet::default(),
+            released: HashSet::default(),
+            wakers: HashMap::default(),
+            done: false,
+        }
+    }
+
+    fn parse_path(&self, subject: &str) -> Option<Box<[usize]>> {
+        let depth = self.pattern.len();
+        let mut path = subject
+            .rsplit('.')
+            .take(depth)
+            .map(|p| p.parse().ok())
+            .collect::<Option<Vec<usize>>>()?;
+        if path.len() != depth {
+            return None;
+        }
+        path.reverse();
+        Some(path.into())
+    }
+
+    /// Checks that a reader can claim `path` within the limits of `scope` and accounts for
+    /// the received `msg`
+    fn receive(&self, path: &[usize], msg: &Message, scope: &Scope) -> std::io::Result<()> {
+        for (pattern, i) in zip(self.pattern.iter(), path) {
+            if pattern.is_none() {
+                scope.limits().check_list_len(i.saturating_add(1))?;
+            }
+        }
+        scope.receive(msg.payload.len())
+    }
+
+    /// Claims `path` for a reader, returns `false` if it was already claimed before
+    fn claim(&mut self, path: &[usize]) -> bool {
+        !self.released.contains(path) && self.claimed.insert(path.into())
+    }
+
+    /// Drops messages buffered for the reader at `path`, which was released
+    fn release(&mut self, path: &[usize]) {
+        self.claimed.remove(path);
+        self.queues.remove(path);
+        self.wakers.remove(path);
+        self.released.insert(path.into());
+    }
+
+    /// Wakes all readers waiting for messages, one of them will poll the subscription next
+    fn wake_all(&mut self) {
+        for (_, waker) in self.wakers.drain() {
+            waker.wake();
+        }
+    }
+
+    #[instrument(level = "trace", skip(self, cx, scope))]
+    fn poll_next(
+        &mut self,
+        cx: &mut Context<'_>,
+        path: &[usize],
+        scope: &Scope,
+    ) -> Poll<Option<std::io::Result<Message>>> {
+        loop {
+            if let Some(msg) = self.queues.get_mut(path).and_then(VecDeque::pop_front) {
+                trace!("received buffered message");
+                self.wake_all();
+                return Poll::Ready(Some(Ok(msg)));
+            }
+            if self.done {
+                return Poll::Ready(None);
+            }
+            match self.sub.poll_next_unpin(cx) {
+                Poll::Ready(Some(msg)) => {
+                    let Some(msg_path) = self.parse_path(&msg.subject) else {
+                        warn!(subject = ?msg.subject, "failed to parse path from message subject");
+                        continue;
+                    };
+                    if let Err(err) = self.receive(&msg_path, &msg, scope) {
+                        self.done = true;
+                        self.wake_all();
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                    if *msg_path == *path {
+                        self.wake_all();
+                        return Poll::Ready(Some(Ok(msg)));
+                    }
+                    if self.released.contains(&msg_path) {
+                        trace!(?msg_path, "discarding message for released path");
+                        continue;
+                    }
+                    if let Some(waker) = self.wakers.remove(&msg_path) {
+                        waker.wake();
+                    }
+                    let queue = self.queues.entry(msg_path).or_default();
+                    if queue.back().is_some_and(|msg| msg.payload.is_empty()) {
+                        warn!(subject = ?msg.subject, "discarding message following end of stream");
+                        continue;
+                    }
+                    trace!(subject = ?msg.subject, "buffering message");
+                    queue.push_back(msg);
+                }
+                Poll::Ready(None) => {
+                    trace!("subscription finished");
+                    self.done = true;
+                    self.wake_all();
+                    return Poll::Ready(None);
+                }
+                Poll::Pending => {
+                    self.wakers.insert(path.into(), cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+/// Source of messages for a [`Reader`]
+enum Subscription {
+    Subscriber(Subscriber),
+    Demux(Arc<std::sync::Mutex<Demux>>, Box<[usize]>),
+}
+
+impl Subscription {
+    fn new(path: &[Option<usize>], sub: Subscriber) -> Self {
+        if path.contains(&None) {
+            Self::Demux(
+                Arc::new(std::sync::Mutex::new(Demux::new(sub, path))),
+                Box::default(),
+            )
+        } else {
+            Self::Subscriber(sub)
+        }
+    }
+
+    /// Polls for the next message, accounting for received messages using `scope`
+    fn poll_next(
+        &mut self,
+        cx: &mut Context<'_>,
+        scope: &Scope,
+    ) -> Poll<Option<std::io::Result<Message>>> {
+        match self {
+            Self::Subscriber(sub) => {
+                let Some(msg) = ready!(sub.poll_next_unpin(cx)) else {
+                    return Poll::Ready(None);
+                };
+                if let Err(err) = scope.receive(msg.payload.len()) {
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Poll::Ready(Some(Ok(msg)))
+            }
+            Self::Demux(demux, path) => {
+                let Ok(mut demux) = demux.lock() else {
+                    warn!("failed to lock demultiplexer");
+                    return Poll::Ready(None);
+                };
+                demux.poll_next(cx, path, scope)
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let Self::Demux(demux, path) = self else {
+            return;
+        };
+        // handles held by the subscription tree are not claimed by a reader
+        if path.is_empty() {
+            return;
+        }
+        let Ok(mut demux) = demux.lock() else {
+            warn!("failed to lock demultiplexer");
+            return;
+        };
+        demux.release(path);
+    }
+}
+
 #[derive(Default)]
 enum SubscriberTree {
     #[default]
     Empty,
-    Leaf(Subscriber),
+    Leaf(Subscription),
     IndexNode {
-        subscriber: Option<Subscriber>,
+        subscriber: Option<Subscription>,
         nested: Vec<Option<SubscriberTree>>,
     },
     WildcardNode {
-        subscriber: Option<Subscriber>,
+        subscriber: Option<Subscription>,
         nested: Option<Box<SubscriberTree>>,
     },
 }
 
-impl<'a> From<(&'a [Option<usize>], Subscriber)> for SubscriberTree {
-    fn from((path, sub): (&'a [Option<usize>], Subscriber)) -> Self {
+impl<'a> From<(&'a [Option<usize>], Subscription)> for SubscriberTree {
+    fn from((path, sub): (&'a [Option<usize>], Subscription)) -> Self {
         match path {
             [] => Self::Leaf(sub),
             [None, path @ ..] => Self::WildcardNode {
@@ -166,7 +1158,8 @@ impl<P: AsRef<[Option<usize>]>> FromIterator<(P, Subscriber)> for SubscriberTree
     fn from_iter<T: IntoIterator<Item = (P, Subscriber)>>(iter: T) -> Self {
         let mut root = Self::Empty;
         for (path, sub) in iter {
-            if !root.insert(path.as_ref(), sub) {
+            let path = path.as_ref();
+            if !root.insert(path, Subscription::new(path, sub)) {
                 return Self::Empty;
             }
         }
@@ -181,7 +1174,15 @@ impl SubscriberTree {
     }
 
     #[instrument(level = "trace", skip_all)]
-    fn take(&mut self, path: &[usize]) -> Option<Subscriber> {
+    fn take(&mut self, path: &[usize]) -> Option<Subscription> {
+        self.take_path(path, path)
+    }
+
+    /// Takes the subscription for `path`, which is the remainder of `full` path.
+    ///
+    /// Subscriptions matched by a wildcard are shared by all paths they match and are
+    /// demultiplexed using the `full` path.
+    fn take_path(&mut self, full: &[usize], path: &[usize]) -> Option<Subscription> {
         let Some((i, path)) = path.split_first() else {
             return match mem::take(self) {
                 SubscriberTree::Empty => None,
@@ -195,36 +1196,78 @@ impl SubscriberTree {
                     }
                     subscriber
                 }
-                SubscriberTree::WildcardNode { .. } => None,
-                // TODO: Demux the subscription
-                //SubscriberTree::WildcardNode { subscriber, nested } => {
-                //    if let Some(nested) = nested {
-                //        *self = SubscriberTree::WildcardNode {
-                //            subscriber: None,
-                //            nested: Some(nested),
-                //        }
-                //    }
-                //    subscriber
-                //}
+                SubscriberTree::WildcardNode { subscriber, nested } => {
+                    if let Some(nested) = nested {
+                        *self = SubscriberTree::WildcardNode {
+                            subscriber: None,
+                            nested: Some(nested),
+                        }
+                    }
+                    subscriber
+                }
             };
         };
         match self {
             Self::Empty | Self::Leaf(..) => None,
-            Self::IndexNode { ref mut nested, .. } => nested
-                .get_mut(*i)
-                .and_then(|nested| nested.as_mut().and_then(|nested| nested.take(path))),
-            Self::WildcardNode { .. } => None,
-            // TODO: Demux the subscription
-            //Self::WildcardNode { ref mut nested, .. } => {
-            //    nested.as_mut().and_then(|nested| nested.take(path))
-            //}
+            Self::IndexNode { ref mut nested, .. } => nested.get_mut(*i).and_then(|nested| {
+                nested
+                    .as_mut()
+                    .and_then(|nested| nested.take_path(full, path))
+            }),
+            Self::WildcardNode {
+                nested: Some(ref mut nested),
+                ..
+            } => nested.take_demux(full, path),
+            Self::WildcardNode { nested: None, .. } => None,
+        }
+    }
+
+    /// Returns a handle to the shared, demultiplexed subscription for `path`, which is the
+    /// remainder of `full` path matched by a wildcard
+    fn take_demux(&mut self, full: &[usize], path: &[usize]) -> Option<Subscription> {
+        let node = match path.split_first() {
+            None => self,
+            Some((i, path)) => {
+                return match self {
+                    Self::Empty | Self::Leaf(..) => None,
+                    Self::IndexNode { ref mut nested, .. } => {
+                        nested.get_mut(*i).and_then(|nested| {
+                            nested
+                                .as_mut()
+                                .and_then(|nested| nested.take_demux(full, path))
+                        })
+                    }
+                    Self::WildcardNode { ref mut nested, .. } => nested
+                        .as_mut()
+                        .and_then(|nested| nested.take_demux(full, path)),
+                }
+            }
+        };
+        match node {
+            Self::Leaf(Subscription::Demux(demux, ..))
+            | Self::IndexNode {
+                subscriber: Some(Subscription::Demux(demux, ..)),
+                ..
+            }
+            | Self::WildcardNode {
+                subscriber: Some(Subscription::Demux(demux, ..)),
+                ..
+            } => {
+                let Ok(mut lock) = demux.lock() else {
+                    warn!("failed to lock demultiplexer");
+                    return None;
+                };
+                lock.claim(full)
+                    .then(|| Subscription::Demux(Arc::clone(demux), full.into()))
+            }
+            _ => None,
         }
     }
 
     /// Inserts `sub` under a `path` - returns `false` if it failed and `true` if it succeeded.
     /// Tree state after `false` is returned in undefined
     #[instrument(level = "trace", skip_all)]
-    fn insert(&mut self, path: &[Option<usize>], sub: Subscriber) -> bool {
+    fn insert(&mut self, path: &[Option<usize>], sub: Subscription) -> bool {
         match self {
             Self::Empty => {
                 *self = Self::from((path, sub));
@@ -281,7 +1324,7 @@ impl SubscriberTree {
                     true
                 }
                 (_, [Some(i), path @ ..]) => {
-                    if nested.len() < *i {
+                    if nested.len() <= *i {
                         nested.resize_with(i.saturating_add(1), Option::default);
                     }
                     let nested = &mut nested[*i];
@@ -296,26 +1339,213 @@ impl SubscriberTree {
             },
         }
     }
+
+    /// Drops all subscriptions, which can no longer be taken by readers at `live` paths,
+    /// which are relative to this node.
+    ///
+    /// Readers can only index paths nested under their own path.
+    fn prune(&mut self, live: &[&[usize]]) {
+        if live.iter().any(|path| path.is_empty()) {
+            return;
+        }
+        match self {
+            Self::Empty => {}
+            _ if live.is_empty() => *self = Self::Empty,
+            Self::Leaf(..) => *self = Self::Empty,
+            Self::IndexNode {
+                ref mut subscriber,
+                ref mut nested,
+            } => {
+                *subscriber = None;
+                for (i, node) in nested.iter_mut().enumerate() {
+                    let Some(tree) = node else {
+                        continue;
+                    };
+                    let live: Vec<_> = live
+                        .iter()
+                        .filter_map(|path| match path.split_first() {
+                            Some((j, path)) if *j == i => Some(path),
+                            _ => None,
+                        })
+                        .collect();
+                    tree.prune(&live);
+                    if tree.is_empty() {
+                        *node = None;
+                    }
+                }
+                if nested.iter().all(Option::is_none) {
+                    *self = Self::Empty;
+                }
+            }
+            Self::WildcardNode {
+                ref mut subscriber,
+                ref mut nested,
+            } => {
+                *subscriber = None;
+                if let Some(tree) = nested {
+                    let live: Vec<_> = live
+                        .iter()
+                        .filter_map(|path| path.split_first().map(|(_, path)| path))
+                        .collect();
+                    tree.prune(&live);
+                    if tree.is_empty() {
+                        *nested = None;
+                    }
+                }
+                if nested.is_none() {
+                    *self = Self::Empty;
+                }
+            }
+        }
+    }
+}
+
+/// Nested subscriptions of an invocation shared by all of its [`Reader`]s
+struct Nested {
+    tree: SubscriberTree,
+    /// Paths of live readers
+    readers: Vec<Arc<[usize]>>,
+}
+
+impl Nested {
+    fn new(tree: SubscriberTree, root: Arc<[usize]>) -> Self {
+        Self {
+            tree,
+            readers: vec![root],
+        }
+    }
+
+    /// Unregisters the reader at `path` and drops subscriptions, which can no longer be used
+    fn release(&mut self, path: &[usize]) {
+        if let Some(i) = self.readers.iter().position(|p| **p == *path) {
+            self.readers.swap_remove(i);
+        }
+        let live: Vec<_> = self.readers.iter().map(AsRef::as_ref).collect();
+        self.tree.prune(&live);
+    }
+}
+
+/// Receives the error transmitted by the peer on the error subject
+struct ErrorSubscriber {
+    sub: Subscriber,
+    buffer: BytesMut,
+    dec: CoreNameDecoder,
+}
+
+impl ErrorSubscriber {
+    fn new(sub: Subscriber) -> Self {
+        Self {
+            sub,
+            buffer: BytesMut::default(),
+            dec: CoreNameDecoder::default(),
+        }
+    }
+
+    /// Polls for a complete error message, returns `Poll::Ready(None)` if the subscription
+    /// finished before an error was received
+    fn poll_error(&mut self, cx: &mut Context<'_>) -> Poll<Option<std::io::Result<String>>> {
+        while let Some(Message { payload, .. }) = ready!(self.sub.poll_next_unpin(cx)) {
+            trace!(?payload, "received error chunk");
+            self.buffer.extend_from_slice(&payload);
+            if let Some(err) = self.dec.decode(&mut self.buffer).transpose() {
+                return Poll::Ready(Some(err));
+            }
+        }
+        Poll::Ready(None)
+    }
 }
 
 pub struct Reader {
     buffer: Bytes,
-    incoming: Subscriber,
-    nested: Arc<std::sync::Mutex<SubscriberTree>>,
+    incoming: Option<Subscription>,
+    path: Arc<[usize]>,
+    nested: Arc<std::sync::Mutex<Nested>>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    cancel: Option<Canceller>,
+    errors: Option<ErrorSubscriber>,
+    flow: Option<FlowReceiver>,
+    /// Accounts for bytes received for the invocation by all readers
+    scope: Scope,
+}
+
+impl Reader {
+    fn new(
+        buffer: Bytes,
+        incoming: Subscriber,
+        nested: SubscriberTree,
+        deadline: Option<Instant>,
+        scope: Scope,
+    ) -> Self {
+        Self {
+            buffer,
+            incoming: Some(Subscription::Subscriber(incoming)),
+            path: Arc::from([]),
+            nested: Arc::new(std::sync::Mutex::new(Nested::new(nested, Arc::from([])))),
+            deadline: deadline.map(|deadline| Box::pin(sleep_until(deadline))),
+            cancel: None,
+            errors: None,
+            flow: None,
+            scope,
+        }
+    }
+
+    /// Returns the invocation deadline, if any
+    #[must_use]
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline.as_ref().map(|sleep| sleep.deadline())
+    }
+
+    fn disarm(&mut self) {
+        if let Some(cancel) = &mut self.cancel {
+            cancel.armed = false;
+        }
+    }
+
+    /// Unsubscribes once the stream is finished or aborted
+    fn close(&mut self) {
+        self.incoming = None;
+        self.errors = None;
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        let Ok(mut nested) = self.nested.lock() else {
+            warn!("failed to lock map");
+            return;
+        };
+        nested.release(&self.path);
+    }
 }
 
 impl wrpc_transport::Index<Self> for Reader {
     #[instrument(level = "trace", skip(self))]
     fn index(&self, path: &[usize]) -> anyhow::Result<Self> {
+        let path: Arc<[usize]> = if self.path.is_empty() {
+            Arc::from(path)
+        } else {
+            Arc::from([self.path.as_ref(), path].concat())
+        };
+        self.scope.limits().check_depth(path.len())?;
         let mut nested = self
             .nested
             .lock()
             .map_err(|err| anyhow!(err.to_string()).context("failed to lock map"))?;
-        let incoming = nested.take(path).context("unknown subscription")?;
+        let incoming = nested.tree.take(&path).context("unknown subscription")?;
+        nested.readers.push(Arc::clone(&path));
+        let flow = self.flow.as_ref().map(|flow| flow.index(&path));
         Ok(Self {
             buffer: Bytes::default(),
-            incoming,
+            incoming: Some(incoming),
+            path,
             nested: Arc::clone(&self.nested),
+            deadline: self
+                .deadline()
+                .map(|deadline| Box::pin(sleep_until(deadline))),
+            cancel: None,
+            errors: None,
+            flow,
+            scope: self.scope.clone(),
         })
     }
 }
@@ -337,15 +1567,67 @@ impl AsyncRead for Reader {
             if self.buffer.len() > cap {
                 buf.put_slice(&self.buffer.split_to(cap));
             } else {
-                buf.put_slice(&self.buffer);
+                buf.put_slice(&mem::take(&mut self.buffer));
             }
             return Poll::Ready(Ok(()));
         }
+        if let Some(deadline) = &mut self.deadline {
+            if deadline.as_mut().poll(cx).is_ready() {
+                trace!("invocation deadline exceeded");
+                self.close();
+                return Poll::Ready(Err(deadline_exceeded_error()));
+            }
+        }
+        if let Some(errors) = &mut self.errors {
+            match errors.poll_error(cx) {
+                Poll::Ready(Some(Ok(err))) => {
+                    trace!(?err, "received error from peer");
+                    self.disarm();
+                    self.close();
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        wrpc_transport::Error::Handler(err),
+                    )));
+                }
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(err)),
+                Poll::Ready(None) => self.errors = None,
+                Poll::Pending => {}
+            }
+        }
         trace!("polling for next message");
-        match self.incoming.poll_next_unpin(cx) {
-            Poll::Ready(Some(Message { mut payload, .. })) => {
+        let this = &mut *self;
+        let Some(incoming) = &mut this.incoming else {
+            trace!("stream already finished");
+            return Poll::Ready(Ok(()));
+        };
+        match incoming.poll_next(cx, &this.scope) {
+            Poll::Ready(Some(Err(err))) => {
+                self.close();
+                Poll::Ready(Err(err))
+            }
+            Poll::Ready(Some(Ok(Message {
+                headers: Some(headers),
+                ..
+            }))) if headers.get(CANCEL_HEADER).is_some() => {
+                trace!("invocation cancelled by peer");
+                self.disarm();
+                self.close();
+                Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionAborted,
+                    "invocation cancelled by peer",
+                )))
+            }
+            Poll::Ready(Some(Ok(Message { mut payload, .. }))) => {
                 trace!(?payload, "received message");
-                if payload.len() > cap {
+                if let Some(flow) = &mut self.flow {
+                    flow.receive(&mut payload)?;
+                }
+                if payload.is_empty() {
+                    trace!("stream finished");
+                    // the invocation can no longer be cancelled
+                    self.disarm();
+                    self.close();
+                } else if payload.len() > cap {
                     trace!(len = payload.len(), cap, "partially reading the message");
                     buf.put_slice(&payload.split_to(cap));
                     self.buffer = payload;
@@ -357,6 +1639,8 @@ impl AsyncRead for Reader {
             }
             Poll::Ready(None) => {
                 trace!("subscription finished");
+                self.disarm();
+                self.close();
                 Poll::Ready(Ok(()))
             }
             Poll::Pending => Poll::Pending,
@@ -364,21 +1648,51 @@ impl AsyncRead for Reader {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct SubjectWriter {
     nats: Arc<async_nats::Client>,
     tx: Subject,
     publisher: Publisher,
+    deadline: Option<Instant>,
+    cancel: Option<Canceller>,
+    flow: Option<FlowSender>,
+}
+
+impl Clone for SubjectWriter {
+    fn clone(&self) -> Self {
+        Self {
+            nats: Arc::clone(&self.nats),
+            tx: self.tx.clone(),
+            publisher: self.publisher.clone(),
+            deadline: self.deadline,
+            cancel: None,
+            flow: self.flow.clone(),
+        }
+    }
 }
 
 impl SubjectWriter {
-    fn new(nats: Arc<async_nats::Client>, tx: Subject, publisher: Publisher) -> Self {
+    fn new(
+        nats: Arc<async_nats::Client>,
+        tx: Subject,
+        publisher: Publisher,
+        deadline: Option<Instant>,
+    ) -> Self {
         Self {
             nats,
             tx,
             publisher,
+            deadline,
+            cancel: None,
+            flow: None,
         }
     }
+
+    /// Returns the invocation deadline, if any
+    #[must_use]
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
 }
 
 impl wrpc_transport::Index<Self> for SubjectWriter {
@@ -388,6 +1702,9 @@ impl wrpc_transport::Index<Self> for SubjectWriter {
             nats: Arc::clone(&self.nats),
             tx: index_path(self.tx.as_str(), path).into(),
             publisher: self.publisher.clone(),
+            deadline: self.deadline,
+            cancel: None,
+            flow: self.flow.as_ref().map(|flow| flow.index(path)),
         })
     }
 }
@@ -399,21 +1716,46 @@ impl AsyncWrite for SubjectWriter {
         cx: &mut Context<'_>,
         mut buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
+        if self
+            .deadline
+            .is_some_and(|deadline| deadline <= Instant::now())
+        {
+            trace!("invocation deadline exceeded");
+            return Poll::Ready(Err(deadline_exceeded_error()));
+        }
+        if let Some(flow) = &self.flow {
+            trace!("polling for credit");
+            ready!(flow.poll_credit(cx))?;
+        }
         trace!("polling for readiness");
         match self.publisher.poll_ready_unpin(cx) {
             Poll::Pending => return Poll::Pending,
             Poll::Ready(Err(..)) => return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
             Poll::Ready(Ok(())) => {}
         }
-        let ServerInfo { max_payload, .. } = self.nats.server_info();
+        let ServerInfo {
+            mut max_payload, ..
+        } = self.nats.server_info();
+        if self.flow.is_some() {
+            max_payload = max_payload.saturating_sub(SEQUENCE_LEN);
+        }
         if max_payload == 0 {
             return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
         }
         if buf.len() > max_payload {
             (buf, _) = buf.split_at(max_payload);
         }
+        let payload = if let Some(flow) = &mut self.flow {
+            let mut payload = BytesMut::with_capacity(SEQUENCE_LEN + buf.len());
+            payload.put_u64(flow.seq);
+            payload.extend_from_slice(buf);
+            flow.seq = flow.seq.saturating_add(1);
+            payload.freeze()
+        } else {
+            Bytes::copy_from_slice(buf)
+        };
         trace!("starting send");
-        match self.publisher.start_send_unpin(Bytes::copy_from_slice(buf)) {
+        match self.publisher.start_send_unpin(payload) {
             Ok(()) => Poll::Ready(Ok(buf.len())),
             Err(..) => Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
         }
@@ -432,9 +1774,44 @@ impl AsyncWrite for SubjectWriter {
         trace!("writing empty buffer to shut down stream");
         ready!(self.as_mut().poll_write(cx, &[]))?;
         trace!("closing");
-        self.publisher
-            .poll_close_unpin(cx)
-            .map_err(|_| std::io::ErrorKind::BrokenPipe.into())
+        ready!(self.publisher.poll_close_unpin(cx))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        if let Some(cancel) = &mut self.cancel {
+            cancel.armed = false;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl wrpc_transport::Reject for SubjectWriter {
+    #[instrument(level = "trace", skip(self))]
+    async fn reject(mut self, err: &str) -> anyhow::Result<()> {
+        if let Some(cancel) = &mut self.cancel {
+            // the error is the terminal message of the invocation
+            cancel.armed = false;
+        }
+        let prefix = self
+            .tx
+            .as_str()
+            .strip_suffix(".results")
+            .or_else(|| self.tx.as_str().strip_suffix(".params"))
+            .context("only root streams can be rejected")?;
+        let tx = Subject::from(error_subject(prefix));
+        let mut buf = BytesMut::default();
+        CoreNameEncoder
+            .encode(err, &mut buf)
+            .context("failed to encode error")?;
+        let mut buf = buf.freeze();
+        let ServerInfo { max_payload, .. } = self.nats.server_info();
+        ensure!(max_payload > 0, "maximum payload size is zero");
+        while !buf.is_empty() {
+            trace!(?tx, "publishing error chunk");
+            self.nats
+                .publish(tx.clone(), buf.split_to(max_payload.min(buf.len())))
+                .await
+                .context("failed to publish error")?;
+        }
+        Ok(())
     }
 }
 
@@ -447,6 +1824,8 @@ pub enum RootParamWriter {
         sub: Subscriber,
         indexed: std::sync::Mutex<Vec<(Vec<usize>, oneshot::Sender<SubjectWriter>)>>,
         buffer: Bytes,
+        peer: Arc<Peer>,
+        timeout: Option<Pin<Box<Sleep>>>,
     },
     Draining {
         tx: SubjectWriter,
@@ -456,12 +1835,20 @@ pub enum RootParamWriter {
 }
 
 impl RootParamWriter {
-    fn new(tx: SubjectWriter, sub: Subscriber, buffer: Bytes) -> Self {
+    fn new(
+        tx: SubjectWriter,
+        sub: Subscriber,
+        buffer: Bytes,
+        peer: Arc<Peer>,
+        timeout: Option<Duration>,
+    ) -> Self {
         Self::Handshaking {
             tx,
             sub,
             indexed: std::sync::Mutex::default(),
             buffer,
+            peer,
+            timeout: timeout.map(|timeout| Box::pin(sleep(timeout))),
         }
     }
 }
@@ -471,13 +1858,16 @@ impl RootParamWriter {
     fn poll_active(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
         match &mut *self {
             Self::Corrupted => Poll::Ready(Err(corrupted_memory_error())),
-            Self::Handshaking { sub, .. } => {
+            Self::Handshaking { sub, timeout, .. } => {
                 trace!("polling for handshake response");
                 match sub.poll_next_unpin(cx) {
                     Poll::Ready(Some(Message {
                         status: Some(StatusCode::NO_RESPONDERS),
                         ..
-                    })) => Poll::Ready(Err(std::io::ErrorKind::NotConnected.into())),
+                    })) => Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::NotConnected,
+                        wrpc_transport::Error::NoResponders,
+                    ))),
                     Poll::Ready(Some(Message {
                         status: Some(StatusCode::TIMEOUT),
                         ..
@@ -499,12 +1889,33 @@ impl RootParamWriter {
                         },
                     ))),
                     Poll::Ready(Some(Message {
-                        reply: Some(tx), ..
+                        reply: Some(tx),
+                        headers,
+                        ..
                     })) => {
+                        if let Self::Handshaking {
+                            tx: SubjectWriter { flow: Some(..), .. },
+                            ..
+                        } = &*self
+                        {
+                            if headers.as_ref().and_then(flow_control).is_none() {
+                                return Poll::Ready(Err(std::io::Error::new(
+                                    std::io::ErrorKind::Unsupported,
+                                    "peer does not support flow control",
+                                )));
+                            }
+                        }
                         let Self::Handshaking {
-                            tx: SubjectWriter { nats, .. },
+                            tx:
+                                SubjectWriter {
+                                    nats,
+                                    deadline,
+                                    flow,
+                                    ..
+                                },
                             indexed,
                             buffer,
+                            peer,
                             ..
                         } = mem::take(&mut *self)
                         else {
@@ -512,7 +1923,9 @@ impl RootParamWriter {
                         };
                         let param_tx = Subject::from(param_subject(&tx));
                         let param_pub = nats.publish_sink(param_tx.clone());
-                        let tx = SubjectWriter::new(nats, param_tx, param_pub);
+                        peer.set(param_tx.clone());
+                        let mut tx = SubjectWriter::new(nats, param_tx, param_pub, deadline);
+                        tx.flow = flow;
                         let indexed = indexed.into_inner().map_err(|err| {
                             std::io::Error::new(std::io::ErrorKind::Other, err.to_string())
                         })?;
@@ -541,7 +1954,18 @@ impl RootParamWriter {
                         *self = Self::Corrupted;
                         Poll::Ready(Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe)))
                     }
-                    Poll::Pending => Poll::Pending,
+                    Poll::Pending => {
+                        if let Some(timeout) = timeout {
+                            if timeout.as_mut().poll(cx).is_ready() {
+                                trace!("handshake timed out");
+                                return Poll::Ready(Err(std::io::Error::new(
+                                    std::io::ErrorKind::TimedOut,
+                                    wrpc_transport::Error::HandshakeTimedOut,
+                                )));
+                            }
+                        }
+                        Poll::Pending
+                    }
                 }
             }
             Self::Draining { tx, buffer } => {
@@ -639,6 +2063,8 @@ impl AsyncWrite for RootParamWriter {
     }
 }
 
+// writers are `Active` for most of their lifetime, boxing the variant would only add an allocation
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Default)]
 pub enum IndexedParamWriter {
     #[default]
@@ -800,7 +2226,7 @@ impl AsyncWrite for ParamWriter {
 }
 
 impl wrpc_transport::Invoke for Client {
-    type Context = Option<HeaderMap>;
+    type Context = InvocationContext;
     type Outgoing = ParamWriter;
     type Incoming = Reader;
 
@@ -813,25 +2239,58 @@ impl wrpc_transport::Invoke for Client {
         mut params: Bytes,
         paths: &[impl AsRef<[Option<usize>]> + Send + Sync],
     ) -> anyhow::Result<(Self::Outgoing, Self::Incoming)> {
-        let rx = Subject::from(self.nats.new_inbox());
-        let (result_rx, handshake_rx, nested) = try_join!(
+        ensure!(
+            cx.compression.is_none() || self.compressed,
+            "compressed invocations require the client to be wrapped in `Compress`"
+        );
+        let limits = Limits::current();
+        let rx = Subject::from(new_inbox(&self.nats, self.inbox_prefix.as_deref()));
+        let window = cx.flow_control.filter(|window| *window > 0);
+        let (result_rx, error_rx, handshake_rx, ack_rx, nested) = try_join!(
             async {
-                self.nats
-                    .subscribe(Subject::from(result_subject(&rx)))
-                    .await
-                    .context("failed to subscribe on result subject")
+                Subscriber::subscribe(
+                    &self.nats,
+                    &self.subscriptions,
+                    Subject::from(result_subject(&rx)),
+                )
+                .await
+                .context("failed to subscribe on result subject")
+            },
+            async {
+                Subscriber::subscribe(
+                    &self.nats,
+                    &self.subscriptions,
+                    Subject::from(error_subject(&rx)),
+                )
+                .await
+                .context("failed to subscribe on error subject")
             },
             async {
-                self.nats
-                    .subscribe(rx.clone())
+                Subscriber::subscribe(&self.nats, &self.subscriptions, rx.clone())
                     .await
                     .context("failed to subscribe on handshake subject")
             },
+            async {
+                if window.is_none() {
+                    return Ok(None);
+                }
+                Subscriber::subscribe(
+                    &self.nats,
+                    &self.subscriptions,
+                    Subject::from(format!("{}.>", ack_subject(&rx))),
+                )
+                .await
+                .map(Some)
+                .context("failed to subscribe on acknowledgement subject")
+            },
             futures::future::try_join_all(paths.iter().map(|path| async {
-                self.nats
-                    .subscribe(Subject::from(subscribe_path(&rx, path.as_ref())))
-                    .await
-                    .context("failed to subscribe on nested result subject")
+                Subscriber::subscribe(
+                    &self.nats,
+                    &self.subscriptions,
+                    Subject::from(subscribe_path(&result_subject(&rx), path.as_ref())),
+                )
+                .await
+                .context("failed to subscribe on nested result subject")
             }))
         )?;
         let nested: SubscriberTree = zip(paths.iter(), nested).collect();
@@ -843,22 +2302,20 @@ impl wrpc_transport::Invoke for Client {
             mut max_payload, ..
         } = self.nats.server_info();
         max_payload = max_payload.saturating_sub(rx.len());
-        let param_tx = Subject::from(invocation_subject(&self.prefix, instance, func));
-        if let Some(headers) = cx {
-            // based on https://github.com/nats-io/nats.rs/blob/0942c473ce56163fdd1fbc62762f8164e3afa7bf/async-nats/src/header.rs#L215-L224
-            max_payload = max_payload
-                .saturating_sub(b"NATS/1.0\r\n".len())
-                .saturating_sub(b"\r\n".len());
-            for (k, vs) in headers.iter() {
-                let k: &[u8] = k.as_ref();
-                for v in vs {
-                    max_payload = max_payload
-                        .saturating_sub(k.len())
-                        .saturating_sub(b": ".len())
-                        .saturating_sub(v.as_str().len())
-                        .saturating_sub(b"\r\n".len());
+        let param_tx = Subject::from(self.layout.invocation_subject(&self.prefix, instance, func));
+        let deadline = cx.deadline.map(deadline_instant);
+        let acks = ack_rx.map(|sub| Arc::new(std::sync::Mutex::new(Acks::new(sub, &rx))));
+        let headers = HeaderMap::from(cx);
+        if headers.iter().next().is_some() {
+            let headers_len = headers_len(&headers);
+            if headers_len > max_payload {
+                return Err(wrpc_transport::Error::PayloadTooLarge {
+                    len: headers_len,
+                    max: max_payload,
                 }
+                .into());
             }
+            max_payload -= headers_len;
             trace!("publishing handshake");
             self.nats
                 .publish_with_reply_and_headers(
@@ -879,21 +2336,39 @@ impl wrpc_transport::Invoke for Client {
                 .await
         }
         .context("failed to send handshake")?;
+        // until all results are received, dropping the reader signals cancellation to the server
+        let peer = Arc::default();
+        let mut tx = SubjectWriter::new(
+            Arc::clone(&self.nats),
+            param_tx.clone(),
+            self.nats.publish_sink(param_tx),
+            deadline,
+        );
+        let flow = window.zip(acks);
+        tx.flow = flow
+            .clone()
+            .map(|(window, acks)| FlowSender::new(acks, "params", window));
+        let mut results = Reader::new(
+            Bytes::default(),
+            result_rx,
+            nested,
+            deadline,
+            Scope::new(limits),
+        );
+        results.cancel = Some(Canceller::new(Arc::clone(&self.nats), Arc::clone(&peer)));
+        results.errors = Some(ErrorSubscriber::new(error_rx));
+        results.flow = flow.map(|(window, _)| {
+            FlowReceiver::new(Arc::clone(&self.nats), Arc::clone(&peer), "results", window)
+        });
         Ok((
             ParamWriter::Root(RootParamWriter::new(
-                SubjectWriter::new(
-                    Arc::clone(&self.nats),
-                    param_tx.clone(),
-                    self.nats.publish_sink(param_tx),
-                ),
+                tx,
                 handshake_rx,
                 params,
+                peer,
+                self.handshake_timeout,
             )),
-            Reader {
-                buffer: Bytes::default(),
-                incoming: result_rx,
-                nested: Arc::new(std::sync::Mutex::new(nested)),
-            },
+            results,
         ))
     }
 }
@@ -901,6 +2376,8 @@ impl wrpc_transport::Invoke for Client {
 #[instrument(level = "trace", skip_all)]
 async fn serve_connection(
     nats: Arc<async_nats::Client>,
+    subscriptions: Arc<AtomicUsize>,
+    inbox_prefix: Option<&str>,
     Message {
         reply: tx,
         payload,
@@ -908,20 +2385,44 @@ async fn serve_connection(
         ..
     }: Message,
     paths: &[impl AsRef<[Option<usize>]>],
-) -> anyhow::Result<(Option<HeaderMap>, SubjectWriter, Reader)> {
+    limits: Limits,
+    compressed: bool,
+) -> anyhow::Result<(InvocationContext, SubjectWriter, Reader)> {
     let tx = tx.context("peer did not specify a reply subject")?;
-    let rx = nats.new_inbox();
+    let rx = new_inbox(&nats, inbox_prefix);
+    let scattered = headers
+        .as_ref()
+        .is_some_and(|headers| headers.get(SCATTER_HEADER).is_some());
+    let cx = headers.map(InvocationContext::from).unwrap_or_default();
+    let window = cx.flow_control;
     trace!("subscribing on subjects");
-    let (param_rx, nested) = try_join!(
+    let (param_rx, ack_rx, nested) = try_join!(
         async {
-            nats.subscribe(Subject::from(param_subject(&rx)))
+            Subscriber::subscribe(&nats, &subscriptions, Subject::from(param_subject(&rx)))
                 .await
                 .context("failed to subscribe on parameter subject")
         },
+        async {
+            if window.is_none() {
+                return Ok(None);
+            }
+            Subscriber::subscribe(
+                &nats,
+                &subscriptions,
+                Subject::from(format!("{}.>", ack_subject(&rx))),
+            )
+            .await
+            .map(Some)
+            .context("failed to subscribe on acknowledgement subject")
+        },
         try_join_all(paths.iter().map(|path| async {
-            nats.subscribe(Subject::from(subscribe_path(&rx, path.as_ref())))
-                .await
-                .context("failed to subscribe on nested parameter subject")
+            Subscriber::subscribe(
+                &nats,
+                &subscriptions,
+                Subject::from(subscribe_path(&param_subject(&rx), path.as_ref())),
+            )
+            .await
+            .context("failed to subscribe on nested parameter subject")
         }))
     )?;
     let nested: SubscriberTree = zip(paths.iter(), nested).collect();
@@ -929,28 +2430,65 @@ async fn serve_connection(
         paths.is_empty() == nested.is_empty(),
         "failed to construct subscription tree"
     );
+    let acks = ack_rx.map(|sub| Arc::new(std::sync::Mutex::new(Acks::new(sub, &rx))));
+    // each responder of a scatter-gather invocation responds under a distinct subject, which
+    // preserves ordering of its handshake response and results received by the client
+    let result_root = if scattered {
+        let id = rx.rsplit_once('.').map_or(rx.as_str(), |(_, id)| id);
+        Subject::from(format!("{tx}.{id}"))
+    } else {
+        tx
+    };
     trace!("publishing handshake response");
-    nats.publish_with_reply(tx.clone(), rx, Bytes::default())
-        .await
-        .context("failed to publish handshake accept")?;
-    let result_tx = Subject::from(result_subject(&tx));
-    Ok((
-        headers,
-        SubjectWriter::new(
-            Arc::clone(&nats),
-            result_tx.clone(),
-            nats.publish_sink(result_tx),
-        ),
-        Reader {
-            buffer: payload,
-            incoming: param_rx,
-            nested: Arc::new(std::sync::Mutex::new(nested)),
-        },
-    ))
+    if let Some(window) = window {
+        // confirm that flow control is used for the invocation
+        let mut accept = HeaderMap::new();
+        set_flow_control(&mut accept, window);
+        nats.publish_with_reply_and_headers(result_root.clone(), rx, accept, Bytes::default())
+            .await
+    } else {
+        nats.publish_with_reply(result_root.clone(), rx, Bytes::default())
+            .await
+    }
+    .context("failed to publish handshake accept")?;
+    let result_tx = Subject::from(result_subject(&result_root));
+    let deadline = cx.deadline.map(deadline_instant);
+    let mut results = SubjectWriter::new(
+        Arc::clone(&nats),
+        result_tx.clone(),
+        nats.publish_sink(result_tx.clone()),
+        deadline,
+    );
+    if cx.compression.is_some() && !compressed {
+        let err = "compressed invocations are not supported by the server";
+        results
+            .reject(err)
+            .await
+            .context("failed to reject compressed invocation")?;
+        bail!(err);
+    }
+    let scope = Scope::new(limits);
+    if let Err(err) = scope.receive(payload.len()) {
+        results
+            .reject(&err.to_string())
+            .await
+            .context("failed to reject invocation exceeding limits")?;
+        return Err(err.into());
+    }
+    // until results are transmitted, dropping the writer signals cancellation to the client
+    let peer = Arc::new(Peer::from(result_tx));
+    results.cancel = Some(Canceller::new(Arc::clone(&nats), Arc::clone(&peer)));
+    let flow = window.zip(acks);
+    results.flow = flow
+        .clone()
+        .map(|(window, acks)| FlowSender::new(acks, "results", window));
+    let mut params = Reader::new(payload, param_rx, nested, deadline, scope);
+    params.flow = flow.map(|(window, _)| FlowReceiver::new(nats, peer, "params", window));
+    Ok((cx, results, params))
 }
 
 impl wrpc_transport::Serve for Client {
-    type Context = Option<HeaderMap>;
+    type Context = InvocationContext;
     type Outgoing = SubjectWriter;
     type Incoming = Reader;
 
@@ -963,16 +2501,106 @@ impl wrpc_transport::Serve for Client {
     ) -> anyhow::Result<
         impl Stream<Item = anyhow::Result<(Self::Context, Self::Outgoing, Self::Incoming)>> + 'static,
     > {
-        let sub = self
-            .nats
-            .subscribe(invocation_subject(&self.prefix, instance, func))
-            .await?;
+        let subject = self.layout.invocation_subject(&self.prefix, instance, func);
+        let sub = if let Some(group) = &self.queue_group {
+            self.nats
+                .queue_subscribe(subject, group.to_string())
+                .await
+                .map(|sub| Subscriber::new(sub, &self.subscriptions))
+                .context("failed to subscribe on invocation subject in a queue group")?
+        } else {
+            Subscriber::subscribe(&self.nats, &self.subscriptions, Subject::from(subject))
+                .await
+                .context("failed to subscribe on invocation subject")?
+        };
         let paths = paths.into();
         let nats = Arc::clone(&self.nats);
+        let subscriptions = Arc::clone(&self.subscriptions);
+        let inbox_prefix = self.inbox_prefix.clone();
+        let limits = Limits::current();
+        let compressed = self.compressed;
         Ok(sub.then(move |msg| {
             let nats = Arc::clone(&nats);
+            let subscriptions = Arc::clone(&subscriptions);
             let paths = Arc::clone(&paths);
-            async move { serve_connection(nats, msg, &paths).await }
+            let inbox_prefix = inbox_prefix.clone();
+            async move {
+                serve_connection(
+                    nats,
+                    subscriptions,
+                    inbox_prefix.as_deref(),
+                    msg,
+                    &paths,
+                    limits,
+                    compressed,
+                )
+                .await
+            }
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use async_nats::header::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn context_headers() {
+        let deadline = UNIX_EPOCH + Duration::from_millis(42);
+        let cx = InvocationContext::default()
+            .with_deadline(deadline)
+            .with_trace_context(
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+                None,
+            )
+            .with_caller("test")
+            .with_content_version("0.1.0")
+            .with_flow_control(8)
+            .with_compression(Compression::Zstd)
+            .with_header("custom", "value");
+        let headers = HeaderMap::from(cx);
+        assert_eq!(
+            headers.get(DEADLINE_HEADER).map(HeaderValue::as_str),
+            Some("42")
+        );
+        assert_eq!(
+            headers.get(CALLER_HEADER).map(HeaderValue::as_str),
+            Some("test")
+        );
+        assert_eq!(
+            headers.get(COMPRESSION_HEADER).map(HeaderValue::as_str),
+            Some("zstd")
+        );
+
+        let cx = InvocationContext::from(headers);
+        assert_eq!(cx.deadline, Some(deadline));
+        assert_eq!(
+            cx.traceparent.as_deref(),
+            Some("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+        );
+        assert_eq!(cx.tracestate, None);
+        assert_eq!(cx.caller.as_deref(), Some("test"));
+        assert_eq!(cx.content_version.as_deref(), Some("0.1.0"));
+        assert_eq!(cx.flow_control, Some(8));
+        assert_eq!(cx.compression, Some(Compression::Zstd));
+        assert_eq!(
+            cx.headers.get("custom").map(HeaderValue::as_str),
+            Some("value")
+        );
+        assert!(cx.headers.get(CALLER_HEADER).is_none());
+    }
+
+    #[test]
+    fn escaped_layout() {
+        assert_eq!(
+            EscapedLayout.invocation_subject("test", "wasi:http/handler@0.2.0", "handle"),
+            "test.wrpc.0.0.1.wasi_http_handler_0_2_0.handle"
+        );
+        assert_eq!(
+            DefaultLayout.invocation_subject("test", "foo", "bar"),
+            invocation_subject("test", "foo", "bar")
+        );
+    }
+}
@@ -0,0 +1,387 @@
+//! Scatter-gather invocations, which are handled by all servers serving a function
+
+use core::time::Duration;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, ensure, Context as _};
+use async_nats::{HeaderMap, Message, ServerInfo, StatusCode, Subject};
+use bytes::{Bytes, BytesMut};
+use futures::{stream, Stream, StreamExt as _};
+use tokio::time::sleep;
+use tokio::try_join;
+use tokio_util::codec::{Decoder as _, Encoder as _};
+use tracing::{instrument, trace, warn};
+use wasm_tokio::CoreNameDecoder;
+use wrpc_transport::limits::Scope;
+use wrpc_transport::{Deferred as _, Limits, TupleDecode, TupleEncode};
+
+use crate::{
+    headers_len, new_inbox, param_subject, Client, InvocationContext, ParamWriter, Reader,
+    Subscriber, CANCEL_HEADER, SCATTER_HEADER,
+};
+
+/// Server, which accepted a scatter-gather invocation
+struct Responder {
+    inbox: Subject,
+    results: BytesMut,
+    errors: BytesMut,
+    dec: CoreNameDecoder,
+    /// Accounts the data received from the responder against the invocation size limit
+    scope: Scope,
+    done: bool,
+}
+
+/// Collects responses to a scatter-gather invocation
+struct Gather {
+    nats: Arc<async_nats::Client>,
+    rx: Subject,
+    max_responders: Option<usize>,
+    /// Limits applied to the response of each responder
+    limits: Limits,
+    /// Responders indexed by the subject token their results are transmitted under
+    responders: HashMap<String, Responder>,
+    completed: usize,
+    no_responders: bool,
+}
+
+impl Gather {
+    fn is_done(&self) -> bool {
+        self.no_responders
+            || self
+                .max_responders
+                .is_some_and(|max| self.responders.len() >= max && self.completed >= max)
+    }
+
+    /// Cancels the invocation of the server with `inbox`
+    async fn cancel(&self, inbox: &str) {
+        let mut headers = HeaderMap::new();
+        headers.insert(CANCEL_HEADER, "1");
+        if let Err(err) = self
+            .nats
+            .publish_with_headers(
+                Subject::from(param_subject(inbox)),
+                headers,
+                Bytes::default(),
+            )
+            .await
+        {
+            warn!(?err, "failed to cancel invocation");
+        }
+    }
+
+    #[instrument(level = "trace", skip(self, inbox))]
+    async fn accept(&mut self, id: &str, inbox: Option<Subject>) {
+        let Some(inbox) = inbox else {
+            warn!("handshake response without a reply subject");
+            return;
+        };
+        let tx = Subject::from(param_subject(&inbox));
+        if self.responders.contains_key(id) {
+            trace!(?inbox, "ignoring duplicate handshake response");
+            return;
+        }
+        if self
+            .max_responders
+            .is_some_and(|max| self.responders.len() >= max)
+        {
+            trace!(
+                ?inbox,
+                "maximum number of responders reached, cancelling invocation"
+            );
+            self.cancel(&inbox).await;
+            return;
+        }
+        trace!(?inbox, "finishing parameter stream");
+        if let Err(err) = self.nats.publish(tx, Bytes::default()).await {
+            warn!(?err, "failed to finish parameter stream");
+        }
+        self.responders.insert(
+            id.to_string(),
+            Responder {
+                inbox,
+                results: BytesMut::default(),
+                errors: BytesMut::default(),
+                dec: CoreNameDecoder::default(),
+                scope: Scope::new(self.limits),
+                done: false,
+            },
+        );
+    }
+
+    /// Handles a message received from a responder, returns the response once complete
+    async fn receive(
+        &mut self,
+        Message {
+            subject,
+            reply,
+            payload,
+            headers,
+            status,
+            ..
+        }: Message,
+    ) -> Option<(Subject, Result<Bytes, wrpc_transport::Error>)> {
+        if subject == self.rx {
+            if status == Some(StatusCode::NO_RESPONDERS) {
+                trace!("no servers available to handle the invocation");
+                self.no_responders = true;
+            } else if let Some(inbox) = reply {
+                // servers, which do not support scatter-gather invocations, respond to the
+                // handshake on the root subject, like they would for a regular invocation
+                warn!(
+                    ?inbox,
+                    "server does not support scatter-gather invocations, cancelling invocation"
+                );
+                self.cancel(&inbox).await;
+                return Some((
+                    inbox,
+                    Err(wrpc_transport::Error::Transport(anyhow!(
+                        "server does not support scatter-gather invocations"
+                    ))),
+                ));
+            }
+            return None;
+        }
+        let Some(rest) = subject
+            .strip_prefix(self.rx.as_str())
+            .and_then(|s| s.strip_prefix('.'))
+        else {
+            warn!(?subject, "received message on unexpected subject");
+            return None;
+        };
+        let Some((id, kind)) = rest.split_once('.') else {
+            if rest == "results" || rest == "error" {
+                trace!(
+                    ?subject,
+                    "ignoring message from server without scatter-gather support"
+                );
+            } else {
+                self.accept(rest, reply).await;
+            }
+            return None;
+        };
+        let Some(responder) = self.responders.get_mut(id).filter(|r| !r.done) else {
+            trace!(
+                ?subject,
+                "ignoring message from unknown or finished responder"
+            );
+            return None;
+        };
+        let res = match kind {
+            "results" => {
+                if headers
+                    .as_ref()
+                    .is_some_and(|headers| headers.get(CANCEL_HEADER).is_some())
+                {
+                    Err(wrpc_transport::Error::Closed(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionAborted,
+                        "invocation cancelled by peer",
+                    )))
+                } else if payload.is_empty() {
+                    Ok(responder.results.split().freeze())
+                } else if let Err(err) = responder.scope.receive(payload.len()) {
+                    Err(err.into())
+                } else {
+                    responder.results.extend_from_slice(&payload);
+                    return None;
+                }
+            }
+            "error" => {
+                if let Err(err) = responder.scope.receive(payload.len()) {
+                    Err(err.into())
+                } else {
+                    responder.errors.extend_from_slice(&payload);
+                    match responder.dec.decode(&mut responder.errors) {
+                        Ok(Some(err)) => Err(wrpc_transport::Error::Handler(err)),
+                        Ok(None) => return None,
+                        Err(err) => Err(wrpc_transport::Error::Io(err)),
+                    }
+                }
+            }
+            _ => {
+                warn!(
+                    ?subject,
+                    "nested results are not supported by scatter-gather invocations"
+                );
+                return None;
+            }
+        };
+        responder.done = true;
+        // release the buffers of the finished responder
+        responder.results = BytesMut::default();
+        responder.errors = BytesMut::default();
+        let inbox = responder.inbox.clone();
+        self.completed = self.completed.saturating_add(1);
+        if let Err(wrpc_transport::Error::LimitExceeded { .. }) = res {
+            trace!(?inbox, "response exceeds limits, cancelling invocation");
+            self.cancel(&inbox).await;
+        }
+        Some((inbox, res))
+    }
+}
+
+impl Client {
+    /// Invoke function `func` on instance `instance` on all servers currently serving it,
+    /// returning a stream of results of each responding server identified by its inbox.
+    ///
+    /// Responses are collected for at most `window` and the stream finishes early once
+    /// `max_responders` servers have responded. Parameters must fit in a single message and
+    /// neither parameters nor results may contain asynchronous values.
+    ///
+    /// The response of each server is limited by the
+    /// [`max_invocation_size`](Limits::max_invocation_size) of the [`Limits`] in scope, servers
+    /// exceeding it are cancelled and reported with an
+    /// [`Error::LimitExceeded`](wrpc_transport::Error::LimitExceeded).
+    ///
+    /// Servers, which do not support scatter-gather invocations, respond to the invocation like
+    /// to a regular one. Their invocation is cancelled and they are reported in the stream with
+    /// an [`Error::Transport`](wrpc_transport::Error::Transport). Such servers do not count
+    /// towards `max_responders`.
+    #[instrument(level = "trace", skip(self, cx, params), fields(params = format!("{params:02x?}")))]
+    pub async fn scatter(
+        &self,
+        cx: InvocationContext,
+        instance: &str,
+        func: &str,
+        params: Bytes,
+        window: Duration,
+        max_responders: Option<usize>,
+    ) -> anyhow::Result<
+        impl Stream<Item = (Subject, Result<Bytes, wrpc_transport::Error>)> + Send + 'static,
+    > {
+        ensure!(
+            cx.flow_control.is_none(),
+            "flow control is not supported by scatter-gather invocations"
+        );
+        ensure!(
+            cx.compression.is_none(),
+            "compression is not supported by scatter-gather invocations"
+        );
+        let rx = Subject::from(new_inbox(&self.nats, self.inbox_prefix.as_deref()));
+        // responders reply under `{rx}.{id}`, `rx` only receives the status of the invocation
+        let (status_rx, response_rx) = try_join!(
+            async {
+                Subscriber::subscribe(&self.nats, &self.subscriptions, rx.clone())
+                    .await
+                    .context("failed to subscribe on status subject")
+            },
+            async {
+                Subscriber::subscribe(
+                    &self.nats,
+                    &self.subscriptions,
+                    Subject::from(format!("{rx}.>")),
+                )
+                .await
+                .context("failed to subscribe on response subject")
+            },
+        )?;
+        let mut headers = HeaderMap::from(cx);
+        headers.insert(SCATTER_HEADER, "1");
+        let ServerInfo { max_payload, .. } = self.nats.server_info();
+        let len = headers_len(&headers)
+            .saturating_add(rx.len())
+            .saturating_add(params.len());
+        if len > max_payload {
+            return Err(wrpc_transport::Error::PayloadTooLarge {
+                len,
+                max: max_payload,
+            }
+            .into());
+        }
+        let subject = self.layout.invocation_subject(&self.prefix, instance, func);
+        trace!(?subject, "publishing invocation");
+        self.nats
+            .publish_with_reply_and_headers(subject, rx.clone(), headers, params)
+            .await
+            .context("failed to publish invocation")?;
+        let msgs = stream::select(status_rx, response_rx).take_until(sleep(window));
+        let gather = Gather {
+            nats: Arc::clone(&self.nats),
+            rx,
+            max_responders,
+            limits: Limits::current(),
+            responders: HashMap::default(),
+            completed: 0,
+            no_responders: false,
+        };
+        Ok(stream::unfold(
+            (Box::pin(msgs), gather),
+            |(mut msgs, mut gather)| async move {
+                loop {
+                    if gather.is_done() {
+                        return None;
+                    }
+                    let msg = msgs.next().await?;
+                    if let Some(res) = gather.receive(msg).await {
+                        return Some((res, (msgs, gather)));
+                    }
+                }
+            },
+        ))
+    }
+
+    /// Invoke function `func` on instance `instance` on all servers currently serving it using
+    /// typed `Params` and `Results`, see [`Client::scatter`]
+    #[instrument(level = "trace", skip(self, cx, params))]
+    pub async fn scatter_values<Params, Results>(
+        &self,
+        cx: InvocationContext,
+        instance: &str,
+        func: &str,
+        params: Params,
+        window: Duration,
+        max_responders: Option<usize>,
+    ) -> Result<
+        impl Stream<Item = (Subject, Result<Results, wrpc_transport::Error>)> + Send + 'static,
+        wrpc_transport::Error,
+    >
+    where
+        Params: TupleEncode<ParamWriter>,
+        Results: TupleDecode<Reader> + Send + 'static,
+        <Params::Encoder as tokio_util::codec::Encoder<Params>>::Error:
+            std::error::Error + Send + Sync + 'static,
+        <Results::Decoder as tokio_util::codec::Decoder>::Error:
+            std::error::Error + Send + Sync + 'static,
+    {
+        let mut buf = BytesMut::default();
+        let mut enc = Params::Encoder::default();
+        trace!("encoding parameters");
+        enc.encode(params, &mut buf)
+            .map_err(wrpc_transport::Error::encode)?;
+        if enc.take_deferred().is_some() {
+            return Err(wrpc_transport::Error::Transport(anyhow!(
+                "asynchronous parameters are not supported by scatter-gather invocations"
+            )));
+        }
+        let responses = self
+            .scatter(cx, instance, func, buf.freeze(), window, max_responders)
+            .await?;
+        Ok(responses.map(|(responder, res)| (responder, res.and_then(|buf| decode_results(&buf)))))
+    }
+}
+
+fn decode_results<Results>(buf: &[u8]) -> Result<Results, wrpc_transport::Error>
+where
+    Results: TupleDecode<Reader>,
+    <Results::Decoder as tokio_util::codec::Decoder>::Error:
+        std::error::Error + Send + Sync + 'static,
+{
+    let mut buf = BytesMut::from(buf);
+    let mut dec = Results::Decoder::default();
+    let Some(results) = dec
+        .decode_eof(&mut buf)
+        .map_err(wrpc_transport::Error::decode)?
+    else {
+        return Err(wrpc_transport::Error::Closed(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "incomplete returns",
+        )));
+    };
+    if dec.take_deferred().is_some() {
+        return Err(wrpc_transport::Error::Transport(anyhow!(
+            "asynchronous results are not supported by scatter-gather invocations"
+        )));
+    }
+    Ok(results)
+}
@@ -3,13 +3,15 @@ use core::future::Future as _;
 use core::net::SocketAddr;
 use core::pin::{pin, Pin};
 use core::task::{ready, Context, Poll};
+use core::time::Duration;
 use core::{mem, str};
 
 use std::collections::{hash_map, HashMap};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, ensure, Context as _};
-use bytes::{Buf as _, Bytes, BytesMut};
+use bytes::{Buf as _, BufMut as _, Bytes, BytesMut};
 use futures::{Stream, StreamExt};
 use pin_project_lite::pin_project;
 use quinn::crypto::rustls::HandshakeData;
@@ -17,12 +19,159 @@ use quinn::{Connection, ConnectionError, Endpoint, RecvStream, SendStream, VarIn
 use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite};
 use tokio::spawn;
 use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::{sleep_until, Instant, Sleep};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::codec::Encoder;
 use tracing::{instrument, trace, warn, Instrument as _, Span};
 use wasm_tokio::{AsyncReadLeb128 as _, Leb128Encoder};
+use wrpc_transport::compress::{Compression, CompressionContext, CompressionTransport};
+use wrpc_transport::otel::TraceContext;
 
-pub const PROTOCOL: u8 = 0;
+/// Version of the parameter stream header, incremented on every change of its layout.
+///
+/// The header consists of:
+/// - the protocol version, `u8`
+/// - the invocation deadline in milliseconds since UNIX epoch, LEB128-encoded `u64`, 0 if none
+/// - the compression algorithm, `u8`, 0 if none
+/// - the W3C `traceparent` and `tracestate`, each as a LEB128-encoded `u32` length followed by
+///   the UTF-8 bytes, empty if none
+pub const PROTOCOL: u8 = 1;
+
+/// Application error code used to close connections of rejected invocations
+pub const ERROR_CODE_REJECTED: u32 = 1;
+
+/// Application error code used to close connections of cancelled invocations
+pub const ERROR_CODE_CANCELLED: u32 = 2;
+
+/// Maximum length of the W3C `traceparent` and `tracestate` in the parameter stream header
+const MAX_TRACE_CONTEXT_LEN: u32 = 1024;
+
+/// QUIC invocation context
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InvocationContext {
+    /// Invocation deadline, transmitted to the server in the parameter stream header
+    pub deadline: Option<SystemTime>,
+    /// Compression algorithm, transmitted to the server in the parameter stream header
+    pub compression: Option<Compression>,
+    /// W3C trace context `traceparent`, transmitted to the server in the parameter stream header
+    pub traceparent: Option<String>,
+    /// W3C trace context `tracestate`, transmitted to the server in the parameter stream header
+    pub tracestate: Option<String>,
+}
+
+impl InvocationContext {
+    /// Constructs a new [`InvocationContext`] with a deadline `timeout` from now
+    #[must_use]
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            deadline: SystemTime::now().checked_add(timeout),
+            ..Self::default()
+        }
+    }
+
+    /// Sets the compression algorithm applied to all streams of the invocation.
+    ///
+    /// Streams are only compressed by clients wrapped in `wrpc_transport::compress::Compress`,
+    /// invocations using a compression algorithm fail otherwise. Servers not wrapped in
+    /// `Compress` reject such invocations.
+    #[must_use]
+    pub fn with_compression(self, compression: Compression) -> Self {
+        Self {
+            compression: Some(compression),
+            ..self
+        }
+    }
+
+    /// Sets the W3C trace context
+    #[must_use]
+    pub fn with_trace_context(
+        self,
+        traceparent: impl Into<String>,
+        tracestate: Option<String>,
+    ) -> Self {
+        Self {
+            traceparent: Some(traceparent.into()),
+            tracestate,
+            ..self
+        }
+    }
+}
+
+impl CompressionContext for InvocationContext {
+    fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+}
+
+impl TraceContext for InvocationContext {
+    fn trace_context(&self) -> Option<(&str, Option<&str>)> {
+        let traceparent = self.traceparent.as_deref()?;
+        Some((traceparent, self.tracestate.as_deref()))
+    }
+
+    fn set_trace_context(&mut self, traceparent: String, tracestate: Option<String>) {
+        self.traceparent = Some(traceparent);
+        self.tracestate = tracestate;
+    }
+}
+
+/// Reads a length-prefixed trace context field, empty fields are equivalent to absent ones
+async fn read_trace_context(rx: &mut RecvStream) -> anyhow::Result<Option<String>> {
+    let n = rx
+        .read_u32_leb128()
+        .await
+        .context("failed to read length")?;
+    ensure!(
+        n <= MAX_TRACE_CONTEXT_LEN,
+        "length of {n} exceeds maximum of {MAX_TRACE_CONTEXT_LEN}"
+    );
+    if n == 0 {
+        return Ok(None);
+    }
+    let mut buf = vec![0; n as usize];
+    rx.read_exact(&mut buf)
+        .await
+        .context("failed to read value")?;
+    let s = String::from_utf8(buf).context("value is not valid UTF-8")?;
+    Ok(Some(s))
+}
+
+fn deadline_instant(deadline: SystemTime) -> Instant {
+    let timeout = deadline
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+    Instant::now() + timeout
+}
+
+fn deadline_exceeded_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, "invocation deadline exceeded")
+}
+
+/// Closes the connection with [`ERROR_CODE_CANCELLED`] on drop, unless disarmed
+#[derive(Debug, Default)]
+pub struct Canceller(Option<Connection>);
+
+impl Canceller {
+    fn armed(conn: Connection) -> Self {
+        Self(Some(conn))
+    }
+
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for Canceller {
+    fn drop(&mut self) {
+        if let Some(conn) = self.0.take() {
+            trace!("signaling invocation cancellation");
+            conn.close(
+                VarInt::from_u32(ERROR_CODE_CANCELLED),
+                b"invocation cancelled",
+            );
+        }
+    }
+}
 
 fn san(instance: &str, func: &str) -> String {
     let mut s = String::with_capacity(
@@ -288,7 +437,19 @@ impl IndexTree {
 }
 
 #[derive(Default)]
-pub struct Server(Mutex<HashMap<String, mpsc::Sender<Connection>>>);
+pub struct Server {
+    handlers: Mutex<HashMap<String, mpsc::Sender<Connection>>>,
+    compressed: bool,
+}
+
+impl CompressionTransport for Server {
+    fn with_compression_enabled(self) -> Self {
+        Self {
+            compressed: true,
+            ..self
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum AcceptError {
@@ -335,7 +496,7 @@ impl Server {
             .downcast::<HandshakeData>()
             .map_err(|_| AcceptError::InvalidData)?;
         let name = data.server_name.ok_or(AcceptError::ServerNameMissing)?;
-        let tx = self.0.lock().await;
+        let tx = self.handlers.lock().await;
         let tx = tx
             .get(&name)
             .ok_or_else(|| AcceptError::UnhandledName(name))?;
@@ -348,6 +509,7 @@ impl Server {
 pub struct Client {
     endpoint: Endpoint,
     addr: SocketAddr,
+    compressed: bool,
 }
 
 impl Client {
@@ -355,6 +517,16 @@ impl Client {
         Self {
             endpoint,
             addr: addr.into(),
+            compressed: false,
+        }
+    }
+}
+
+impl CompressionTransport for Client {
+    fn with_compression_enabled(self) -> Self {
+        Self {
+            compressed: true,
+            ..self
         }
     }
 }
@@ -365,18 +537,70 @@ pin_project! {
         Accepting {
             index: Arc<std::sync::Mutex<IndexTree>>,
             path: Arc<[usize]>,
+            deadline: Option<Pin<Box<Sleep>>>,
             #[pin]
             rx: oneshot::Receiver<RecvStream>,
         },
         Active {
             index: Arc<std::sync::Mutex<IndexTree>>,
             path: Arc<[usize]>,
+            deadline: Option<Pin<Box<Sleep>>>,
+            cancel: Canceller,
             #[pin]
             rx: RecvStream,
         },
     }
 }
 
+impl Incoming {
+    /// Returns the invocation deadline, if any
+    #[must_use]
+    pub fn deadline(&self) -> Option<Instant> {
+        let (Self::Accepting { deadline, .. } | Self::Active { deadline, .. }) = self;
+        deadline.as_ref().map(|sleep| sleep.deadline())
+    }
+}
+
+/// Maps connection closures signaled by the peer to errors carrying the invocation status
+fn map_read_error(err: std::io::Error) -> std::io::Error {
+    let close = err
+        .get_ref()
+        .and_then(|err| err.downcast_ref::<quinn::ReadError>())
+        .and_then(|err| match err {
+            quinn::ReadError::ConnectionLost(ConnectionError::ApplicationClosed(close)) => Some((
+                close.error_code,
+                String::from_utf8_lossy(&close.reason).into_owned(),
+            )),
+            _ => None,
+        });
+    match close {
+        Some((code, reason)) if code == VarInt::from_u32(ERROR_CODE_REJECTED) => {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                wrpc_transport::Error::Handler(reason),
+            )
+        }
+        Some((code, _)) if code == VarInt::from_u32(ERROR_CODE_CANCELLED) => std::io::Error::new(
+            std::io::ErrorKind::ConnectionAborted,
+            "invocation cancelled by peer",
+        ),
+        _ => err,
+    }
+}
+
+fn poll_deadline(
+    cx: &mut Context<'_>,
+    deadline: &mut Option<Pin<Box<Sleep>>>,
+) -> std::io::Result<()> {
+    if let Some(deadline) = deadline {
+        if deadline.as_mut().poll(cx).is_ready() {
+            trace!("invocation deadline exceeded");
+            return Err(deadline_exceeded_error());
+        }
+    }
+    Ok(())
+}
+
 impl wrpc_transport::Index<Self> for Incoming {
     #[instrument(level = "trace", skip(self))]
     fn index(&self, path: &[usize]) -> anyhow::Result<Self> {
@@ -405,6 +629,9 @@ impl wrpc_transport::Index<Self> for Incoming {
                 Ok(Self::Accepting {
                     index: Arc::clone(index),
                     path,
+                    deadline: self
+                        .deadline()
+                        .map(|deadline| Box::pin(sleep_until(deadline))),
                     rx,
                 })
             }
@@ -423,22 +650,38 @@ impl AsyncRead for Incoming {
             IncomingProj::Accepting {
                 index,
                 path,
+                deadline,
                 mut rx,
             } => {
+                poll_deadline(cx, deadline)?;
                 trace!(?path, "polling channel");
                 let rx = ready!(rx.as_mut().poll(cx))
                     .map_err(|err| std::io::Error::new(std::io::ErrorKind::BrokenPipe, err))?;
                 *self = Self::Active {
                     index: Arc::clone(index),
                     path: Arc::clone(path),
+                    deadline: deadline.take(),
+                    cancel: Canceller::default(),
                     rx,
                 };
                 self.poll_read(cx, buf)
             }
-            IncomingProj::Active { rx, path, .. } => {
+            IncomingProj::Active {
+                rx,
+                path,
+                deadline,
+                cancel,
+                ..
+            } => {
+                poll_deadline(cx, deadline)?;
                 trace!(?path, "reading buffer");
-                ready!(AsyncRead::poll_read(rx, cx, buf))?;
+                let n = buf.filled().len();
+                ready!(AsyncRead::poll_read(rx, cx, buf)).map_err(map_read_error)?;
                 trace!(buf = ?buf.filled(), "read from buffer");
+                if buf.filled().len() == n && buf.remaining() > 0 {
+                    // the stream is finished, so the invocation can no longer be cancelled
+                    cancel.disarm();
+                }
                 Poll::Ready(Ok(()))
             }
         }
@@ -451,12 +694,15 @@ pin_project! {
         Opening {
             header: Bytes,
             path: Arc<[usize]>,
+            deadline: Option<Instant>,
             #[pin]
             conn: Connection,
         },
         Active {
             header: Bytes,
             path: Arc<[usize]>,
+            deadline: Option<Instant>,
+            cancel: Canceller,
             #[pin]
             conn: Connection,
             #[pin]
@@ -482,13 +728,20 @@ impl wrpc_transport::Index<Self> for Outgoing {
         }
         match self {
             Self::Opening {
-                path: base, conn, ..
+                path: base,
+                deadline,
+                conn,
+                ..
             }
             | Self::Active {
-                path: base, conn, ..
+                path: base,
+                deadline,
+                conn,
+                ..
             } => Ok(Self::Opening {
                 header: header.freeze(),
                 path: Arc::from([base, path].concat()),
+                deadline: *deadline,
                 conn: conn.clone(),
             }),
         }
@@ -513,18 +766,32 @@ fn poll_write_header(
 }
 
 impl Outgoing {
+    /// Returns the invocation deadline, if any
+    #[must_use]
+    pub fn deadline(&self) -> Option<Instant> {
+        let (Self::Opening { deadline, .. } | Self::Active { deadline, .. }) = self;
+        *deadline
+    }
+
     #[instrument(level = "trace", skip_all, ret)]
     fn poll_flush_header(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<std::io::Result<()>> {
         match self.as_mut().project() {
-            OutgoingProj::Opening { path, conn, header } => {
+            OutgoingProj::Opening {
+                path,
+                deadline,
+                conn,
+                header,
+            } => {
                 trace!(?path, "opening connection");
                 let tx = ready!(pin!(conn.open_uni()).poll(cx)).map_err(std::io::Error::from)?;
                 *self = Self::Active {
                     header: header.clone(),
                     path: Arc::clone(path),
+                    deadline: *deadline,
+                    cancel: Canceller::default(),
                     conn: conn.clone(),
                     tx,
                 };
@@ -548,6 +815,13 @@ impl AsyncWrite for Outgoing {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
+        if self
+            .deadline()
+            .is_some_and(|deadline| deadline <= Instant::now())
+        {
+            trace!("invocation deadline exceeded");
+            return Poll::Ready(Err(deadline_exceeded_error()));
+        }
         ready!(self.as_mut().poll_flush_header(cx))?;
         match self.as_mut().project() {
             OutgoingProj::Opening { .. } => Poll::Ready(Err(corrupted_memory_error())),
@@ -575,17 +849,37 @@ impl AsyncWrite for Outgoing {
         ready!(self.as_mut().poll_flush_header(cx))?;
         match self.as_mut().project() {
             OutgoingProj::Opening { .. } => Poll::Ready(Err(corrupted_memory_error())),
-            OutgoingProj::Active { tx, .. } => {
+            OutgoingProj::Active { tx, cancel, .. } => {
                 trace!("shutting down stream");
-                tx.poll_shutdown(cx)
+                ready!(tx.poll_shutdown(cx))?;
+                cancel.disarm();
+                Poll::Ready(Ok(()))
             }
         }
     }
 }
 
+impl wrpc_transport::Reject for Outgoing {
+    #[instrument(level = "trace", skip(self))]
+    async fn reject(self, err: &str) -> anyhow::Result<()> {
+        let conn = match self {
+            Self::Opening { conn, .. } => conn,
+            Self::Active {
+                conn, mut cancel, ..
+            } => {
+                cancel.disarm();
+                conn
+            }
+        };
+        conn.close(VarInt::from_u32(ERROR_CODE_REJECTED), err.as_bytes());
+        Ok(())
+    }
+}
+
 async fn demux_connection(
     index: Arc<std::sync::Mutex<IndexTree>>,
     conn: Connection,
+    limits: wrpc_transport::Limits,
 ) -> std::io::Result<()> {
     loop {
         // TODO: Figure out how to tie the lifetime of this Tokio task to all streams and explicitly close
@@ -616,6 +910,7 @@ async fn demux_connection(
             .try_into()
             .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
         trace!(n, "read path length");
+        limits.check_depth(n)?;
         let mut path = Vec::with_capacity(n);
         for i in 0..n {
             trace!(i, "reading path element");
@@ -643,7 +938,7 @@ async fn demux_connection(
 }
 
 impl wrpc_transport::Invoke for Client {
-    type Context = ();
+    type Context = InvocationContext;
     type Outgoing = Outgoing;
     type Incoming = Incoming;
 
@@ -656,6 +951,10 @@ impl wrpc_transport::Invoke for Client {
         params: Bytes,
         paths: &[impl AsRef<[Option<usize>]> + Send + Sync],
     ) -> anyhow::Result<(Self::Outgoing, Self::Incoming)> {
+        ensure!(
+            cx.compression.is_none() || self.compressed,
+            "compressed invocations require the client to be wrapped in `Compress`"
+        );
         let san = san(instance, func);
         trace!(?san, "establishing connection");
         let conn = self
@@ -670,22 +969,72 @@ impl wrpc_transport::Invoke for Client {
             .await
             .context("failed to open parameter stream")?;
         let index = Arc::new(std::sync::Mutex::new(paths.iter().collect()));
-        spawn(demux_connection(Arc::clone(&index), conn.clone()).in_current_span());
+        spawn(
+            demux_connection(
+                Arc::clone(&index),
+                conn.clone(),
+                wrpc_transport::Limits::current(),
+            )
+            .in_current_span(),
+        );
+        let mut header = BytesMut::with_capacity(
+            cx.traceparent
+                .as_ref()
+                .map_or(0, String::len)
+                .saturating_add(cx.tracestate.as_ref().map_or(0, String::len))
+                .saturating_add(22),
+        );
+        header.put_u8(PROTOCOL);
+        let deadline_ms = cx
+            .deadline
+            .map(|deadline| {
+                let ms = deadline
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                // 0 is reserved for "no deadline"
+                u64::try_from(ms).unwrap_or(u64::MAX).max(1)
+            })
+            .unwrap_or_default();
+        trace!(deadline_ms, "encoding deadline");
+        Leb128Encoder
+            .encode(deadline_ms, &mut header)
+            .context("failed to encode deadline")?;
+        // 0 is reserved for "no compression"
+        header.put_u8(cx.compression.map(u8::from).unwrap_or_default());
+        trace!("encoding trace context");
+        for v in [&cx.traceparent, &cx.tracestate] {
+            let v = v.as_deref().unwrap_or_default();
+            let n = u32::try_from(v.len())
+                .ok()
+                .filter(|n| *n <= MAX_TRACE_CONTEXT_LEN)
+                .context("trace context too long")?;
+            Leb128Encoder
+                .encode(n, &mut header)
+                .context("failed to encode trace context length")?;
+            header.put_slice(v.as_bytes());
+        }
         trace!("writing parameters");
         param_tx
-            .write_all_chunks(&mut [Bytes::from_static(&[PROTOCOL]), params])
+            .write_all_chunks(&mut [header.freeze(), params])
             .await
             .context("failed to write parameters")?;
+        let deadline = cx.deadline.map(deadline_instant);
         Ok((
             Outgoing::Active {
                 header: Bytes::default(),
                 path: Arc::from([]),
+                deadline,
+                cancel: Canceller::default(),
                 conn: conn.clone(),
                 tx: param_tx,
             },
             Incoming::Active {
                 index: Arc::clone(&index),
                 path: Arc::from([]),
+                deadline: deadline.map(|deadline| Box::pin(sleep_until(deadline))),
+                // until all results are received, dropping the stream cancels the invocation
+                cancel: Canceller::armed(conn),
                 rx: ret_rx,
             },
         ))
@@ -696,7 +1045,9 @@ impl wrpc_transport::Invoke for Client {
 async fn serve_connection(
     conn: Connection,
     paths: &[impl AsRef<[Option<usize>]>],
-) -> anyhow::Result<(Outgoing, Incoming)> {
+    limits: wrpc_transport::Limits,
+    compressed: bool,
+) -> anyhow::Result<(InvocationContext, Outgoing, Incoming)> {
     trace!("accepting parameter stream");
     let (ret_tx, mut param_rx) = conn
         .accept_bi()
@@ -707,26 +1058,71 @@ async fn serve_connection(
         .read_u8()
         .await
         .context("failed to read parameter stream header")?;
-    ensure!(x == PROTOCOL);
+    if x != PROTOCOL {
+        let err = format!("unsupported protocol version `{x}`, expected `{PROTOCOL}`");
+        conn.close(VarInt::from_u32(ERROR_CODE_REJECTED), err.as_bytes());
+        bail!(err);
+    }
+    trace!("reading deadline");
+    let deadline_ms = param_rx
+        .read_u64_leb128()
+        .await
+        .context("failed to read deadline")?;
+    let deadline = (deadline_ms > 0)
+        .then(|| UNIX_EPOCH.checked_add(Duration::from_millis(deadline_ms)))
+        .flatten();
+    trace!("reading compression");
+    let compression = param_rx
+        .read_u8()
+        .await
+        .context("failed to read compression")?;
+    let compression = (compression > 0)
+        .then(|| Compression::try_from(compression))
+        .transpose()?;
+    if compression.is_some() && !compressed {
+        let err = "compressed invocations are not supported by the server";
+        conn.close(VarInt::from_u32(ERROR_CODE_REJECTED), err.as_bytes());
+        bail!(err);
+    }
+    trace!("reading trace context");
+    let traceparent = read_trace_context(&mut param_rx)
+        .await
+        .context("failed to read `traceparent`")?;
+    let tracestate = read_trace_context(&mut param_rx)
+        .await
+        .context("failed to read `tracestate`")?;
     let index = Arc::new(std::sync::Mutex::new(paths.iter().collect()));
-    spawn(demux_connection(Arc::clone(&index), conn.clone()).in_current_span());
+    spawn(demux_connection(Arc::clone(&index), conn.clone(), limits).in_current_span());
+    let cx = InvocationContext {
+        deadline,
+        compression,
+        traceparent,
+        tracestate,
+    };
+    let deadline = deadline.map(deadline_instant);
     Ok((
+        cx,
         Outgoing::Active {
             header: Bytes::default(),
             path: Arc::from([]),
+            deadline,
+            // until results are transmitted, dropping the stream cancels the invocation
+            cancel: Canceller::armed(conn.clone()),
             conn,
             tx: ret_tx,
         },
         Incoming::Active {
             index,
             path: Arc::from([]),
+            deadline: deadline.map(|deadline| Box::pin(sleep_until(deadline))),
+            cancel: Canceller::default(),
             rx: param_rx,
         },
     ))
 }
 
 impl wrpc_transport::Serve for Server {
-    type Context = ();
+    type Context = InvocationContext;
     type Outgoing = Outgoing;
     type Incoming = Incoming;
 
@@ -741,7 +1137,7 @@ impl wrpc_transport::Serve for Server {
     > {
         let san = san(instance, func);
         let (tx, rx) = mpsc::channel(1024);
-        let mut handlers = self.0.lock().await;
+        let mut handlers = self.handlers.lock().await;
         match handlers.entry(san) {
             hash_map::Entry::Occupied(_) => {
                 bail!("handler for `{func}` from `{instance}` already exists")
@@ -752,13 +1148,12 @@ impl wrpc_transport::Serve for Server {
         }
         let paths = paths.into();
         let span = Span::current();
+        let limits = wrpc_transport::Limits::current();
+        let compressed = self.compressed;
         Ok(ReceiverStream::new(rx).then(move |conn| {
             let paths = Arc::clone(&paths);
-            async move {
-                let (tx, rx) = serve_connection(conn, &paths).await?;
-                Ok(((), tx, rx))
-            }
-            .instrument(span.clone())
+            async move { serve_connection(conn, &paths, limits, compressed).await }
+                .instrument(span.clone())
         }))
     }
 }
@@ -1,27 +1,32 @@
+use core::iter::zip;
 use core::net::Ipv4Addr;
 
-use core::pin::pin;
+use core::pin::{pin, Pin};
+use core::time::Duration;
 use std::sync::Arc;
 
-use anyhow::Context as _;
-use futures::StreamExt as _;
+use anyhow::{bail, Context as _};
+use bytes::Bytes;
+use futures::{Stream, StreamExt as _};
 use quinn::crypto::rustls::QuicClientConfig;
-use quinn::{ClientConfig, EndpointConfig, ServerConfig, TokioRuntime};
+use quinn::{ClientConfig, ConnectionError, EndpointConfig, ServerConfig, TokioRuntime, VarInt};
 use rcgen::{generate_simple_self_signed, CertifiedKey};
 use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
 use rustls::version::TLS13;
 use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::time::timeout;
 use tokio::try_join;
 use tracing::info;
-use wrpc_transport::{Index as _, Invoke as _, Serve as _};
-use wrpc_transport_quic::{Client, Server};
+use wrpc_transport::compress::{Compress, Compression};
+use wrpc_transport::{Error, Index as _, Invoke as _, Limits, Serve as _};
+use wrpc_transport_quic::{Client, InvocationContext, Outgoing, Server, ERROR_CODE_CANCELLED};
 
-#[test_log::test(tokio::test(flavor = "multi_thread"))]
-async fn loopback() -> anyhow::Result<()> {
+/// Creates a client connecting to the returned server endpoint
+fn endpoints() -> anyhow::Result<(Client, quinn::Endpoint)> {
     let CertifiedKey {
         cert: srv_crt,
         key_pair: srv_key,
-    } = generate_simple_self_signed(["bar.foo.server.wrpc".into()]).unwrap();
+    } = generate_simple_self_signed(["*.foo.server.wrpc".into()]).unwrap();
     let CertifiedKey {
         cert: clt_crt,
         key_pair: clt_key,
@@ -64,6 +69,12 @@ async fn loopback() -> anyhow::Result<()> {
     .context("failed to create server endpoint")?;
 
     let clt = Client::new(clt_ep, (Ipv4Addr::LOCALHOST, srv_addr.port()));
+    Ok((clt, srv_ep))
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn loopback() -> anyhow::Result<()> {
+    let (clt, srv_ep) = endpoints()?;
     let srv = Server::default();
     let invocations = srv
         .serve("foo", "bar", [[Some(42), Some(0)]])
@@ -73,7 +84,13 @@ async fn loopback() -> anyhow::Result<()> {
     try_join!(
         async {
             let (mut outgoing, mut incoming) = clt
-                .invoke((), "foo", "bar", "test".into(), &[&[Some(0), Some(42)]])
+                .invoke(
+                    InvocationContext::with_timeout(Duration::from_secs(60)),
+                    "foo",
+                    "bar",
+                    "test".into(),
+                    &[&[Some(0), Some(42)]],
+                )
                 .await
                 .context("failed to invoke `foo.bar`")?;
             let mut nested_tx = outgoing.index(&[42, 0]).context("failed to index `42.0`")?;
@@ -137,11 +154,12 @@ async fn loopback() -> anyhow::Result<()> {
                 .await
                 .context("failed to accept client connection")?;
             assert!(ok);
-            let ((), mut outgoing, mut incoming) = invocations
+            let (cx, mut outgoing, mut incoming) = invocations
                 .next()
                 .await
                 .context("invocation stream unexpectedly finished")?
                 .context("failed to get invocation")?;
+            assert!(cx.deadline.is_some());
             let mut nested_tx = outgoing.index(&[0, 42]).context("failed to index `0.42`")?;
             let mut nested_rx = incoming.index(&[42, 0]).context("failed to index `42.0`")?;
             try_join!(
@@ -210,3 +228,483 @@ async fn loopback() -> anyhow::Result<()> {
     )?;
     Ok(())
 }
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn deadline() -> anyhow::Result<()> {
+    let (clt, srv_ep) = endpoints()?;
+    let srv = Server::default();
+    let invocations = srv
+        .serve("foo", "bar", [[None; 0]])
+        .await
+        .context("failed to serve `foo.bar`")?;
+    let mut invocations = pin!(invocations);
+    let ((_outgoing, mut incoming), (cx, _results, mut params)) = try_join!(
+        async {
+            clt.invoke(
+                InvocationContext::with_timeout(Duration::from_millis(100)),
+                "foo",
+                "bar",
+                Bytes::default(),
+                &[[None; 0]],
+            )
+            .await
+            .context("failed to invoke `foo.bar`")
+        },
+        async {
+            let ok = srv
+                .accept(&srv_ep)
+                .await
+                .context("failed to accept client connection")?;
+            assert!(ok);
+            invocations
+                .next()
+                .await
+                .context("invocation stream unexpectedly finished")?
+                .context("failed to get invocation")
+        }
+    )?;
+    assert!(cx.deadline.is_some());
+    // neither peer finishes its stream, so reads only complete once the deadline is exceeded
+    let Err(err) = incoming.read_to_end(&mut vec![]).await else {
+        bail!("reading results should have timed out")
+    };
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    let Err(err) = params.read_to_end(&mut vec![]).await else {
+        bail!("reading parameters should have timed out")
+    };
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    Ok(())
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn cancel() -> anyhow::Result<()> {
+    let (clt, srv_ep) = endpoints()?;
+    let srv = Server::default();
+    let invocations = srv
+        .serve("foo", "bar", [[None; 0]])
+        .await
+        .context("failed to serve `foo.bar`")?;
+    let mut invocations = pin!(invocations);
+    let ((_outgoing, mut incoming), (_, mut results, mut params)) = try_join!(
+        async {
+            clt.invoke(
+                InvocationContext::default(),
+                "foo",
+                "bar",
+                Bytes::default(),
+                &[[None; 0]],
+            )
+            .await
+            .context("failed to invoke `foo.bar`")
+        },
+        async {
+            let ok = srv
+                .accept(&srv_ep)
+                .await
+                .context("failed to accept client connection")?;
+            assert!(ok);
+            invocations
+                .next()
+                .await
+                .context("invocation stream unexpectedly finished")?
+                .context("failed to get invocation")
+        }
+    )?;
+    results
+        .write_all(b"foo")
+        .await
+        .context("failed to write `foo`")?;
+    results.flush().await.context("failed to flush results")?;
+    let mut buf = [0; 3];
+    incoming
+        .read_exact(&mut buf)
+        .await
+        .context("failed to read `foo`")?;
+    assert_eq!(&buf, b"foo");
+    // results are not finished, so dropping the stream cancels the invocation
+    drop(incoming);
+    let Err(err) = params.read_to_end(&mut vec![]).await else {
+        bail!("invocation should have been cancelled")
+    };
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionAborted);
+    Ok(())
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn complete() -> anyhow::Result<()> {
+    let (clt, srv_ep) = endpoints()?;
+    let srv = Server::default();
+    let invocations = srv
+        .serve("foo", "bar", [[None; 0]])
+        .await
+        .context("failed to serve `foo.bar`")?;
+    let mut invocations = pin!(invocations);
+    // sync returns only, followed by a stream received asynchronously
+    for (returns, nested) in [(&b"\x2a"[..], None), (b"\x00", Some(&b"\x01\x2a\x00"[..]))] {
+        let (n, conn) = try_join!(
+            async {
+                if nested.is_none() {
+                    let (n,) = clt
+                        .invoke_values_blocking::<_, (u32,)>(
+                            InvocationContext::default(),
+                            "foo",
+                            "bar",
+                            (),
+                            &[[None; 0]],
+                        )
+                        .await
+                        .context("failed to invoke `foo.bar`")?;
+                    return anyhow::Ok(n);
+                }
+                let (items,) = clt
+                    .invoke_values_blocking::<_, (Pin<Box<dyn Stream<Item = u32> + Send + Sync>>,)>(
+                        InvocationContext::default(),
+                        "foo",
+                        "bar",
+                        (),
+                        &[[Some(0)]],
+                    )
+                    .await
+                    .context("failed to invoke `foo.bar`")?;
+                let items = items.collect::<Vec<_>>().await;
+                assert_eq!(items, [0x2a]);
+                anyhow::Ok(0x2a)
+            },
+            async {
+                let ok = srv
+                    .accept(&srv_ep)
+                    .await
+                    .context("failed to accept client connection")?;
+                assert!(ok);
+                let (_, mut results, mut params) = invocations
+                    .next()
+                    .await
+                    .context("invocation stream unexpectedly finished")?
+                    .context("failed to get invocation")?;
+                let Outgoing::Active { conn, .. } = &results else {
+                    bail!("results stream should be active")
+                };
+                let conn = conn.clone();
+                params
+                    .read_to_end(&mut vec![])
+                    .await
+                    .context("failed to read parameters")?;
+                results
+                    .write_all(returns)
+                    .await
+                    .context("failed to write returns")?;
+                results
+                    .shutdown()
+                    .await
+                    .context("failed to shutdown results")?;
+                if let Some(nested) = nested {
+                    let mut nested_tx = results.index(&[0]).context("failed to index stream")?;
+                    nested_tx
+                        .write_all(nested)
+                        .await
+                        .context("failed to write stream")?;
+                    nested_tx
+                        .shutdown()
+                        .await
+                        .context("failed to shutdown stream")?;
+                }
+                anyhow::Ok(conn)
+            }
+        )?;
+        assert_eq!(n, 0x2a);
+        // all results were received, so the client must not cancel the invocation
+        if let Ok(err) = timeout(Duration::from_millis(100), conn.closed()).await {
+            assert!(
+                !matches!(
+                    err,
+                    ConnectionError::ApplicationClosed(ref close)
+                        if close.error_code == VarInt::from_u32(ERROR_CODE_CANCELLED)
+                ),
+                "invocation was cancelled: {err:?}"
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn limits() -> anyhow::Result<()> {
+    let (clt, srv_ep) = endpoints()?;
+    let srv = Server::default();
+    let limits = Limits {
+        max_list_len: 2,
+        max_string_len: 2,
+        max_chunk_len: 2,
+        ..Limits::default()
+    };
+    let (lists, strings, streams) = limits
+        .scope(async {
+            try_join!(
+                srv.serve_values::<_, (Vec<u32>,), ()>("foo", "list", [[None; 0]]),
+                srv.serve_values::<_, (String,), ()>("foo", "string", [[None; 0]]),
+                srv.serve_values::<_, (Pin<Box<dyn Stream<Item = u32> + Send + Sync>>,), ()>(
+                    "foo",
+                    "stream",
+                    [[Some(0)]],
+                ),
+            )
+        })
+        .await
+        .context("failed to serve functions")?;
+    let mut lists = pin!(lists);
+    let mut strings = pin!(strings);
+    let mut streams = pin!(streams);
+
+    for (func, params) in [("list", b"\x03\x01\x02\x03"), ("string", b"\x03foo")] {
+        let (_, res) = try_join!(
+            async {
+                clt.invoke(
+                    InvocationContext::default(),
+                    "foo",
+                    func,
+                    Bytes::from_static(params),
+                    &[[None; 0]],
+                )
+                .await
+                .with_context(|| format!("failed to invoke `foo.{func}`"))
+            },
+            async {
+                let ok = srv
+                    .accept(&srv_ep)
+                    .await
+                    .context("failed to accept client connection")?;
+                assert!(ok);
+                let res = if func == "list" {
+                    lists.next().await.map(|res| res.map(|_| ()))
+                } else {
+                    strings.next().await.map(|res| res.map(|_| ()))
+                };
+                res.context("invocation stream unexpectedly finished")
+            }
+        )?;
+        let Err(err) = res else {
+            bail!("decoding `{func}` parameters should have failed")
+        };
+        assert!(
+            matches!(err, Error::LimitExceeded { len: 3, max: 2, .. }),
+            "unexpected error: {err:?}"
+        );
+    }
+
+    // stream is pending, its chunks are received asynchronously
+    let ((outgoing, _incoming), res) = try_join!(
+        async {
+            clt.invoke(
+                InvocationContext::default(),
+                "foo",
+                "stream",
+                Bytes::from_static(b"\x00"),
+                &[[None; 0]],
+            )
+            .await
+            .context("failed to invoke `foo.stream`")
+        },
+        async {
+            let ok = srv
+                .accept(&srv_ep)
+                .await
+                .context("failed to accept client connection")?;
+            assert!(ok);
+            streams
+                .next()
+                .await
+                .context("invocation stream unexpectedly finished")?
+                .context("failed to decode `foo.stream` parameters")
+        }
+    )?;
+    let (_, _, rx, _) = res;
+    let rx = rx.context("stream should be received asynchronously")?;
+    let mut chunks = outgoing.index(&[0]).context("failed to index stream")?;
+    let (_, res) = try_join!(
+        async {
+            chunks
+                .write_all(b"\x03\x01\x02\x03")
+                .await
+                .context("failed to write stream chunk")?;
+            chunks.shutdown().await.context("failed to shutdown stream")
+        },
+        async { Ok(rx.await) }
+    )?;
+    let Err(err) = res else {
+        bail!("receiving stream chunk should have failed")
+    };
+    let err = Error::from(err);
+    assert!(
+        matches!(
+            err,
+            Error::LimitExceeded {
+                limit: "stream chunk length",
+                len: 3,
+                max: 2,
+            }
+        ),
+        "unexpected error: {err:?}"
+    );
+    Ok(())
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn list_incremental() -> anyhow::Result<()> {
+    // encodes to more than `LIST_CHUNK_SIZE` bytes, so the list is written in multiple chunks
+    const LEN: u32 = 20_000;
+
+    let (clt, srv_ep) = endpoints()?;
+    let srv = Server::default();
+    let invocations = srv
+        .serve_values::<_, (u32, Vec<u32>), (u32, u64)>("foo", "bar", [[None; 0]])
+        .await
+        .context("failed to serve `foo.bar`")?;
+    let mut invocations = pin!(invocations);
+    let ((returns, tx), ()) = try_join!(
+        async {
+            clt.invoke_values_with_list::<_, _, _, (u32, u64)>(
+                InvocationContext::default(),
+                "foo",
+                "bar",
+                (42u32,),
+                LEN,
+                futures::stream::iter(0..LEN),
+                &[[None; 0]],
+            )
+            .await
+            .context("failed to invoke `foo.bar`")
+        },
+        async {
+            let ok = srv
+                .accept(&srv_ep)
+                .await
+                .context("failed to accept client connection")?;
+            assert!(ok);
+            let (_, (x, items), rx, tx) = invocations
+                .next()
+                .await
+                .context("invocation stream unexpectedly finished")?
+                .context("failed to decode parameters")?;
+            assert!(rx.is_none());
+            assert_eq!(x, 42);
+            assert_eq!(items.len(), LEN as usize);
+            assert!(zip(0.., &items).all(|(i, v)| i == *v));
+            let n = items.len().try_into()?;
+            let sum = items.into_iter().map(u64::from).sum();
+            tx(Ok((n, sum))).await.context("failed to send returns")
+        }
+    )?;
+    assert!(tx.is_none());
+    assert_eq!(returns, (LEN, u64::from(LEN) * u64::from(LEN - 1) / 2));
+    Ok(())
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn compression() -> anyhow::Result<()> {
+    let payload = Bytes::from(br#"{"foo":"bar","baz":[1,2,3]}"#.repeat(1 << 12));
+    let (clt, srv_ep) = endpoints()?;
+    let clt = Compress::new(clt);
+    let srv = Compress::new(Server::default());
+    let invocations = srv
+        .serve_values::<_, (Bytes, Pin<Box<dyn Stream<Item = Bytes> + Send + Sync>>), (Bytes,)>(
+            "foo",
+            "bar",
+            [[Some(1)]],
+        )
+        .await
+        .context("failed to serve `foo.bar`")?;
+    let mut invocations = pin!(invocations);
+    for compression in [Compression::Zstd, Compression::Deflate] {
+        let (results, ()) = try_join!(
+            async {
+                let items = [payload.clone(), Bytes::from_static(b"foo")];
+                let (results,) = clt
+                    .invoke_values_blocking::<_, (Bytes,)>(
+                        InvocationContext::default().with_compression(compression),
+                        "foo",
+                        "bar",
+                        (
+                            payload.clone(),
+                            Box::pin(futures::stream::iter(items))
+                                as Pin<Box<dyn Stream<Item = _> + Send + Sync>>,
+                        ),
+                        &[[None; 0]],
+                    )
+                    .await
+                    .context("failed to invoke `foo.bar`")?;
+                anyhow::Ok(results)
+            },
+            async {
+                let ok = srv
+                    .get_ref()
+                    .accept(&srv_ep)
+                    .await
+                    .context("failed to accept client connection")?;
+                assert!(ok);
+                let (cx, (buf, items), rx, tx) = invocations
+                    .next()
+                    .await
+                    .context("invocation stream unexpectedly finished")?
+                    .context("failed to decode parameters")?;
+                assert_eq!(cx.compression, Some(compression));
+                // stream items are written to an indexed stream, which is never shut down
+                let rx = rx.context("stream should be received asynchronously")?;
+                let (items, ()) = try_join!(
+                    async { anyhow::Ok(items.collect::<Vec<_>>().await) },
+                    async { rx.await.context("failed to receive stream") }
+                )?;
+                assert_eq!(items, [payload.clone(), Bytes::from_static(b"foo")]);
+                tx(Ok((buf,))).await.context("failed to send results")
+            }
+        )?;
+        assert_eq!(results, payload);
+    }
+    Ok(())
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn compression_unsupported() -> anyhow::Result<()> {
+    let (clt, srv_ep) = endpoints()?;
+    let srv = Server::default();
+    let invocations = srv
+        .serve_values::<_, (Bytes,), (Bytes,)>("foo", "bar", [[None; 0]])
+        .await
+        .context("failed to serve `foo.bar`")?;
+    let mut invocations = pin!(invocations);
+    let cx = InvocationContext::default().with_compression(Compression::Zstd);
+
+    // streams are only compressed by `Compress`
+    let Err(err) = clt
+        .invoke(cx.clone(), "foo", "bar", Bytes::default(), &[[None; 0]])
+        .await
+    else {
+        bail!("compressed invocation should have failed")
+    };
+    assert!(err.to_string().contains("`Compress`"), "{err:#}");
+
+    // servers not wrapped in `Compress` reject compressed invocations
+    let clt = Compress::new(clt);
+    let (res, ok) = tokio::join!(
+        clt.invoke_values_blocking::<_, (Bytes,)>(
+            cx,
+            "foo",
+            "bar",
+            (Bytes::from_static(b"foo"),),
+            &[[None; 0]],
+        ),
+        async {
+            let ok = srv
+                .accept(&srv_ep)
+                .await
+                .context("failed to accept client connection")?;
+            let res = invocations
+                .next()
+                .await
+                .context("invocation stream unexpectedly finished")?;
+            anyhow::Ok((ok, res.is_err()))
+        }
+    );
+    assert!(res.is_err(), "invocation should have been rejected");
+    assert_eq!(ok?, (true, true));
+    Ok(())
+}
@@ -0,0 +1,530 @@
+//! Payload compression negotiated per invocation.
+//!
+//! The [`Compression`] algorithm is selected by the client using the invocation context, which
+//! transports transmit to the server (e.g. as a NATS header or in the QUIC parameter stream
+//! header). With the `compress` feature enabled, `Compress` wraps an [`Invoke`] or [`Serve`]
+//! transport and compresses each stream of the invocation, including every indexed stream,
+//! independently using the negotiated algorithm.
+//!
+//! Transports reject invocations using a [`Compression`] unless they are wrapped in `Compress`,
+//! see [`CompressionTransport`].
+//!
+//! [`Invoke`]: crate::Invoke
+//! [`Serve`]: crate::Serve
+
+use core::fmt;
+use core::str::FromStr;
+
+use anyhow::bail;
+
+/// Compression algorithm applied to invocation streams
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Compression {
+    /// Zstandard
+    Zstd,
+    /// Raw DEFLATE
+    Deflate,
+}
+
+impl Compression {
+    /// Returns the name of the algorithm, as transmitted to the peer
+    #[must_use]
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Deflate => "deflate",
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "zstd" => Ok(Self::Zstd),
+            "deflate" => Ok(Self::Deflate),
+            _ => bail!("unsupported compression algorithm `{s}`"),
+        }
+    }
+}
+
+/// Binary representation of the algorithm, `0` is reserved for no compression
+impl From<Compression> for u8 {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::Zstd => 1,
+            Compression::Deflate => 2,
+        }
+    }
+}
+
+impl TryFrom<u8> for Compression {
+    type Error = anyhow::Error;
+
+    fn try_from(v: u8) -> anyhow::Result<Self> {
+        match v {
+            1 => Ok(Self::Zstd),
+            2 => Ok(Self::Deflate),
+            _ => bail!("unsupported compression algorithm `{v}`"),
+        }
+    }
+}
+
+/// Invocation context carrying the negotiated [`Compression`]
+pub trait CompressionContext {
+    /// Returns the compression algorithm used by the invocation, if any
+    fn compression(&self) -> Option<Compression>;
+}
+
+/// Transport, which can be wrapped in `Compress`.
+///
+/// Transports reject invocations using a [`Compression`] unless compression is enabled,
+/// since the streams would otherwise be passed through without being compressed or decoded.
+/// `Compress` enables compression of the transport it wraps, wrappers of other transports
+/// forward it to the wrapped transport.
+pub trait CompressionTransport: Sized {
+    /// Returns the transport, which accepts invocations using a [`Compression`]
+    #[must_use]
+    fn with_compression_enabled(self) -> Self;
+}
+
+#[cfg(feature = "compress")]
+pub use codec::*;
+
+#[cfg(feature = "compress")]
+mod codec {
+    use core::pin::Pin;
+    use core::task::{ready, Context, Poll};
+
+    use std::sync::Arc;
+
+    use async_compression::tokio::bufread::{DeflateDecoder, ZstdDecoder};
+    use async_compression::tokio::write::{DeflateEncoder, ZstdEncoder};
+    use bytes::Bytes;
+    use futures::{Stream, StreamExt as _};
+    use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt as _, BufReader, ReadBuf};
+    use tracing::{instrument, trace};
+
+    use super::{Compression, CompressionContext, CompressionTransport};
+    use crate::layer::Layer;
+    use crate::{Index, Invoke, Reject, Serve};
+
+    /// Compresses invocation streams using the [`Compression`] negotiated in the context
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct CompressLayer;
+
+    impl<T: CompressionTransport> Layer<T> for CompressLayer {
+        type Invoke = Compress<T>;
+
+        fn layer(&self, inner: T) -> Self::Invoke {
+            Compress::new(inner)
+        }
+    }
+
+    /// [`Invoke`] and [`Serve`] transport produced by [`CompressLayer`]
+    #[derive(Clone, Debug)]
+    pub struct Compress<T> {
+        inner: T,
+    }
+
+    impl<T> Compress<T> {
+        /// Wraps `inner` and enables its compression
+        pub fn new(inner: T) -> Self
+        where
+            T: CompressionTransport,
+        {
+            Self {
+                inner: inner.with_compression_enabled(),
+            }
+        }
+
+        /// Returns a reference to the wrapped transport
+        pub fn get_ref(&self) -> &T {
+            &self.inner
+        }
+
+        /// Returns the wrapped transport
+        pub fn into_inner(self) -> T {
+            self.inner
+        }
+    }
+
+    /// Compresses `params` into a self-contained frame, which the server decodes
+    /// followed by the frame written to the root outgoing stream
+    pub(super) async fn compress_params(
+        compression: Compression,
+        params: &[u8],
+    ) -> std::io::Result<Bytes> {
+        let mut enc = Encoder::new(Vec::with_capacity(params.len() / 2), Some(compression));
+        enc.write_all(params).await?;
+        enc.shutdown().await?;
+        Ok(enc.into_inner().into())
+    }
+
+    impl<T> Invoke for Compress<T>
+    where
+        T: Invoke,
+        T::Context: CompressionContext,
+    {
+        type Context = T::Context;
+        type Outgoing = Compressed<T::Outgoing>;
+        type Incoming = Decompressed<T::Incoming>;
+
+        #[instrument(level = "trace", skip(self, cx, params, paths))]
+        async fn invoke(
+            &self,
+            cx: Self::Context,
+            instance: &str,
+            func: &str,
+            params: Bytes,
+            paths: &[impl AsRef<[Option<usize>]> + Send + Sync],
+        ) -> anyhow::Result<(Self::Outgoing, Self::Incoming)> {
+            let compression = cx.compression();
+            let params = if let Some(compression) = compression {
+                let n = params.len();
+                let params = compress_params(compression, &params).await?;
+                trace!(%compression, n, compressed = params.len(), "compressed parameters");
+                params
+            } else {
+                params
+            };
+            let (outgoing, incoming) = self.inner.invoke(cx, instance, func, params, paths).await?;
+            Ok((
+                Compressed::new(outgoing, compression),
+                Decompressed::new(incoming, compression),
+            ))
+        }
+    }
+
+    impl<T> Serve for Compress<T>
+    where
+        T: Serve,
+        T::Context: CompressionContext,
+    {
+        type Context = T::Context;
+        type Outgoing = Compressed<T::Outgoing>;
+        type Incoming = Decompressed<T::Incoming>;
+
+        #[instrument(level = "trace", skip(self, paths))]
+        async fn serve<P: AsRef<[Option<usize>]> + Send + Sync + 'static>(
+            &self,
+            instance: &str,
+            func: &str,
+            paths: impl Into<Arc<[P]>> + Send + Sync + 'static,
+        ) -> anyhow::Result<
+            impl Stream<Item = anyhow::Result<(Self::Context, Self::Outgoing, Self::Incoming)>>
+                + Send
+                + 'static,
+        > {
+            let invocations = self.inner.serve(instance, func, paths).await?;
+            Ok(invocations.map(|res| {
+                let (cx, tx, rx) = res?;
+                let compression = cx.compression();
+                Ok((
+                    cx,
+                    Compressed::new(tx, compression),
+                    Decompressed::new(rx, compression),
+                ))
+            }))
+        }
+    }
+
+    enum Encoder<T> {
+        Identity(T),
+        Zstd(ZstdEncoder<T>),
+        Deflate(DeflateEncoder<T>),
+    }
+
+    impl<T: AsyncWrite> Encoder<T> {
+        fn new(inner: T, compression: Option<Compression>) -> Self {
+            match compression {
+                None => Self::Identity(inner),
+                Some(Compression::Zstd) => Self::Zstd(ZstdEncoder::new(inner)),
+                Some(Compression::Deflate) => Self::Deflate(DeflateEncoder::new(inner)),
+            }
+        }
+
+        fn compression(&self) -> Option<Compression> {
+            match self {
+                Self::Identity(..) => None,
+                Self::Zstd(..) => Some(Compression::Zstd),
+                Self::Deflate(..) => Some(Compression::Deflate),
+            }
+        }
+
+        fn get_ref(&self) -> &T {
+            match self {
+                Self::Identity(inner) => inner,
+                Self::Zstd(enc) => enc.get_ref(),
+                Self::Deflate(enc) => enc.get_ref(),
+            }
+        }
+
+        fn into_inner(self) -> T {
+            match self {
+                Self::Identity(inner) => inner,
+                Self::Zstd(enc) => enc.into_inner(),
+                Self::Deflate(enc) => enc.into_inner(),
+            }
+        }
+    }
+
+    impl<T: AsyncWrite + Unpin> AsyncWrite for Encoder<T> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            match &mut *self {
+                Self::Identity(inner) => Pin::new(inner).poll_write(cx, buf),
+                Self::Zstd(enc) => Pin::new(enc).poll_write(cx, buf),
+                Self::Deflate(enc) => Pin::new(enc).poll_write(cx, buf),
+            }
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            match &mut *self {
+                Self::Identity(inner) => Pin::new(inner).poll_flush(cx),
+                Self::Zstd(enc) => Pin::new(enc).poll_flush(cx),
+                Self::Deflate(enc) => Pin::new(enc).poll_flush(cx),
+            }
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            match &mut *self {
+                Self::Identity(inner) => Pin::new(inner).poll_shutdown(cx),
+                Self::Zstd(enc) => Pin::new(enc).poll_shutdown(cx),
+                Self::Deflate(enc) => Pin::new(enc).poll_shutdown(cx),
+            }
+        }
+    }
+
+    /// Number of bytes written to [`Compressed`], after which the compressed data is flushed
+    pub(super) const FLUSH_THRESHOLD: usize = 64 * 1024;
+
+    /// Outgoing stream of [`Compress`].
+    ///
+    /// Compressed data is written to the underlying stream when the stream is flushed or shut
+    /// down, or once 64 KiB were written since the last flush.
+    ///
+    /// Data written since the last flush is lost if the stream is dropped, it must be flushed
+    /// or shut down first. Encoded values, which are written to indexed streams (e.g. stream
+    /// items), are flushed by the encoder.
+    pub struct Compressed<T> {
+        enc: Encoder<T>,
+        /// Number of bytes written since the last flush
+        buffered: usize,
+    }
+
+    impl<T: AsyncWrite> Compressed<T> {
+        /// Compresses `inner` using `compression`, if set
+        pub fn new(inner: T, compression: Option<Compression>) -> Self {
+            Self {
+                enc: Encoder::new(inner, compression),
+                buffered: 0,
+            }
+        }
+
+        /// Returns the compression algorithm used by the stream, if any
+        pub fn compression(&self) -> Option<Compression> {
+            self.enc.compression()
+        }
+
+        /// Returns a reference to the underlying stream
+        pub fn get_ref(&self) -> &T {
+            self.enc.get_ref()
+        }
+    }
+
+    impl<T: AsyncWrite + Index<T>> Index<Self> for Compressed<T> {
+        fn index(&self, path: &[usize]) -> anyhow::Result<Self> {
+            let inner = self.get_ref().index(path)?;
+            Ok(Self::new(inner, self.compression()))
+        }
+    }
+
+    impl<T: AsyncWrite + Reject + Send> Reject for Compressed<T> {
+        async fn reject(self, err: &str) -> anyhow::Result<()> {
+            self.enc.into_inner().reject(err).await
+        }
+    }
+
+    impl<T: AsyncWrite + Unpin> AsyncWrite for Compressed<T> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            if let Encoder::Identity(inner) = &mut self.enc {
+                return Pin::new(inner).poll_write(cx, buf);
+            }
+            if self.buffered >= FLUSH_THRESHOLD {
+                trace!(buffered = self.buffered, "flushing compressed data");
+                ready!(self.as_mut().poll_flush(cx))?;
+            }
+            let n = ready!(Pin::new(&mut self.enc).poll_write(cx, buf))?;
+            self.buffered = self.buffered.saturating_add(n);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            ready!(Pin::new(&mut self.enc).poll_flush(cx))?;
+            self.buffered = 0;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.enc).poll_shutdown(cx)
+        }
+    }
+
+    enum Decoder<T> {
+        Identity(T),
+        Zstd(ZstdDecoder<BufReader<T>>),
+        Deflate(DeflateDecoder<BufReader<T>>),
+    }
+
+    /// Incoming stream of [`Compress`]
+    pub struct Decompressed<T> {
+        dec: Decoder<T>,
+    }
+
+    impl<T: AsyncRead> Decompressed<T> {
+        /// Decompresses `inner` using `compression`, if set
+        pub fn new(inner: T, compression: Option<Compression>) -> Self {
+            // compressed parameters precede the frame of the root stream
+            let dec = match compression {
+                None => Decoder::Identity(inner),
+                Some(Compression::Zstd) => {
+                    let mut dec = ZstdDecoder::new(BufReader::new(inner));
+                    dec.multiple_members(true);
+                    Decoder::Zstd(dec)
+                }
+                Some(Compression::Deflate) => {
+                    let mut dec = DeflateDecoder::new(BufReader::new(inner));
+                    dec.multiple_members(true);
+                    Decoder::Deflate(dec)
+                }
+            };
+            Self { dec }
+        }
+
+        /// Returns the compression algorithm used by the stream, if any
+        pub fn compression(&self) -> Option<Compression> {
+            match self.dec {
+                Decoder::Identity(..) => None,
+                Decoder::Zstd(..) => Some(Compression::Zstd),
+                Decoder::Deflate(..) => Some(Compression::Deflate),
+            }
+        }
+
+        /// Returns a reference to the underlying stream
+        pub fn get_ref(&self) -> &T {
+            match &self.dec {
+                Decoder::Identity(inner) => inner,
+                Decoder::Zstd(dec) => dec.get_ref().get_ref(),
+                Decoder::Deflate(dec) => dec.get_ref().get_ref(),
+            }
+        }
+    }
+
+    impl<T: AsyncRead + Index<T>> Index<Self> for Decompressed<T> {
+        fn index(&self, path: &[usize]) -> anyhow::Result<Self> {
+            let inner = self.get_ref().index(path)?;
+            Ok(Self::new(inner, self.compression()))
+        }
+    }
+
+    impl<T: AsyncRead + Unpin> AsyncRead for Decompressed<T> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            match &mut self.dec {
+                Decoder::Identity(inner) => Pin::new(inner).poll_read(cx, buf),
+                Decoder::Zstd(dec) => Pin::new(dec).poll_read(cx, buf),
+                Decoder::Deflate(dec) => Pin::new(dec).poll_read(cx, buf),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "compress"))]
+mod tests {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn round_trip() -> anyhow::Result<()> {
+        let payload = br#"{"foo":"bar","baz":[1,2,3]}"#.repeat(1024);
+        for compression in [Compression::Zstd, Compression::Deflate] {
+            assert_eq!(compression.as_str().parse::<Compression>()?, compression);
+            assert_eq!(Compression::try_from(u8::from(compression))?, compression);
+
+            let params = compress_params(compression, &payload).await?;
+            assert!(params.len() * 10 < payload.len());
+
+            let mut tx = Compressed::new(Vec::new(), Some(compression));
+            tx.write_all(&payload).await?;
+            // writes are buffered until the stream is flushed
+            assert!(tx.get_ref().is_empty());
+            tx.flush().await?;
+
+            // flushed writes are decodable before the stream is shut down
+            let buf = [params.as_ref(), tx.get_ref()].concat();
+            let mut rx = Decompressed::new(buf.as_slice(), Some(compression));
+            let mut decoded = vec![0; payload.len() * 2];
+            rx.read_exact(&mut decoded).await?;
+            assert_eq!(decoded, payload.repeat(2));
+
+            tx.shutdown().await?;
+            let buf = [params.as_ref(), tx.get_ref()].concat();
+            let mut rx = Decompressed::new(buf.as_slice(), Some(compression));
+            let mut decoded = Vec::new();
+            rx.read_to_end(&mut decoded).await?;
+            assert_eq!(decoded, payload.repeat(2));
+        }
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn unflushed() -> anyhow::Result<()> {
+        let payload = br#"{"foo":"bar","baz":[1,2,3]}"#.repeat(1 << 14);
+        for compression in [Compression::Zstd, Compression::Deflate] {
+            let mut tx = Compressed::new(Vec::new(), Some(compression));
+            for chunk in payload.chunks(4096) {
+                tx.write_all(chunk).await?;
+            }
+
+            // at most `FLUSH_THRESHOLD` bytes are buffered without an explicit flush
+            let buf = tx.get_ref().clone();
+            let mut rx = Decompressed::new(buf.as_slice(), Some(compression));
+            let mut decoded = vec![0; payload.len() - FLUSH_THRESHOLD - 4096];
+            rx.read_exact(&mut decoded).await?;
+            assert_eq!(decoded, payload[..decoded.len()]);
+
+            // shutting down the stream flushes the remaining data
+            tx.shutdown().await?;
+            let mut rx = Decompressed::new(tx.get_ref().as_slice(), Some(compression));
+            let mut decoded = Vec::new();
+            rx.read_to_end(&mut decoded).await?;
+            assert_eq!(decoded, payload);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use core::fmt;
+
+/// wRPC invocation error
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// No server is available to handle the invocation
+    NoResponders,
+    /// No server accepted the invocation in time
+    HandshakeTimedOut,
+    /// Payload of `len` bytes exceeds the maximum of `max` bytes supported by the transport
+    PayloadTooLarge { len: usize, max: usize },
+    /// Value received from the peer exceeds a configured [`Limits`](crate::Limits) entry
+    LimitExceeded {
+        limit: &'static str,
+        len: u64,
+        max: u64,
+    },
+    /// Handling the invocation failed on the peer, contains the error message sent by the peer
+    Handler(String),
+    /// The invocation deadline was exceeded
+    TimedOut(std::io::Error),
+    /// The peer closed the connection or cancelled the invocation
+    Closed(std::io::Error),
+    /// Failed to encode values
+    Encode(Box<dyn std::error::Error + Send + Sync>),
+    /// Failed to decode values
+    Decode(Box<dyn std::error::Error + Send + Sync>),
+    /// I/O error
+    Io(std::io::Error),
+    /// Transport-specific error
+    Transport(anyhow::Error),
+}
+
+impl Error {
+    /// Constructs an [`Error`] from a value encoding error
+    pub fn encode(err: impl std::error::Error + Send + Sync + 'static) -> Self {
+        Self::Encode(Box::new(err))
+    }
+
+    /// Constructs an [`Error`] from a value decoding error.
+    ///
+    /// Decoding errors caused by underlying stream I/O failures are classified as such.
+    pub fn decode(err: impl std::error::Error + Send + Sync + 'static) -> Self {
+        let err: Box<dyn std::error::Error + Send + Sync> = Box::new(err);
+        match err.downcast::<std::io::Error>() {
+            Ok(err) if err.kind() == std::io::ErrorKind::InvalidData => Self::Decode(err),
+            Ok(err) => Self::from(*err),
+            Err(err) => Self::Decode(err),
+        }
+    }
+
+    /// Returns `true` if the invocation failed on the peer
+    #[must_use]
+    pub fn is_handler(&self) -> bool {
+        matches!(self, Self::Handler(..))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoResponders => write!(f, "no servers available to handle the invocation"),
+            Self::HandshakeTimedOut => {
+                write!(f, "timed out waiting for a server to accept the invocation")
+            }
+            Self::PayloadTooLarge { len, max } => {
+                write!(
+                    f,
+                    "payload of {len} bytes exceeds the maximum of {max} bytes"
+                )
+            }
+            Self::LimitExceeded { limit, len, max } => {
+                write!(f, "{limit} of {len} exceeds the maximum of {max}")
+            }
+            Self::Handler(err) => write!(f, "invocation failed: {err}"),
+            Self::TimedOut(..) => write!(f, "invocation timed out"),
+            Self::Closed(..) => write!(f, "peer closed the invocation"),
+            Self::Encode(..) => write!(f, "failed to encode values"),
+            Self::Decode(..) => write!(f, "failed to decode values"),
+            Self::Io(..) => write!(f, "I/O failed"),
+            Self::Transport(..) => write!(f, "transport failed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::NoResponders
+            | Self::HandshakeTimedOut
+            | Self::PayloadTooLarge { .. }
+            | Self::LimitExceeded { .. }
+            | Self::Handler(..) => None,
+            Self::TimedOut(err) | Self::Closed(err) | Self::Io(err) => Some(err),
+            Self::Encode(err) | Self::Decode(err) => Some(err.as_ref()),
+            Self::Transport(err) => Some(err.as_ref()),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        if err
+            .get_ref()
+            .is_some_and(|err| err.downcast_ref::<Self>().is_some())
+        {
+            let kind = err.kind();
+            return match err.into_inner().map(|err| err.downcast::<Self>()) {
+                Some(Ok(err)) => *err,
+                _ => Self::Io(kind.into()),
+            };
+        }
+        match err.kind() {
+            std::io::ErrorKind::TimedOut => Self::TimedOut(err),
+            std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::UnexpectedEof => Self::Closed(err),
+            _ => Self::Io(err),
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<Self>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        match err.downcast::<std::io::Error>() {
+            Ok(err) => Self::from(err),
+            Err(err) => Self::Transport(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context as _;
+
+    use super::*;
+
+    #[test]
+    fn classify() {
+        let err = std::io::Error::other(Error::NoResponders);
+        assert!(matches!(Error::from(err), Error::NoResponders));
+
+        let err = std::io::Error::other(Error::Handler("test".into()));
+        let err = anyhow::Error::from(err).context("failed to invoke");
+        assert!(matches!(Error::from(err), Error::Handler(err) if err == "test"));
+
+        let err = std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+        assert!(matches!(Error::decode(err), Error::Closed(..)));
+
+        let err = std::io::Error::from(std::io::ErrorKind::InvalidData);
+        assert!(matches!(Error::decode(err), Error::Decode(..)));
+
+        let err = std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            Error::LimitExceeded {
+                limit: "list length",
+                len: 2,
+                max: 1,
+            },
+        );
+        assert!(matches!(
+            Error::decode(err),
+            Error::LimitExceeded { len: 2, max: 1, .. }
+        ));
+
+        let err = Err::<(), _>(Error::NoResponders)
+            .context("failed to invoke")
+            .unwrap_err();
+        assert!(matches!(Error::from(err), Error::NoResponders));
+
+        let err = anyhow::anyhow!("test");
+        assert!(matches!(Error::from(err), Error::Transport(..)));
+    }
+}
@@ -0,0 +1,463 @@
+//! Server-side interceptors for [`Serve`] transports.
+//!
+//! [`Intercepted`] wraps a [`Serve`] implementation and applies an [`Interceptor`] to each
+//! accepted invocation before it is yielded to the handler. Invocations rejected by the
+//! interceptor are reported to the peer using [`Reject`] and are never yielded.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{anyhow, bail};
+use futures::{Stream, StreamExt as _};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, instrument, warn};
+
+use crate::compress::CompressionTransport;
+use crate::{Index, Reject, Serve};
+
+/// Inspects accepted invocations, admitting or rejecting them
+pub trait Interceptor<C>: Send + Sync + 'static {
+    /// Value held until all streams of an admitted invocation are dropped
+    type Guard: Send + Sync + 'static;
+
+    /// Intercept an invocation of function `func` from instance `instance`.
+    ///
+    /// Returns the (possibly modified) context and a guard on success. The error returned
+    /// on failure is transmitted to the peer.
+    fn intercept(
+        &self,
+        instance: &str,
+        func: &str,
+        cx: C,
+    ) -> impl Future<Output = anyhow::Result<(C, Self::Guard)>> + Send;
+}
+
+impl<C: Send + 'static> Interceptor<C> for () {
+    type Guard = ();
+
+    async fn intercept(&self, _instance: &str, _func: &str, cx: C) -> anyhow::Result<(C, ())> {
+        Ok((cx, ()))
+    }
+}
+
+impl<C, A, B> Interceptor<C> for (A, B)
+where
+    C: Send + 'static,
+    A: Interceptor<C>,
+    B: Interceptor<C>,
+{
+    type Guard = (A::Guard, B::Guard);
+
+    async fn intercept(
+        &self,
+        instance: &str,
+        func: &str,
+        cx: C,
+    ) -> anyhow::Result<(C, Self::Guard)> {
+        let (cx, a) = self.0.intercept(instance, func, cx).await?;
+        let (cx, b) = self.1.intercept(instance, func, cx).await?;
+        Ok((cx, (a, b)))
+    }
+}
+
+/// [`Serve`] transport applying an [`Interceptor`] to all accepted invocations
+pub struct Intercepted<S, I> {
+    inner: S,
+    interceptor: Arc<I>,
+}
+
+impl<S, I> Intercepted<S, I> {
+    pub fn new(inner: S, interceptor: I) -> Self {
+        Self {
+            inner,
+            interceptor: Arc::new(interceptor),
+        }
+    }
+
+    /// Returns the wrapped transport
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: CompressionTransport, I> CompressionTransport for Intercepted<S, I> {
+    fn with_compression_enabled(self) -> Self {
+        Self {
+            inner: self.inner.with_compression_enabled(),
+            ..self
+        }
+    }
+}
+
+impl<S, I> Serve for Intercepted<S, I>
+where
+    S: Serve,
+    I: Interceptor<S::Context>,
+{
+    type Context = S::Context;
+    type Outgoing = Guarded<S::Outgoing, I::Guard>;
+    type Incoming = Guarded<S::Incoming, I::Guard>;
+
+    #[instrument(level = "trace", skip(self, paths))]
+    async fn serve<P: AsRef<[Option<usize>]> + Send + Sync + 'static>(
+        &self,
+        instance: &str,
+        func: &str,
+        paths: impl Into<Arc<[P]>> + Send + Sync + 'static,
+    ) -> anyhow::Result<
+        impl Stream<Item = anyhow::Result<(Self::Context, Self::Outgoing, Self::Incoming)>>
+            + Send
+            + 'static,
+    > {
+        let invocations = self.inner.serve(instance, func, paths).await?;
+        let interceptor = Arc::clone(&self.interceptor);
+        let instance = Arc::<str>::from(instance);
+        let func = Arc::<str>::from(func);
+        Ok(invocations.filter_map(move |res| {
+            let interceptor = Arc::clone(&interceptor);
+            let instance = Arc::clone(&instance);
+            let func = Arc::clone(&func);
+            async move {
+                let (cx, tx, rx) = match res {
+                    Ok(invocation) => invocation,
+                    Err(err) => return Some(Err(err)),
+                };
+                match interceptor.intercept(&instance, &func, cx).await {
+                    Ok((cx, guard)) => {
+                        let guard = Arc::new(guard);
+                        Some(Ok((
+                            cx,
+                            Guarded {
+                                inner: tx,
+                                guard: Arc::clone(&guard),
+                            },
+                            Guarded { inner: rx, guard },
+                        )))
+                    }
+                    Err(err) => {
+                        debug!(?err, "rejecting invocation");
+                        if let Err(err) = tx.reject(&format!("{err:#}")).await {
+                            warn!(?err, "failed to reject invocation");
+                        }
+                        None
+                    }
+                }
+            }
+        }))
+    }
+}
+
+/// Stream holding the guard of an intercepted invocation
+pub struct Guarded<T, G> {
+    inner: T,
+    guard: Arc<G>,
+}
+
+impl<T, G> Guarded<T, G> {
+    /// Returns the guard returned by the [`Interceptor`]
+    pub fn guard(&self) -> &G {
+        &self.guard
+    }
+}
+
+impl<T: Index<T>, G> Index<Self> for Guarded<T, G> {
+    fn index(&self, path: &[usize]) -> anyhow::Result<Self> {
+        let inner = self.inner.index(path)?;
+        Ok(Self {
+            inner,
+            guard: Arc::clone(&self.guard),
+        })
+    }
+}
+
+impl<T: Reject + Send, G: Send + Sync> Reject for Guarded<T, G> {
+    async fn reject(self, err: &str) -> anyhow::Result<()> {
+        self.inner.reject(err).await
+    }
+}
+
+impl<T: AsyncRead + Unpin, G> AsyncRead for Guarded<T, G> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin, G> AsyncWrite for Guarded<T, G> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Authenticates invocations using the invocation context
+pub struct Authenticate<F>(pub F);
+
+impl<C, F> Interceptor<C> for Authenticate<F>
+where
+    C: Send + 'static,
+    F: Fn(&str, &str, &C) -> anyhow::Result<()> + Send + Sync + 'static,
+{
+    type Guard = ();
+
+    async fn intercept(&self, instance: &str, func: &str, cx: C) -> anyhow::Result<(C, ())> {
+        (self.0)(instance, func, &cx).map_err(|err| err.context("unauthenticated"))?;
+        Ok((cx, ()))
+    }
+}
+
+/// Rejects invocations once the number of concurrently handled invocations reaches a limit
+#[derive(Clone, Debug)]
+pub struct AdmissionControl {
+    semaphore: Arc<Semaphore>,
+}
+
+impl AdmissionControl {
+    /// Admit at most `max` concurrent invocations
+    #[must_use]
+    pub fn new(max: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max)),
+        }
+    }
+}
+
+impl<C: Send + 'static> Interceptor<C> for AdmissionControl {
+    type Guard = OwnedSemaphorePermit;
+
+    async fn intercept(
+        &self,
+        _instance: &str,
+        _func: &str,
+        cx: C,
+    ) -> anyhow::Result<(C, OwnedSemaphorePermit)> {
+        let Ok(permit) = Arc::clone(&self.semaphore).try_acquire_owned() else {
+            bail!("server is at capacity")
+        };
+        Ok((cx, permit))
+    }
+}
+
+/// Metrics of a single function
+#[derive(Debug, Default)]
+pub struct FunctionMetrics {
+    invocations: AtomicU64,
+    in_flight: AtomicU64,
+    duration_us: AtomicU64,
+}
+
+impl FunctionMetrics {
+    /// Total number of invocations
+    pub fn invocations(&self) -> u64 {
+        self.invocations.load(Ordering::Relaxed)
+    }
+
+    /// Number of invocations currently in progress
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Total duration of all finished invocations
+    pub fn duration(&self) -> Duration {
+        Duration::from_micros(self.duration_us.load(Ordering::Relaxed))
+    }
+}
+
+/// Guard tracking an invocation in progress, see [`Metrics`]
+pub struct InFlight {
+    metrics: Arc<FunctionMetrics>,
+    start: Instant,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed().as_micros();
+        self.metrics
+            .duration_us
+            .fetch_add(elapsed.try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+        self.metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Collects per-function invocation metrics.
+///
+/// Only invocations reaching this interceptor are accounted for, i.e. invocations rejected
+/// by interceptors preceding it are not counted.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    functions: Arc<std::sync::Mutex<HashMap<(String, String), Arc<FunctionMetrics>>>>,
+}
+
+impl Metrics {
+    /// Returns metrics of function `func` from instance `instance`
+    pub fn function(&self, instance: &str, func: &str) -> anyhow::Result<Arc<FunctionMetrics>> {
+        let mut functions = self
+            .functions
+            .lock()
+            .map_err(|err| anyhow!(err.to_string()).context("failed to lock map"))?;
+        let metrics = functions
+            .entry((instance.to_string(), func.to_string()))
+            .or_default();
+        Ok(Arc::clone(metrics))
+    }
+}
+
+impl<C: Send + 'static> Interceptor<C> for Metrics {
+    type Guard = InFlight;
+
+    async fn intercept(&self, instance: &str, func: &str, cx: C) -> anyhow::Result<(C, InFlight)> {
+        let metrics = self.function(instance, func)?;
+        metrics.invocations.fetch_add(1, Ordering::Relaxed);
+        metrics.in_flight.fetch_add(1, Ordering::Relaxed);
+        Ok((
+            cx,
+            InFlight {
+                metrics,
+                start: Instant::now(),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+
+    use anyhow::bail;
+    use futures::{stream, TryStreamExt as _};
+
+    use super::*;
+
+    struct NoopStream(Arc<AtomicUsize>);
+
+    impl Index<Self> for NoopStream {
+        fn index(&self, _path: &[usize]) -> anyhow::Result<Self> {
+            Ok(Self(Arc::clone(&self.0)))
+        }
+    }
+
+    impl Reject for NoopStream {
+        async fn reject(self, _err: &str) -> anyhow::Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for NoopStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for NoopStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Yields `n` invocations with context set to the invocation index
+    struct Fixed {
+        n: usize,
+        rejected: Arc<AtomicUsize>,
+    }
+
+    impl Serve for Fixed {
+        type Context = usize;
+        type Outgoing = NoopStream;
+        type Incoming = NoopStream;
+
+        async fn serve<P: AsRef<[Option<usize>]> + Send + Sync + 'static>(
+            &self,
+            _instance: &str,
+            _func: &str,
+            _paths: impl Into<Arc<[P]>> + Send + Sync + 'static,
+        ) -> anyhow::Result<
+            impl Stream<Item = anyhow::Result<(Self::Context, Self::Outgoing, Self::Incoming)>>
+                + Send
+                + 'static,
+        > {
+            let rejected = Arc::clone(&self.rejected);
+            Ok(stream::iter(0..self.n).map(move |i| {
+                Ok((
+                    i,
+                    NoopStream(Arc::clone(&rejected)),
+                    NoopStream(Arc::clone(&rejected)),
+                ))
+            }))
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn intercept() -> anyhow::Result<()> {
+        let rejected = Arc::default();
+        let metrics = Metrics::default();
+        let srv = Intercepted::new(
+            Fixed {
+                n: 4,
+                rejected: Arc::clone(&rejected),
+            },
+            (
+                Authenticate(|_: &str, _: &str, cx: &usize| {
+                    if *cx == 0 {
+                        bail!("invalid credentials")
+                    }
+                    Ok(())
+                }),
+                (AdmissionControl::new(2), metrics.clone()),
+            ),
+        );
+        let invocations: Vec<_> = srv
+            .serve("foo", "bar", [[None; 0]])
+            .await?
+            .try_collect()
+            .await?;
+        let cxs: Vec<_> = invocations.iter().map(|(cx, ..)| *cx).collect();
+        assert_eq!(cxs, [1, 2]);
+        assert_eq!(rejected.load(Ordering::Relaxed), 2);
+
+        let bar = metrics.function("foo", "bar")?;
+        assert_eq!(bar.invocations(), 2);
+        assert_eq!(bar.in_flight(), 2);
+        drop(invocations);
+        assert_eq!(bar.in_flight(), 0);
+        Ok(())
+    }
+}
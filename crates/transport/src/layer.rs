@@ -0,0 +1,732 @@
+//! Composable middleware for [`Invoke`] transports.
+//!
+//! A [`Layer`] wraps an [`Invoke`] implementation and produces another one, which can intercept
+//! the invocation parameters and context as well as the returned streams. Layers can be composed
+//! using [`Stack`] or [`InvokeBuilder`] and work with any transport.
+
+use core::fmt;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context as _};
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, debug_span, instrument, warn, Instrument as _, Span};
+
+use crate::compress::CompressionTransport;
+use crate::{Index, Invoke, Reject};
+
+/// Decorates an [`Invoke`] transport with additional functionality, analogous to `tower::Layer`
+pub trait Layer<T> {
+    /// The wrapped transport
+    type Invoke;
+
+    /// Wrap the `inner` transport
+    fn layer(&self, inner: T) -> Self::Invoke;
+}
+
+impl<T, L: Layer<T>> Layer<T> for &L {
+    type Invoke = L::Invoke;
+
+    fn layer(&self, inner: T) -> Self::Invoke {
+        (*self).layer(inner)
+    }
+}
+
+/// A no-op [`Layer`], which returns the transport unchanged
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Identity;
+
+impl<T> Layer<T> for Identity {
+    type Invoke = T;
+
+    fn layer(&self, inner: T) -> Self::Invoke {
+        inner
+    }
+}
+
+/// Two [`Layer`]s chained together, `inner` is applied first
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stack<Inner, Outer> {
+    inner: Inner,
+    outer: Outer,
+}
+
+impl<Inner, Outer> Stack<Inner, Outer> {
+    pub fn new(inner: Inner, outer: Outer) -> Self {
+        Self { inner, outer }
+    }
+}
+
+impl<T, Inner, Outer> Layer<T> for Stack<Inner, Outer>
+where
+    Inner: Layer<T>,
+    Outer: Layer<Inner::Invoke>,
+{
+    type Invoke = Outer::Invoke;
+
+    fn layer(&self, inner: T) -> Self::Invoke {
+        self.outer.layer(self.inner.layer(inner))
+    }
+}
+
+/// Builds a stack of [`Layer`]s, analogous to `tower::ServiceBuilder`.
+///
+/// Layers added first are outermost, i.e. they observe invocations first.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InvokeBuilder<L> {
+    layer: L,
+}
+
+impl InvokeBuilder<Identity> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self { layer: Identity }
+    }
+}
+
+impl<L> InvokeBuilder<L> {
+    /// Add a new [`Layer`] to the stack
+    pub fn layer<T>(self, layer: T) -> InvokeBuilder<Stack<T, L>> {
+        InvokeBuilder {
+            layer: Stack::new(layer, self.layer),
+        }
+    }
+
+    /// Add a [`TimeoutLayer`] to the stack
+    pub fn timeout(self, timeout: Duration) -> InvokeBuilder<Stack<TimeoutLayer, L>> {
+        self.layer(TimeoutLayer::new(timeout))
+    }
+
+    /// Add a [`RetryLayer`] to the stack
+    pub fn retry(self, attempts: usize) -> InvokeBuilder<Stack<RetryLayer, L>> {
+        self.layer(RetryLayer::new(attempts))
+    }
+
+    /// Add a [`ConcurrencyLimitLayer`] to the stack
+    pub fn concurrency_limit(self, max: usize) -> InvokeBuilder<Stack<ConcurrencyLimitLayer, L>> {
+        self.layer(ConcurrencyLimitLayer::new(max))
+    }
+
+    /// Add a [`TraceLayer`] to the stack
+    pub fn trace(self) -> InvokeBuilder<Stack<TraceLayer, L>> {
+        self.layer(TraceLayer)
+    }
+
+    /// Add a [`RecordLayer`](crate::record::RecordLayer) writing to `sink` to the stack
+    pub fn record(
+        self,
+        sink: impl std::io::Write + Send + 'static,
+    ) -> InvokeBuilder<Stack<crate::record::RecordLayer, L>> {
+        self.layer(crate::record::RecordLayer::new(sink))
+    }
+
+    /// Add a [`CompressLayer`](crate::compress::CompressLayer) to the stack
+    #[cfg(feature = "compress")]
+    pub fn compress(self) -> InvokeBuilder<Stack<crate::compress::CompressLayer, L>> {
+        self.layer(crate::compress::CompressLayer)
+    }
+
+    /// Wrap `inner` transport with the stack of layers
+    pub fn invoke<T>(&self, inner: T) -> L::Invoke
+    where
+        L: Layer<T>,
+    {
+        self.layer.layer(inner)
+    }
+}
+
+/// Enforces a timeout on establishing an invocation
+#[derive(Clone, Copy, Debug)]
+pub struct TimeoutLayer {
+    timeout: Duration,
+}
+
+impl TimeoutLayer {
+    #[must_use]
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl<T> Layer<T> for TimeoutLayer {
+    type Invoke = Timeout<T>;
+
+    fn layer(&self, inner: T) -> Self::Invoke {
+        Timeout {
+            inner,
+            timeout: self.timeout,
+        }
+    }
+}
+
+/// [`Invoke`] transport produced by [`TimeoutLayer`]
+#[derive(Clone, Debug)]
+pub struct Timeout<T> {
+    inner: T,
+    timeout: Duration,
+}
+
+impl<T: CompressionTransport> CompressionTransport for Timeout<T> {
+    fn with_compression_enabled(self) -> Self {
+        Self {
+            inner: self.inner.with_compression_enabled(),
+            ..self
+        }
+    }
+}
+
+impl<T: Invoke> Invoke for Timeout<T> {
+    type Context = T::Context;
+    type Outgoing = T::Outgoing;
+    type Incoming = T::Incoming;
+
+    #[instrument(level = "trace", skip(self, cx, params, paths))]
+    async fn invoke(
+        &self,
+        cx: Self::Context,
+        instance: &str,
+        func: &str,
+        params: Bytes,
+        paths: &[impl AsRef<[Option<usize>]> + Send + Sync],
+    ) -> anyhow::Result<(Self::Outgoing, Self::Incoming)> {
+        tokio::time::timeout(
+            self.timeout,
+            self.inner.invoke(cx, instance, func, params, paths),
+        )
+        .await
+        .map_err(|_| anyhow!(std::io::Error::from(std::io::ErrorKind::TimedOut)))
+        .context("invocation timed out")?
+    }
+}
+
+/// Returns `true` if `err` was caused by a failure to establish a connection to the peer
+#[must_use]
+pub fn is_connection_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        err.downcast_ref::<std::io::Error>().is_some_and(|err| {
+            matches!(
+                err.kind(),
+                std::io::ErrorKind::NotConnected
+                    | std::io::ErrorKind::ConnectionRefused
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+            )
+        })
+    })
+}
+
+/// Retries invocations, which failed due to a connection failure.
+///
+/// The invocation context is cloned for each attempt.
+#[derive(Clone, Copy)]
+pub struct RetryLayer {
+    attempts: usize,
+    backoff: Duration,
+    should_retry: fn(&anyhow::Error) -> bool,
+}
+
+impl fmt::Debug for RetryLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryLayer")
+            .field("attempts", &self.attempts)
+            .field("backoff", &self.backoff)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RetryLayer {
+    /// Retry failed invocations at most `attempts` times using [`is_connection_error`]
+    /// to determine whether a failed invocation should be retried
+    #[must_use]
+    pub fn new(attempts: usize) -> Self {
+        Self {
+            attempts,
+            backoff: Duration::ZERO,
+            should_retry: is_connection_error,
+        }
+    }
+
+    /// Wait `backoff` before each retry, the duration doubles with each attempt
+    #[must_use]
+    pub fn with_backoff(self, backoff: Duration) -> Self {
+        Self { backoff, ..self }
+    }
+
+    /// Use a custom predicate to determine whether a failed invocation should be retried
+    #[must_use]
+    pub fn with_predicate(self, should_retry: fn(&anyhow::Error) -> bool) -> Self {
+        Self {
+            should_retry,
+            ..self
+        }
+    }
+}
+
+impl<T> Layer<T> for RetryLayer {
+    type Invoke = Retry<T>;
+
+    fn layer(&self, inner: T) -> Self::Invoke {
+        Retry {
+            inner,
+            policy: *self,
+        }
+    }
+}
+
+/// [`Invoke`] transport produced by [`RetryLayer`]
+#[derive(Clone, Debug)]
+pub struct Retry<T> {
+    inner: T,
+    policy: RetryLayer,
+}
+
+impl<T: CompressionTransport> CompressionTransport for Retry<T> {
+    fn with_compression_enabled(self) -> Self {
+        Self {
+            inner: self.inner.with_compression_enabled(),
+            ..self
+        }
+    }
+}
+
+impl<T> Invoke for Retry<T>
+where
+    T: Invoke,
+    T::Context: Clone,
+{
+    type Context = T::Context;
+    type Outgoing = T::Outgoing;
+    type Incoming = T::Incoming;
+
+    #[instrument(level = "trace", skip(self, cx, params, paths))]
+    async fn invoke(
+        &self,
+        cx: Self::Context,
+        instance: &str,
+        func: &str,
+        params: Bytes,
+        paths: &[impl AsRef<[Option<usize>]> + Send + Sync],
+    ) -> anyhow::Result<(Self::Outgoing, Self::Incoming)> {
+        let RetryLayer {
+            attempts,
+            mut backoff,
+            should_retry,
+        } = self.policy;
+        let mut attempt = 0;
+        loop {
+            match self
+                .inner
+                .invoke(cx.clone(), instance, func, params.clone(), paths)
+                .await
+            {
+                Ok(res) => return Ok(res),
+                Err(err) if attempt < attempts && should_retry(&err) => {
+                    attempt += 1;
+                    warn!(?err, attempt, "invocation failed, retrying");
+                    if !backoff.is_zero() {
+                        tokio::time::sleep(backoff).await;
+                        backoff = backoff.saturating_mul(2);
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Limits the number of concurrent invocations.
+///
+/// An invocation is considered to be in progress until both of the returned streams and all
+/// of their indexed descendants are dropped.
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimitLayer {
+    semaphore: Arc<Semaphore>,
+}
+
+impl ConcurrencyLimitLayer {
+    /// Allow at most `max` concurrent invocations
+    #[must_use]
+    pub fn new(max: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max)),
+        }
+    }
+}
+
+impl<T> Layer<T> for ConcurrencyLimitLayer {
+    type Invoke = ConcurrencyLimit<T>;
+
+    /// Transports produced by the same layer share the limit
+    fn layer(&self, inner: T) -> Self::Invoke {
+        ConcurrencyLimit {
+            inner,
+            semaphore: Arc::clone(&self.semaphore),
+        }
+    }
+}
+
+/// [`Invoke`] transport produced by [`ConcurrencyLimitLayer`]
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimit<T> {
+    inner: T,
+    semaphore: Arc<Semaphore>,
+}
+
+impl<T: CompressionTransport> CompressionTransport for ConcurrencyLimit<T> {
+    fn with_compression_enabled(self) -> Self {
+        Self {
+            inner: self.inner.with_compression_enabled(),
+            ..self
+        }
+    }
+}
+
+impl<T: Invoke> Invoke for ConcurrencyLimit<T> {
+    type Context = T::Context;
+    type Outgoing = Permitted<T::Outgoing>;
+    type Incoming = Permitted<T::Incoming>;
+
+    #[instrument(level = "trace", skip(self, cx, params, paths))]
+    async fn invoke(
+        &self,
+        cx: Self::Context,
+        instance: &str,
+        func: &str,
+        params: Bytes,
+        paths: &[impl AsRef<[Option<usize>]> + Send + Sync],
+    ) -> anyhow::Result<(Self::Outgoing, Self::Incoming)> {
+        let permit = Arc::clone(&self.semaphore)
+            .acquire_owned()
+            .await
+            .context("failed to acquire concurrency permit")?;
+        let permit = Arc::new(permit);
+        let (outgoing, incoming) = self.inner.invoke(cx, instance, func, params, paths).await?;
+        Ok((
+            Permitted {
+                inner: outgoing,
+                permit: Arc::clone(&permit),
+            },
+            Permitted {
+                inner: incoming,
+                permit,
+            },
+        ))
+    }
+}
+
+/// Stream holding a concurrency permit of [`ConcurrencyLimit`]
+pub struct Permitted<T> {
+    inner: T,
+    permit: Arc<OwnedSemaphorePermit>,
+}
+
+impl<T: Index<T>> Index<Self> for Permitted<T> {
+    fn index(&self, path: &[usize]) -> anyhow::Result<Self> {
+        let inner = self.inner.index(path)?;
+        Ok(Self {
+            inner,
+            permit: Arc::clone(&self.permit),
+        })
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Permitted<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Permitted<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Instruments invocations and the returned streams with a [`tracing`] span
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TraceLayer;
+
+impl<T> Layer<T> for TraceLayer {
+    type Invoke = Trace<T>;
+
+    fn layer(&self, inner: T) -> Self::Invoke {
+        Trace { inner }
+    }
+}
+
+/// [`Invoke`] transport produced by [`TraceLayer`]
+#[derive(Clone, Debug)]
+pub struct Trace<T> {
+    inner: T,
+}
+
+impl<T: CompressionTransport> CompressionTransport for Trace<T> {
+    fn with_compression_enabled(self) -> Self {
+        Self {
+            inner: self.inner.with_compression_enabled(),
+        }
+    }
+}
+
+impl<T: Invoke> Invoke for Trace<T> {
+    type Context = T::Context;
+    type Outgoing = Traced<T::Outgoing>;
+    type Incoming = Traced<T::Incoming>;
+
+    async fn invoke(
+        &self,
+        cx: Self::Context,
+        instance: &str,
+        func: &str,
+        params: Bytes,
+        paths: &[impl AsRef<[Option<usize>]> + Send + Sync],
+    ) -> anyhow::Result<(Self::Outgoing, Self::Incoming)> {
+        let span = debug_span!("invoke", instance, func, params = params.len());
+        async {
+            debug!("invoking function");
+            match self.inner.invoke(cx, instance, func, params, paths).await {
+                Ok((outgoing, incoming)) => {
+                    debug!("invocation established");
+                    let span = Span::current();
+                    Ok((
+                        Traced::new(outgoing, span.clone()),
+                        Traced::new(incoming, span),
+                    ))
+                }
+                Err(err) => {
+                    debug!(?err, "invocation failed");
+                    Err(err)
+                }
+            }
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+/// Stream instrumented with the invocation span of [`Trace`]
+pub struct Traced<T> {
+    inner: T,
+    span: Span,
+}
+
+impl<T> Traced<T> {
+    pub(crate) fn new(inner: T, span: Span) -> Self {
+        Self { inner, span }
+    }
+}
+
+impl<T: Reject + Send> Reject for Traced<T> {
+    async fn reject(self, err: &str) -> anyhow::Result<()> {
+        self.inner.reject(err).instrument(self.span).await
+    }
+}
+
+impl<T: Index<T>> Index<Self> for Traced<T> {
+    fn index(&self, path: &[usize]) -> anyhow::Result<Self> {
+        let _span = self.span.enter();
+        debug!(?path, "indexing stream");
+        let inner = self.inner.index(path)?;
+        Ok(Self {
+            inner,
+            span: self.span.clone(),
+        })
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Traced<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let Self { inner, span } = &mut *self;
+        let _span = span.enter();
+        let filled = buf.filled().len();
+        let res = Pin::new(inner).poll_read(cx, buf);
+        match &res {
+            Poll::Ready(Ok(())) => debug!(n = buf.filled().len() - filled, "read bytes"),
+            Poll::Ready(Err(err)) => debug!(?err, "read failed"),
+            Poll::Pending => {}
+        }
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Traced<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let Self { inner, span } = &mut *self;
+        let _span = span.enter();
+        let res = Pin::new(inner).poll_write(cx, buf);
+        match &res {
+            Poll::Ready(Ok(n)) => debug!(n, "wrote bytes"),
+            Poll::Ready(Err(err)) => debug!(?err, "write failed"),
+            Poll::Pending => {}
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let Self { inner, span } = &mut *self;
+        let _span = span.enter();
+        Pin::new(inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let Self { inner, span } = &mut *self;
+        let _span = span.enter();
+        debug!("shutting down stream");
+        Pin::new(inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use anyhow::bail;
+
+    use super::*;
+
+    struct NoopStream;
+
+    impl Index<Self> for NoopStream {
+        fn index(&self, _path: &[usize]) -> anyhow::Result<Self> {
+            Ok(Self)
+        }
+    }
+
+    impl AsyncRead for NoopStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for NoopStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Fails the first `failures` invocations with a connection error
+    #[derive(Default)]
+    struct Flaky {
+        failures: usize,
+        calls: AtomicUsize,
+    }
+
+    impl Invoke for Flaky {
+        type Context = ();
+        type Outgoing = NoopStream;
+        type Incoming = NoopStream;
+
+        async fn invoke(
+            &self,
+            (): Self::Context,
+            _instance: &str,
+            _func: &str,
+            _params: Bytes,
+            _paths: &[impl AsRef<[Option<usize>]> + Send + Sync],
+        ) -> anyhow::Result<(Self::Outgoing, Self::Incoming)> {
+            let n = self.calls.fetch_add(1, Ordering::Relaxed);
+            if n < self.failures {
+                bail!(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+            }
+            Ok((NoopStream, NoopStream))
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn retry() -> anyhow::Result<()> {
+        let clt = InvokeBuilder::new().retry(2).invoke(Flaky {
+            failures: 2,
+            ..Flaky::default()
+        });
+        clt.invoke((), "foo", "bar", Bytes::default(), &[[None; 0]])
+            .await?;
+
+        let clt = InvokeBuilder::new().retry(2).invoke(Flaky {
+            failures: 3,
+            ..Flaky::default()
+        });
+        let Err(err) = clt
+            .invoke((), "foo", "bar", Bytes::default(), &[[None; 0]])
+            .await
+        else {
+            bail!("invocation should have failed")
+        };
+        assert!(is_connection_error(&err));
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn concurrency_limit() -> anyhow::Result<()> {
+        let clt = InvokeBuilder::new()
+            .trace()
+            .timeout(Duration::from_millis(100))
+            .concurrency_limit(1)
+            .invoke(Flaky::default());
+        let (tx, rx) = clt
+            .invoke((), "foo", "bar", Bytes::default(), &[[None; 0]])
+            .await?;
+        let nested = rx.index(&[0])?;
+        assert!(
+            clt.invoke((), "foo", "bar", Bytes::default(), &[[None; 0]])
+                .await
+                .is_err(),
+            "invocation should have timed out"
+        );
+        drop((tx, rx));
+        assert!(
+            clt.invoke((), "foo", "bar", Bytes::default(), &[[None; 0]])
+                .await
+                .is_err(),
+            "invocation should have timed out, nested stream still holds permit"
+        );
+        drop(nested);
+        clt.invoke((), "foo", "bar", Bytes::default(), &[[None; 0]])
+            .await?;
+        Ok(())
+    }
+}
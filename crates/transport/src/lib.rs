@@ -1,27 +1,46 @@
 #![allow(clippy::type_complexity)]
 
+pub mod compress;
 #[cfg(feature = "frame")]
 pub mod frame;
+pub mod intercept;
+pub mod layer;
+pub mod limits;
+pub mod otel;
+pub mod record;
 
+mod error;
 mod value;
 
+pub use error::Error;
 #[cfg(feature = "frame")]
 pub use frame::{Decoder as FrameDecoder, Encoder as FrameEncoder, FrameRef};
+pub use limits::{LimitedListDecoder, LimitedStringDecoder, Limits};
 pub use value::*;
+#[cfg(feature = "derive")]
+pub use wrpc_transport_derive::{Decode, Encode};
+
+#[doc(hidden)]
+pub mod __private {
+    pub use bytes;
+    pub use tokio_util;
+    pub use wasm_tokio;
+}
 
 use core::future::Future;
 use core::pin::Pin;
 
 use std::sync::Arc;
 
-use anyhow::{bail, Context as _};
 use bytes::{Bytes, BytesMut};
-use futures::{SinkExt as _, Stream, TryStreamExt as _};
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt as _};
+use futures::{SinkExt as _, Stream, StreamExt as _, TryStreamExt as _};
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
 use tokio::try_join;
 use tokio_util::codec::{Encoder as _, FramedRead, FramedWrite};
 use tracing::{debug, instrument, trace, Instrument as _, Span};
 
+use crate::limits::{InvocationDecoder, Scope};
+
 /// `Index` implementations are capable of multiplexing underlying connections using a particular
 /// structural `path`
 pub trait Index<T> {
@@ -29,6 +48,14 @@ pub trait Index<T> {
     fn index(&self, path: &[usize]) -> anyhow::Result<T>;
 }
 
+/// `Reject` implementations are capable of transmitting an invocation error to the peer
+///
+/// [`Serve::Outgoing`] is required to implement this trait, see [`Serve`] for details.
+pub trait Reject {
+    /// Reject the invocation, transmitting the error message `err` to the peer
+    fn reject(self, err: &str) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
 /// Client-side handle to a wRPC transport
 pub trait Invoke: Send + Sync + 'static {
     /// Transport-specific invocation context
@@ -60,10 +87,13 @@ pub trait Invoke: Send + Sync + 'static {
         params: Params,
         paths: &[impl AsRef<[Option<usize>]> + Send + Sync],
     ) -> impl Future<
-        Output = anyhow::Result<(
-            Results,
-            Option<impl Future<Output = anyhow::Result<()>> + Send + 'static>,
-        )>,
+        Output = Result<
+            (
+                Results,
+                Option<impl Future<Output = Result<(), Error>> + Send + 'static>,
+            ),
+            Error,
+        >,
     > + Send
     where
         Params: TupleEncode<Self::Outgoing> + Send,
@@ -74,72 +104,91 @@ pub trait Invoke: Send + Sync + 'static {
             std::error::Error + Send + Sync + 'static,
     {
         async {
+            let scope = Scope::new(Limits::current());
             let mut buf = BytesMut::default();
             let mut enc = Params::Encoder::default();
             trace!("encoding parameters");
-            enc.encode(params, &mut buf)
-                .context("failed to encode parameters")?;
+            enc.encode(params, &mut buf).map_err(Error::encode)?;
             debug!("invoking function");
-            let (mut outgoing, incoming) = self
-                .invoke(cx, instance, func, buf.freeze(), paths)
-                .await
-                .context("failed to invoke function")?;
-            outgoing
-                .shutdown()
-                .await
-                .context("failed to shutdown synchronous parameter channel")?;
+            let (mut outgoing, incoming) =
+                self.invoke(cx, instance, func, buf.freeze(), paths).await?;
+            outgoing.shutdown().await?;
             let tx = enc.take_deferred().map(|tx| {
                 tokio::spawn(
                     async {
                         trace!("writing async parameters");
-                        tx(outgoing.into(), Vec::with_capacity(8))
-                            .await
-                            .context("failed to write async parameters")
+                        tx(outgoing.into(), Vec::with_capacity(8)).await
                     }
                     .in_current_span(),
                 )
             });
 
-            let mut dec = FramedRead::new(incoming, Results::Decoder::default());
-            debug!("receiving sync returns");
-            let Some(returns) = dec
-                .try_next()
-                .await
-                .context("failed to receive sync returns")?
-            else {
-                bail!("incomplete returns")
-            };
-            trace!("received sync returns");
-            let rx = dec.decoder_mut().take_deferred();
-            Ok((
-                returns,
-                (tx.is_some() || rx.is_some()).then_some(async {
-                    match (tx, rx) {
-                        (Some(tx), Some(rx)) => {
-                            try_join!(
-                                async {
-                                    debug!("receiving async returns");
-                                    rx(dec.into_inner().into(), Vec::with_capacity(8))
-                                        .await
-                                        .context("receiving async returns failed")
-                                },
-                                async { tx.await.context("writing async parameters failed")? }
-                            )?;
-                        }
-                        (Some(tx), None) => {
-                            tx.await.context("writing async parameters failed")??;
-                        }
-                        (None, Some(rx)) => {
-                            debug!("receiving async returns");
-                            rx(dec.into_inner().into(), Vec::with_capacity(8))
-                                .await
-                                .context("receiving async returns failed")?;
-                        }
-                        _ => {}
+            receive_returns(scope, incoming, tx).await
+        }
+    }
+
+    /// Invoke function `func` on instance `instance` using typed `Params` followed by a
+    /// trailing `list` parameter of `len` elements produced by `items`.
+    ///
+    /// Elements of the trailing `list` are encoded and written to the transport incrementally,
+    /// see [`write_list`]. They must not contain asynchronous values.
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(level = "trace", skip(self, cx, params, items, paths))]
+    fn invoke_values_with_list<Params, T, S, Results>(
+        &self,
+        cx: Self::Context,
+        instance: &str,
+        func: &str,
+        params: Params,
+        len: u32,
+        items: S,
+        paths: &[impl AsRef<[Option<usize>]> + Send + Sync],
+    ) -> impl Future<
+        Output = Result<
+            (
+                Results,
+                Option<impl Future<Output = Result<(), Error>> + Send + 'static>,
+            ),
+            Error,
+        >,
+    > + Send
+    where
+        Params: TupleEncode<Self::Outgoing> + Send,
+        T: Encode<Self::Outgoing> + Send,
+        S: Stream<Item = T> + Send,
+        Results: TupleDecode<Self::Incoming>,
+        <Params::Encoder as tokio_util::codec::Encoder<Params>>::Error:
+            std::error::Error + Send + Sync + 'static,
+        std::io::Error: From<<T::Encoder as tokio_util::codec::Encoder<T>>::Error>,
+        <Results::Decoder as tokio_util::codec::Decoder>::Error:
+            std::error::Error + Send + Sync + 'static,
+    {
+        async {
+            let scope = Scope::new(Limits::current());
+            let mut buf = BytesMut::default();
+            let mut enc = Params::Encoder::default();
+            trace!("encoding parameters");
+            enc.encode(params, &mut buf).map_err(Error::encode)?;
+            debug!("invoking function");
+            let (mut outgoing, incoming) =
+                self.invoke(cx, instance, func, buf.freeze(), paths).await?;
+            trace!("writing list parameter");
+            if write_list(&mut outgoing, len, items).await?.is_some() {
+                return Err(Error::Encode(
+                    "asynchronous list elements cannot be written incrementally".into(),
+                ));
+            }
+            outgoing.shutdown().await?;
+            let tx = enc.take_deferred().map(|tx| {
+                tokio::spawn(
+                    async {
+                        trace!("writing async parameters");
+                        tx(outgoing.into(), Vec::with_capacity(8)).await
                     }
-                    Ok(())
-                }),
-            ))
+                    .in_current_span(),
+                )
+            });
+            receive_returns(scope, incoming, tx).await
         }
     }
 
@@ -153,7 +202,7 @@ pub trait Invoke: Send + Sync + 'static {
         func: &str,
         params: Params,
         paths: &[impl AsRef<[Option<usize>]> + Send + Sync],
-    ) -> impl Future<Output = anyhow::Result<Results>> + Send
+    ) -> impl Future<Output = Result<Results, Error>> + Send
     where
         Params: TupleEncode<Self::Outgoing> + Send,
         Results: TupleDecode<Self::Incoming> + Send,
@@ -174,13 +223,136 @@ pub trait Invoke: Send + Sync + 'static {
     }
 }
 
+/// Receives `Results` of an invocation from `incoming`, while parameters are written by `tx`
+async fn receive_returns<Results, Incoming>(
+    scope: Scope,
+    incoming: Incoming,
+    tx: Option<tokio::task::JoinHandle<std::io::Result<()>>>,
+) -> Result<
+    (
+        Results,
+        Option<impl Future<Output = Result<(), Error>> + Send + 'static>,
+    ),
+    Error,
+>
+where
+    Results: TupleDecode<Incoming>,
+    Incoming: AsyncRead + Index<Incoming> + Send + Sync + Unpin + 'static,
+    <Results::Decoder as tokio_util::codec::Decoder>::Error:
+        std::error::Error + Send + Sync + 'static,
+{
+    let mut dec = FramedRead::new(incoming, InvocationDecoder(Results::Decoder::default()));
+    debug!("receiving sync returns");
+    let Some(returns) = scope
+        .clone()
+        .run(dec.try_next())
+        .await
+        .map_err(Error::decode)?
+    else {
+        return Err(Error::Closed(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "incomplete returns",
+        )));
+    };
+    trace!("received sync returns");
+    let rx = dec.decoder_mut().take_deferred();
+    if !dec.read_buffer().is_empty() {
+        return Err(Error::Decode(
+            "unexpected bytes following sync returns".into(),
+        ));
+    }
+    let mut incoming = dec.into_inner();
+    if tx.is_none() && rx.is_none() {
+        // there is no I/O left to return, finish the stream in the background
+        tokio::spawn(
+            async move {
+                if let Err(err) = finish_returns(&mut incoming).await {
+                    debug!(?err, "failed to finish results stream");
+                }
+            }
+            .in_current_span(),
+        );
+        return Ok((returns, None));
+    }
+    Ok((
+        returns,
+        Some(scope.run(async move {
+            match (tx, rx) {
+                (Some(tx), Some(rx)) => {
+                    try_join!(receive_async_returns(incoming, rx), async {
+                        tx.await.map_err(std::io::Error::from)?
+                    })?;
+                }
+                (Some(tx), None) => {
+                    try_join!(finish_returns(&mut incoming), async {
+                        tx.await.map_err(std::io::Error::from)?
+                    })?;
+                }
+                (None, Some(rx)) => {
+                    receive_async_returns(incoming, rx).await?;
+                }
+                _ => {}
+            }
+            Ok(())
+        })),
+    ))
+}
+
+/// Receives async returns from `incoming` using `rx` and finishes `incoming` afterwards
+async fn receive_async_returns<Incoming>(
+    incoming: Incoming,
+    rx: DeferredFn<Incoming>,
+) -> std::io::Result<()>
+where
+    Incoming: AsyncRead + Unpin,
+{
+    debug!("receiving async returns");
+    let incoming = Arc::new(incoming);
+    rx(Arc::clone(&incoming), Vec::with_capacity(8)).await?;
+    match Arc::try_unwrap(incoming) {
+        Ok(mut incoming) => finish_returns(&mut incoming).await,
+        Err(_) => {
+            trace!("results stream is still referenced, leaving it unfinished");
+            Ok(())
+        }
+    }
+}
+
+/// Reads the results stream to the end.
+///
+/// Transports cancel invocations, whose results stream is dropped before it is finished.
+async fn finish_returns(incoming: &mut (impl AsyncRead + Unpin)) -> std::io::Result<()> {
+    trace!("awaiting end of results stream");
+    if incoming.read(&mut [0]).await? > 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "unexpected bytes following sync returns",
+        ));
+    }
+    Ok(())
+}
+
 /// Server-side handle to a wRPC transport
+///
+/// # Migrating from 0.26
+///
+/// Handler errors are transmitted to clients, which requires the following breaking changes:
+///
+/// - [`Self::Outgoing`] must implement [`Reject`]
+/// - the response closure returned by [`Self::serve_values`] takes a
+///   `Result<Results, String>` and the [`Err`] variant is transmitted to the client. Handlers,
+///   which previously passed `returns`, now pass `Ok(returns)`
+/// - the future returned by the response closure is no longer [`Sync`]
+/// - [`Self::serve_values`], [`Invoke::invoke_values`] and [`Invoke::invoke_values_blocking`]
+///   return the typed [`Error`] instead of [`anyhow::Error`], which it converts into using `?`
+///
+/// [`Self::serve`] and [`Invoke::invoke`] are unchanged.
 pub trait Serve: Sync {
     /// Transport-specific invocation context
     type Context: Send + Sync + 'static;
 
     /// Outgoing multiplexed byte stream
-    type Outgoing: AsyncWrite + Index<Self::Outgoing> + Send + Sync + Unpin + 'static;
+    type Outgoing: AsyncWrite + Index<Self::Outgoing> + Reject + Send + Sync + Unpin + 'static;
 
     /// Incoming multiplexed byte stream
     type Incoming: AsyncRead + Index<Self::Incoming> + Send + Sync + Unpin + 'static;
@@ -199,7 +371,11 @@ pub trait Serve: Sync {
         >,
     > + Send;
 
-    /// Serve function `func` from instance `instance` using typed `Params` and `Results`
+    /// Serve function `func` from instance `instance` using typed `Params` and `Results`.
+    ///
+    /// Handlers respond by calling the returned closure with either the `Results` or an error
+    /// message, which is transmitted to the client using [`Reject`] and surfaces on the client as
+    /// [`Error::Handler`]. See [`Serve`] for changes compared to 0.26.
     #[instrument(level = "trace", skip(self, paths))]
     fn serve_values<P, Params, Results>(
         &self,
@@ -207,21 +383,29 @@ pub trait Serve: Sync {
         func: &str,
         paths: impl Into<Arc<[P]>> + Send + Sync + 'static,
     ) -> impl Future<
-        Output = anyhow::Result<
+        Output = Result<
             impl Stream<
-                    Item = anyhow::Result<(
-                        Self::Context,
-                        Params,
-                        Option<
-                            impl Future<Output = std::io::Result<()>> + Sync + Send + Unpin + 'static,
-                        >,
-                        impl FnOnce(
-                            Results,
-                        )
-                            -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Sync + Send>>,
-                    )>,
+                    Item = Result<
+                        (
+                            Self::Context,
+                            Params,
+                            Option<
+                                impl Future<Output = std::io::Result<()>>
+                                    + Sync
+                                    + Send
+                                    + Unpin
+                                    + 'static,
+                            >,
+                            impl FnOnce(
+                                Result<Results, String>,
+                            )
+                                -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>,
+                        ),
+                        Error,
+                    >,
                 > + Send
                 + 'static,
+            Error,
         >,
     > + Send
     where
@@ -234,63 +418,79 @@ pub trait Serve: Sync {
             std::error::Error + Send + Sync + 'static,
     {
         async {
+            let limits = Limits::current();
             let invocations = self.serve(instance, func, paths).await?;
             let span = Span::current();
-            Ok(invocations.and_then(move |(cx, outgoing, incoming)| {
-                async {
-                    let mut dec = FramedRead::new(incoming, Params::Decoder::default());
-                    debug!("receiving sync parameters");
-                    let Some(params) = dec
-                        .try_next()
-                        .await
-                        .context("failed to receive sync parameters")?
-                    else {
-                        bail!("incomplete sync parameters")
-                    };
-                    trace!("received sync parameters");
-                    let rx = dec.decoder_mut().take_deferred();
-                    let span = Span::current();
-                    Ok((
-                        cx,
-                        params,
-                        rx.map(|f| f(dec.into_inner().into(), Vec::with_capacity(8))),
-                        move |returns| {
-                            Box::pin(
-                                async {
-                                    let mut enc =
-                                        FramedWrite::new(outgoing, Results::Encoder::default());
-                                    debug!("transmitting sync returns");
-                                    enc.send(returns)
-                                        .await
-                                        .context("failed to transmit synchronous returns")?;
-                                    let tx = enc.encoder_mut().take_deferred();
-                                    let mut outgoing = enc.into_inner();
-                                    outgoing
-                                        .shutdown()
-                                        .await
-                                        .context("failed to shutdown synchronous return channel")?;
-                                    if let Some(tx) = tx {
-                                        debug!("transmitting async returns");
-                                        tx(outgoing.into(), Vec::with_capacity(8))
-                                            .await
-                                            .context("failed to write async returns")?;
+            Ok(invocations.map(|res| res.map_err(Error::from)).and_then(
+                move |(cx, outgoing, incoming)| {
+                    async move {
+                        let scope = Scope::new(limits);
+                        let mut dec = FramedRead::new(
+                            incoming,
+                            InvocationDecoder(Params::Decoder::default()),
+                        );
+                        debug!("receiving sync parameters");
+                        let Some(params) = scope
+                            .clone()
+                            .run(dec.try_next())
+                            .await
+                            .map_err(Error::decode)?
+                        else {
+                            return Err(Error::Closed(std::io::Error::new(
+                                std::io::ErrorKind::UnexpectedEof,
+                                "incomplete sync parameters",
+                            )));
+                        };
+                        trace!("received sync parameters");
+                        let rx = dec.decoder_mut().take_deferred();
+                        let span = Span::current();
+                        Ok((
+                            cx,
+                            params,
+                            rx.map(|f| {
+                                Box::pin(
+                                    scope.run(f(dec.into_inner().into(), Vec::with_capacity(8))),
+                                )
+                            }),
+                            move |returns: Result<Results, String>| {
+                                Box::pin(
+                                    async {
+                                        let returns = match returns {
+                                            Ok(returns) => returns,
+                                            Err(err) => {
+                                                debug!(?err, "transmitting error");
+                                                outgoing.reject(&err).await?;
+                                                return Ok(());
+                                            }
+                                        };
+                                        let mut enc =
+                                            FramedWrite::new(outgoing, Results::Encoder::default());
+                                        debug!("transmitting sync returns");
+                                        enc.send(returns).await.map_err(Error::encode)?;
+                                        let tx = enc.encoder_mut().take_deferred();
+                                        let mut outgoing = enc.into_inner();
+                                        outgoing.shutdown().await?;
+                                        if let Some(tx) = tx {
+                                            debug!("transmitting async returns");
+                                            tx(outgoing.into(), Vec::with_capacity(8)).await?;
+                                        }
+                                        Ok(())
                                     }
-                                    Ok(())
-                                }
-                                .instrument(span),
-                            ) as Pin<_>
-                        },
-                    ))
-                }
-                .instrument(span.clone())
-            }))
+                                    .instrument(span),
+                                ) as Pin<_>
+                            },
+                        ))
+                    }
+                    .instrument(span.clone())
+                },
+            ))
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use futures::{stream, StreamExt as _};
+    use futures::stream;
 
     use super::*;
 
@@ -0,0 +1,380 @@
+//! Limits on resources consumed by decoding values received from peers
+
+use core::future::Future;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use tracing::trace;
+
+use crate::{Deferred, DeferredFn, Error};
+
+/// Limits on resources consumed by decoding values received from untrusted peers.
+///
+/// Limits are honoured by all decoders in this crate running within [`Limits::scope`].
+/// [`Serve::serve_values`](crate::Serve::serve_values) and
+/// [`Invoke::invoke_values`](crate::Invoke::invoke_values) apply the limits in scope when
+/// called to each invocation. Exceeding a limit fails decoding with [`Error::LimitExceeded`].
+///
+/// Transports apply the limits in scope when invocations are served or invoked to the async
+/// values of each invocation, which are received after the call returns.
+///
+/// # Defaults
+///
+/// All limits are unbounded by default, except for [`Limits::max_depth`], which is 32.
+/// Previous releases did not limit the nesting depth, therefore values nested deeper than that,
+/// which were accepted before, are now rejected unless a higher limit is set explicitly:
+///
+/// ```
+/// # async fn serve() {}
+/// # async fn run() {
+/// wrpc_transport::Limits {
+///     max_depth: u32::MAX,
+///     ..Default::default()
+/// }
+/// .scope(serve())
+/// .await
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Limits {
+    /// Maximum number of elements in a `list`
+    pub max_list_len: u32,
+    /// Maximum length of a `string` in bytes
+    pub max_string_len: u32,
+    /// Maximum nesting depth of asynchronous values, 32 by default
+    pub max_depth: u32,
+    /// Maximum number of bytes received for a single invocation
+    pub max_invocation_size: u64,
+    /// Maximum number of elements in a single `stream` chunk
+    pub max_chunk_len: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_list_len: u32::MAX,
+            max_string_len: u32::MAX,
+            max_depth: 32,
+            max_invocation_size: u64::MAX,
+            max_chunk_len: u32::MAX,
+        }
+    }
+}
+
+impl Limits {
+    /// Returns the limits in scope of the current task
+    #[must_use]
+    pub fn current() -> Self {
+        SCOPE.try_with(|scope| scope.limits).unwrap_or_default()
+    }
+
+    /// Applies the limits to all values decoded by `fut`
+    pub fn scope<F: Future>(self, fut: F) -> impl Future<Output = F::Output> {
+        Scope::new(self).run(fut)
+    }
+
+    /// Checks the length `len` of a `list` against [`Limits::max_list_len`]
+    pub fn check_list_len(&self, len: usize) -> std::io::Result<()> {
+        check_len(
+            "list length",
+            len.try_into().unwrap_or(u32::MAX),
+            self.max_list_len,
+        )
+    }
+
+    /// Checks the nesting `depth` of an asynchronous value against [`Limits::max_depth`]
+    pub fn check_depth(&self, depth: usize) -> std::io::Result<()> {
+        check_len(
+            "nesting depth",
+            depth.try_into().unwrap_or(u32::MAX),
+            self.max_depth,
+        )
+    }
+}
+
+tokio::task_local! {
+    static SCOPE: Scope;
+}
+
+/// [Limits] applied to a single invocation.
+///
+/// Transports use [`Scope::receive`] to account for the bytes they receive for an invocation.
+#[derive(Clone, Debug, Default)]
+pub struct Scope {
+    limits: Limits,
+    received: Arc<AtomicU64>,
+}
+
+impl Scope {
+    /// Constructs a new [`Scope`] of an invocation, which enforces `limits`
+    #[must_use]
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            received: Arc::default(),
+        }
+    }
+
+    /// Returns the limits enforced by this [`Scope`]
+    #[must_use]
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Accounts for `n` bytes received for the invocation, fails if
+    /// [`Limits::max_invocation_size`] is exceeded
+    pub fn receive(&self, n: usize) -> std::io::Result<()> {
+        let n = n.try_into().unwrap_or(u64::MAX);
+        let len = self
+            .received
+            .fetch_add(n, Ordering::Relaxed)
+            .saturating_add(n);
+        self.check_received(len)
+    }
+
+    pub(crate) fn run<F: Future>(self, fut: F) -> impl Future<Output = F::Output> {
+        SCOPE.scope(self, fut)
+    }
+
+    fn check_received(&self, len: u64) -> std::io::Result<()> {
+        if len > self.limits.max_invocation_size {
+            return Err(limit_exceeded(
+                "invocation size",
+                len,
+                self.limits.max_invocation_size,
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn limit_exceeded(limit: &'static str, len: u64, max: u64) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        Error::LimitExceeded { limit, len, max },
+    )
+}
+
+fn check_len(limit: &'static str, len: u32, max: u32) -> std::io::Result<()> {
+    if len > max {
+        return Err(limit_exceeded(limit, len.into(), max.into()));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_list_len(len: u32) -> std::io::Result<()> {
+    check_len("list length", len, Limits::current().max_list_len)
+}
+
+pub(crate) fn check_depth(path: &[usize]) -> std::io::Result<()> {
+    Limits::current().check_depth(path.len())
+}
+
+/// Returns the LEB128-encoded length prefix of `src` without consuming it
+fn peek_len(src: &[u8]) -> std::io::Result<Option<u32>> {
+    let mut len = 0u32;
+    for (i, b) in src.iter().take(5).enumerate() {
+        let v = u32::from(b & 0x7f);
+        if i == 4 && v > 0x0f {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "length prefix overflows u32",
+            ));
+        }
+        len |= v << (i * 7);
+        if b & 0x80 == 0 {
+            return Ok(Some(len));
+        }
+    }
+    if src.len() >= 5 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "length prefix overflows u32",
+        ));
+    }
+    Ok(None)
+}
+
+/// Checks the length prefix of the value in `src` using `check` before decoding it with `dec`
+fn decode_checked<T: tokio_util::codec::Decoder>(
+    dec: &mut T,
+    checked: &mut bool,
+    src: &mut BytesMut,
+    check: impl FnOnce(u32) -> std::io::Result<()>,
+) -> Result<Option<T::Item>, T::Error> {
+    if !*checked {
+        let Some(len) = peek_len(src)? else {
+            return Ok(None);
+        };
+        check(len)?;
+        *checked = true;
+    }
+    let v = dec.decode(src)?;
+    if v.is_some() {
+        *checked = false;
+    }
+    Ok(v)
+}
+
+/// Decoder of length-prefixed `list` values, which enforces [`Limits::max_list_len`]
+#[derive(Debug, Default)]
+pub struct LimitedListDecoder<T> {
+    dec: T,
+    checked: bool,
+}
+
+impl<T, R> Deferred<R> for LimitedListDecoder<T>
+where
+    T: Deferred<R>,
+{
+    fn take_deferred(&mut self) -> Option<DeferredFn<R>> {
+        self.dec.take_deferred()
+    }
+}
+
+impl<T: tokio_util::codec::Decoder> tokio_util::codec::Decoder for LimitedListDecoder<T> {
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_checked(&mut self.dec, &mut self.checked, src, check_list_len)
+    }
+}
+
+/// Decoder of length-prefixed `string` values, which enforces [`Limits::max_string_len`]
+#[derive(Debug, Default)]
+pub struct LimitedStringDecoder<T> {
+    dec: T,
+    checked: bool,
+}
+
+impl<T, R> Deferred<R> for LimitedStringDecoder<T>
+where
+    T: Deferred<R>,
+{
+    fn take_deferred(&mut self) -> Option<DeferredFn<R>> {
+        self.dec.take_deferred()
+    }
+}
+
+impl<T: tokio_util::codec::Decoder> tokio_util::codec::Decoder for LimitedStringDecoder<T> {
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_checked(&mut self.dec, &mut self.checked, src, |len| {
+            check_len("string length", len, Limits::current().max_string_len)
+        })
+    }
+}
+
+/// Decoder of `stream` chunks, which enforces [`Limits::max_chunk_len`]
+#[derive(Debug, Default)]
+pub(crate) struct ChunkDecoder<T> {
+    dec: T,
+    checked: bool,
+}
+
+impl<T, R> Deferred<R> for ChunkDecoder<T>
+where
+    T: Deferred<R>,
+{
+    fn take_deferred(&mut self) -> Option<DeferredFn<R>> {
+        self.dec.take_deferred()
+    }
+}
+
+impl<T: tokio_util::codec::Decoder> tokio_util::codec::Decoder for ChunkDecoder<T> {
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        decode_checked(&mut self.dec, &mut self.checked, src, |len| {
+            check_len("stream chunk length", len, Limits::current().max_chunk_len)
+        })
+    }
+}
+
+/// Decoder reading directly from an invocation stream, which enforces
+/// [`Limits::max_invocation_size`]
+#[derive(Debug, Default)]
+pub(crate) struct InvocationDecoder<T>(pub T);
+
+impl<T, R> Deferred<R> for InvocationDecoder<T>
+where
+    T: Deferred<R>,
+{
+    fn take_deferred(&mut self) -> Option<DeferredFn<R>> {
+        self.0.take_deferred()
+    }
+}
+
+impl<T: tokio_util::codec::Decoder> tokio_util::codec::Decoder for InvocationDecoder<T> {
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let buffered = src.len();
+        // scope is not present outside of invocations, nothing is tracked in that case
+        if let Ok(res) = SCOPE.try_with(|scope| {
+            let received = scope.received.load(Ordering::Relaxed);
+            scope.check_received(received.saturating_add(buffered.try_into().unwrap_or(u64::MAX)))
+        }) {
+            res?;
+        }
+        let v = self.0.decode(src)?;
+        let n = buffered.saturating_sub(src.len());
+        if n > 0 {
+            trace!(n, "received invocation bytes");
+            if let Ok(res) = SCOPE.try_with(|scope| scope.receive(n)) {
+                res?;
+            }
+        }
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::codec::Decoder as _;
+    use wasm_tokio::CoreNameDecoder;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn limits() {
+        let limits = Limits {
+            max_string_len: 3,
+            max_invocation_size: 6,
+            ..Limits::default()
+        };
+        limits
+            .scope(async {
+                let mut dec = InvocationDecoder(LimitedStringDecoder::<CoreNameDecoder>::default());
+                let mut buf = BytesMut::from(&b"\x03foo\x04"[..]);
+                let s = dec.decode(&mut buf).expect("failed to decode string");
+                assert_eq!(s.as_deref(), Some("foo"));
+
+                let err = dec
+                    .decode(&mut buf)
+                    .expect_err("decoding long string should fail");
+                assert!(matches!(
+                    Error::from(err),
+                    Error::LimitExceeded { len: 4, max: 3, .. }
+                ));
+
+                let mut buf = BytesMut::from(&b"\x02ba"[..]);
+                let err = dec
+                    .decode(&mut buf)
+                    .expect_err("exceeding invocation size should fail");
+                assert!(matches!(
+                    Error::from(err),
+                    Error::LimitExceeded { len: 7, max: 6, .. }
+                ));
+            })
+            .await;
+        assert_eq!(Limits::current(), Limits::default());
+    }
+}
@@ -0,0 +1,378 @@
+//! OpenTelemetry trace context propagation.
+//!
+//! Transports transmit the W3C `traceparent` and `tracestate` of the caller in the invocation
+//! context (e.g. as NATS headers or in the QUIC parameter stream header). With the `otel`
+//! feature enabled, `Otel` wraps an [`Invoke`] transport to inject the context of the
+//! invocation span and a [`Serve`] transport to parent the span of each accepted invocation to
+//! the span of the caller. Spans are exported by a `tracing_opentelemetry` layer.
+//!
+//! [`Invoke`]: crate::Invoke
+//! [`Serve`]: crate::Serve
+
+/// Invocation context carrying the W3C trace context of the caller
+pub trait TraceContext {
+    /// Returns the `traceparent` and `tracestate`, if any
+    fn trace_context(&self) -> Option<(&str, Option<&str>)>;
+
+    /// Sets the `traceparent` and `tracestate`
+    fn set_trace_context(&mut self, traceparent: String, tracestate: Option<String>);
+}
+
+#[cfg(feature = "otel")]
+pub use propagate::*;
+
+#[cfg(feature = "otel")]
+mod propagate {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use futures::{Stream, StreamExt as _};
+    use opentelemetry::propagation::{Extractor, TextMapPropagator as _};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use tracing::{debug, info_span, Instrument as _, Span};
+    use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+
+    use super::TraceContext;
+    use crate::compress::CompressionTransport;
+    use crate::layer::{Layer, Traced};
+    use crate::{Invoke, Serve};
+
+    const TRACEPARENT: &str = "traceparent";
+    const TRACESTATE: &str = "tracestate";
+
+    struct Carrier<'a> {
+        traceparent: &'a str,
+        tracestate: Option<&'a str>,
+    }
+
+    impl Extractor for Carrier<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            match key {
+                TRACEPARENT => Some(self.traceparent),
+                TRACESTATE => self.tracestate,
+                _ => None,
+            }
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            if self.tracestate.is_some() {
+                vec![TRACEPARENT, TRACESTATE]
+            } else {
+                vec![TRACEPARENT]
+            }
+        }
+    }
+
+    /// Returns the OpenTelemetry context of the caller transmitted in `cx`, if any
+    pub fn extract(cx: &impl TraceContext) -> Option<opentelemetry::Context> {
+        let (traceparent, tracestate) = cx.trace_context()?;
+        Some(TraceContextPropagator::new().extract(&Carrier {
+            traceparent,
+            tracestate,
+        }))
+    }
+
+    /// Sets the trace context in `cx` to the OpenTelemetry context of `span`
+    pub fn inject(span: &Span, cx: &mut impl TraceContext) {
+        let mut carrier = HashMap::new();
+        TraceContextPropagator::new().inject_context(&span.context(), &mut carrier);
+        if let Some(traceparent) = carrier.remove(TRACEPARENT) {
+            cx.set_trace_context(traceparent, carrier.remove(TRACESTATE));
+        }
+    }
+
+    fn set_parent(span: &Span, cx: &impl TraceContext) {
+        if let Some(parent) = extract(cx) {
+            if let Err(err) = span.set_parent(parent) {
+                debug!(?err, "failed to set parent span");
+            }
+        }
+    }
+
+    /// Propagates the trace context of invocations, see the [module documentation](crate::otel)
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct OtelLayer;
+
+    impl<T> Layer<T> for OtelLayer {
+        type Invoke = Otel<T>;
+
+        fn layer(&self, inner: T) -> Self::Invoke {
+            Otel { inner }
+        }
+    }
+
+    /// [`Invoke`] and [`Serve`] transport produced by [`OtelLayer`].
+    ///
+    /// Each invocation is instrumented with a span, which lasts until all of the invocation
+    /// streams are dropped. On the client, the span is a child of the current span, unless
+    /// the context already carries a trace context, in which case that is the parent.
+    /// On the server, the span is a child of the span of the caller.
+    #[derive(Clone, Debug)]
+    pub struct Otel<T> {
+        inner: T,
+    }
+
+    impl<T> Otel<T> {
+        pub fn new(inner: T) -> Self {
+            Self { inner }
+        }
+
+        /// Returns the wrapped transport
+        pub fn into_inner(self) -> T {
+            self.inner
+        }
+    }
+
+    impl<T: CompressionTransport> CompressionTransport for Otel<T> {
+        fn with_compression_enabled(self) -> Self {
+            Self {
+                inner: self.inner.with_compression_enabled(),
+            }
+        }
+    }
+
+    impl<T> Invoke for Otel<T>
+    where
+        T: Invoke,
+        T::Context: TraceContext,
+    {
+        type Context = T::Context;
+        type Outgoing = Traced<T::Outgoing>;
+        type Incoming = Traced<T::Incoming>;
+
+        async fn invoke(
+            &self,
+            mut cx: Self::Context,
+            instance: &str,
+            func: &str,
+            params: Bytes,
+            paths: &[impl AsRef<[Option<usize>]> + Send + Sync],
+        ) -> anyhow::Result<(Self::Outgoing, Self::Incoming)> {
+            let span = info_span!("invoke", instance, func, otel.kind = "client");
+            set_parent(&span, &cx);
+            inject(&span, &mut cx);
+            let (outgoing, incoming) = self
+                .inner
+                .invoke(cx, instance, func, params, paths)
+                .instrument(span.clone())
+                .await?;
+            Ok((
+                Traced::new(outgoing, span.clone()),
+                Traced::new(incoming, span),
+            ))
+        }
+    }
+
+    impl<T> Serve for Otel<T>
+    where
+        T: Serve,
+        T::Context: TraceContext,
+    {
+        type Context = T::Context;
+        type Outgoing = Traced<T::Outgoing>;
+        type Incoming = Traced<T::Incoming>;
+
+        async fn serve<P: AsRef<[Option<usize>]> + Send + Sync + 'static>(
+            &self,
+            instance: &str,
+            func: &str,
+            paths: impl Into<Arc<[P]>> + Send + Sync + 'static,
+        ) -> anyhow::Result<
+            impl Stream<Item = anyhow::Result<(Self::Context, Self::Outgoing, Self::Incoming)>>
+                + Send
+                + 'static,
+        > {
+            let invocations = self.inner.serve(instance, func, paths).await?;
+            let instance = Arc::<str>::from(instance);
+            let func = Arc::<str>::from(func);
+            Ok(invocations.map(move |res| {
+                let (cx, tx, rx) = res?;
+                let span = info_span!(
+                    "serve",
+                    instance = instance.as_ref(),
+                    func = func.as_ref(),
+                    otel.kind = "server"
+                );
+                set_parent(&span, &cx);
+                Ok((cx, Traced::new(tx, span.clone()), Traced::new(rx, span)))
+            }))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use core::pin::{pin, Pin};
+    use core::task::{Context, Poll};
+
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::Context as _;
+    use bytes::Bytes;
+    use futures::{Stream, StreamExt as _};
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+    use tracing::{info_span, Instrument as _};
+    use tracing_subscriber::layer::SubscriberExt as _;
+
+    use super::*;
+    use crate::layer::Layer as _;
+    use crate::{Index, Invoke as _, Reject, Serve as _};
+
+    #[derive(Default)]
+    struct Cx(Option<(String, Option<String>)>);
+
+    impl TraceContext for Cx {
+        fn trace_context(&self) -> Option<(&str, Option<&str>)> {
+            let (traceparent, tracestate) = self.0.as_ref()?;
+            Some((traceparent, tracestate.as_deref()))
+        }
+
+        fn set_trace_context(&mut self, traceparent: String, tracestate: Option<String>) {
+            self.0 = Some((traceparent, tracestate));
+        }
+    }
+
+    struct NoopStream;
+
+    impl Index<Self> for NoopStream {
+        fn index(&self, _path: &[usize]) -> anyhow::Result<Self> {
+            Ok(Self)
+        }
+    }
+
+    impl Reject for NoopStream {
+        async fn reject(self, _err: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for NoopStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for NoopStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Delivers invocation contexts from the client to the server
+    struct Loopback {
+        tx: mpsc::UnboundedSender<Cx>,
+        rx: Mutex<Option<mpsc::UnboundedReceiver<Cx>>>,
+    }
+
+    impl Default for Loopback {
+        fn default() -> Self {
+            let (tx, rx) = mpsc::unbounded_channel();
+            Self {
+                tx,
+                rx: Mutex::new(Some(rx)),
+            }
+        }
+    }
+
+    impl crate::Invoke for Loopback {
+        type Context = Cx;
+        type Outgoing = NoopStream;
+        type Incoming = NoopStream;
+
+        async fn invoke(
+            &self,
+            cx: Self::Context,
+            _instance: &str,
+            _func: &str,
+            _params: Bytes,
+            _paths: &[impl AsRef<[Option<usize>]> + Send + Sync],
+        ) -> anyhow::Result<(Self::Outgoing, Self::Incoming)> {
+            self.tx.send(cx).context("server is closed")?;
+            Ok((NoopStream, NoopStream))
+        }
+    }
+
+    impl crate::Serve for Loopback {
+        type Context = Cx;
+        type Outgoing = NoopStream;
+        type Incoming = NoopStream;
+
+        async fn serve<P: AsRef<[Option<usize>]> + Send + Sync + 'static>(
+            &self,
+            _instance: &str,
+            _func: &str,
+            _paths: impl Into<Arc<[P]>> + Send + Sync + 'static,
+        ) -> anyhow::Result<
+            impl Stream<Item = anyhow::Result<(Self::Context, Self::Outgoing, Self::Incoming)>>
+                + Send
+                + 'static,
+        > {
+            let rx = self.rx.lock().unwrap().take().context("already serving")?;
+            Ok(UnboundedReceiverStream::new(rx).map(|cx| Ok((cx, NoopStream, NoopStream))))
+        }
+    }
+
+    #[tokio::test]
+    async fn propagate() -> anyhow::Result<()> {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let transport = OtelLayer.layer(Loopback::default());
+        let root = info_span!("root");
+        let (tx, rx) = transport
+            .invoke(Cx::default(), "foo", "bar", Bytes::default(), &[[None; 0]])
+            .instrument(root.clone())
+            .await?;
+        drop((tx, rx, root));
+
+        let invocations = transport.serve("foo", "bar", [[None; 0]]).await?;
+        let mut invocations = pin!(invocations);
+        let (cx, tx, rx) = invocations
+            .next()
+            .await
+            .context("unexpected end of stream")??;
+        assert!(cx.trace_context().is_some());
+        drop((tx, rx));
+
+        let spans = exporter.get_finished_spans()?;
+        let span = |name: &str| {
+            spans
+                .iter()
+                .find(|span| span.name == name)
+                .with_context(|| format!("span `{name}` not exported"))
+        };
+        let root = span("root")?;
+        let invoke = span("invoke")?;
+        let serve = span("serve")?;
+        assert_eq!(invoke.parent_span_id, root.span_context.span_id());
+        assert_eq!(serve.parent_span_id, invoke.span_context.span_id());
+        assert_eq!(serve.span_context.trace_id(), root.span_context.trace_id());
+        Ok(())
+    }
+}
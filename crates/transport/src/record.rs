@@ -0,0 +1,917 @@
+//! Recording and replay of invocations for deterministic tests.
+//!
+//! [`Record`] wraps an [`Invoke`] or [`Serve`] transport and writes every invocation, i.e. the
+//! bytes transferred on each of its streams along with their timing, to a sink once all of
+//! the invocation streams are dropped. [`Replay`] is a [`Serve`] transport, which serves the
+//! recorded invocations back, e.g. to handlers generated by `serve_interface`, and checks the
+//! results written by the handlers against the recorded ones.
+//!
+//! A recording starts with the 7-byte magic `wrpcrec` followed by the `u8` format version,
+//! currently [`VERSION`], followed by the recorded invocations, each encoded using
+//! [`Invocation::encode`].
+
+use core::fmt;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, ensure, Context as _};
+use bytes::{Bytes, BytesMut};
+use futures::{stream, Stream, StreamExt as _};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::oneshot;
+use tokio_util::codec::{Decoder as _, Encoder};
+use tracing::{instrument, trace, warn};
+
+use crate::compress::CompressionTransport;
+use crate::layer::Layer;
+use crate::{Decode, Encode, Index, Invoke, Reject, Serve};
+
+/// Magic bytes starting every recording
+const MAGIC: &[u8; 7] = b"wrpcrec";
+
+/// Version of the recording format
+pub const VERSION: u8 = 1;
+
+/// Bytes transferred on a stream
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Chunk {
+    /// Time elapsed since the start of the invocation
+    pub elapsed: Duration,
+    /// Transferred bytes
+    pub data: Bytes,
+}
+
+/// Bytes transferred on a single stream of an invocation
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecordedStream {
+    /// Structural path of the stream, empty for the root stream
+    pub path: Vec<usize>,
+    /// Transferred bytes in order of transmission
+    pub chunks: Vec<Chunk>,
+}
+
+impl RecordedStream {
+    /// Returns all bytes transferred on the stream
+    #[must_use]
+    pub fn data(&self) -> Bytes {
+        match self.chunks.as_slice() {
+            [] => Bytes::default(),
+            [chunk] => chunk.data.clone(),
+            chunks => chunks
+                .iter()
+                .fold(BytesMut::default(), |mut buf, chunk| {
+                    buf.extend_from_slice(&chunk.data);
+                    buf
+                })
+                .freeze(),
+        }
+    }
+}
+
+/// Recorded invocation
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Invocation {
+    /// Invoked instance
+    pub instance: String,
+    /// Invoked function
+    pub func: String,
+    /// Streams transferring parameters from the client to the server. The root stream
+    /// starts with the synchronous parameters passed to [`Invoke::invoke`]
+    pub params: Vec<RecordedStream>,
+    /// Streams transferring results from the server to the client
+    pub results: Vec<RecordedStream>,
+}
+
+type WireStreams = Vec<(Vec<u32>, Vec<(u64, Bytes)>)>;
+type WireInvocation = (String, String, WireStreams, WireStreams);
+
+/// Stream type used to encode recordings, which contain no asynchronous values
+struct Unindexed;
+
+impl Index<Self> for Unindexed {
+    fn index(&self, _path: &[usize]) -> anyhow::Result<Self> {
+        bail!("recordings contain no asynchronous values")
+    }
+}
+
+fn streams_to_wire(streams: Vec<RecordedStream>) -> WireStreams {
+    streams
+        .into_iter()
+        .map(|RecordedStream { path, chunks }| {
+            let path = path
+                .into_iter()
+                .map(|i| i.try_into().unwrap_or(u32::MAX))
+                .collect();
+            let chunks = chunks
+                .into_iter()
+                .map(|Chunk { elapsed, data }| {
+                    (elapsed.as_micros().try_into().unwrap_or(u64::MAX), data)
+                })
+                .collect();
+            (path, chunks)
+        })
+        .collect()
+}
+
+fn streams_from_wire(streams: WireStreams) -> anyhow::Result<Vec<RecordedStream>> {
+    streams
+        .into_iter()
+        .map(|(path, chunks)| {
+            let path = path
+                .into_iter()
+                .map(usize::try_from)
+                .collect::<Result<_, _>>()
+                .context("invalid stream path")?;
+            let chunks = chunks
+                .into_iter()
+                .map(|(elapsed, data)| Chunk {
+                    elapsed: Duration::from_micros(elapsed),
+                    data,
+                })
+                .collect();
+            Ok(RecordedStream { path, chunks })
+        })
+        .collect()
+}
+
+impl Invocation {
+    /// Encodes the invocation to `dst`
+    pub fn encode(self, dst: &mut BytesMut) -> std::io::Result<()> {
+        let Self {
+            instance,
+            func,
+            params,
+            results,
+        } = self;
+        let invocation: WireInvocation = (
+            instance,
+            func,
+            streams_to_wire(params),
+            streams_to_wire(results),
+        );
+        let mut enc = <WireInvocation as Encode<Unindexed>>::Encoder::default();
+        Encoder::<WireInvocation>::encode(&mut enc, invocation, dst)
+    }
+
+    /// Decodes an invocation from `src`, returns `None` if `src` is empty
+    pub fn decode(src: &mut BytesMut) -> anyhow::Result<Option<Self>> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        let mut dec = <WireInvocation as Decode<Unindexed>>::Decoder::default();
+        let Some((instance, func, params, results)) =
+            dec.decode(src).context("failed to decode invocation")?
+        else {
+            bail!("recording is truncated")
+        };
+        Ok(Some(Self {
+            instance,
+            func,
+            params: streams_from_wire(params)?,
+            results: streams_from_wire(results)?,
+        }))
+    }
+}
+
+enum Command {
+    /// Write an encoded invocation
+    Write(BytesMut),
+    /// Flush the sink and report the first failure since the last flush
+    Flush(oneshot::Sender<std::io::Result<()>>),
+}
+
+/// Sender of [`Command`]s to the thread writing to the sink
+type Sink = mpsc::Sender<Command>;
+
+/// Writes the recording header and invocations received on `rx` to `sink` until all senders
+/// are dropped
+fn write_recording(mut sink: impl Write, rx: &mpsc::Receiver<Command>) {
+    let mut res = sink
+        .write_all(MAGIC)
+        .and_then(|()| sink.write_all(&[VERSION]));
+    for cmd in rx {
+        match cmd {
+            Command::Write(buf) => {
+                if res.is_ok() {
+                    res = sink.write_all(&buf);
+                }
+            }
+            Command::Flush(tx) => {
+                let res = core::mem::replace(&mut res, Ok(())).and_then(|()| sink.flush());
+                _ = tx.send(res);
+            }
+        }
+    }
+    if let Err(err) = res.and_then(|()| sink.flush()) {
+        warn!(?err, "failed to write recording");
+    }
+}
+
+/// Records invocations to a sink.
+///
+/// The sink is written to by a dedicated thread, therefore recording never blocks the
+/// transport. Use [`RecordLayer::flush`] to wait for recorded invocations to be written.
+#[derive(Clone)]
+pub struct RecordLayer {
+    sink: Sink,
+}
+
+impl fmt::Debug for RecordLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecordLayer").finish_non_exhaustive()
+    }
+}
+
+impl RecordLayer {
+    /// Write recorded invocations to `sink`
+    pub fn new(sink: impl Write + Send + 'static) -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || write_recording(sink, &rx));
+        Self { sink: tx }
+    }
+
+    /// Write recorded invocations to a file at `path`, which is created or truncated
+    pub fn create(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        File::create(path).map(Self::new)
+    }
+
+    /// Waits until all invocations finished so far are written to the sink and flushes it.
+    ///
+    /// Returns the first error encountered writing to the sink since the last flush, if any.
+    pub async fn flush(&self) -> std::io::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        let closed = || std::io::Error::new(std::io::ErrorKind::BrokenPipe, "recording closed");
+        self.sink.send(Command::Flush(tx)).map_err(|_| closed())?;
+        rx.await.map_err(|_| closed())?
+    }
+}
+
+impl<T> Layer<T> for RecordLayer {
+    type Invoke = Record<T>;
+
+    /// Transports produced by the same layer share the sink
+    fn layer(&self, inner: T) -> Self::Invoke {
+        Record {
+            inner,
+            sink: self.sink.clone(),
+        }
+    }
+}
+
+/// [`Invoke`] and [`Serve`] transport produced by [`RecordLayer`]
+#[derive(Clone)]
+pub struct Record<T> {
+    inner: T,
+    sink: Sink,
+}
+
+impl<T: fmt::Debug> fmt::Debug for Record<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Record")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Invocation in progress, sent to the sink once all of its streams are dropped
+struct State {
+    instance: String,
+    func: String,
+    start: Instant,
+    params: Mutex<BTreeMap<Vec<usize>, Vec<Chunk>>>,
+    results: Mutex<BTreeMap<Vec<usize>, Vec<Chunk>>>,
+    sink: Sink,
+}
+
+impl State {
+    fn new(instance: &str, func: &str, sink: Sink) -> Self {
+        Self {
+            instance: instance.to_string(),
+            func: func.to_string(),
+            start: Instant::now(),
+            params: Mutex::default(),
+            results: Mutex::default(),
+            sink,
+        }
+    }
+
+    fn record(&self, direction: Direction, path: &[usize], data: &[u8]) {
+        let streams = match direction {
+            Direction::Params => &self.params,
+            Direction::Results => &self.results,
+        };
+        let chunk = Chunk {
+            elapsed: self.start.elapsed(),
+            data: Bytes::copy_from_slice(data),
+        };
+        match streams.lock() {
+            Ok(mut streams) => streams.entry(path.to_vec()).or_default().push(chunk),
+            Err(err) => warn!(?err, "failed to lock recorded streams"),
+        }
+    }
+
+    fn send(&mut self) -> anyhow::Result<()> {
+        let streams = |streams: &mut Mutex<BTreeMap<Vec<usize>, Vec<Chunk>>>| {
+            let streams = streams
+                .get_mut()
+                .map_err(|err| anyhow!(err.to_string()).context("failed to lock streams"))?;
+            anyhow::Ok(
+                core::mem::take(streams)
+                    .into_iter()
+                    .map(|(path, chunks)| RecordedStream { path, chunks })
+                    .collect(),
+            )
+        };
+        let invocation = Invocation {
+            instance: core::mem::take(&mut self.instance),
+            func: core::mem::take(&mut self.func),
+            params: streams(&mut self.params)?,
+            results: streams(&mut self.results)?,
+        };
+        let mut buf = BytesMut::default();
+        invocation
+            .encode(&mut buf)
+            .context("failed to encode invocation")?;
+        self.sink
+            .send(Command::Write(buf))
+            .map_err(|_| anyhow!("recording closed"))
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        trace!(
+            instance = self.instance,
+            func = self.func,
+            "sending recorded invocation"
+        );
+        if let Err(err) = self.send() {
+            warn!(?err, "failed to record invocation");
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Direction {
+    Params,
+    Results,
+}
+
+impl<T: CompressionTransport> CompressionTransport for Record<T> {
+    fn with_compression_enabled(self) -> Self {
+        Self {
+            inner: self.inner.with_compression_enabled(),
+            ..self
+        }
+    }
+}
+
+impl<T: Invoke> Invoke for Record<T> {
+    type Context = T::Context;
+    type Outgoing = Recorded<T::Outgoing>;
+    type Incoming = Recorded<T::Incoming>;
+
+    #[instrument(level = "trace", skip(self, cx, params, paths))]
+    async fn invoke(
+        &self,
+        cx: Self::Context,
+        instance: &str,
+        func: &str,
+        params: Bytes,
+        paths: &[impl AsRef<[Option<usize>]> + Send + Sync],
+    ) -> anyhow::Result<(Self::Outgoing, Self::Incoming)> {
+        let state = State::new(instance, func, self.sink.clone());
+        let (outgoing, incoming) = self
+            .inner
+            .invoke(cx, instance, func, params.clone(), paths)
+            .await?;
+        let state = Arc::new(state);
+        state.record(Direction::Params, &[], &params);
+        Ok((
+            Recorded::new(outgoing, Direction::Params, Arc::clone(&state)),
+            Recorded::new(incoming, Direction::Results, state),
+        ))
+    }
+}
+
+impl<T: Serve> Serve for Record<T> {
+    type Context = T::Context;
+    type Outgoing = Recorded<T::Outgoing>;
+    type Incoming = Recorded<T::Incoming>;
+
+    #[instrument(level = "trace", skip(self, paths))]
+    async fn serve<P: AsRef<[Option<usize>]> + Send + Sync + 'static>(
+        &self,
+        instance: &str,
+        func: &str,
+        paths: impl Into<Arc<[P]>> + Send + Sync + 'static,
+    ) -> anyhow::Result<
+        impl Stream<Item = anyhow::Result<(Self::Context, Self::Outgoing, Self::Incoming)>>
+            + Send
+            + 'static,
+    > {
+        let invocations = self.inner.serve(instance, func, paths).await?;
+        let sink = self.sink.clone();
+        let instance = Arc::<str>::from(instance);
+        let func = Arc::<str>::from(func);
+        Ok(invocations.map(move |res| {
+            let (cx, tx, rx) = res?;
+            let state = Arc::new(State::new(&instance, &func, sink.clone()));
+            Ok((
+                cx,
+                Recorded::new(tx, Direction::Results, Arc::clone(&state)),
+                Recorded::new(rx, Direction::Params, state),
+            ))
+        }))
+    }
+}
+
+/// Stream of an invocation recorded by [`Record`]
+pub struct Recorded<T> {
+    inner: T,
+    path: Arc<[usize]>,
+    direction: Direction,
+    state: Arc<State>,
+}
+
+impl<T> Recorded<T> {
+    fn new(inner: T, direction: Direction, state: Arc<State>) -> Self {
+        Self {
+            inner,
+            path: Arc::from([]),
+            direction,
+            state,
+        }
+    }
+}
+
+impl<T: Index<T>> Index<Self> for Recorded<T> {
+    fn index(&self, path: &[usize]) -> anyhow::Result<Self> {
+        let inner = self.inner.index(path)?;
+        Ok(Self {
+            inner,
+            path: [self.path.as_ref(), path].concat().into(),
+            direction: self.direction,
+            state: Arc::clone(&self.state),
+        })
+    }
+}
+
+impl<T: Reject + Send> Reject for Recorded<T> {
+    async fn reject(self, err: &str) -> anyhow::Result<()> {
+        self.inner.reject(err).await
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Recorded<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let data = &buf.filled()[filled..];
+            if !data.is_empty() {
+                self.state.record(self.direction, &self.path, data);
+            }
+        }
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Recorded<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            if n > 0 {
+                self.state.record(self.direction, &self.path, &buf[..n]);
+            }
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Results written by a handler for a replayed invocation
+#[derive(Default)]
+struct Written {
+    streams: BTreeMap<Vec<usize>, BytesMut>,
+    rejected: Option<String>,
+}
+
+/// Invocation served by [`Replay`]
+struct Replayed {
+    invocation: Arc<Invocation>,
+    written: Arc<Mutex<Written>>,
+}
+
+/// [`Serve`] transport replaying recorded invocations.
+///
+/// Each recorded invocation of a function is served exactly once, in order of recording,
+/// after which the stream of invocations returned by [`Serve::serve`] ends. Recorded bytes
+/// are available immediately, i.e. the recorded timing is not reproduced. The invocation
+/// context is the recorded [`Invocation`].
+#[derive(Default)]
+pub struct Replay {
+    pending: Mutex<VecDeque<Invocation>>,
+    served: Arc<Mutex<Vec<Replayed>>>,
+}
+
+impl Replay {
+    /// Replay `invocations`
+    pub fn new(invocations: impl IntoIterator<Item = Invocation>) -> Self {
+        Self {
+            pending: Mutex::new(invocations.into_iter().collect()),
+            served: Arc::default(),
+        }
+    }
+
+    /// Replay invocations recorded by [`RecordLayer`] read from `r`
+    pub fn read(mut r: impl Read) -> anyhow::Result<Self> {
+        let mut buf = Vec::default();
+        r.read_to_end(&mut buf)
+            .context("failed to read recording")?;
+        let Some((magic, rest)) = buf.split_first_chunk::<7>() else {
+            bail!("recording is missing the header")
+        };
+        ensure!(magic == MAGIC, "not a wRPC recording");
+        match rest.first() {
+            Some(&VERSION) => {}
+            Some(v) => bail!("unsupported recording version `{v}`, expected `{VERSION}`"),
+            None => bail!("recording is missing the version"),
+        }
+        let mut buf = BytesMut::from(&rest[1..]);
+        let mut invocations = Vec::default();
+        while let Some(invocation) = Invocation::decode(&mut buf)? {
+            invocations.push(invocation);
+        }
+        Ok(Self::new(invocations))
+    }
+
+    /// Replay invocations recorded by [`RecordLayer`] to a file at `path`
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("failed to open recording at `{}`", path.display()))?;
+        Self::read(file)
+    }
+
+    /// Checks that the results written by handlers of all served invocations match the
+    /// recorded results
+    pub fn check(&self) -> anyhow::Result<()> {
+        let served = self
+            .served
+            .lock()
+            .map_err(|err| anyhow!(err.to_string()).context("failed to lock invocations"))?;
+        for Replayed {
+            invocation,
+            written,
+        } in served.iter()
+        {
+            let Invocation {
+                instance,
+                func,
+                results,
+                ..
+            } = invocation.as_ref();
+            let written = written
+                .lock()
+                .map_err(|err| anyhow!(err.to_string()).context("failed to lock results"))?;
+            if let Some(err) = &written.rejected {
+                bail!("invocation of `{func}` from `{instance}` was rejected: {err}")
+            }
+            for stream in results {
+                let data = stream.data();
+                let got = written
+                    .streams
+                    .get(&stream.path)
+                    .map(BytesMut::as_ref)
+                    .unwrap_or_default();
+                ensure!(
+                    got == data.as_ref(),
+                    "results of `{func}` from `{instance}` at path `{:?}` differ: `{got:02x?}` != `{:02x?}`",
+                    stream.path,
+                    data.as_ref(),
+                );
+            }
+            for (path, buf) in &written.streams {
+                ensure!(
+                    buf.is_empty() || results.iter().any(|stream| stream.path == *path),
+                    "unexpected results of `{func}` from `{instance}` written at path `{path:?}`",
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serve for Replay {
+    type Context = Arc<Invocation>;
+    type Outgoing = ReplayOutgoing;
+    type Incoming = ReplayIncoming;
+
+    #[instrument(level = "trace", skip(self, _paths))]
+    async fn serve<P: AsRef<[Option<usize>]> + Send + Sync + 'static>(
+        &self,
+        instance: &str,
+        func: &str,
+        _paths: impl Into<Arc<[P]>> + Send + Sync + 'static,
+    ) -> anyhow::Result<
+        impl Stream<Item = anyhow::Result<(Self::Context, Self::Outgoing, Self::Incoming)>>
+            + Send
+            + 'static,
+    > {
+        let invocations = {
+            let mut pending = self
+                .pending
+                .lock()
+                .map_err(|err| anyhow!(err.to_string()).context("failed to lock invocations"))?;
+            let (matching, rest) = pending
+                .drain(..)
+                .partition::<Vec<_>, _>(|inv| inv.instance == instance && inv.func == func);
+            pending.extend(rest);
+            matching
+        };
+        trace!(n = invocations.len(), "replaying invocations");
+        let served = Arc::clone(&self.served);
+        Ok(stream::iter(invocations).map(move |invocation| {
+            let invocation = Arc::new(invocation);
+            let written = Arc::default();
+            served
+                .lock()
+                .map_err(|err| anyhow!(err.to_string()).context("failed to lock invocations"))?
+                .push(Replayed {
+                    invocation: Arc::clone(&invocation),
+                    written: Arc::clone(&written),
+                });
+            Ok((
+                Arc::clone(&invocation),
+                ReplayOutgoing {
+                    path: Arc::from([]),
+                    written,
+                },
+                ReplayIncoming::new(invocation, Arc::from([])),
+            ))
+        }))
+    }
+}
+
+/// Incoming stream of [`Replay`], which yields the recorded parameters
+pub struct ReplayIncoming {
+    invocation: Arc<Invocation>,
+    path: Arc<[usize]>,
+    buf: Bytes,
+}
+
+impl ReplayIncoming {
+    fn new(invocation: Arc<Invocation>, path: Arc<[usize]>) -> Self {
+        let buf = invocation
+            .params
+            .iter()
+            .find(|stream| *stream.path == *path)
+            .map(RecordedStream::data)
+            .unwrap_or_default();
+        Self {
+            invocation,
+            path,
+            buf,
+        }
+    }
+}
+
+impl Index<Self> for ReplayIncoming {
+    fn index(&self, path: &[usize]) -> anyhow::Result<Self> {
+        Ok(Self::new(
+            Arc::clone(&self.invocation),
+            [self.path.as_ref(), path].concat().into(),
+        ))
+    }
+}
+
+impl AsyncRead for ReplayIncoming {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let n = buf.remaining().min(self.buf.len());
+        buf.put_slice(&self.buf.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Outgoing stream of [`Replay`], which captures the results written by the handler
+pub struct ReplayOutgoing {
+    path: Arc<[usize]>,
+    written: Arc<Mutex<Written>>,
+}
+
+impl Index<Self> for ReplayOutgoing {
+    fn index(&self, path: &[usize]) -> anyhow::Result<Self> {
+        Ok(Self {
+            path: [self.path.as_ref(), path].concat().into(),
+            written: Arc::clone(&self.written),
+        })
+    }
+}
+
+impl Reject for ReplayOutgoing {
+    async fn reject(self, err: &str) -> anyhow::Result<()> {
+        let mut written = self
+            .written
+            .lock()
+            .map_err(|err| anyhow!(err.to_string()).context("failed to lock results"))?;
+        written.rejected = Some(err.to_string());
+        Ok(())
+    }
+}
+
+impl AsyncWrite for ReplayOutgoing {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut written = self
+            .written
+            .lock()
+            .map_err(|err| std::io::Error::other(err.to_string()))?;
+        written
+            .streams
+            .entry(self.path.to_vec())
+            .or_default()
+            .extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::pin::pin;
+
+    use std::io::Cursor;
+
+    use futures::TryStreamExt as _;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Discards parameters and responds with `results`
+    struct Canned {
+        results: Vec<u8>,
+    }
+
+    struct CannedStream(Cursor<Vec<u8>>);
+
+    impl Index<Self> for CannedStream {
+        fn index(&self, _path: &[usize]) -> anyhow::Result<Self> {
+            Ok(Self(Cursor::default()))
+        }
+    }
+
+    impl AsyncRead for CannedStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for CannedStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Invoke for Canned {
+        type Context = ();
+        type Outgoing = CannedStream;
+        type Incoming = CannedStream;
+
+        async fn invoke(
+            &self,
+            (): Self::Context,
+            _instance: &str,
+            _func: &str,
+            _params: Bytes,
+            _paths: &[impl AsRef<[Option<usize>]> + Send + Sync],
+        ) -> anyhow::Result<(Self::Outgoing, Self::Incoming)> {
+            Ok((
+                CannedStream(Cursor::default()),
+                CannedStream(Cursor::new(self.results.clone())),
+            ))
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn record_replay() -> anyhow::Result<()> {
+        let sink = SharedBuf::default();
+        let layer = RecordLayer::new(sink.clone());
+        let clt = layer.layer(Canned {
+            results: vec![0x2a],
+        });
+        let (x,) = clt
+            .invoke_values_blocking::<_, (u32,)>((), "foo", "bar", (1u32, "test"), &[[None; 0]])
+            .await?;
+        assert_eq!(x, 42);
+
+        layer.flush().await?;
+        let recording = sink.0.lock().unwrap().clone();
+        assert_eq!(recording[..8], *b"wrpcrec\x01");
+        let replay = Replay::read(recording.as_slice())?;
+        let invocations = replay
+            .serve_values::<_, (u32, String), (u32,)>("foo", "bar", [[None; 0]])
+            .await?;
+        let mut invocations = pin!(invocations);
+        let (cx, (x, s), rx, tx) = invocations
+            .try_next()
+            .await?
+            .context("unexpected end of stream")?;
+        assert!(invocations.try_next().await?.is_none());
+        assert_eq!((cx.instance.as_str(), cx.func.as_str()), ("foo", "bar"));
+        assert!(rx.is_none());
+        assert_eq!((x, s.as_str()), (1, "test"));
+        tx(Ok((x + 41,))).await?;
+        replay.check()?;
+
+        let replay = Replay::read(recording.as_slice())?;
+        let invocations = replay
+            .serve_values::<_, (u32, String), (u32,)>("foo", "bar", [[None; 0]])
+            .await?;
+        let mut invocations = pin!(invocations);
+        let (_, (x, _), _, tx) = invocations
+            .try_next()
+            .await?
+            .context("unexpected end of stream")?;
+        tx(Ok((x,))).await?;
+        assert!(replay.check().is_err(), "results should differ");
+
+        assert!(
+            Replay::read(&recording[1..]).is_err(),
+            "magic should be checked"
+        );
+        let mut recording = recording;
+        recording[7] = VERSION + 1;
+        assert!(
+            Replay::read(recording.as_slice()).is_err(),
+            "version should be checked"
+        );
+        Ok(())
+    }
+}
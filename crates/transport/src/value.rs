@@ -1,19 +1,23 @@
-use core::any::TypeId;
+use core::any::{type_name, TypeId};
 use core::fmt::{self, Debug};
 use core::future::Future;
+use core::hash::{BuildHasher, Hash};
 use core::iter::zip;
 use core::marker::PhantomData;
 use core::mem;
 use core::ops::{Deref, DerefMut};
-use core::pin::Pin;
+use core::pin::{pin, Pin};
 
-use bytes::{BufMut as _, Bytes, BytesMut};
+use bytes::{Buf as _, BufMut as _, Bytes, BytesMut};
+use futures::future;
 use futures::stream::{self, FuturesUnordered};
 use futures::{Stream, StreamExt as _, TryStreamExt as _};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt as _};
 use tokio::select;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::codec::{Encoder as _, FramedRead};
 use tracing::{instrument, trace};
@@ -29,6 +33,9 @@ use wasm_tokio::{
     Leb128Encoder, Utf8Codec,
 };
 
+use crate::limits::{check_depth, check_list_len, ChunkDecoder, InvocationDecoder};
+use crate::{LimitedListDecoder, LimitedStringDecoder};
+
 #[repr(transparent)]
 pub struct ResourceBorrow<T: ?Sized> {
     repr: Bytes,
@@ -221,6 +228,8 @@ impl_deferred_sync!(CoreVecDecoder<F32Codec>);
 impl_deferred_sync!(CoreVecDecoder<F64Codec>);
 impl_deferred_sync!(CoreVecDecoder<CoreNameDecoder>);
 impl_deferred_sync!(CoreVecDecoder<CoreVecDecoderBytes>);
+impl_deferred_sync!(CoreVecDecoder<LimitedStringDecoder<CoreNameDecoder>>);
+impl_deferred_sync!(CoreVecDecoder<LimitedListDecoder<CoreVecDecoderBytes>>);
 impl_deferred_sync!(CoreVecDecoder<Utf8Codec>);
 impl_deferred_sync!(CoreVecDecoder<Leb128DecoderI8>);
 impl_deferred_sync!(CoreVecDecoder<Leb128DecoderU8>);
@@ -465,9 +474,10 @@ where
 impl<T, R> Decode<R> for Option<T>
 where
     T: Decode<R>,
+    R: 'static,
 {
     type Decoder = OptionDecoder<T::Decoder>;
-    type ListDecoder = CoreVecDecoder<Self::Decoder>;
+    type ListDecoder = ListDecoder<Self::Decoder, R>;
 }
 
 impl<O, E, W> Deferred<W> for ResultEncoder<O, E>
@@ -539,9 +549,10 @@ where
     E: Decode<R>,
     std::io::Error: From<<O::Decoder as tokio_util::codec::Decoder>::Error>,
     std::io::Error: From<<E::Decoder as tokio_util::codec::Decoder>::Error>,
+    R: 'static,
 {
     type Decoder = ResultDecoder<O::Decoder, E::Decoder>;
-    type ListDecoder = CoreVecDecoder<Self::Decoder>;
+    type ListDecoder = ListDecoder<Self::Decoder, R>;
 }
 
 pub struct ListEncoder<W> {
@@ -717,6 +728,7 @@ where
             if len == 0 {
                 return Ok(Some(Vec::default()));
             }
+            check_list_len(len)?;
             let len = len
                 .try_into()
                 .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
@@ -746,201 +758,739 @@ where
     type ListDecoder = ListDecoder<Self::Decoder, R>;
 }
 
-macro_rules! impl_copy_codec {
-    ($t:ty, $c:tt) => {
-        impl<W> Encode<W> for $t {
-            type Encoder = $c;
-
-            #[instrument(level = "trace", skip(items))]
-            fn encode_iter_own<I>(
-                items: I,
-                enc: &mut Self::Encoder,
-                dst: &mut BytesMut,
-            ) -> Result<
-                Option<DeferredFn<W>>,
-                <Self::Encoder as tokio_util::codec::Encoder<Self>>::Error,
-            >
-            where
-                I: IntoIterator<Item = Self>,
-                I::IntoIter: ExactSizeIterator,
-            {
-                let items = items.into_iter();
-                dst.reserve(items.len());
-                for item in items {
-                    enc.encode(item, dst)?;
-                }
-                Ok(None)
-            }
-
-            #[instrument(level = "trace", skip(items))]
-            fn encode_iter_ref<'a, I>(
-                items: I,
-                enc: &mut Self::Encoder,
-                dst: &mut BytesMut,
-            ) -> Result<
-                Option<DeferredFn<W>>,
-                <Self::Encoder as tokio_util::codec::Encoder<&'a Self>>::Error,
-            >
-            where
-                I: IntoIterator<Item = &'a Self>,
-                I::IntoIter: ExactSizeIterator,
-            {
-                let items = items.into_iter();
-                dst.reserve(items.len());
-                for item in items {
-                    enc.encode(*item, dst)?;
-                }
-                Ok(None)
-            }
+/// Encodes `items` as a `list`
+fn encode_list_iter<T, W, I>(
+    items: I,
+    dst: &mut BytesMut,
+) -> Result<Option<DeferredFn<W>>, <T::Encoder as tokio_util::codec::Encoder<T>>::Error>
+where
+    T: Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+    I: IntoIterator<Item = T>,
+    I::IntoIter: ExactSizeIterator,
+{
+    let items = items.into_iter();
+    let n = u32::try_from(items.len())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    dst.reserve(5 + items.len());
+    Leb128Encoder.encode(n, dst)?;
+    T::encode_iter_own(items, &mut T::Encoder::default(), dst)
+}
+
+/// Encodes borrowed `items` as a `list`
+fn encode_list_iter_ref<'a, T, W, I>(
+    items: I,
+    dst: &mut BytesMut,
+) -> Result<Option<DeferredFn<W>>, <T::Encoder as tokio_util::codec::Encoder<&'a T>>::Error>
+where
+    T: Encode<W> + 'a,
+    T::Encoder: tokio_util::codec::Encoder<&'a T>,
+    W: crate::Index<W> + Send + Sync + 'static,
+    I: IntoIterator<Item = &'a T>,
+    I::IntoIter: ExactSizeIterator,
+{
+    let items = items.into_iter();
+    let n = u32::try_from(items.len())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    dst.reserve(5 + items.len());
+    Leb128Encoder.encode(n, dst)?;
+    T::encode_iter_ref(items, &mut T::Encoder::default(), dst)
+}
+
+/// Size of chunks written by [`write_list`] in bytes
+const LIST_CHUNK_SIZE: usize = 8192;
+
+/// Encodes a `list` of `len` elements produced by `items` and writes it to `w` incrementally.
+///
+/// Encoded elements are buffered until at least 8 KiB are available, which are written to `w`
+/// before any further elements are polled, so the whole list is never held in memory.
+/// Fails if `items` does not produce exactly `len` elements.
+#[instrument(level = "trace", skip(w, items), fields(ty = "list"))]
+pub async fn write_list<T, W, S>(
+    w: &mut W,
+    len: u32,
+    items: S,
+) -> std::io::Result<Option<DeferredFn<W>>>
+where
+    T: Encode<W>,
+    W: AsyncWrite + crate::Index<W> + Send + Sync + Unpin + 'static,
+    S: Stream<Item = T>,
+    std::io::Error: From<<T::Encoder as tokio_util::codec::Encoder<T>>::Error>,
+{
+    let mut items = pin!(items);
+    let mut buf = BytesMut::with_capacity(LIST_CHUNK_SIZE);
+    Leb128Encoder.encode(len, &mut buf)?;
+    let mut enc = T::Encoder::default();
+    let mut deferred = Vec::new();
+    let mut n = 0;
+    while let Some(item) = items.next().await {
+        if n == len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("list contains more than {len} elements"),
+            ));
         }
-
-        impl<'b, W> Encode<W> for &'b $t {
-            type Encoder = $c;
-
-            #[instrument(level = "trace", skip(items))]
-            fn encode_iter_own<I>(
-                items: I,
-                enc: &mut Self::Encoder,
-                dst: &mut BytesMut,
-            ) -> Result<
-                Option<DeferredFn<W>>,
-                <Self::Encoder as tokio_util::codec::Encoder<Self>>::Error,
-            >
-            where
-                I: IntoIterator<Item = Self>,
-                I::IntoIter: ExactSizeIterator,
-            {
-                let items = items.into_iter();
-                dst.reserve(items.len());
-                for item in items {
-                    enc.encode(*item, dst)?;
-                }
-                Ok(None)
-            }
-
-            #[instrument(level = "trace", skip(items))]
-            fn encode_iter_ref<'a, I>(
-                items: I,
-                enc: &mut Self::Encoder,
-                dst: &mut BytesMut,
-            ) -> Result<
-                Option<DeferredFn<W>>,
-                <Self::Encoder as tokio_util::codec::Encoder<&'a Self>>::Error,
-            >
-            where
-                I: IntoIterator<Item = &'a Self>,
-                I::IntoIter: ExactSizeIterator,
-                'b: 'a,
-            {
-                let items = items.into_iter();
-                dst.reserve(items.len());
-                for item in items {
-                    enc.encode(item, dst)?;
-                }
-                Ok(None)
-            }
+        enc.encode(item, &mut buf)?;
+        if let Some(f) = enc.take_deferred() {
+            deferred.push((n.try_into().unwrap_or(usize::MAX), f));
         }
-
-        impl<R> Decode<R> for $t {
-            type Decoder = $c;
-            type ListDecoder = CoreVecDecoder<Self::Decoder>;
+        n += 1;
+        if buf.len() >= LIST_CHUNK_SIZE {
+            trace!(len = buf.len(), "writing list chunk");
+            w.write_all(&buf).await?;
+            buf.clear();
         }
-    };
+    }
+    if n != len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("list contains {n} elements, expected {len}"),
+        ));
+    }
+    if !buf.is_empty() {
+        trace!(len = buf.len(), "writing list chunk");
+        w.write_all(&buf).await?;
+    }
+    if deferred.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Box::new(|w, path| {
+        Box::pin(async move {
+            let mut futs: FuturesUnordered<_> = deferred
+                .into_iter()
+                .map(|(i, f)| {
+                    let mut path = path.clone();
+                    path.push(i);
+                    f(Arc::clone(&w), path)
+                })
+                .collect();
+            while let Some(()) = futs.try_next().await? {}
+            Ok(())
+        })
+    })))
+}
+
+impl<T, W, const N: usize> tokio_util::codec::Encoder<[T; N]> for ListEncoder<W>
+where
+    T: Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Error = <T::Encoder as tokio_util::codec::Encoder<T>>::Error;
+
+    fn encode(&mut self, items: [T; N], dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.deferred = encode_list_iter(items, dst)?;
+        Ok(())
+    }
 }
 
-impl_copy_codec!(bool, BoolCodec);
-impl_copy_codec!(i8, S8Codec);
-impl_copy_codec!(i16, S16Codec);
-impl_copy_codec!(u16, U16Codec);
-impl_copy_codec!(i32, S32Codec);
-impl_copy_codec!(u32, U32Codec);
-impl_copy_codec!(i64, S64Codec);
-impl_copy_codec!(u64, U64Codec);
-impl_copy_codec!(f32, F32Codec);
-impl_copy_codec!(f64, F64Codec);
-impl_copy_codec!(char, Utf8Codec);
+impl<'a, T, W, const N: usize> tokio_util::codec::Encoder<&'a [T; N]> for ListEncoder<W>
+where
+    T: Encode<W>,
+    T::Encoder: tokio_util::codec::Encoder<&'a T>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Error = <T::Encoder as tokio_util::codec::Encoder<&'a T>>::Error;
 
-impl<T> Encode<T> for u8 {
-    type Encoder = U8Codec;
+    fn encode(&mut self, items: &'a [T; N], dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut enc = T::Encoder::default();
+        self.deferred = T::encode_list_ref(items, &mut enc, dst)?;
+        Ok(())
+    }
+}
 
-    #[instrument(level = "trace", skip(items))]
-    fn encode_iter_own<I>(
-        items: I,
-        enc: &mut Self::Encoder,
-        dst: &mut BytesMut,
-    ) -> Result<Option<DeferredFn<T>>, <Self::Encoder as tokio_util::codec::Encoder<Self>>::Error>
-    where
-        I: IntoIterator<Item = Self>,
-        I::IntoIter: ExactSizeIterator,
-    {
-        let items = items.into_iter();
-        dst.reserve(items.len());
-        dst.extend(items);
-        Ok(None)
+impl<T, W> tokio_util::codec::Encoder<VecDeque<T>> for ListEncoder<W>
+where
+    T: Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Error = <T::Encoder as tokio_util::codec::Encoder<T>>::Error;
+
+    fn encode(&mut self, items: VecDeque<T>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut enc = T::Encoder::default();
+        self.deferred = T::encode_list_own(items.into(), &mut enc, dst)?;
+        Ok(())
     }
+}
 
-    #[instrument(level = "trace", skip(items))]
-    fn encode_iter_ref<'a, I>(
-        items: I,
-        enc: &mut Self::Encoder,
-        dst: &mut BytesMut,
-    ) -> Result<Option<DeferredFn<T>>, <Self::Encoder as tokio_util::codec::Encoder<&'a Self>>::Error>
-    where
-        I: IntoIterator<Item = &'a Self>,
-        I::IntoIter: ExactSizeIterator,
-    {
-        let items = items.into_iter();
-        dst.reserve(items.len());
-        dst.extend(items);
-        Ok(None)
+impl<'a, T, W> tokio_util::codec::Encoder<&'a VecDeque<T>> for ListEncoder<W>
+where
+    T: Encode<W>,
+    T::Encoder: tokio_util::codec::Encoder<&'a T>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Error = <T::Encoder as tokio_util::codec::Encoder<&'a T>>::Error;
+
+    fn encode(&mut self, items: &'a VecDeque<T>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.deferred = encode_list_iter_ref(items, dst)?;
+        Ok(())
     }
+}
 
-    #[instrument(level = "trace", skip(items), fields(ty = "list<u8>"))]
-    fn encode_list_own(
-        items: Vec<Self>,
-        enc: &mut Self::Encoder,
-        dst: &mut BytesMut,
-    ) -> Result<Option<DeferredFn<T>>, <Self::Encoder as tokio_util::codec::Encoder<Self>>::Error>
-    {
-        CoreVecEncoderBytes.encode(items, dst)?;
-        Ok(None)
+impl<T, S, W> tokio_util::codec::Encoder<HashSet<T, S>> for ListEncoder<W>
+where
+    T: Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Error = <T::Encoder as tokio_util::codec::Encoder<T>>::Error;
+
+    fn encode(&mut self, items: HashSet<T, S>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.deferred = encode_list_iter(items, dst)?;
+        Ok(())
     }
+}
 
-    #[instrument(level = "trace", skip(items), fields(ty = "list<u8>"))]
-    fn encode_list_ref<'a>(
-        items: &'a [Self],
-        enc: &mut Self::Encoder,
-        dst: &mut BytesMut,
-    ) -> Result<Option<DeferredFn<T>>, <Self::Encoder as tokio_util::codec::Encoder<&'a Self>>::Error>
-    where
-        Self::Encoder: tokio_util::codec::Encoder<&'a Self>,
-    {
-        CoreVecEncoderBytes.encode(items, dst)?;
-        Ok(None)
+impl<'a, T, S, W> tokio_util::codec::Encoder<&'a HashSet<T, S>> for ListEncoder<W>
+where
+    T: Encode<W>,
+    T::Encoder: tokio_util::codec::Encoder<&'a T>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Error = <T::Encoder as tokio_util::codec::Encoder<&'a T>>::Error;
+
+    fn encode(&mut self, items: &'a HashSet<T, S>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.deferred = encode_list_iter_ref(items, dst)?;
+        Ok(())
     }
 }
 
-impl<'b, T> Encode<T> for &'b u8 {
-    type Encoder = U8Codec;
+impl<T, W> tokio_util::codec::Encoder<BTreeSet<T>> for ListEncoder<W>
+where
+    T: Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Error = <T::Encoder as tokio_util::codec::Encoder<T>>::Error;
 
-    #[instrument(level = "trace", skip(items))]
-    fn encode_iter_own<I>(
-        items: I,
-        enc: &mut Self::Encoder,
-        dst: &mut BytesMut,
-    ) -> Result<Option<DeferredFn<T>>, <Self::Encoder as tokio_util::codec::Encoder<Self>>::Error>
-    where
-        I: IntoIterator<Item = Self>,
-        I::IntoIter: ExactSizeIterator,
-    {
-        let items = items.into_iter();
-        dst.reserve(items.len());
-        dst.extend(items);
-        Ok(None)
+    fn encode(&mut self, items: BTreeSet<T>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.deferred = encode_list_iter(items, dst)?;
+        Ok(())
     }
+}
 
-    #[instrument(level = "trace", skip(items))]
-    fn encode_iter_ref<'a, I>(
+impl<'a, T, W> tokio_util::codec::Encoder<&'a BTreeSet<T>> for ListEncoder<W>
+where
+    T: Encode<W>,
+    T::Encoder: tokio_util::codec::Encoder<&'a T>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Error = <T::Encoder as tokio_util::codec::Encoder<&'a T>>::Error;
+
+    fn encode(&mut self, items: &'a BTreeSet<T>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.deferred = encode_list_iter_ref(items, dst)?;
+        Ok(())
+    }
+}
+
+impl<K, V, S, W> tokio_util::codec::Encoder<HashMap<K, V, S>> for ListEncoder<W>
+where
+    (K, V): Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Error = <<(K, V) as Encode<W>>::Encoder as tokio_util::codec::Encoder<(K, V)>>::Error;
+
+    fn encode(&mut self, items: HashMap<K, V, S>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.deferred = encode_list_iter(items, dst)?;
+        Ok(())
+    }
+}
+
+impl<'a, K, V, S, W> tokio_util::codec::Encoder<&'a HashMap<K, V, S>> for ListEncoder<W>
+where
+    (&'a K, &'a V): Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Error = <<(&'a K, &'a V) as Encode<W>>::Encoder as tokio_util::codec::Encoder<(
+        &'a K,
+        &'a V,
+    )>>::Error;
+
+    fn encode(
+        &mut self,
+        items: &'a HashMap<K, V, S>,
+        dst: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        self.deferred = encode_list_iter(items, dst)?;
+        Ok(())
+    }
+}
+
+impl<K, V, W> tokio_util::codec::Encoder<BTreeMap<K, V>> for ListEncoder<W>
+where
+    (K, V): Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Error = <<(K, V) as Encode<W>>::Encoder as tokio_util::codec::Encoder<(K, V)>>::Error;
+
+    fn encode(&mut self, items: BTreeMap<K, V>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.deferred = encode_list_iter(items, dst)?;
+        Ok(())
+    }
+}
+
+impl<'a, K, V, W> tokio_util::codec::Encoder<&'a BTreeMap<K, V>> for ListEncoder<W>
+where
+    (&'a K, &'a V): Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Error = <<(&'a K, &'a V) as Encode<W>>::Encoder as tokio_util::codec::Encoder<(
+        &'a K,
+        &'a V,
+    )>>::Error;
+
+    fn encode(&mut self, items: &'a BTreeMap<K, V>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.deferred = encode_list_iter(items, dst)?;
+        Ok(())
+    }
+}
+
+impl<T, W, const N: usize> Encode<W> for [T; N]
+where
+    T: Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Encoder = ListEncoder<W>;
+}
+
+impl<'a, T, W, const N: usize> Encode<W> for &'a [T; N]
+where
+    T: Encode<W>,
+    T::Encoder: tokio_util::codec::Encoder<&'a T>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Encoder = ListEncoder<W>;
+}
+
+impl<T, W> Encode<W> for VecDeque<T>
+where
+    T: Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Encoder = ListEncoder<W>;
+}
+
+impl<'a, T, W> Encode<W> for &'a VecDeque<T>
+where
+    T: Encode<W>,
+    T::Encoder: tokio_util::codec::Encoder<&'a T>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Encoder = ListEncoder<W>;
+}
+
+impl<T, S, W> Encode<W> for HashSet<T, S>
+where
+    T: Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Encoder = ListEncoder<W>;
+}
+
+impl<'a, T, S, W> Encode<W> for &'a HashSet<T, S>
+where
+    T: Encode<W>,
+    T::Encoder: tokio_util::codec::Encoder<&'a T>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Encoder = ListEncoder<W>;
+}
+
+impl<T, W> Encode<W> for BTreeSet<T>
+where
+    T: Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Encoder = ListEncoder<W>;
+}
+
+impl<'a, T, W> Encode<W> for &'a BTreeSet<T>
+where
+    T: Encode<W>,
+    T::Encoder: tokio_util::codec::Encoder<&'a T>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Encoder = ListEncoder<W>;
+}
+
+impl<K, V, S, W> Encode<W> for HashMap<K, V, S>
+where
+    (K, V): Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Encoder = ListEncoder<W>;
+}
+
+impl<'a, K, V, S, W> Encode<W> for &'a HashMap<K, V, S>
+where
+    (&'a K, &'a V): Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Encoder = ListEncoder<W>;
+}
+
+impl<K, V, W> Encode<W> for BTreeMap<K, V>
+where
+    (K, V): Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Encoder = ListEncoder<W>;
+}
+
+impl<'a, K, V, W> Encode<W> for &'a BTreeMap<K, V>
+where
+    (&'a K, &'a V): Encode<W>,
+    W: crate::Index<W> + Send + Sync + 'static,
+{
+    type Encoder = ListEncoder<W>;
+}
+
+/// Decoder, which converts values decoded by `T` into `U`
+pub struct ConvertDecoder<T, U> {
+    dec: T,
+    _ty: PhantomData<fn() -> U>,
+}
+
+impl<T: Default, U> Default for ConvertDecoder<T, U> {
+    fn default() -> Self {
+        Self {
+            dec: T::default(),
+            _ty: PhantomData,
+        }
+    }
+}
+
+impl<T, U, R> Deferred<R> for ConvertDecoder<T, U>
+where
+    T: Deferred<R>,
+{
+    fn take_deferred(&mut self) -> Option<DeferredFn<R>> {
+        self.dec.take_deferred()
+    }
+}
+
+impl<T, U> tokio_util::codec::Decoder for ConvertDecoder<T, U>
+where
+    T: tokio_util::codec::Decoder,
+    U: TryFrom<T::Item>,
+{
+    type Item = U;
+    type Error = T::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(v) = self.dec.decode(src)? else {
+            return Ok(None);
+        };
+        let v = U::try_from(v).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "failed to convert `{}` to `{}`",
+                    type_name::<T::Item>(),
+                    type_name::<U>()
+                ),
+            )
+        })?;
+        Ok(Some(v))
+    }
+}
+
+/// Decoder, which collects list elements decoded by `T` into `U`
+pub struct CollectDecoder<T, U> {
+    dec: T,
+    _ty: PhantomData<fn() -> U>,
+}
+
+impl<T: Default, U> Default for CollectDecoder<T, U> {
+    fn default() -> Self {
+        Self {
+            dec: T::default(),
+            _ty: PhantomData,
+        }
+    }
+}
+
+impl<T, U, R> Deferred<R> for CollectDecoder<T, U>
+where
+    T: Deferred<R>,
+{
+    fn take_deferred(&mut self) -> Option<DeferredFn<R>> {
+        self.dec.take_deferred()
+    }
+}
+
+impl<T, U, I> tokio_util::codec::Decoder for CollectDecoder<T, U>
+where
+    T: tokio_util::codec::Decoder<Item = Vec<I>>,
+    U: FromIterator<I>,
+{
+    type Item = U;
+    type Error = T::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let v = self.dec.decode(src)?;
+        Ok(v.map(U::from_iter))
+    }
+}
+
+impl<T, R, const N: usize> Decode<R> for [T; N]
+where
+    T: Decode<R> + Send + Sync + 'static,
+    T::ListDecoder: Deferred<R> + Send,
+    R: crate::Index<R> + Send + Sync + 'static,
+{
+    type Decoder = ConvertDecoder<T::ListDecoder, Self>;
+    type ListDecoder = ListDecoder<Self::Decoder, R>;
+}
+
+impl<T, R> Decode<R> for VecDeque<T>
+where
+    T: Decode<R> + Send + Sync + 'static,
+    T::ListDecoder: Deferred<R> + Send,
+    R: crate::Index<R> + Send + Sync + 'static,
+{
+    type Decoder = ConvertDecoder<T::ListDecoder, Self>;
+    type ListDecoder = ListDecoder<Self::Decoder, R>;
+}
+
+impl<T, S, R> Decode<R> for HashSet<T, S>
+where
+    T: Decode<R> + Eq + Hash + Send + Sync + 'static,
+    T::ListDecoder: Deferred<R> + Send,
+    S: BuildHasher + Default + 'static,
+    R: crate::Index<R> + Send + Sync + 'static,
+{
+    type Decoder = CollectDecoder<T::ListDecoder, Self>;
+    type ListDecoder = ListDecoder<Self::Decoder, R>;
+}
+
+impl<T, R> Decode<R> for BTreeSet<T>
+where
+    T: Decode<R> + Ord + Send + Sync + 'static,
+    T::ListDecoder: Deferred<R> + Send,
+    R: crate::Index<R> + Send + Sync + 'static,
+{
+    type Decoder = CollectDecoder<T::ListDecoder, Self>;
+    type ListDecoder = ListDecoder<Self::Decoder, R>;
+}
+
+impl<K, V, S, R> Decode<R> for HashMap<K, V, S>
+where
+    K: Eq + Hash + 'static,
+    V: 'static,
+    (K, V): Decode<R>,
+    <(K, V) as Decode<R>>::ListDecoder: Deferred<R> + Send,
+    S: BuildHasher + Default + 'static,
+    R: crate::Index<R> + Send + Sync + 'static,
+{
+    type Decoder = CollectDecoder<<(K, V) as Decode<R>>::ListDecoder, Self>;
+    type ListDecoder = ListDecoder<Self::Decoder, R>;
+}
+
+impl<K, V, R> Decode<R> for BTreeMap<K, V>
+where
+    K: Ord + 'static,
+    V: 'static,
+    (K, V): Decode<R>,
+    <(K, V) as Decode<R>>::ListDecoder: Deferred<R> + Send,
+    R: crate::Index<R> + Send + Sync + 'static,
+{
+    type Decoder = CollectDecoder<<(K, V) as Decode<R>>::ListDecoder, Self>;
+    type ListDecoder = ListDecoder<Self::Decoder, R>;
+}
+
+macro_rules! impl_copy_codec {
+    ($t:ty, $c:tt) => {
+        impl<W> Encode<W> for $t {
+            type Encoder = $c;
+
+            #[instrument(level = "trace", skip(items))]
+            fn encode_iter_own<I>(
+                items: I,
+                enc: &mut Self::Encoder,
+                dst: &mut BytesMut,
+            ) -> Result<
+                Option<DeferredFn<W>>,
+                <Self::Encoder as tokio_util::codec::Encoder<Self>>::Error,
+            >
+            where
+                I: IntoIterator<Item = Self>,
+                I::IntoIter: ExactSizeIterator,
+            {
+                let items = items.into_iter();
+                dst.reserve(items.len());
+                for item in items {
+                    enc.encode(item, dst)?;
+                }
+                Ok(None)
+            }
+
+            #[instrument(level = "trace", skip(items))]
+            fn encode_iter_ref<'a, I>(
+                items: I,
+                enc: &mut Self::Encoder,
+                dst: &mut BytesMut,
+            ) -> Result<
+                Option<DeferredFn<W>>,
+                <Self::Encoder as tokio_util::codec::Encoder<&'a Self>>::Error,
+            >
+            where
+                I: IntoIterator<Item = &'a Self>,
+                I::IntoIter: ExactSizeIterator,
+            {
+                let items = items.into_iter();
+                dst.reserve(items.len());
+                for item in items {
+                    enc.encode(*item, dst)?;
+                }
+                Ok(None)
+            }
+        }
+
+        impl<'b, W> Encode<W> for &'b $t {
+            type Encoder = $c;
+
+            #[instrument(level = "trace", skip(items))]
+            fn encode_iter_own<I>(
+                items: I,
+                enc: &mut Self::Encoder,
+                dst: &mut BytesMut,
+            ) -> Result<
+                Option<DeferredFn<W>>,
+                <Self::Encoder as tokio_util::codec::Encoder<Self>>::Error,
+            >
+            where
+                I: IntoIterator<Item = Self>,
+                I::IntoIter: ExactSizeIterator,
+            {
+                let items = items.into_iter();
+                dst.reserve(items.len());
+                for item in items {
+                    enc.encode(*item, dst)?;
+                }
+                Ok(None)
+            }
+
+            #[instrument(level = "trace", skip(items))]
+            fn encode_iter_ref<'a, I>(
+                items: I,
+                enc: &mut Self::Encoder,
+                dst: &mut BytesMut,
+            ) -> Result<
+                Option<DeferredFn<W>>,
+                <Self::Encoder as tokio_util::codec::Encoder<&'a Self>>::Error,
+            >
+            where
+                I: IntoIterator<Item = &'a Self>,
+                I::IntoIter: ExactSizeIterator,
+                'b: 'a,
+            {
+                let items = items.into_iter();
+                dst.reserve(items.len());
+                for item in items {
+                    enc.encode(item, dst)?;
+                }
+                Ok(None)
+            }
+        }
+
+        impl<R> Decode<R> for $t {
+            type Decoder = $c;
+            type ListDecoder = LimitedListDecoder<CoreVecDecoder<Self::Decoder>>;
+        }
+    };
+}
+
+impl_copy_codec!(bool, BoolCodec);
+impl_copy_codec!(i8, S8Codec);
+impl_copy_codec!(i16, S16Codec);
+impl_copy_codec!(u16, U16Codec);
+impl_copy_codec!(i32, S32Codec);
+impl_copy_codec!(u32, U32Codec);
+impl_copy_codec!(i64, S64Codec);
+impl_copy_codec!(u64, U64Codec);
+impl_copy_codec!(f32, F32Codec);
+impl_copy_codec!(f64, F64Codec);
+impl_copy_codec!(char, Utf8Codec);
+
+impl<T> Encode<T> for u8 {
+    type Encoder = U8Codec;
+
+    #[instrument(level = "trace", skip(items))]
+    fn encode_iter_own<I>(
+        items: I,
+        enc: &mut Self::Encoder,
+        dst: &mut BytesMut,
+    ) -> Result<Option<DeferredFn<T>>, <Self::Encoder as tokio_util::codec::Encoder<Self>>::Error>
+    where
+        I: IntoIterator<Item = Self>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let items = items.into_iter();
+        dst.reserve(items.len());
+        dst.extend(items);
+        Ok(None)
+    }
+
+    #[instrument(level = "trace", skip(items))]
+    fn encode_iter_ref<'a, I>(
+        items: I,
+        enc: &mut Self::Encoder,
+        dst: &mut BytesMut,
+    ) -> Result<Option<DeferredFn<T>>, <Self::Encoder as tokio_util::codec::Encoder<&'a Self>>::Error>
+    where
+        I: IntoIterator<Item = &'a Self>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let items = items.into_iter();
+        dst.reserve(items.len());
+        dst.extend(items);
+        Ok(None)
+    }
+
+    #[instrument(level = "trace", skip(items), fields(ty = "list<u8>"))]
+    fn encode_list_own(
+        items: Vec<Self>,
+        enc: &mut Self::Encoder,
+        dst: &mut BytesMut,
+    ) -> Result<Option<DeferredFn<T>>, <Self::Encoder as tokio_util::codec::Encoder<Self>>::Error>
+    {
+        CoreVecEncoderBytes.encode(items, dst)?;
+        Ok(None)
+    }
+
+    #[instrument(level = "trace", skip(items), fields(ty = "list<u8>"))]
+    fn encode_list_ref<'a>(
+        items: &'a [Self],
+        enc: &mut Self::Encoder,
+        dst: &mut BytesMut,
+    ) -> Result<Option<DeferredFn<T>>, <Self::Encoder as tokio_util::codec::Encoder<&'a Self>>::Error>
+    where
+        Self::Encoder: tokio_util::codec::Encoder<&'a Self>,
+    {
+        CoreVecEncoderBytes.encode(items, dst)?;
+        Ok(None)
+    }
+}
+
+impl<'b, T> Encode<T> for &'b u8 {
+    type Encoder = U8Codec;
+
+    #[instrument(level = "trace", skip(items))]
+    fn encode_iter_own<I>(
+        items: I,
+        enc: &mut Self::Encoder,
+        dst: &mut BytesMut,
+    ) -> Result<Option<DeferredFn<T>>, <Self::Encoder as tokio_util::codec::Encoder<Self>>::Error>
+    where
+        I: IntoIterator<Item = Self>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let items = items.into_iter();
+        dst.reserve(items.len());
+        dst.extend(items);
+        Ok(None)
+    }
+
+    #[instrument(level = "trace", skip(items))]
+    fn encode_iter_ref<'a, I>(
         items: I,
         enc: &mut Self::Encoder,
         dst: &mut BytesMut,
@@ -959,58 +1509,365 @@ impl<'b, T> Encode<T> for &'b u8 {
 
 #[derive(Debug, Default)]
 #[repr(transparent)]
-pub struct ListDecoderU8(CoreVecDecoderBytes);
+pub struct ListDecoderU8(LimitedListDecoder<CoreVecDecoderBytes>);
+
+impl tokio_util::codec::Decoder for ListDecoderU8 {
+    type Item = Vec<u8>;
+    type Error = <CoreVecDecoderBytes as tokio_util::codec::Decoder>::Error;
+
+    #[instrument(level = "trace", skip(self), fields(ty = "list<u8>"))]
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(buf) = self.0.decode(src)? else {
+            return Ok(None);
+        };
+        Ok(Some(buf.into()))
+    }
+}
+
+impl<R> Decode<R> for u8 {
+    type Decoder = U8Codec;
+    type ListDecoder = ListDecoderU8;
+}
+
+impl<W> Encode<W> for &str {
+    type Encoder = CoreNameEncoder;
+}
+
+impl<W> Encode<W> for &&str {
+    type Encoder = CoreNameEncoder;
+}
+
+impl<W> Encode<W> for String {
+    type Encoder = CoreNameEncoder;
+}
+
+impl<W> Encode<W> for &String {
+    type Encoder = CoreNameEncoder;
+}
+
+impl<R> Decode<R> for String {
+    type Decoder = LimitedStringDecoder<CoreNameDecoder>;
+    type ListDecoder = LimitedListDecoder<CoreVecDecoder<Self::Decoder>>;
+}
+
+impl<W> Encode<W> for Bytes {
+    type Encoder = CoreVecEncoderBytes;
+}
+
+impl<W> Encode<W> for &Bytes {
+    type Encoder = CoreVecEncoderBytes;
+}
+
+impl<R> Decode<R> for Bytes {
+    type Decoder = LimitedListDecoder<CoreVecDecoderBytes>;
+    type ListDecoder = LimitedListDecoder<CoreVecDecoder<Self::Decoder>>;
+}
+
+/// UTF-8 string backed by [Bytes], which can be decoded without copying
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct Utf8Bytes(Bytes);
+
+impl Utf8Bytes {
+    /// Creates [Utf8Bytes] from a static string slice without copying
+    #[must_use]
+    pub const fn from_static(s: &'static str) -> Self {
+        Self(Bytes::from_static(s.as_bytes()))
+    }
+
+    /// Returns the string slice contained in this [Utf8Bytes]
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        // SAFETY: the contents are validated to be UTF-8 on construction
+        unsafe { core::str::from_utf8_unchecked(&self.0) }
+    }
+
+    /// Returns the underlying [Bytes]
+    #[must_use]
+    pub fn into_bytes(self) -> Bytes {
+        self.0
+    }
+}
+
+impl core::ops::Deref for Utf8Bytes {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for Utf8Bytes {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<[u8]> for Utf8Bytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl core::borrow::Borrow<str> for Utf8Bytes {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl core::fmt::Debug for Utf8Bytes {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl core::fmt::Display for Utf8Bytes {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl PartialEq<str> for Utf8Bytes {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Utf8Bytes {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialEq<String> for Utf8Bytes {
+    fn eq(&self, other: &String) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl From<String> for Utf8Bytes {
+    fn from(s: String) -> Self {
+        Self(s.into())
+    }
+}
+
+impl From<&'static str> for Utf8Bytes {
+    fn from(s: &'static str) -> Self {
+        Self::from_static(s)
+    }
+}
+
+impl From<Utf8Bytes> for Bytes {
+    fn from(s: Utf8Bytes) -> Self {
+        s.0
+    }
+}
+
+impl From<Utf8Bytes> for String {
+    fn from(s: Utf8Bytes) -> Self {
+        s.as_str().into()
+    }
+}
+
+impl TryFrom<Bytes> for Utf8Bytes {
+    type Error = core::str::Utf8Error;
+
+    fn try_from(buf: Bytes) -> Result<Self, Self::Error> {
+        core::str::from_utf8(&buf)?;
+        Ok(Self(buf))
+    }
+}
+
+/// Codec for [Utf8Bytes], which decodes `string` values without copying
+#[derive(Debug, Default)]
+#[repr(transparent)]
+pub struct Utf8BytesCodec(LimitedStringDecoder<CoreVecDecoderBytes>);
 
-impl tokio_util::codec::Decoder for ListDecoderU8 {
-    type Item = Vec<u8>;
-    type Error = <CoreVecDecoderBytes as tokio_util::codec::Decoder>::Error;
+impl_deferred_sync!(Utf8BytesCodec);
+impl_deferred_sync!(CoreVecDecoder<Utf8BytesCodec>);
 
-    #[instrument(level = "trace", skip(self), fields(ty = "list<u8>"))]
+impl tokio_util::codec::Encoder<Utf8Bytes> for Utf8BytesCodec {
+    type Error = std::io::Error;
+
+    #[instrument(level = "trace", skip(self, item), fields(ty = "string"))]
+    fn encode(&mut self, item: Utf8Bytes, dst: &mut BytesMut) -> std::io::Result<()> {
+        CoreVecEncoderBytes.encode(item.0, dst)
+    }
+}
+
+impl tokio_util::codec::Encoder<&Utf8Bytes> for Utf8BytesCodec {
+    type Error = std::io::Error;
+
+    #[instrument(level = "trace", skip(self, item), fields(ty = "string"))]
+    fn encode(&mut self, item: &Utf8Bytes, dst: &mut BytesMut) -> std::io::Result<()> {
+        CoreVecEncoderBytes.encode(&item.0, dst)
+    }
+}
+
+impl tokio_util::codec::Decoder for Utf8BytesCodec {
+    type Item = Utf8Bytes;
+    type Error = std::io::Error;
+
+    #[instrument(level = "trace", skip(self), fields(ty = "string"))]
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         let Some(buf) = self.0.decode(src)? else {
             return Ok(None);
         };
-        Ok(Some(buf.into()))
+        let s = Utf8Bytes::try_from(buf)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        Ok(Some(s))
     }
 }
 
-impl<R> Decode<R> for u8 {
-    type Decoder = U8Codec;
-    type ListDecoder = ListDecoderU8;
+impl<W> Encode<W> for Utf8Bytes {
+    type Encoder = Utf8BytesCodec;
 }
 
-impl<W> Encode<W> for &str {
-    type Encoder = CoreNameEncoder;
+impl<W> Encode<W> for &Utf8Bytes {
+    type Encoder = Utf8BytesCodec;
 }
 
-impl<W> Encode<W> for &&str {
-    type Encoder = CoreNameEncoder;
+impl<R> Decode<R> for Utf8Bytes {
+    type Decoder = Utf8BytesCodec;
+    type ListDecoder = LimitedListDecoder<CoreVecDecoder<Self::Decoder>>;
 }
 
-impl<W> Encode<W> for String {
-    type Encoder = CoreNameEncoder;
+/// Encoder of values behind a pointer, like [Box] or [Arc], using `T`
+#[derive(Debug, Default)]
+pub struct DerefEncoder<T>(T);
+
+impl<T, W> Deferred<W> for DerefEncoder<T>
+where
+    T: Deferred<W>,
+{
+    fn take_deferred(&mut self) -> Option<DeferredFn<W>> {
+        self.0.take_deferred()
+    }
 }
 
-impl<W> Encode<W> for &String {
-    type Encoder = CoreNameEncoder;
+impl<T, C> tokio_util::codec::Encoder<Box<T>> for DerefEncoder<C>
+where
+    C: tokio_util::codec::Encoder<T>,
+{
+    type Error = C::Error;
+
+    fn encode(&mut self, item: Box<T>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.0.encode(*item, dst)
+    }
 }
 
-impl<R> Decode<R> for String {
-    type Decoder = CoreNameDecoder;
-    type ListDecoder = CoreVecDecoder<Self::Decoder>;
+impl<'a, T, C> tokio_util::codec::Encoder<&'a Box<T>> for DerefEncoder<C>
+where
+    C: tokio_util::codec::Encoder<&'a T>,
+{
+    type Error = C::Error;
+
+    fn encode(&mut self, item: &'a Box<T>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.0.encode(item, dst)
+    }
 }
 
-impl<W> Encode<W> for Bytes {
-    type Encoder = CoreVecEncoderBytes;
+impl<T, C> tokio_util::codec::Encoder<Arc<T>> for DerefEncoder<C>
+where
+    C: for<'a> tokio_util::codec::Encoder<&'a T, Error = std::io::Error>,
+{
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: Arc<T>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.0.encode(&item, dst)
+    }
 }
 
-impl<W> Encode<W> for &Bytes {
-    type Encoder = CoreVecEncoderBytes;
+impl<'a, T, C> tokio_util::codec::Encoder<&'a Arc<T>> for DerefEncoder<C>
+where
+    C: tokio_util::codec::Encoder<&'a T>,
+{
+    type Error = C::Error;
+
+    fn encode(&mut self, item: &'a Arc<T>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.0.encode(item, dst)
+    }
 }
 
-impl<R> Decode<R> for Bytes {
-    type Decoder = CoreVecDecoderBytes;
-    type ListDecoder = CoreVecDecoder<Self::Decoder>;
+impl tokio_util::codec::Encoder<Cow<'_, str>> for DerefEncoder<CoreNameEncoder> {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: Cow<'_, str>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.0.encode(item.as_ref(), dst)
+    }
+}
+
+impl tokio_util::codec::Encoder<&Cow<'_, str>> for DerefEncoder<CoreNameEncoder> {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: &Cow<'_, str>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.0.encode(item.as_ref(), dst)
+    }
+}
+
+impl<T, W> Encode<W> for Box<T>
+where
+    T: Encode<W>,
+{
+    type Encoder = DerefEncoder<T::Encoder>;
+}
+
+impl<'a, T, W> Encode<W> for &'a Box<T>
+where
+    T: Encode<W>,
+    T::Encoder: tokio_util::codec::Encoder<&'a T>,
+{
+    type Encoder = DerefEncoder<T::Encoder>;
+}
+
+impl<T, W> Encode<W> for Arc<T>
+where
+    T: Encode<W>,
+    T::Encoder: for<'a> tokio_util::codec::Encoder<&'a T, Error = std::io::Error>,
+{
+    type Encoder = DerefEncoder<T::Encoder>;
+}
+
+impl<'a, T, W> Encode<W> for &'a Arc<T>
+where
+    T: Encode<W>,
+    T::Encoder: tokio_util::codec::Encoder<&'a T>,
+{
+    type Encoder = DerefEncoder<T::Encoder>;
+}
+
+impl<W> Encode<W> for Cow<'_, str> {
+    type Encoder = DerefEncoder<CoreNameEncoder>;
+}
+
+impl<W> Encode<W> for &Cow<'_, str> {
+    type Encoder = DerefEncoder<CoreNameEncoder>;
+}
+
+impl<T, R> Decode<R> for Box<T>
+where
+    T: Decode<R> + 'static,
+    R: crate::Index<R> + Send + Sync + 'static,
+{
+    type Decoder = ConvertDecoder<T::Decoder, Self>;
+    type ListDecoder = ListDecoder<Self::Decoder, R>;
+}
+
+impl<T, R> Decode<R> for Arc<T>
+where
+    T: Decode<R> + 'static,
+    R: crate::Index<R> + Send + Sync + 'static,
+{
+    type Decoder = ConvertDecoder<T::Decoder, Self>;
+    type ListDecoder = ListDecoder<Self::Decoder, R>;
+}
+
+impl<R> Decode<R> for Cow<'static, str>
+where
+    R: crate::Index<R> + Send + Sync + 'static,
+{
+    type Decoder = ConvertDecoder<LimitedStringDecoder<CoreNameDecoder>, Self>;
+    type ListDecoder = ListDecoder<Self::Decoder, R>;
 }
 
 #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
@@ -1072,14 +1929,14 @@ impl<T: ?Sized, W> Encode<W> for &ResourceBorrow<T> {
 #[derive(Debug)]
 #[repr(transparent)]
 pub struct ResourceBorrowDecoder<T: ?Sized> {
-    dec: CoreVecDecoderBytes,
+    dec: LimitedListDecoder<CoreVecDecoderBytes>,
     _ty: PhantomData<T>,
 }
 
 impl<T: ?Sized> Default for ResourceBorrowDecoder<T> {
     fn default() -> Self {
         Self {
-            dec: CoreVecDecoderBytes::default(),
+            dec: LimitedListDecoder::default(),
             _ty: PhantomData,
         }
     }
@@ -1099,7 +1956,7 @@ impl<R, T: ?Sized> Deferred<R> for CoreVecDecoder<ResourceBorrowDecoder<T>> {
 
 impl<R, T: ?Sized + Send + Sync + 'static> Decode<R> for ResourceBorrow<T> {
     type Decoder = ResourceBorrowDecoder<T>;
-    type ListDecoder = CoreVecDecoder<Self::Decoder>;
+    type ListDecoder = LimitedListDecoder<CoreVecDecoder<Self::Decoder>>;
 }
 
 impl<T: ?Sized> tokio_util::codec::Decoder for ResourceBorrowDecoder<T> {
@@ -1116,14 +1973,14 @@ impl<T: ?Sized> tokio_util::codec::Decoder for ResourceBorrowDecoder<T> {
 #[derive(Debug)]
 #[repr(transparent)]
 pub struct ResourceOwnDecoder<T: ?Sized> {
-    dec: CoreVecDecoderBytes,
+    dec: LimitedListDecoder<CoreVecDecoderBytes>,
     _ty: PhantomData<T>,
 }
 
 impl<T: ?Sized> Default for ResourceOwnDecoder<T> {
     fn default() -> Self {
         Self {
-            dec: CoreVecDecoderBytes::default(),
+            dec: LimitedListDecoder::default(),
             _ty: PhantomData,
         }
     }
@@ -1143,7 +2000,7 @@ impl<R, T: ?Sized> Deferred<R> for CoreVecDecoder<ResourceOwnDecoder<T>> {
 
 impl<R, T: ?Sized + Send + Sync + 'static> Decode<R> for ResourceOwn<T> {
     type Decoder = ResourceOwnDecoder<T>;
-    type ListDecoder = CoreVecDecoder<Self::Decoder>;
+    type ListDecoder = LimitedListDecoder<CoreVecDecoder<Self::Decoder>>;
 }
 
 impl<T: ?Sized> tokio_util::codec::Decoder for ResourceOwnDecoder<T> {
@@ -1429,7 +2286,7 @@ impl<T, W, Fut> tokio_util::codec::Encoder<Fut> for FutureEncoder<W>
 where
     T: Encode<W>,
     W: AsyncWrite + crate::Index<W> + Send + Sync + Unpin + 'static,
-    Fut: Future<Output = T> + Send + Sync + 'static,
+    Fut: Future<Output = std::io::Result<T>> + Send + Sync + 'static,
     std::io::Error: From<<T::Encoder as tokio_util::codec::Encoder<T>>::Error>,
 {
     type Error = std::io::Error;
@@ -1442,11 +2299,13 @@ where
         self.deferred = Some(Box::new(|w, mut path| {
             Box::pin(async move {
                 let mut root = w.index(&path).map_err(std::io::Error::other)?;
-                let item = item.await;
+                let item = item.await?;
                 let mut enc = T::Encoder::default();
                 let mut buf = BytesMut::default();
                 enc.encode(item, &mut buf)?;
                 root.write_all(&buf).await?;
+                // indexed streams are not shut down, flush buffering writers like `Compressed`
+                root.flush().await?;
                 if let Some(f) = enc.take_deferred() {
                     path.push(0);
                     f(w, path).await?;
@@ -1458,6 +2317,154 @@ where
     }
 }
 
+impl<W> Deferred<W> for FutureEncoder<W> {
+    fn take_deferred(&mut self) -> Option<DeferredFn<W>> {
+        self.deferred.take()
+    }
+}
+
+impl<T, W> Encode<W> for Pin<Box<dyn Future<Output = std::io::Result<T>> + Send + Sync>>
+where
+    T: Encode<W> + 'static,
+    W: AsyncWrite + crate::Index<W> + Send + Sync + Unpin + 'static,
+    std::io::Error: From<<T::Encoder as tokio_util::codec::Encoder<T>>::Error>,
+{
+    type Encoder = FutureEncoder<W>;
+}
+
+pub struct FutureDecoder<T, R> {
+    dec: Option<T>,
+    deferred: Option<DeferredFn<R>>,
+}
+
+impl<T, R> Default for FutureDecoder<T, R> {
+    fn default() -> Self {
+        Self {
+            dec: None,
+            deferred: None,
+        }
+    }
+}
+
+impl<T, R> Deferred<R> for FutureDecoder<T, R> {
+    fn take_deferred(&mut self) -> Option<DeferredFn<R>> {
+        self.deferred.take()
+    }
+}
+
+#[instrument(level = "trace", skip(r, tx), ret)]
+async fn handle_deferred_future<C, T, R>(
+    r: Arc<R>,
+    mut path: Vec<usize>,
+    tx: oneshot::Sender<std::io::Result<T>>,
+) -> std::io::Result<()>
+where
+    C: tokio_util::codec::Decoder<Item = T> + Deferred<R> + Send + Sync + Default,
+    T: Send + Sync + 'static,
+    R: AsyncRead + crate::Index<R> + Send + Sync + Unpin + 'static,
+    std::io::Error: From<C::Error>,
+{
+    let res = async {
+        check_depth(&path)?;
+        let indexed = r.index(&path).map_err(std::io::Error::other)?;
+        let mut framed = FramedRead::new(indexed, InvocationDecoder(C::default()));
+        trace!("receiving future value");
+        let Some(v) = framed.next().await.transpose()? else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "future value missing",
+            ));
+        };
+        Ok((v, framed.decoder_mut().take_deferred()))
+    }
+    .await;
+    let (v, deferred) = match res {
+        Ok(v) => v,
+        Err(err) => {
+            // the original error is returned by the future, the caller gets a copy
+            let copy = std::io::Error::new(err.kind(), err.to_string());
+            _ = tx.send(Err(err));
+            return Err(copy);
+        }
+    };
+    tx.send(Ok(v))
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "receiver closed"))?;
+    if let Some(f) = deferred {
+        path.push(0);
+        f(r, path).await?;
+    }
+    Ok(())
+}
+
+impl<C, T, R> tokio_util::codec::Decoder for FutureDecoder<C, R>
+where
+    C: tokio_util::codec::Decoder<Item = T> + Deferred<R> + Send + Sync + Default,
+    T: Send + Sync + 'static,
+    R: AsyncRead + crate::Index<R> + Send + Sync + Unpin + 'static,
+    std::io::Error: From<C::Error>,
+{
+    type Item = Pin<Box<dyn Future<Output = std::io::Result<T>> + Send + Sync>>;
+    type Error = std::io::Error;
+
+    #[instrument(level = "trace", skip(self), fields(ty = "future"))]
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let dec = if let Some(dec) = &mut self.dec {
+            dec
+        } else {
+            let Some(status) = src.first().copied() else {
+                return Ok(None);
+            };
+            src.advance(1);
+            match status {
+                0x00 => {
+                    // future is pending, the value will be received on a nested stream.
+                    // If that fails, the error is returned both by the deferred function and
+                    // the future
+                    let (tx, rx) = oneshot::channel();
+                    self.deferred = Some(Box::new(|r, path| {
+                        Box::pin(
+                            async move { handle_deferred_future::<C, T, R>(r, path, tx).await },
+                        )
+                    }));
+                    return Ok(Some(Box::pin(async move {
+                        rx.await.unwrap_or_else(|_| {
+                            Err(std::io::Error::new(
+                                std::io::ErrorKind::BrokenPipe,
+                                "future value was not received",
+                            ))
+                        })
+                    })));
+                }
+                0x01 => self.dec.insert(C::default()),
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("invalid future status byte `{status}`"),
+                    ))
+                }
+            }
+        };
+        let Some(v) = dec.decode(src)? else {
+            return Ok(None);
+        };
+        if let Some(mut dec) = self.dec.take() {
+            self.deferred = dec.take_deferred();
+        }
+        Ok(Some(Box::pin(future::ready(Ok(v)))))
+    }
+}
+
+impl<T, R> Decode<R> for Pin<Box<dyn Future<Output = std::io::Result<T>> + Send + Sync>>
+where
+    T: Decode<R> + Send + Sync + 'static,
+    T::Decoder: Sync,
+    R: AsyncRead + crate::Index<R> + Send + Sync + Unpin + 'static,
+    std::io::Error: From<<T::Decoder as tokio_util::codec::Decoder>::Error>,
+{
+    type Decoder = FutureDecoder<T::Decoder, R>;
+    type ListDecoder = ListDecoder<Self::Decoder, R>;
+}
+
 pub struct StreamEncoder<W> {
     deferred: Option<DeferredFn<W>>,
 }
@@ -1502,6 +2509,7 @@ where
                     enc.encode(item, &mut buf)?;
                     trace!(i, buf = format!("{buf:02x?}"), "writing stream item");
                     root.write_all(&buf).await?;
+                    root.flush().await?;
                     buf.clear();
                     if let Some(f) = enc.take_deferred() {
                         path.push(i);
@@ -1512,7 +2520,8 @@ where
                     }
                 }
                 trace!("writing stream end");
-                root.write_all(&[0x00]).await
+                root.write_all(&[0x00]).await?;
+                root.flush().await
             })
         }));
         Ok(())
@@ -1529,14 +2538,14 @@ where
 }
 
 pub struct StreamDecoder<T, R> {
-    dec: T,
+    dec: ChunkDecoder<T>,
     deferred: Option<DeferredFn<R>>,
 }
 
 impl<T: Default, R> Default for StreamDecoder<T, R> {
     fn default() -> Self {
         Self {
-            dec: T::default(),
+            dec: ChunkDecoder::default(),
             deferred: None,
         }
     }
@@ -1561,8 +2570,9 @@ where
     R: AsyncRead + crate::Index<R> + Send + Sync + Unpin + 'static,
     std::io::Error: From<C::Error>,
 {
+    check_depth(&path)?;
     let indexed = r.index(&path).map_err(std::io::Error::other)?;
-    let mut framed = FramedRead::new(indexed, C::default());
+    let mut framed = FramedRead::new(indexed, InvocationDecoder(ChunkDecoder::<C>::default()));
     let mut deferred = FuturesUnordered::default();
     let mut offset = 0usize;
     loop {
@@ -1654,6 +2664,7 @@ mod tests {
     use anyhow::bail;
 
     use super::*;
+    use crate::{Error, Limits};
 
     struct NoopStream;
 
@@ -1663,6 +2674,44 @@ mod tests {
         }
     }
 
+    #[derive(Default)]
+    struct WriteStream {
+        buf: Vec<u8>,
+        writes: usize,
+    }
+
+    impl crate::Index<Self> for WriteStream {
+        fn index(&self, path: &[usize]) -> anyhow::Result<Self> {
+            panic!("index should not be called with path {path:?}")
+        }
+    }
+
+    impl AsyncWrite for WriteStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut core::task::Context<'_>,
+            buf: &[u8],
+        ) -> core::task::Poll<std::io::Result<usize>> {
+            self.buf.extend_from_slice(buf);
+            self.writes += 1;
+            core::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut core::task::Context<'_>,
+        ) -> core::task::Poll<std::io::Result<()>> {
+            core::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _cx: &mut core::task::Context<'_>,
+        ) -> core::task::Poll<std::io::Result<()>> {
+            core::task::Poll::Ready(Ok(()))
+        }
+    }
+
     #[test_log::test(tokio::test)]
     async fn codec() -> anyhow::Result<()> {
         let mut buf = BytesMut::new();
@@ -1674,4 +2723,156 @@ mod tests {
         assert_eq!(buf.as_ref(), b"\x42\x42");
         Ok(())
     }
+
+    fn encode<T: Encode<NoopStream>>(v: T) -> anyhow::Result<BytesMut>
+    where
+        anyhow::Error: From<<T::Encoder as tokio_util::codec::Encoder<T>>::Error>,
+    {
+        let mut buf = BytesMut::new();
+        let mut enc = T::Encoder::default();
+        enc.encode(v, &mut buf)?;
+        if enc.take_deferred().is_some() {
+            bail!("no deferred write should have been returned");
+        }
+        Ok(buf)
+    }
+
+    fn decode<T: Decode<NoopStream>>(mut buf: BytesMut) -> anyhow::Result<T>
+    where
+        anyhow::Error: From<<T::Decoder as tokio_util::codec::Decoder>::Error>,
+    {
+        let mut dec = T::Decoder::default();
+        let Some(v) = tokio_util::codec::Decoder::decode(&mut dec, &mut buf)? else {
+            bail!("value incomplete")
+        };
+        if dec.take_deferred().is_some() {
+            bail!("no deferred read should have been returned");
+        }
+        assert!(buf.is_empty());
+        Ok(v)
+    }
+
+    #[test]
+    fn containers() -> anyhow::Result<()> {
+        let pairs = vec![(1u32, "foo".to_string()), (2, "bar".to_string())];
+        let map = BTreeMap::from_iter(pairs.clone());
+        assert_eq!(encode(&map)?, encode(&pairs)?);
+        assert_eq!(encode(map.clone())?, encode(&pairs)?);
+        assert_eq!(decode::<BTreeMap<u32, String>>(encode(&pairs)?)?, map);
+        let map = HashMap::from_iter(pairs.clone());
+        assert_eq!(decode::<HashMap<u32, String>>(encode(&map)?)?, map);
+
+        let items = vec![1u32, 2, 3];
+        let set = BTreeSet::from_iter(items.clone());
+        assert_eq!(encode(set)?, encode(&items)?);
+        let set = HashSet::from_iter(items.clone());
+        assert_eq!(decode::<HashSet<u32>>(encode(&set)?)?, set);
+        let deque = VecDeque::from_iter(items.clone());
+        assert_eq!(encode(&deque)?, encode(&items)?);
+        assert_eq!(decode::<VecDeque<u32>>(encode(deque)?)?, items);
+
+        assert_eq!(encode([1u32, 2, 3])?, encode(&items)?);
+        assert_eq!(encode([1u8, 2, 3])?, encode(vec![1u8, 2, 3])?);
+        assert_eq!(decode::<[u32; 3]>(encode(&items)?)?, [1, 2, 3]);
+        assert!(decode::<[u32; 2]>(encode(&items)?).is_err());
+
+        assert_eq!(encode(Box::new(42u32))?.as_ref(), b"\x2a");
+        assert_eq!(decode::<Box<u32>>(encode(Box::new(42u32))?)?, Box::new(42));
+        let s = Arc::new("foo".to_string());
+        assert_eq!(encode(Arc::clone(&s))?, encode("foo")?);
+        assert_eq!(decode::<Arc<String>>(encode(&s)?)?, s);
+        assert_eq!(encode(Cow::Borrowed("foo"))?, encode("foo")?);
+        assert_eq!(decode::<Cow<'static, str>>(encode("foo")?)?, "foo");
+
+        assert_eq!(encode('ą')?.as_ref(), "ą".as_bytes());
+        assert_eq!(decode::<char>(encode('ą')?)?, 'ą');
+        Ok(())
+    }
+
+    #[test]
+    fn list_of_options() -> anyhow::Result<()> {
+        let v = vec![Some(1u32), None, Some(3)];
+        assert_eq!(decode::<Vec<Option<u32>>>(encode(v.clone())?)?, v);
+        let v: Vec<Result<u32, String>> = vec![Ok(1), Err("foo".to_string())];
+        assert_eq!(decode::<Vec<Result<u32, String>>>(encode(v.clone())?)?, v);
+        Ok(())
+    }
+
+    #[test]
+    fn zero_copy() -> anyhow::Result<()> {
+        let buf = encode("foo")?;
+        let s = Utf8Bytes::from_static("foo");
+        assert_eq!(encode(&s)?, buf);
+        assert_eq!(encode(s)?, buf);
+        assert_eq!(encode(Utf8Bytes::from("foo".to_string()))?, buf);
+        let ptr = buf[1..].as_ptr();
+        let s = decode::<Utf8Bytes>(buf)?;
+        assert_eq!(s, "foo");
+        assert_eq!(s.as_ptr(), ptr);
+
+        let buf = encode(Bytes::from_static(b"bar"))?;
+        let ptr = buf[1..].as_ptr();
+        assert_eq!(decode::<Bytes>(buf)?.as_ptr(), ptr);
+
+        let mut dec = <Utf8Bytes as Decode<NoopStream>>::Decoder::default();
+        let err =
+            tokio_util::codec::Decoder::decode(&mut dec, &mut BytesMut::from(&b"\x01\xff"[..]))
+                .expect_err("decoding invalid UTF-8 should fail");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn list_limits() {
+        fn assert_limited<T: core::fmt::Debug>(res: anyhow::Result<T>) {
+            let err = res
+                .expect_err("decoding long list should fail")
+                .downcast::<std::io::Error>()
+                .expect("error should be an I/O error");
+            assert!(
+                matches!(
+                    Error::from(err),
+                    Error::LimitExceeded {
+                        limit: "list length",
+                        len: 3,
+                        max: 2,
+                    }
+                ),
+                "unexpected error"
+            );
+        }
+
+        Limits {
+            max_list_len: 2,
+            ..Limits::default()
+        }
+        .scope(async {
+            let buf = BytesMut::from(&b"\x03\x01a\x01b\x01c"[..]);
+            assert_limited(decode::<Vec<Utf8Bytes>>(buf.clone()));
+            assert_limited(decode::<Vec<ResourceBorrow<()>>>(buf.clone()));
+            assert_limited(decode::<Vec<ResourceOwn<()>>>(buf));
+        })
+        .await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn list_write() -> anyhow::Result<()> {
+        let items = vec![0x42u32; 10_000];
+        let mut w = WriteStream::default();
+        let deferred = write_list(&mut w, 10_000, stream::iter(items.clone())).await?;
+        assert!(deferred.is_none());
+        assert_eq!(w.buf, encode(&items)?);
+        assert_eq!(w.writes, 2);
+
+        let Err(err) = write_list(&mut WriteStream::default(), 2, stream::iter([1u8, 2, 3])).await
+        else {
+            panic!("writing too many elements should fail")
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let Err(err) = write_list(&mut WriteStream::default(), 2, stream::iter([1u8])).await else {
+            panic!("writing too few elements should fail")
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        Ok(())
+    }
 }
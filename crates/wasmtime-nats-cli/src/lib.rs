@@ -14,9 +14,8 @@ use wrpc_transport::Invoke;
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// NATS address to use
-    #[arg(short, long, default_value = wrpc_cli::nats::DEFAULT_URL)]
-    nats: String,
+    #[command(flatten)]
+    nats: wrpc_cli::nats::ConnectOptions,
 
     /// Prefix to listen on
     prefix: String,
@@ -53,16 +52,14 @@ impl<C: Invoke> WasiView for Ctx<C> {
 
 #[instrument(level = "trace", ret)]
 pub async fn run() -> anyhow::Result<()> {
-    wrpc_cli::tracing::init();
+    let _tracing = wrpc_cli::tracing::init_with_otlp().context("failed to initialize tracing")?;
 
     let Args {
         nats,
         prefix,
         workload,
     } = Args::parse();
-    let nats = wrpc_cli::nats::connect(nats)
-        .await
-        .context("failed to connect to NATS")?;
+    let nats = nats.connect().await.context("failed to connect to NATS")?;
 
     let engine = wasmtime::Engine::new(
         wasmtime::Config::new()
@@ -192,7 +189,13 @@ pub async fn run() -> anyhow::Result<()> {
                 continue;
             }
         };
-        if let Err(err) = link_instance(&engine, &mut linker, instance, instance_name, None) {
+        if let Err(err) = link_instance(
+            &engine,
+            &mut linker,
+            instance,
+            instance_name,
+            Default::default(),
+        ) {
             error!(?err, "failed to polyfill instance");
         }
     }
@@ -92,6 +92,9 @@ impl Parse for Config {
                     Opt::GenerateUnusedTypes(enable) => {
                         opts.generate_unused_types = enable.value();
                     }
+                    Opt::ZeroCopy(enable) => {
+                        opts.zero_copy = enable.value();
+                    }
                     Opt::AnyhowPath(path) => {
                         opts.anyhow_path = Some(path.into_token_stream().to_string());
                     }
@@ -223,6 +226,7 @@ mod kw {
     syn::custom_keyword!(additional_derives);
     syn::custom_keyword!(with);
     syn::custom_keyword!(generate_unused_types);
+    syn::custom_keyword!(zero_copy);
     syn::custom_keyword!(anyhow_path);
     syn::custom_keyword!(bitflags_path);
     syn::custom_keyword!(bytes_path);
@@ -242,6 +246,7 @@ enum Opt {
     AdditionalDerives(Vec<syn::Path>),
     With(HashMap<String, String>),
     GenerateUnusedTypes(syn::LitBool),
+    ZeroCopy(syn::LitBool),
     AnyhowPath(syn::Path),
     BitflagsPath(syn::Path),
     BytesPath(syn::Path),
@@ -293,6 +298,10 @@ impl Parse for Opt {
             input.parse::<kw::generate_unused_types>()?;
             input.parse::<Token![:]>()?;
             Ok(Opt::GenerateUnusedTypes(input.parse()?))
+        } else if l.peek(kw::zero_copy) {
+            input.parse::<kw::zero_copy>()?;
+            input.parse::<Token![:]>()?;
+            Ok(Opt::ZeroCopy(input.parse()?))
         } else if l.peek(kw::anyhow_path) {
             input.parse::<kw::anyhow_path>()?;
             input.parse::<Token![:]>()?;
@@ -295,7 +295,7 @@ pub async fn serve_interface<T: {wrpc_transport}::Serve, U>(
                 r#"
                         ).await {{
                             Ok(returns) => {{
-                                match tx("#,
+                                match tx(Ok("#,
             );
             if func.results.len() == 1 {
                 // wrap single-element returns into a tuple for correct indexing
@@ -306,7 +306,7 @@ pub async fn serve_interface<T: {wrpc_transport}::Serve, U>(
             uwrite!(
                 self.src,
                 r#"
-                                ).await {{
+                                )).await {{
                                     Ok(()) => continue,
                                     Err(err) => {{
                                         if let Some(rx) = rx {{
@@ -321,6 +321,9 @@ pub async fn serve_interface<T: {wrpc_transport}::Serve, U>(
                                     rx.abort();
                                 }}
                                 {tracing}::warn!(?err, "failed to serve `{instance}.{wit_name}` invocation");
+                                if let Err(err) = tx(Err(format!("{{err:#}}"))).await {{
+                                    {tracing}::warn!(?err, "failed to send error");
+                                }}
                             }}
                         }}
                     }},
@@ -649,7 +652,13 @@ pub async fn serve_interface<T: {wrpc_transport}::Serve, U>(
             Type::F64 => self.push_str("f64"),
             Type::Char => self.push_str("char"),
             Type::String => {
-                if owned {
+                if owned && self.gen.opts.zero_copy {
+                    uwrite!(
+                        self.src,
+                        "{wrpc_transport}::Utf8Bytes",
+                        wrpc_transport = self.gen.wrpc_transport_path()
+                    );
+                } else if owned {
                     self.push_str("String");
                 } else {
                     self.push_str("&str");
@@ -746,7 +755,9 @@ pub async fn serve_interface<T: {wrpc_transport}::Serve, U>(
     }
 
     fn print_list(&mut self, ty: &Type, owned: bool, submodule: bool) {
-        if owned {
+        if owned && self.gen.opts.zero_copy && matches!(ty, Type::U8) {
+            uwrite!(self.src, "{bytes}::Bytes", bytes = self.gen.bytes_path());
+        } else if owned {
             self.push_str("Vec<");
             self.print_ty(ty, true, submodule);
             self.push_str(">");
@@ -1363,7 +1374,7 @@ where
     R: Send + Sync + {wrpc_transport}::Index<R> + 'static,
 {{
     type Decoder = {mod_name}::Codec;
-    type ListDecoder = {wrpc_transport}::SyncCodec<{wasm_tokio}::CoreVecDecoder<Self::Decoder>>;
+    type ListDecoder = {wrpc_transport}::SyncCodec<{wrpc_transport}::LimitedListDecoder<{wasm_tokio}::CoreVecDecoder<Self::Decoder>>>;
 }}
 
 use {bitflags} as __{mod_name}__bitflags;
@@ -1525,7 +1536,7 @@ impl<W> {wrpc_transport}::Encode<W> for &self::{name} {{
 
 impl<R> {wrpc_transport}::Decode<R> for self::{name} {{
     type Decoder = {mod_name}::Codec;
-    type ListDecoder = {wrpc_transport}::SyncCodec<{wasm_tokio}::CoreVecDecoder<Self::Decoder>>;
+    type ListDecoder = {wrpc_transport}::SyncCodec<{wrpc_transport}::LimitedListDecoder<{wasm_tokio}::CoreVecDecoder<Self::Decoder>>>;
 }}
 
 mod {mod_name} {{
@@ -1998,7 +2009,7 @@ impl<W> {wrpc_transport}::Encode<W> for &self::{name} {{
 
 impl<R> {wrpc_transport}::Decode<R> for self::{name} {{
     type Decoder = {mod_name}::Codec;
-    type ListDecoder = {wrpc_transport}::SyncCodec<{wasm_tokio}::CoreVecDecoder<Self::Decoder>>;
+    type ListDecoder = {wrpc_transport}::SyncCodec<{wrpc_transport}::LimitedListDecoder<{wasm_tokio}::CoreVecDecoder<Self::Decoder>>>;
 }}
 
 mod {mod_name} {{
@@ -144,6 +144,11 @@ pub struct Opts {
     /// Whether to generate unused structures, not generated by default (false)
     #[cfg_attr(feature = "clap", arg(long))]
     pub generate_unused_types: bool,
+
+    /// Whether to use `Bytes` for `list<u8>` and `Utf8Bytes` for `string` in owned types,
+    /// which are decoded without copying, not used by default (false)
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub zero_copy: bool,
 }
 
 impl Opts {
@@ -467,6 +467,41 @@ mod generate_unused_types {
     });
 }
 
+#[allow(unused)]
+mod zero_copy {
+    use wit_bindgen_wrpc::bytes::Bytes;
+    use wit_bindgen_wrpc::wrpc_transport::Utf8Bytes;
+
+    wit_bindgen_wrpc::generate!({
+        inline: "
+            package foo:bar;
+
+            world bindings {
+                export component;
+            }
+
+            interface component {
+                record payload {
+                    name: string,
+                    data: list<u8>,
+                    chunks: list<list<u8>>,
+                }
+
+                handle: func(p: payload) -> string;
+            }
+        ",
+        zero_copy: true,
+    });
+
+    fn payload() -> exports::foo::bar::component::Payload {
+        exports::foo::bar::component::Payload {
+            name: Utf8Bytes::from_static("foo"),
+            data: Bytes::from_static(b"bar"),
+            chunks: vec![Bytes::from_static(b"baz")],
+        }
+    }
+}
+
 #[allow(unused)]
 mod interface_export_example {
     wit_bindgen_wrpc::generate!({
@@ -778,6 +778,11 @@
 ///     // By default, they will not be generated unless they are used as input
 ///     // or return value of a function.
 ///     generate_unused_types: false,
+///
+///     // Whether to use `Bytes` for `list<u8>` and `Utf8Bytes` for `string`
+///     // in owned types. Both are decoded without copying the payload, which
+///     // avoids allocations for services handling large payloads.
+///     zero_copy: false,
 /// });
 /// ```
 ///
@@ -1,7 +1,7 @@
 use anyhow::Context as _;
 use clap::Parser;
 use tokio::signal;
-use url::Url;
+use wrpc_transport_nats::InvocationContext;
 
 mod bindings {
     wit_bindgen_wrpc::generate!();
@@ -10,9 +10,12 @@ mod bindings {
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// NATS.io URL to connect to
-    #[arg(short, long, default_value = "nats://127.0.0.1:4222")]
-    nats: Url,
+    #[command(flatten)]
+    nats: wrpc_cli::nats::ConnectOptions,
+
+    /// NATS.io queue group to join, invocations are load-balanced across members of the group
+    #[arg(short, long)]
+    group: Option<String>,
 
     /// Prefix to serve `wrpc-examples:hello/handler.hello` on
     #[arg(default_value = "rust")]
@@ -22,10 +25,8 @@ struct Args {
 #[derive(Clone, Copy)]
 struct Server;
 
-impl bindings::exports::wrpc_examples::hello::handler::Handler<Option<async_nats::HeaderMap>>
-    for Server
-{
-    async fn hello(&self, _: Option<async_nats::HeaderMap>) -> anyhow::Result<String> {
+impl bindings::exports::wrpc_examples::hello::handler::Handler<InvocationContext> for Server {
+    async fn hello(&self, _: InvocationContext) -> anyhow::Result<String> {
         Ok("hello from Rust".to_string())
     }
 }
@@ -34,20 +35,24 @@ impl bindings::exports::wrpc_examples::hello::handler::Handler<Option<async_nats
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt().init();
 
-    let Args { nats, prefix } = Args::parse();
+    let Args {
+        nats,
+        group,
+        prefix,
+    } = Args::parse();
 
-    let nats = async_nats::connect_with_options(
-        String::from(nats),
-        async_nats::ConnectOptions::new().retry_on_initial_connect(),
-    )
-    .await
-    .context("failed to connect to NATS.io server")?;
+    let nats = nats
+        .connect()
+        .await
+        .context("failed to connect to NATS.io server")?;
 
-    bindings::serve(
-        &wrpc_transport_nats::Client::new(nats, prefix),
-        Server,
-        async { signal::ctrl_c().await.expect("failed to listen for ^C") },
-    )
+    let mut wrpc = wrpc_transport_nats::Client::new(nats, prefix);
+    if let Some(group) = group {
+        wrpc = wrpc.with_queue_group(group);
+    }
+    bindings::serve(&wrpc, Server, async {
+        signal::ctrl_c().await.expect("failed to listen for ^C")
+    })
     .await
     .context("failed to invoke `wrpc-examples.hello/handler.hello`")
 }
@@ -0,0 +1,48 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::{Decoder as _, Encoder as _};
+use wrpc_fuzz::decode_chunked;
+use wrpc_transport::frame::{Decoder, Encoder, Frame};
+
+/// Decodes all frames in `buf` feeding the decoder at most `chunk` bytes at a time
+fn decode_frames(buf: &[u8], chunk: usize) -> (Vec<Frame>, Option<std::io::ErrorKind>) {
+    let mut dec = Decoder::new(32, 1 << 16);
+    let mut src = BytesMut::with_capacity(buf.len());
+    let mut frames = vec![];
+    for buf in buf.chunks(chunk.max(1)) {
+        src.extend_from_slice(buf);
+        loop {
+            match dec.decode(&mut src) {
+                Ok(Some(frame)) => frames.push(frame),
+                Ok(None) => break,
+                Err(err) => return (frames, Some(err.kind())),
+            }
+        }
+    }
+    (frames, None)
+}
+
+fuzz_target!(|input: (u8, &[u8])| {
+    let (chunk, buf) = input;
+    let whole = decode_frames(buf, buf.len());
+    let chunked = decode_frames(buf, chunk.into());
+    assert_eq!(
+        whole, chunked,
+        "decoding in chunks of {chunk} bytes diverged"
+    );
+
+    let (frames, _) = whole;
+    for frame in frames {
+        let mut buf = BytesMut::default();
+        Encoder
+            .encode(&frame, &mut buf)
+            .expect("failed to encode frame");
+        let (decoded, n) = decode_chunked(&mut Decoder::new(32, 1 << 16), &buf, chunk.into())
+            .expect("failed to decode encoded frame")
+            .expect("encoded frame is incomplete");
+        assert_eq!(n, buf.len(), "encoded frame not fully consumed");
+        assert_eq!(decoded, frame);
+    }
+});
@@ -0,0 +1,20 @@
+#![no_main]
+
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use wrpc_fuzz::{check_decode, check_stream_decode};
+
+fuzz_target!(|input: (u8, u8, &[u8])| {
+    let (ty, chunk, buf) = input;
+    let chunk = chunk.into();
+    match ty % 8 {
+        0 => check_decode::<(u8, u32, String)>(buf, chunk),
+        1 => check_decode::<Vec<Vec<u8>>>(buf, chunk),
+        2 => check_decode::<Vec<Vec<String>>>(buf, chunk),
+        3 => check_decode::<Option<Result<String, i64>>>(buf, chunk),
+        4 => check_decode::<Result<Vec<(bool, char)>, Option<u16>>>(buf, chunk),
+        5 => check_decode::<(Bytes, Vec<Option<u64>>, i8)>(buf, chunk),
+        6 => check_decode::<Vec<(String, Vec<u32>, Result<(), u8>)>>(buf, chunk),
+        _ => check_stream_decode(buf, chunk),
+    }
+});
@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use wrpc_fuzz::check_round_trip;
+
+type Value = (
+    Vec<Vec<u8>>,
+    Option<Result<String, i64>>,
+    Vec<(bool, char, u16)>,
+    Result<Vec<String>, Option<u64>>,
+    (i8, u32, Vec<Option<i32>>),
+);
+
+fuzz_target!(|input: (u8, Value)| {
+    let (chunk, v) = input;
+    check_round_trip(v, chunk.into());
+});
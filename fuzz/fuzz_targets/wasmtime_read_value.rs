@@ -0,0 +1,107 @@
+#![no_main]
+
+use core::pin::pin;
+
+use std::sync::OnceLock;
+
+use bytes::BytesMut;
+use futures::executor::block_on;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Encoder as _;
+use wasmtime::component::types::ComponentItem;
+use wasmtime::component::{Component, Type, Val};
+use wasmtime::{AsContextMut as _, Config, Engine, Store};
+use wasmtime_wasi::{ResourceTable, WasiCtx, WasiCtxBuilder, WasiView};
+use wrpc_fuzz::{limits, Conn};
+use wrpc_runtime_wasmtime::{read_value, ValEncoder};
+
+const COMPONENT: &str = r#"
+(component
+  (import "f" (func
+    (param "bool" bool)
+    (param "s8" s8)
+    (param "u16" u16)
+    (param "s32" s32)
+    (param "u64" u64)
+    (param "char" char)
+    (param "string" string)
+    (param "list" (list (list u8)))
+    (param "option" (option (tuple u8 string)))
+    (param "result" (result string (error u32)))
+    (param "empty-result" (result))
+    (param "tuple" (tuple (option s64) (list (result u8))))
+  ))
+)
+"#;
+
+struct Ctx {
+    table: ResourceTable,
+    wasi: WasiCtx,
+}
+
+impl WasiView for Ctx {
+    fn ctx(&mut self) -> &mut WasiCtx {
+        &mut self.wasi
+    }
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.table
+    }
+}
+
+/// Returns the engine and parameter types of the function imported by [`COMPONENT`]
+fn types() -> &'static (Engine, Vec<Type>) {
+    static TYPES: OnceLock<(Engine, Vec<Type>)> = OnceLock::new();
+    TYPES.get_or_init(|| {
+        let mut config = Config::new();
+        config.wasm_component_model(true);
+        let engine = Engine::new(&config).expect("failed to create engine");
+        let component = Component::new(&engine, COMPONENT).expect("failed to compile component");
+        let tys = component
+            .component_type()
+            .imports(&engine)
+            .flat_map(|(_, item)| match item {
+                ComponentItem::ComponentFunc(ty) => ty.params().collect(),
+                _ => vec![],
+            })
+            .collect();
+        (engine, tys)
+    })
+}
+
+fuzz_target!(|input: (u8, &[u8])| {
+    let (ty, buf) = input;
+    let (engine, tys) = types();
+    let ty = &tys[usize::from(ty) % tys.len()];
+    let mut store = Store::new(
+        engine,
+        Ctx {
+            table: ResourceTable::new(),
+            wasi: WasiCtxBuilder::new().build(),
+        },
+    );
+    block_on(limits().scope(async {
+        let mut val = Val::Bool(false);
+        if read_value(&mut store, &mut pin!(Conn::from(buf)), &mut val, ty, &[])
+            .await
+            .is_err()
+        {
+            return;
+        }
+
+        let mut buf = BytesMut::default();
+        ValEncoder::<_, Conn>::new(store.as_context_mut(), ty)
+            .encode(&val, &mut buf)
+            .expect("failed to encode value");
+        let mut decoded = Val::Bool(false);
+        read_value(
+            &mut store,
+            &mut pin!(Conn::from(buf.freeze())),
+            &mut decoded,
+            ty,
+            &[],
+        )
+        .await
+        .expect("failed to decode encoded value");
+        assert_eq!(decoded, val);
+    }));
+});
@@ -0,0 +1,189 @@
+//! Utilities shared by wRPC fuzz targets
+
+use core::fmt::Debug;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use std::io::Cursor;
+
+use bytes::{Bytes, BytesMut};
+use futures::executor::block_on;
+use futures::{Stream, StreamExt as _};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::codec::{Decoder, Encoder as _};
+use wrpc_transport::{Decode, Deferred, Encode, Limits};
+
+/// In-memory connection, which reads from a buffer and discards all writes.
+///
+/// All nested connections are empty.
+#[derive(Debug, Default)]
+pub struct Conn(Cursor<Bytes>);
+
+impl From<Bytes> for Conn {
+    fn from(buf: Bytes) -> Self {
+        Self(Cursor::new(buf))
+    }
+}
+
+impl From<&[u8]> for Conn {
+    fn from(buf: &[u8]) -> Self {
+        Bytes::copy_from_slice(buf).into()
+    }
+}
+
+impl wrpc_transport::Index<Self> for Conn {
+    fn index(&self, _path: &[usize]) -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Limits applied to decoding fuzzer input, which keep allocations proportional to it
+#[must_use]
+pub fn limits() -> Limits {
+    Limits {
+        max_list_len: 1 << 12,
+        max_string_len: 1 << 16,
+        max_depth: 32,
+        max_invocation_size: 1 << 20,
+        max_chunk_len: 1 << 12,
+    }
+}
+
+/// Decodes a value from `buf` feeding `dec` at most `chunk` bytes at a time.
+///
+/// Returns the decoded value and the number of bytes consumed.
+pub fn decode_chunked<D>(
+    dec: &mut D,
+    buf: &[u8],
+    chunk: usize,
+) -> std::io::Result<Option<(D::Item, usize)>>
+where
+    D: Decoder,
+    std::io::Error: From<D::Error>,
+{
+    let mut src = BytesMut::with_capacity(buf.len());
+    let mut n = 0;
+    for buf in buf.chunks(chunk.max(1)) {
+        src.extend_from_slice(buf);
+        n += buf.len();
+        if let Some(v) = dec.decode(&mut src)? {
+            return Ok(Some((v, n - src.len())));
+        }
+    }
+    Ok(None)
+}
+
+fn round_trip<T>(v: T, chunk: usize)
+where
+    T: Decode<Conn> + Encode<Conn> + Clone + Debug + PartialEq,
+    std::io::Error: From<<T::Decoder as Decoder>::Error>,
+    std::io::Error: From<<T::Encoder as tokio_util::codec::Encoder<T>>::Error>,
+{
+    let mut buf = BytesMut::default();
+    let mut enc = T::Encoder::default();
+    enc.encode(v.clone(), &mut buf)
+        .map_err(std::io::Error::from)
+        .expect("failed to encode value");
+    assert!(
+        Deferred::<Conn>::take_deferred(&mut enc).is_none(),
+        "value should not be asynchronous"
+    );
+    for chunk in [buf.len(), chunk] {
+        let (decoded, n) = decode_chunked(&mut T::Decoder::default(), &buf, chunk)
+            .expect("failed to decode encoded value")
+            .expect("encoded value is incomplete");
+        assert_eq!(n, buf.len(), "encoded value not fully consumed");
+        assert_eq!(decoded, v);
+    }
+}
+
+/// Checks that `v` is decoded from its encoding, fed to the decoder at once and in chunks
+/// of `chunk` bytes
+pub fn check_round_trip<T>(v: T, chunk: usize)
+where
+    T: Decode<Conn> + Encode<Conn> + Clone + Debug + PartialEq,
+    std::io::Error: From<<T::Decoder as Decoder>::Error>,
+    std::io::Error: From<<T::Encoder as tokio_util::codec::Encoder<T>>::Error>,
+{
+    round_trip(v, chunk);
+}
+
+/// Checks that decoding `buf` as `T` in chunks of `chunk` bytes is equivalent to decoding it
+/// at once and that the decoded value round-trips
+pub fn check_decode<T>(buf: &[u8], chunk: usize)
+where
+    T: Decode<Conn> + Encode<Conn> + Clone + Debug + PartialEq,
+    std::io::Error: From<<T::Decoder as Decoder>::Error>,
+    std::io::Error: From<<T::Encoder as tokio_util::codec::Encoder<T>>::Error>,
+{
+    block_on(limits().scope(async {
+        let whole = decode_chunked(&mut T::Decoder::default(), buf, buf.len());
+        let chunked = decode_chunked(&mut T::Decoder::default(), buf, chunk);
+        match (whole, chunked) {
+            (Ok(Some((whole, n))), Ok(Some((chunked, m)))) => {
+                assert_eq!(whole, chunked);
+                assert_eq!(n, m, "number of consumed bytes differs");
+                round_trip(whole, chunk);
+            }
+            (Ok(None), Ok(None)) | (Err(..), Err(..)) => {}
+            (whole, chunked) => {
+                panic!("decoding in chunks of {chunk} bytes diverged: `{whole:?}` != `{chunked:?}`")
+            }
+        }
+    }));
+}
+
+async fn decode_stream(buf: &[u8], chunk: usize) -> std::io::Result<Option<(Vec<u32>, usize)>> {
+    let mut dec =
+        <Pin<Box<dyn Stream<Item = u32> + Send + Sync>> as Decode<Conn>>::Decoder::default();
+    let Some((items, n)) = decode_chunked(&mut dec, buf, chunk)? else {
+        return Ok(None);
+    };
+    // pending elements are read from nested connections, which are empty
+    drop(Deferred::<Conn>::take_deferred(&mut dec));
+    Ok(Some((items.collect().await, n)))
+}
+
+/// Checks that decoding `buf` as a `stream<u32>` in chunks of `chunk` bytes is equivalent to
+/// decoding it at once
+pub fn check_stream_decode(buf: &[u8], chunk: usize) {
+    block_on(limits().scope(async {
+        let whole = decode_stream(buf, buf.len()).await;
+        let chunked = decode_stream(buf, chunk).await;
+        match (whole, chunked) {
+            (Ok(whole), Ok(chunked)) => assert_eq!(whole, chunked),
+            (Err(..), Err(..)) => {}
+            (whole, chunked) => {
+                panic!("decoding in chunks of {chunk} bytes diverged: `{whole:?}` != `{chunked:?}`")
+            }
+        }
+    }));
+}
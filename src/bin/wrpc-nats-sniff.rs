@@ -0,0 +1,4 @@
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    wrpc_nats_sniff_cli::run().await
+}
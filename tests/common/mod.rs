@@ -52,12 +52,27 @@ pub async fn start_nats() -> anyhow::Result<(
     async_nats::Client,
     JoinHandle<anyhow::Result<ExitStatus>>,
     oneshot::Sender<()>,
+)> {
+    start_nats_with_args(&[]).await
+}
+
+#[cfg(feature = "nats")]
+pub async fn start_nats_with_args(
+    args: &[&str],
+) -> anyhow::Result<(
+    u16,
+    async_nats::Client,
+    JoinHandle<anyhow::Result<ExitStatus>>,
+    oneshot::Sender<()>,
 )> {
     let port = free_port().await?;
-    let (server, stop_tx) =
-        spawn_server(Command::new("nats-server").args(["-V", "-T=false", "-p", &port.to_string()]))
-            .await
-            .context("failed to start NATS.io server")?;
+    let (server, stop_tx) = spawn_server(
+        Command::new("nats-server")
+            .args(["-V", "-T=false", "-p", &port.to_string()])
+            .args(args),
+    )
+    .await
+    .context("failed to start NATS.io server")?;
 
     let client = wrpc_cli::nats::connect(format!("nats://localhost:{port}"))
         .await
@@ -66,11 +81,14 @@ pub async fn start_nats() -> anyhow::Result<(
 }
 
 #[cfg(feature = "nats")]
-pub async fn with_nats<T, Fut>(f: impl FnOnce(u16, async_nats::Client) -> Fut) -> anyhow::Result<T>
+async fn with_nats_args<T, Fut>(
+    args: &[&str],
+    f: impl FnOnce(u16, async_nats::Client) -> Fut,
+) -> anyhow::Result<T>
 where
     Fut: Future<Output = anyhow::Result<T>>,
 {
-    let (port, nats_client, nats_server, stop_tx) = start_nats()
+    let (port, nats_client, nats_server, stop_tx) = start_nats_with_args(args)
         .await
         .context("failed to start NATS.io server")?;
     let res = f(port, nats_client).await.context("closure failed")?;
@@ -82,6 +100,29 @@ where
     Ok(res)
 }
 
+#[cfg(feature = "nats")]
+pub async fn with_nats<T, Fut>(f: impl FnOnce(u16, async_nats::Client) -> Fut) -> anyhow::Result<T>
+where
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    with_nats_args(&[], f).await
+}
+
+#[cfg(feature = "nats")]
+pub async fn with_nats_jetstream<T, Fut>(
+    f: impl FnOnce(u16, async_nats::Client) -> Fut,
+) -> anyhow::Result<T>
+where
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let dir = tempfile::TempDir::new().context("failed to create temporary directory")?;
+    let path = dir
+        .path()
+        .to_str()
+        .context("temporary directory path is not valid UTF-8")?;
+    with_nats_args(&["-js", "-sd", path], f).await
+}
+
 #[cfg(feature = "quic")]
 pub async fn with_quic<T, Fut>(
     names: &[&str],
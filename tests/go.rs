@@ -8,12 +8,16 @@ mod common;
 #[instrument(ret)]
 #[test_log::test(tokio::test(flavor = "multi_thread"))]
 async fn go_bindgen() -> anyhow::Result<()> {
+    use core::pin::Pin;
     use core::time::Duration;
 
     use anyhow::{anyhow, bail};
+    use futures::{Stream, StreamExt as _};
     use tokio::fs;
+    use tokio::join;
     use tokio::time::sleep;
     use tracing::info;
+    use wrpc_transport::Invoke as _;
 
     if let Err(err) = fs::remove_dir_all("tests/go/bindings").await {
         match err.kind() {
@@ -31,9 +35,16 @@ async fn go_bindgen() -> anyhow::Result<()> {
         .context("failed to call `go generate`")?;
     ensure!(status.success(), "`go generate` failed");
 
+    let (resolve, pkg) = test_helpers::round_trip::parse("tests/wit")?;
+    let ty = test_helpers::round_trip::interface_type(&resolve, pkg, "round-trip", "values")?;
+    let corpus = test_helpers::test_directory("round-trip", "go", "values").join("corpus.hex");
+    test_helpers::round_trip::write_corpus(&corpus, &resolve, &ty, 256)
+        .context("failed to write round-trip corpus")?;
+
     let status = Command::new("go")
         .current_dir("tests/go")
         .args(["test", "-v"])
+        .env("WRPC_ROUND_TRIP_CORPUS", &corpus)
         .kill_on_drop(true)
         .status()
         .await
@@ -68,30 +79,30 @@ async fn go_bindgen() -> anyhow::Result<()> {
         sleep(Duration::from_secs(1)).await;
 
         info!("calling `wrpc-test:integration/sync-client.foo.f`");
-        let v = foo::f(&client, None, "f")
+        let v = foo::f(&client, Default::default(), "f")
             .await
             .context("failed to call `wrpc-test:integration/sync-client.foo.f`")?;
         ensure!(v == 42);
 
         info!("calling `wrpc-test:integration/sync-client.foo.foo`");
-        foo::foo(&client, None, "foo")
+        foo::foo(&client, Default::default(), "foo")
             .await
             .context("failed to call `wrpc-test:integration/sync-client.foo.foo`")?;
 
         info!("calling `wrpc-test:integration/sync.fallible`");
-        let res = sync::fallible(&client, None, true)
+        let res = sync::fallible(&client, Default::default(), true)
             .await
             .context("failed to call `wrpc-test:integration/sync.fallible`")?;
         ensure!(res == Ok(true));
 
         info!("calling `wrpc-test:integration/sync.fallible`");
-        let res = sync::fallible(&client, None, false)
+        let res = sync::fallible(&client, Default::default(), false)
             .await
             .context("failed to call `wrpc-test:integration/sync.fallible`")?;
         ensure!(res == Err("test".to_string()));
 
         info!("calling `wrpc-test:integration/sync.numbers`");
-        let (a, b, c, d, e, f, g, h, i, j) = sync::numbers(&client, None)
+        let (a, b, c, d, e, f, g, h, i, j) = sync::numbers(&client, Default::default())
             .await
             .context("failed to call `wrpc-test:integration/sync.numbers`")?;
         ensure!(a == 1);
@@ -106,19 +117,19 @@ async fn go_bindgen() -> anyhow::Result<()> {
         ensure!(j == 10.);
 
         info!("calling `wrpc-test:integration/sync.with-flags`");
-        let v = sync::with_flags(&client, None, true, false, true)
+        let v = sync::with_flags(&client, Default::default(), true, false, true)
             .await
             .context("failed to call `wrpc-test:integration/sync.with-flags`")?;
         ensure!(v == Abc::A | Abc::C, "{v:?}");
 
         info!("calling `wrpc-test:integration/sync.with-variant-option`");
-        let v = sync::with_variant_option(&client, None, false)
+        let v = sync::with_variant_option(&client, Default::default(), false)
             .await
             .context("failed to call `wrpc-test:integration/sync.with-variant-option`")?;
         ensure!(v.is_none(), "{v:?}");
 
         info!("calling `wrpc-test:integration/sync.with-variant-option`");
-        let v = sync::with_variant_option(&client, None, true)
+        let v = sync::with_variant_option(&client, Default::default(), true)
             .await
             .context("failed to call `wrpc-test:integration/sync.with-variant-option`")?;
         ensure!(
@@ -131,7 +142,7 @@ async fn go_bindgen() -> anyhow::Result<()> {
         );
 
         info!("calling `wrpc-test:integration/sync.with-record`");
-        let v = sync::with_record(&client, None)
+        let v = sync::with_record(&client, Default::default())
             .await
             .context("failed to call `wrpc-test:integration/sync.with-record`")?;
         ensure!(
@@ -144,13 +155,13 @@ async fn go_bindgen() -> anyhow::Result<()> {
         );
 
         info!("calling `wrpc-test:integration/sync.with-record-list`");
-        let v = sync::with_record_list(&client, None, 0)
+        let v = sync::with_record_list(&client, Default::default(), 0)
             .await
             .context("failed to call `wrpc-test:integration/sync.with-record-list`")?;
         ensure!(v.is_empty(), "{v:?}");
 
         info!("calling `wrpc-test:integration/sync.with-record-list`");
-        let v = sync::with_record_list(&client, None, 3)
+        let v = sync::with_record_list(&client, Default::default(), 3)
             .await
             .context("failed to call `wrpc-test:integration/sync.with-record-list`")?;
         ensure!(
@@ -175,7 +186,7 @@ async fn go_bindgen() -> anyhow::Result<()> {
         );
 
         info!("calling `wrpc-test:integration/sync.with-record-tuple`");
-        let v = sync::with_record_tuple(&client, None)
+        let v = sync::with_record_tuple(&client, Default::default())
             .await
             .context("failed to call `wrpc-test:integration/sync.with-record-tuple`")?;
         ensure!(
@@ -195,7 +206,7 @@ async fn go_bindgen() -> anyhow::Result<()> {
         );
 
         info!("calling `wrpc-test:integration/sync.with-enum`");
-        let v = sync::with_enum(&client, None)
+        let v = sync::with_enum(&client, Default::default())
             .await
             .context("failed to call `wrpc-test:integration/sync.with-enum-tuple`")?;
         ensure!(v == Foobar::Bar, "{v:?}",);
@@ -208,6 +219,61 @@ async fn go_bindgen() -> anyhow::Result<()> {
             .await
             .context("failed to wait for `sync-server-nats` to exit")?;
 
+        info!("starting `async-server-nats`");
+        let mut server = Command::new("go")
+            .current_dir("tests/go")
+            .args([
+                "run",
+                "./cmd/async-server-nats",
+                &format!("nats://localhost:{port}"),
+            ])
+            .kill_on_drop(true)
+            .spawn()
+            .context("failed to run `async-server-nats`")?;
+
+        // TODO: Remove the need for this
+        sleep(Duration::from_secs(1)).await;
+
+        info!("calling `wrpc-test:integration/async.with-streams`");
+        let ((bytes, lists), io): (
+            (
+                Pin<Box<dyn Stream<Item = u8> + Send + Sync>>,
+                Pin<Box<dyn Stream<Item = Vec<String>> + Send + Sync>>,
+            ),
+            _,
+        ) = client
+            .invoke_values(
+                Default::default(),
+                "wrpc-test:integration/async",
+                "with-streams",
+                (false,),
+                &[[Some(0)], [Some(1)]],
+            )
+            .await
+            .context("failed to call `wrpc-test:integration/async.with-streams`")?;
+        let (bytes, lists, io) = join!(
+            bytes.collect::<Vec<_>>(),
+            lists.collect::<Vec<_>>(),
+            async {
+                if let Some(io) = io {
+                    io.await
+                } else {
+                    Ok(())
+                }
+            }
+        );
+        io.context("failed to complete async I/O")?;
+        ensure!(bytes == b"test", "{bytes:?}");
+        ensure!(lists == [vec!["foo", "bar"], vec!["baz"]], "{lists:?}");
+
+        server
+            .start_kill()
+            .context("failed to kill `async-server-nats`")?;
+        server
+            .wait_with_output()
+            .await
+            .context("failed to wait for `async-server-nats` to exit")?;
+
         Ok(())
     })
     .await
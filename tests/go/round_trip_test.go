@@ -0,0 +1,82 @@
+//go:generate $WIT_BINDGEN_WRPC go --gofmt=false --world round-trip --out-dir bindings/round_trip --package github.com/wrpc/wrpc/tests/go/bindings/round_trip ../wit
+
+package integration_test
+
+import (
+	"bufio"
+	"bytes"
+	"context"
+	"encoding/hex"
+	"os"
+	"testing"
+
+	wrpc "github.com/wrpc/wrpc/go"
+	"github.com/wrpc/wrpc/tests/go/bindings/round_trip/wrpc_test/integration/round_trip"
+)
+
+type indexWriter struct {
+	*bytes.Buffer
+}
+
+func (w *indexWriter) Index(path ...uint32) (wrpc.IndexWriter, error) {
+	panic("not implemented")
+}
+
+type indexReadCloser struct {
+	*indexReader
+}
+
+func (r *indexReadCloser) Close() error {
+	return nil
+}
+
+// corpusInvoker serves invocations from an in-memory buffer and records all parameters written
+type corpusInvoker struct {
+	results []byte
+	params  bytes.Buffer
+}
+
+func (inv *corpusInvoker) Invoke(ctx context.Context, instance string, name string, f func(wrpc.IndexWriter, wrpc.IndexReadCloser) error, subs ...wrpc.SubscribePath) error {
+	return f(&indexWriter{&inv.params}, &indexReadCloser{&indexReader{bytes.NewBuffer(inv.results)}})
+}
+
+// TestRoundTrip decodes values generated by the Rust test harness and checks that they are
+// re-encoded byte for byte
+func TestRoundTrip(t *testing.T) {
+	path := os.Getenv("WRPC_ROUND_TRIP_CORPUS")
+	if path == "" {
+		t.Skip("`WRPC_ROUND_TRIP_CORPUS` not set")
+	}
+	f, err := os.Open(path)
+	if err != nil {
+		t.Fatalf("failed to open corpus: %s", err)
+	}
+	defer f.Close()
+
+	ctx := context.Background()
+	lines := bufio.NewScanner(f)
+	for lines.Scan() {
+		buf, err := hex.DecodeString(lines.Text())
+		if err != nil {
+			t.Fatalf("failed to decode corpus line: %s", err)
+		}
+
+		inv := &corpusInvoker{results: buf}
+		v, _, err := round_trip.Get(ctx, inv)
+		if err != nil {
+			t.Errorf("failed to decode `%x`: %s", buf, err)
+			continue
+		}
+		inv = &corpusInvoker{}
+		if _, err := round_trip.Set(ctx, inv, v); err != nil {
+			t.Errorf("failed to encode value decoded from `%x`: %s", buf, err)
+			continue
+		}
+		if !bytes.Equal(inv.params.Bytes(), buf) {
+			t.Errorf("encoding mismatch\nexpected: %x\ngot:      %x", buf, inv.params.Bytes())
+		}
+	}
+	if err := lines.Err(); err != nil {
+		t.Fatalf("failed to read corpus: %s", err)
+	}
+}
@@ -3,6 +3,7 @@
 
 mod common;
 
+use core::pin::Pin;
 use core::str;
 use core::time::Duration;
 
@@ -439,6 +440,1250 @@ async fn rust_bindgen_nats() -> anyhow::Result<()> {
     .await
 }
 
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_wildcard() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use wrpc_transport::Index as _;
+
+    common::with_nats(|_, nats_client| async {
+        let clt = wrpc_transport_nats::Client::new(nats_client, "test-prefix".to_string());
+        let invocations = clt
+            .serve("test", "wildcard", [[None]])
+            .await
+            .context("failed to serve `test.wildcard`")?;
+        let mut invocations = pin!(invocations);
+        try_join!(
+            async {
+                let (_, _, incoming) = invocations
+                    .try_next()
+                    .await
+                    .context("failed to accept invocation")?
+                    .context("unexpected end of stream")?;
+                // read elements out of order to exercise buffering
+                for (i, expected) in [(1, "bar"), (0, "foo")] {
+                    let mut nested = incoming
+                        .index(&[i])
+                        .with_context(|| format!("failed to index `{i}`"))?;
+                    let mut buf = vec![];
+                    nested
+                        .read_to_end(&mut buf)
+                        .await
+                        .with_context(|| format!("failed to read `{i}`"))?;
+                    assert_eq!(buf, expected.as_bytes());
+                }
+                anyhow::Ok(())
+            },
+            async {
+                let (mut outgoing, _) = clt
+                    .invoke(
+                        Default::default(),
+                        "test",
+                        "wildcard",
+                        Bytes::default(),
+                        &[[None; 0]],
+                    )
+                    .await
+                    .context("failed to invoke `test.wildcard`")?;
+                outgoing
+                    .shutdown()
+                    .await
+                    .context("failed to shutdown parameter stream")?;
+                for (i, v) in [(0, "foo"), (1, "bar")] {
+                    let mut nested = outgoing
+                        .index(&[i])
+                        .with_context(|| format!("failed to index `{i}`"))?;
+                    nested
+                        .write_all(v.as_bytes())
+                        .await
+                        .with_context(|| format!("failed to write `{i}`"))?;
+                    nested
+                        .shutdown()
+                        .await
+                        .with_context(|| format!("failed to shutdown `{i}`"))?;
+                }
+                anyhow::Ok(())
+            },
+        )?;
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_wildcard_limits() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    use anyhow::bail;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use wrpc_transport::{Error, Index as _, Limits};
+
+    common::with_nats(|_, nats_client| async {
+        let clt = wrpc_transport_nats::Client::new(nats_client, "test-prefix".to_string());
+        let invocations = Limits {
+            max_list_len: 2,
+            ..Limits::default()
+        }
+        .scope(clt.serve("test", "wildcard", [[None]]))
+        .await
+        .context("failed to serve `test.wildcard`")?;
+        let mut invocations = pin!(invocations);
+        let (mut outgoing, _incoming) = clt
+            .invoke(
+                Default::default(),
+                "test",
+                "wildcard",
+                Bytes::default(),
+                &[[None; 0]],
+            )
+            .await
+            .context("failed to invoke `test.wildcard`")?;
+        outgoing
+            .shutdown()
+            .await
+            .context("failed to shutdown parameter stream")?;
+        let (_, _, incoming) = invocations
+            .try_next()
+            .await
+            .context("failed to accept invocation")?
+            .context("unexpected end of stream")?;
+        let mut nested = incoming.index(&[0]).context("failed to index `0`")?;
+        // each path can only be claimed by a single reader
+        assert!(incoming.index(&[0]).is_err());
+
+        // index `2` can never be claimed by a list within limits, so it is not buffered
+        let mut invalid = outgoing.index(&[2]).context("failed to index `2`")?;
+        invalid
+            .write_all(b"foo")
+            .await
+            .context("failed to write `2`")?;
+        let Err(err) = nested.read_to_end(&mut vec![]).await else {
+            bail!("reading should have failed")
+        };
+        assert!(
+            matches!(
+                Error::from(err),
+                Error::LimitExceeded {
+                    limit: "list length",
+                    len: 3,
+                    max: 2,
+                }
+            ),
+            "unexpected error"
+        );
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_subscriptions() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use wrpc_transport::Index as _;
+
+    common::with_nats(|_, nats_client| async {
+        let srv = wrpc_transport_nats::Client::new(nats_client.clone(), "test-prefix".to_string());
+        let clt = wrpc_transport_nats::Client::new(nats_client, "test-prefix".to_string());
+        let invocations = srv
+            .serve("test", "subscriptions", [[Some(0)], [Some(1)]])
+            .await
+            .context("failed to serve `test.subscriptions`")?;
+        let mut invocations = pin!(invocations);
+        assert_eq!(srv.live_subscriptions(), 1);
+
+        let (mut outgoing, mut incoming) = clt
+            .invoke(
+                Default::default(),
+                "test",
+                "subscriptions",
+                Bytes::from("foo"),
+                &[[None; 0]],
+            )
+            .await
+            .context("failed to invoke `test.subscriptions`")?;
+        let (_, mut results, mut params) = invocations
+            .try_next()
+            .await
+            .context("failed to accept invocation")?
+            .context("unexpected end of stream")?;
+        assert_eq!(srv.live_subscriptions(), 4);
+
+        outgoing
+            .shutdown()
+            .await
+            .context("failed to shutdown parameter stream")?;
+        let mut buf = vec![];
+        params
+            .read_to_end(&mut buf)
+            .await
+            .context("failed to read parameters")?;
+        assert_eq!(buf, b"foo");
+        // finished root stream is unsubscribed from
+        assert_eq!(srv.live_subscriptions(), 3);
+
+        let nested = params.index(&[0]).context("failed to index `0`")?;
+        drop(params);
+        // `1` can no longer be indexed
+        assert_eq!(srv.live_subscriptions(), 2);
+        drop(nested);
+        assert_eq!(srv.live_subscriptions(), 1);
+
+        results
+            .write_all(b"bar")
+            .await
+            .context("failed to write results")?;
+        results
+            .shutdown()
+            .await
+            .context("failed to shutdown result stream")?;
+        let mut buf = vec![];
+        incoming
+            .read_to_end(&mut buf)
+            .await
+            .context("failed to read results")?;
+        assert_eq!(buf, b"bar");
+        drop(outgoing);
+        drop(incoming);
+        assert_eq!(clt.live_subscriptions(), 0);
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_inbox_prefix() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    use tokio::time::timeout;
+
+    common::with_nats(|_, nats_client| async move {
+        let mut custom = nats_client
+            .subscribe("test-inbox.>")
+            .await
+            .context("failed to subscribe on custom inbox subjects")?;
+        let mut default = nats_client
+            .subscribe("_INBOX.>")
+            .await
+            .context("failed to subscribe on default inbox subjects")?;
+        let srv = wrpc_transport_nats::Client::new(nats_client.clone(), "test-prefix")
+            .with_inbox_prefix("test-inbox.srv");
+        let clt = wrpc_transport_nats::Client::new(nats_client, "test-prefix")
+            .with_inbox_prefix("test-inbox.clt");
+        let invocations = srv
+            .serve_values::<_, (String,), (String,)>("test", "inbox", [[None; 0]])
+            .await
+            .context("failed to serve `test.inbox`")?;
+        let mut invocations = pin!(invocations);
+        try_join!(
+            async {
+                let (_, (s,), _, tx) = invocations
+                    .try_next()
+                    .await
+                    .context("failed to accept invocation")?
+                    .context("unexpected end of stream")?;
+                tx(Ok((s,))).await.context("failed to transmit results")?;
+                anyhow::Ok(())
+            },
+            async {
+                let (s,) = clt
+                    .invoke_values_blocking::<_, (String,)>(
+                        Default::default(),
+                        "test",
+                        "inbox",
+                        ("foo".to_string(),),
+                        &[[None; 0]],
+                    )
+                    .await
+                    .context("failed to invoke `test.inbox`")?;
+                assert_eq!(s, "foo");
+                anyhow::Ok(())
+            },
+        )?;
+        // both peers receive messages under their custom inbox prefix only
+        let mut subjects = vec![];
+        while let Ok(Some(msg)) = timeout(Duration::from_millis(100), custom.next()).await {
+            subjects.push(msg.subject.to_string());
+        }
+        assert!(subjects.iter().any(|s| s.starts_with("test-inbox.clt.")));
+        assert!(subjects.iter().any(|s| s.starts_with("test-inbox.srv.")));
+        assert!(timeout(Duration::from_millis(100), default.next())
+            .await
+            .is_err());
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_deadline() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    use anyhow::bail;
+    use tokio::io::AsyncReadExt as _;
+
+    common::with_nats(|_, nats_client| async {
+        let clt = wrpc_transport_nats::Client::new(nats_client, "test-prefix".to_string());
+        let invocations = clt
+            .serve("test", "deadline", [[None; 0]])
+            .await
+            .context("failed to serve `test.deadline`")?;
+        let mut invocations = pin!(invocations);
+        let cx = wrpc_transport_nats::InvocationContext::default()
+            .with_timeout(Duration::from_millis(100));
+        let (_outgoing, mut incoming) = clt
+            .invoke(cx, "test", "deadline", Bytes::default(), &[[None; 0]])
+            .await
+            .context("failed to invoke `test.deadline`")?;
+        let (cx, _results, mut params) = invocations
+            .try_next()
+            .await
+            .context("failed to accept invocation")?
+            .context("unexpected end of stream")?;
+        assert!(cx.deadline.is_some());
+        // neither peer finishes its stream, so reads only complete once the deadline is exceeded
+        let Err(err) = incoming.read_to_end(&mut vec![]).await else {
+            bail!("reading results should have timed out")
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        let Err(err) = params.read_to_end(&mut vec![]).await else {
+            bail!("reading parameters should have timed out")
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_list_incremental() -> anyhow::Result<()> {
+    use core::iter::zip;
+    use core::pin::pin;
+
+    // encodes to more than `LIST_CHUNK_SIZE` bytes, so the list is written in multiple chunks
+    const LEN: u32 = 20_000;
+
+    common::with_nats(|_, nats_client| async {
+        let clt = wrpc_transport_nats::Client::new(nats_client, "test-prefix".to_string());
+        let invocations = clt
+            .serve_values::<_, (u32, Vec<u32>), (u32, u64)>("test", "list", [[None; 0]])
+            .await
+            .context("failed to serve `test.list`")?;
+        let mut invocations = pin!(invocations);
+        let ((returns, tx), ()) = try_join!(
+            async {
+                clt.invoke_values_with_list::<_, _, _, (u32, u64)>(
+                    Default::default(),
+                    "test",
+                    "list",
+                    (42u32,),
+                    LEN,
+                    stream::iter(0..LEN),
+                    &[[None; 0]],
+                )
+                .await
+                .context("failed to invoke `test.list`")
+            },
+            async {
+                let (_, (x, items), rx, tx) = invocations
+                    .next()
+                    .await
+                    .context("unexpected end of stream")?
+                    .context("failed to decode parameters")?;
+                assert!(rx.is_none());
+                assert_eq!(x, 42);
+                assert_eq!(items.len(), LEN as usize);
+                assert!(zip(0.., &items).all(|(i, v)| i == *v));
+                let n = items.len().try_into()?;
+                let sum = items.into_iter().map(u64::from).sum();
+                tx(Ok((n, sum))).await.context("failed to send returns")
+            }
+        )?;
+        assert!(tx.is_none());
+        assert_eq!(returns, (LEN, u64::from(LEN) * u64::from(LEN - 1) / 2));
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_limits() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    use anyhow::bail;
+    use tokio::io::AsyncWriteExt as _;
+    use wrpc_transport::{Error, Index as _, Limits};
+
+    common::with_nats(|_, nats_client| async {
+        let clt = wrpc_transport_nats::Client::new(nats_client, "test-prefix".to_string());
+        let limits = Limits {
+            max_list_len: 2,
+            max_invocation_size: 8,
+            ..Limits::default()
+        };
+        let (lists, streams) = limits
+            .scope(async {
+                try_join!(
+                    clt.serve_values::<_, (Vec<u32>,), ()>("test", "list", [[None; 0]]),
+                    clt.serve_values::<_, (Pin<Box<dyn Stream<Item = u32> + Send + Sync>>,), ()>(
+                        "test",
+                        "stream",
+                        [[Some(0)]],
+                    ),
+                )
+            })
+            .await
+            .context("failed to serve functions")?;
+        let mut lists = pin!(lists);
+        let mut streams = pin!(streams);
+
+        let (_outgoing, _incoming) = clt
+            .invoke(
+                Default::default(),
+                "test",
+                "list",
+                Bytes::from_static(b"\x03\x01\x02\x03"),
+                &[[None; 0]],
+            )
+            .await
+            .context("failed to invoke `test.list`")?;
+        let Err(err) = lists.next().await.context("unexpected end of stream")? else {
+            bail!("decoding list should have failed")
+        };
+        assert!(
+            matches!(
+                err,
+                Error::LimitExceeded {
+                    limit: "list length",
+                    len: 3,
+                    max: 2,
+                }
+            ),
+            "unexpected error: {err:?}"
+        );
+
+        // stream is pending, its chunks are received asynchronously on a nested subject
+        let (outgoing, _incoming) = clt
+            .invoke(
+                Default::default(),
+                "test",
+                "stream",
+                Bytes::from_static(b"\x00"),
+                &[[None; 0]],
+            )
+            .await
+            .context("failed to invoke `test.stream`")?;
+        let (_, _, rx, _) = streams
+            .next()
+            .await
+            .context("unexpected end of stream")?
+            .context("failed to decode `test.stream` parameters")?;
+        let rx = rx.context("stream should be received asynchronously")?;
+        let mut chunks = outgoing.index(&[0]).context("failed to index stream")?;
+        let (_, res) = join!(
+            async {
+                chunks.write_all(&[0x0a; 11]).await?;
+                chunks.shutdown().await
+            },
+            rx
+        );
+        let Err(err) = res else {
+            bail!("receiving stream chunk should have failed")
+        };
+        let err = Error::from(err);
+        assert!(
+            matches!(
+                err,
+                Error::LimitExceeded {
+                    limit: "invocation size",
+                    max: 8,
+                    ..
+                }
+            ),
+            "unexpected error: {err:?}"
+        );
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_cancel() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    use anyhow::bail;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    common::with_nats(|_, nats_client| async {
+        let clt = wrpc_transport_nats::Client::new(nats_client, "test-prefix".to_string());
+        let invocations = clt
+            .serve("test", "cancel", [[None; 0]])
+            .await
+            .context("failed to serve `test.cancel`")?;
+        let mut invocations = pin!(invocations);
+        let (mut outgoing, mut incoming) = clt
+            .invoke(
+                Default::default(),
+                "test",
+                "cancel",
+                Bytes::default(),
+                &[[None; 0]],
+            )
+            .await
+            .context("failed to invoke `test.cancel`")?;
+        let (_, mut results, mut params) = invocations
+            .try_next()
+            .await
+            .context("failed to accept invocation")?
+            .context("unexpected end of stream")?;
+        // complete the handshake without finishing the parameter stream
+        outgoing
+            .flush()
+            .await
+            .context("failed to flush parameter stream")?;
+        results
+            .write_all(b"foo")
+            .await
+            .context("failed to write results")?;
+        results.flush().await.context("failed to flush results")?;
+        let mut buf = [0; 3];
+        incoming
+            .read_exact(&mut buf)
+            .await
+            .context("failed to read results")?;
+        assert_eq!(&buf, b"foo");
+        // results are not finished, so dropping the stream cancels the invocation
+        drop(incoming);
+        let Err(err) = params.read_to_end(&mut vec![]).await else {
+            bail!("invocation should have been cancelled")
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionAborted);
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_complete() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    use tokio::time::timeout;
+
+    common::with_nats(|_, nats_client| async {
+        let mut messages = nats_client
+            .subscribe(">")
+            .await
+            .context("failed to subscribe")?;
+        let clt = wrpc_transport_nats::Client::new(nats_client, "test-prefix");
+        let (sync, streams) = try_join!(
+            clt.serve_values::<_, (u32,), (u32,)>("test", "sync", [[None; 0]]),
+            clt.serve_values::<_, (u32,), (Pin<Box<dyn Stream<Item = u32> + Send + Sync>>,)>(
+                "test",
+                "stream",
+                [[None; 0]],
+            ),
+        )
+        .context("failed to serve functions")?;
+        let server = spawn(async move {
+            let mut sync = pin!(sync);
+            let mut streams = pin!(streams);
+            let (_, (n,), _, tx) = sync.try_next().await?.context("unexpected end of stream")?;
+            tx(Ok((n,))).await?;
+            let (_, (n,), _, tx) = streams
+                .try_next()
+                .await?
+                .context("unexpected end of stream")?;
+            tx(Ok((
+                Box::pin(stream::iter([n])) as Pin<Box<dyn Stream<Item = _> + Send + Sync>>,
+            )))
+            .await?;
+            anyhow::Ok(())
+        });
+        let (n,) = clt
+            .invoke_values_blocking::<_, (u32,)>(
+                Default::default(),
+                "test",
+                "sync",
+                (42,),
+                &[[None; 0]],
+            )
+            .await
+            .context("failed to invoke `test.sync`")?;
+        assert_eq!(n, 42);
+        let (items,) = clt
+            .invoke_values_blocking::<_, (Pin<Box<dyn Stream<Item = u32> + Send + Sync>>,)>(
+                Default::default(),
+                "test",
+                "stream",
+                (42,),
+                &[[Some(0)]],
+            )
+            .await
+            .context("failed to invoke `test.stream`")?;
+        assert_eq!(items.collect::<Vec<_>>().await, [42]);
+        server.await??;
+        // all results were received, so the client must not cancel the invocations
+        let cancelled = timeout(Duration::from_millis(100), async {
+            while let Some(msg) = messages.next().await {
+                if msg.headers.as_ref().is_some_and(|headers| {
+                    headers.get(wrpc_transport_nats::CANCEL_HEADER).is_some()
+                }) {
+                    return Some(msg);
+                }
+            }
+            None
+        })
+        .await;
+        assert!(
+            cancelled.is_err(),
+            "invocation was cancelled: {cancelled:?}"
+        );
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_queue_group() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    use std::sync::Mutex;
+
+    const N: u32 = 32;
+
+    common::with_nats(|_, nats_client| async {
+        let handled = Arc::new(Mutex::new(Vec::default()));
+        let mut servers = Vec::default();
+        for i in 0..2 {
+            let srv = wrpc_transport_nats::Client::new(nats_client.clone(), "test-prefix")
+                .with_queue_group("test-group");
+            let invocations = srv
+                .serve_values::<_, (u32,), (u32,)>("test", "queue", [[None; 0]])
+                .await
+                .context("failed to serve `test.queue`")?;
+            let handled = Arc::clone(&handled);
+            servers.push(spawn(async move {
+                let mut invocations = pin!(invocations);
+                while let Some((_, (n,), _, tx)) = invocations.try_next().await? {
+                    info!(i, n, "server handling invocation");
+                    handled.lock().unwrap().push(n);
+                    tx(Ok((n,))).await?;
+                }
+                anyhow::Ok(())
+            }));
+        }
+        let clt = wrpc_transport_nats::Client::new(nats_client, "test-prefix");
+        for n in 0..N {
+            let (m,) = clt
+                .invoke_values_blocking::<_, (u32,)>(
+                    Default::default(),
+                    "test",
+                    "queue",
+                    (n,),
+                    &[[None; 0]],
+                )
+                .await
+                .context("failed to invoke `test.queue`")?;
+            assert_eq!(m, n);
+        }
+        for server in servers {
+            server.abort();
+        }
+        // each invocation is handled by exactly one member of the queue group
+        let mut handled = handled.lock().unwrap().clone();
+        handled.sort_unstable();
+        assert_eq!(handled, (0..N).collect::<Vec<_>>());
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_handshake_timeout() -> anyhow::Result<()> {
+    use anyhow::bail;
+    use tokio::io::AsyncWriteExt as _;
+
+    common::with_nats(|_, nats_client| async {
+        // subscribe to invocations without ever accepting them
+        let _sub = nats_client
+            .subscribe(wrpc_transport_nats::invocation_subject(
+                "test-prefix",
+                "test",
+                "timeout",
+            ))
+            .await
+            .context("failed to subscribe to `test.timeout`")?;
+        let clt = wrpc_transport_nats::Client::new(nats_client, "test-prefix")
+            .with_handshake_timeout(Duration::from_millis(100));
+        let (mut outgoing, _incoming) = clt
+            .invoke(
+                Default::default(),
+                "test",
+                "timeout",
+                Bytes::default(),
+                &[[None; 0]],
+            )
+            .await
+            .context("failed to invoke `test.timeout`")?;
+        let Err(err) = outgoing.flush().await else {
+            bail!("handshake should have timed out")
+        };
+        assert!(matches!(
+            wrpc_transport::Error::from(err),
+            wrpc_transport::Error::HandshakeTimedOut
+        ));
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_flow_control() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    const CHUNKS: u8 = 64;
+
+    common::with_nats(|_, nats_client| async {
+        let clt = wrpc_transport_nats::Client::new(nats_client, "test-prefix".to_string());
+        let invocations = clt
+            .serve("test", "flow", [[None; 0]])
+            .await
+            .context("failed to serve `test.flow`")?;
+        let mut invocations = pin!(invocations);
+        try_join!(
+            async {
+                let (_, mut outgoing, mut incoming) = invocations
+                    .try_next()
+                    .await
+                    .context("failed to accept invocation")?
+                    .context("unexpected end of stream")?;
+                let mut buf = vec![];
+                incoming
+                    .read_to_end(&mut buf)
+                    .await
+                    .context("failed to read parameters")?;
+                assert_eq!(buf, (0..CHUNKS).collect::<Vec<_>>());
+                for i in 0..CHUNKS {
+                    outgoing
+                        .write_all(&[i])
+                        .await
+                        .with_context(|| format!("failed to write result chunk `{i}`"))?;
+                }
+                outgoing
+                    .shutdown()
+                    .await
+                    .context("failed to shutdown result stream")?;
+                anyhow::Ok(())
+            },
+            async {
+                let cx = wrpc_transport_nats::InvocationContext::default().with_flow_control(2);
+                let (mut outgoing, mut incoming) = clt
+                    .invoke(cx, "test", "flow", Bytes::default(), &[[None; 0]])
+                    .await
+                    .context("failed to invoke `test.flow`")?;
+                for i in 0..CHUNKS {
+                    outgoing
+                        .write_all(&[i])
+                        .await
+                        .with_context(|| format!("failed to write parameter chunk `{i}`"))?;
+                }
+                outgoing
+                    .shutdown()
+                    .await
+                    .context("failed to shutdown parameter stream")?;
+                let mut buf = vec![];
+                incoming
+                    .read_to_end(&mut buf)
+                    .await
+                    .context("failed to read results")?;
+                assert_eq!(buf, (0..CHUNKS).collect::<Vec<_>>());
+                anyhow::Ok(())
+            },
+        )?;
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_flow_control_sequence() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    use anyhow::bail;
+    use async_nats::HeaderMap;
+    use bytes::{BufMut as _, BytesMut};
+    use tokio::io::AsyncReadExt as _;
+
+    common::with_nats(|_, nats_client| async move {
+        let clt = wrpc_transport_nats::Client::new(nats_client.clone(), "test-prefix");
+        let invocations = clt
+            .serve("test", "sequence", [[None; 0]])
+            .await
+            .context("failed to serve `test.sequence`")?;
+        let mut invocations = pin!(invocations);
+        // a gap and a reordering of messages in the parameter stream
+        for seqs in [[0, 2], [1, 0]] {
+            let inbox = nats_client.new_inbox();
+            let mut handshake = nats_client
+                .subscribe(inbox.clone())
+                .await
+                .context("failed to subscribe on inbox")?;
+            let mut headers = HeaderMap::new();
+            headers.insert(wrpc_transport_nats::FLOW_CONTROL_HEADER, "8");
+            nats_client
+                .publish_with_reply_and_headers(
+                    wrpc_transport_nats::invocation_subject("test-prefix", "test", "sequence"),
+                    inbox,
+                    headers,
+                    Bytes::default(),
+                )
+                .await
+                .context("failed to publish invocation")?;
+            let (_, _outgoing, mut incoming) = invocations
+                .try_next()
+                .await
+                .context("failed to accept invocation")?
+                .context("unexpected end of stream")?;
+            let accept = handshake
+                .next()
+                .await
+                .context("failed to receive handshake response")?;
+            let rx = accept
+                .reply
+                .context("server did not specify a reply subject")?;
+            for seq in seqs {
+                let mut payload = BytesMut::with_capacity(9);
+                payload.put_u64(seq);
+                payload.put_u8(0xff);
+                nats_client
+                    .publish(wrpc_transport_nats::param_subject(&rx), payload.freeze())
+                    .await
+                    .context("failed to publish parameter chunk")?;
+            }
+            let Err(err) = incoming.read_to_end(&mut vec![]).await else {
+                bail!("sequence {seqs:?} should have been rejected")
+            };
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_compression() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    use wrpc_transport::compress::{Compress, Compression};
+
+    // exceeds the default NATS `max_payload` of 1 MiB unless compressed
+    let payload = Bytes::from(br#"{"foo":"bar","baz":[1,2,3]}"#.repeat(1 << 16));
+    common::with_nats(|_, nats_client| async {
+        let clt = Compress::new(wrpc_transport_nats::Client::new(
+            nats_client,
+            "test-prefix".to_string(),
+        ));
+        let invocations = clt
+            .serve_values("test", "compressed", [[None; 0]])
+            .await
+            .context("failed to serve `test.compressed`")?;
+        let mut invocations = pin!(invocations);
+        for compression in [Compression::Zstd, Compression::Deflate] {
+            let (_, results) = try_join!(
+                async {
+                    let (cx, (buf,), rx, tx) = invocations
+                        .try_next()
+                        .await
+                        .context("failed to accept invocation")?
+                        .context("unexpected end of stream")?;
+                    let cx: wrpc_transport_nats::InvocationContext = cx;
+                    assert_eq!(cx.compression, Some(compression));
+                    assert!(rx.is_none());
+                    let buf: Bytes = buf;
+                    tx(Ok((buf,))).await.context("failed to send response")?;
+                    anyhow::Ok(())
+                },
+                async {
+                    let cx = wrpc_transport_nats::InvocationContext::default()
+                        .with_compression(compression);
+                    let (buf,) = clt
+                        .invoke_values_blocking::<_, (Bytes,)>(
+                            cx,
+                            "test",
+                            "compressed",
+                            (payload.clone(),),
+                            &[[None; 0]],
+                        )
+                        .await
+                        .context("failed to invoke `test.compressed`")?;
+                    anyhow::Ok(buf)
+                },
+            )?;
+            assert_eq!(results, payload);
+        }
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_compression_unsupported() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    use anyhow::bail;
+    use wrpc_transport::compress::{Compress, Compression};
+
+    common::with_nats(|_, nats_client| async {
+        let clt = wrpc_transport_nats::Client::new(nats_client, "test-prefix".to_string());
+        let invocations = clt
+            .serve_values::<_, (Bytes,), (Bytes,)>("test", "plain", [[None; 0]])
+            .await
+            .context("failed to serve `test.plain`")?;
+        let mut invocations = pin!(invocations);
+        let cx =
+            wrpc_transport_nats::InvocationContext::default().with_compression(Compression::Zstd);
+
+        // streams are only compressed by `Compress`
+        let Err(err) = clt
+            .invoke(cx.clone(), "test", "plain", Bytes::default(), &[[None; 0]])
+            .await
+        else {
+            bail!("compressed invocation should have failed")
+        };
+        assert!(err.to_string().contains("`Compress`"), "{err:#}");
+
+        // servers not wrapped in `Compress` reject compressed invocations
+        let clt = Compress::new(clt);
+        let (res, rejected) = join!(
+            clt.invoke_values_blocking::<_, (Bytes,)>(
+                cx,
+                "test",
+                "plain",
+                (Bytes::from_static(b"foo"),),
+                &[[None; 0]],
+            ),
+            invocations.next()
+        );
+        let Err(err) = res else {
+            bail!("invocation should have been rejected")
+        };
+        assert!(err.is_handler(), "{err:?}");
+        assert!(matches!(rejected, Some(Err(..))));
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_scatter() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    common::with_nats(|_, nats_client| async {
+        let a = wrpc_transport_nats::Client::new(nats_client.clone(), "test-prefix".to_string());
+        let b = wrpc_transport_nats::Client::new(nats_client.clone(), "test-prefix".to_string());
+        let clt = wrpc_transport_nats::Client::new(nats_client, "test-prefix".to_string());
+        let a_inv = a
+            .serve_values("test", "scatter", [[None; 0]])
+            .await
+            .context("failed to serve `test.scatter`")?;
+        let b_inv = b
+            .serve_values("test", "scatter", [[None; 0]])
+            .await
+            .context("failed to serve `test.scatter`")?;
+        let mut a_inv = pin!(a_inv);
+        let mut b_inv = pin!(b_inv);
+        let (_, _, mut results) = try_join!(
+            async {
+                let (_, (x,), rx, tx) = a_inv
+                    .try_next()
+                    .await
+                    .context("failed to accept invocation")?
+                    .context("unexpected end of stream")?;
+                assert!(rx.is_none());
+                let x: u32 = x;
+                tx(Ok((x + 1,))).await.context("failed to send response")?;
+                anyhow::Ok(())
+            },
+            async {
+                let (_, (x,), rx, tx) = b_inv
+                    .try_next()
+                    .await
+                    .context("failed to accept invocation")?
+                    .context("unexpected end of stream")?;
+                assert!(rx.is_none());
+                let x: u32 = x;
+                tx(Ok((x + 2,))).await.context("failed to send response")?;
+                anyhow::Ok(())
+            },
+            async {
+                let responses = clt
+                    .scatter_values::<_, (u32,)>(
+                        Default::default(),
+                        "test",
+                        "scatter",
+                        (40u32,),
+                        Duration::from_secs(10),
+                        Some(2),
+                    )
+                    .await
+                    .context("failed to invoke `test.scatter`")?;
+                let mut results = vec![];
+                let mut responses = pin!(responses);
+                while let Some((_, res)) = responses.next().await {
+                    let (x,) = res.context("invocation failed")?;
+                    results.push(x);
+                }
+                anyhow::Ok(results)
+            },
+        )?;
+        results.sort_unstable();
+        assert_eq!(results, [41, 42]);
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_scatter_limits() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    use wrpc_transport::{Error, Limits};
+
+    common::with_nats(|_, nats_client| async {
+        let a = wrpc_transport_nats::Client::new(nats_client.clone(), "test-prefix".to_string());
+        let b = wrpc_transport_nats::Client::new(nats_client.clone(), "test-prefix".to_string());
+        let clt = wrpc_transport_nats::Client::new(nats_client, "test-prefix".to_string());
+        let a_inv = a
+            .serve_values::<_, (), (Bytes,)>("test", "scatter", [[None; 0]])
+            .await
+            .context("failed to serve `test.scatter`")?;
+        let b_inv = b
+            .serve_values::<_, (), (Bytes,)>("test", "scatter", [[None; 0]])
+            .await
+            .context("failed to serve `test.scatter`")?;
+        let mut a_inv = pin!(a_inv);
+        let mut b_inv = pin!(b_inv);
+        let (_, _, results) = try_join!(
+            async {
+                let (_, (), _, tx) = a_inv
+                    .try_next()
+                    .await
+                    .context("failed to accept invocation")?
+                    .context("unexpected end of stream")?;
+                tx(Ok((Bytes::from_static(b"foo"),)))
+                    .await
+                    .context("failed to send response")?;
+                anyhow::Ok(())
+            },
+            async {
+                let (_, (), _, tx) = b_inv
+                    .try_next()
+                    .await
+                    .context("failed to accept invocation")?
+                    .context("unexpected end of stream")?;
+                // the invocation is cancelled by the client once the limit is exceeded
+                _ = tx(Ok((Bytes::from(vec![0x42; 1024]),))).await;
+                anyhow::Ok(())
+            },
+            Limits {
+                max_invocation_size: 16,
+                ..Limits::default()
+            }
+            .scope(async {
+                let responses = clt
+                    .scatter_values::<_, (Bytes,)>(
+                        Default::default(),
+                        "test",
+                        "scatter",
+                        (),
+                        Duration::from_secs(10),
+                        Some(2),
+                    )
+                    .await
+                    .context("failed to invoke `test.scatter`")?;
+                let mut results = vec![];
+                let mut responses = pin!(responses);
+                while let Some((_, res)) = responses.next().await {
+                    results.push(res);
+                }
+                anyhow::Ok(results)
+            }),
+        )?;
+        assert_eq!(results.len(), 2);
+        assert!(results
+            .iter()
+            .any(|res| matches!(res, Ok((buf,)) if buf == "foo")));
+        assert!(results.iter().any(|res| matches!(
+            res,
+            Err(Error::LimitExceeded {
+                limit: "invocation size",
+                max: 16,
+                ..
+            })
+        )));
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_scatter_unsupported() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    common::with_nats(|_, nats_client| async move {
+        let srv = wrpc_transport_nats::Client::new(nats_client.clone(), "test-prefix");
+        let clt = wrpc_transport_nats::Client::new(nats_client.clone(), "test-prefix");
+        let invocations = srv
+            .serve_values("test", "scatter", [[None; 0]])
+            .await
+            .context("failed to serve `test.scatter`")?;
+        let mut invocations = pin!(invocations);
+        // server, which does not support scatter-gather invocations, accepts the invocation
+        // like a regular one
+        let mut legacy = nats_client
+            .subscribe(wrpc_transport_nats::invocation_subject(
+                "test-prefix",
+                "test",
+                "scatter",
+            ))
+            .await
+            .context("failed to subscribe on invocation subject")?;
+        let legacy_inbox = nats_client.new_inbox();
+        let (_, _, mut responses) = try_join!(
+            async {
+                let (_, (x,), _, tx) = invocations
+                    .try_next()
+                    .await
+                    .context("failed to accept invocation")?
+                    .context("unexpected end of stream")?;
+                let x: u32 = x;
+                tx(Ok((x + 1,))).await.context("failed to send response")?;
+                anyhow::Ok(())
+            },
+            async {
+                let msg = legacy
+                    .next()
+                    .await
+                    .context("failed to receive invocation")?;
+                let reply = msg.reply.context("invocation has no reply subject")?;
+                nats_client
+                    .publish_with_reply(reply, legacy_inbox.clone(), Bytes::default())
+                    .await
+                    .context("failed to publish handshake response")?;
+                anyhow::Ok(())
+            },
+            async {
+                let responses = clt
+                    .scatter_values::<_, (u32,)>(
+                        Default::default(),
+                        "test",
+                        "scatter",
+                        (40u32,),
+                        Duration::from_secs(1),
+                        None,
+                    )
+                    .await
+                    .context("failed to invoke `test.scatter`")?;
+                anyhow::Ok(responses.collect::<Vec<_>>().await)
+            },
+        )?;
+        assert_eq!(responses.len(), 2);
+        responses.sort_by_key(|(_, res)| res.is_ok());
+        let (inbox, res) = responses.remove(0);
+        assert_eq!(inbox.as_str(), legacy_inbox);
+        assert!(matches!(res, Err(wrpc_transport::Error::Transport(..))));
+        let (_, res) = responses.remove(0);
+        assert_eq!(res?, (41,));
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(feature = "nats")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn rust_nats_jetstream() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    common::with_nats_jetstream(|_, nats_client| async {
+        let max_payload = nats_client.server_info().max_payload;
+        let clt =
+            wrpc_transport_nats::jetstream::Client::new(nats_client, "test-prefix", "WRPC_TEST")
+                .await
+                .context("failed to construct durable client")?;
+        clt.invoke_values(
+            Default::default(),
+            "test",
+            "durable",
+            ("foo".to_string(), 42u32),
+        )
+        .await
+        .context("failed to invoke `test.durable`")?;
+
+        let invocations = clt
+            .serve_values::<(String, u32)>("test", "durable")
+            .await
+            .context("failed to serve `test.durable`")?;
+        let mut invocations = pin!(invocations);
+
+        let (inv, params) = invocations
+            .try_next()
+            .await
+            .context("failed to receive invocation")?
+            .context("unexpected end of stream")?;
+        assert_eq!(params, ("foo".to_string(), 42));
+        assert_eq!(inv.delivered()?, 1);
+        inv.nak(None).await?;
+
+        // negatively acknowledged invocations are redelivered
+        let (inv, params) = invocations
+            .try_next()
+            .await
+            .context("failed to receive invocation")?
+            .context("unexpected end of stream")?;
+        assert_eq!(params, ("foo".to_string(), 42));
+        assert_eq!(inv.delivered()?, 2);
+        inv.ack().await?;
+
+        // invocations exceeding the maximum payload size are rejected before publishing
+        let res = clt
+            .invoke_values(
+                Default::default(),
+                "test",
+                "durable",
+                ("a".repeat(max_payload), 42u32),
+            )
+            .await;
+        assert!(matches!(
+            res,
+            Err(wrpc_transport::Error::PayloadTooLarge { .. })
+        ));
+        Ok(())
+    })
+    .await
+}
+
 #[cfg(feature = "quic")]
 #[test_log::test(tokio::test(flavor = "multi_thread"))]
 async fn rust_bindgen_quic() -> anyhow::Result<()> {
@@ -509,7 +1754,7 @@ async fn rust_dynamic() -> anyhow::Result<()> {
                         .expect("failed to accept client connection");
                     assert!(ok);
                     info!("receiving `test.sync` parameters");
-                    let ((), params, rx, tx) = sync_inv
+                    let (_, params, rx, tx) = sync_inv
                         .try_next()
                         .await
                         .expect("failed to accept invocation")
@@ -546,7 +1791,7 @@ async fn rust_dynamic() -> anyhow::Result<()> {
                     assert_eq!(m, "test");
                     assert_eq!(n, [[b"foo"]]);
                     info!("transmitting `test.sync` returns");
-                    tx((
+                    tx(Ok((
                         true,
                         0xfe_u8,
                         0xfeff_u16,
@@ -561,7 +1806,7 @@ async fn rust_dynamic() -> anyhow::Result<()> {
                         'a',
                         "test",
                         vec![vec!["foo".as_bytes()]],
-                    ))
+                    )))
                     .await
                     .expect("failed to send response");
                 }
@@ -570,7 +1815,7 @@ async fn rust_dynamic() -> anyhow::Result<()> {
                     info!("invoking `test.sync`");
                     let returns = clt
                         .invoke_values_blocking(
-                            (),
+                            Default::default(),
                             "test",
                             "sync",
                             (
@@ -639,7 +1884,7 @@ async fn rust_dynamic() -> anyhow::Result<()> {
                         .expect("failed to accept client connection");
                     assert!(ok);
                     info!("receiving `test.async` parameters");
-                    let ((), params, rx, tx) = async_inv
+                    let (_, params, rx, tx) = async_inv
                         .try_next()
                         .await
                         .expect("failed to accept invocation")
@@ -662,7 +1907,7 @@ async fn rust_dynamic() -> anyhow::Result<()> {
                             .expect("failed to perform async I/O");
                     }
                     info!("transmitting `test.async` returns");
-                    tx((a, b)).await.expect("failed to send response");
+                    tx(Ok((a, b))).await.expect("failed to send response");
                 }
                 .instrument(info_span!("server")),
                 async {
@@ -672,7 +1917,13 @@ async fn rust_dynamic() -> anyhow::Result<()> {
                         Box::pin(stream::iter(["foo", "bar"]));
                     info!("invoking `test.async`");
                     let (returns, io) = clt
-                        .invoke_values((), "test", "async", (a, b), &[[Some(0)], [Some(1)]])
+                        .invoke_values(
+                            Default::default(),
+                            "test",
+                            "async",
+                            (a, b),
+                            &[[Some(0)], [Some(1)]],
+                        )
                         .await
                         .expect("failed to invoke `test.async`");
                     let (a, b): (
@@ -699,3 +1950,168 @@ async fn rust_dynamic() -> anyhow::Result<()> {
     )
     .await
 }
+
+/// In-memory buffer of a single value, which does not support nested values
+struct Buffer(std::io::Cursor<Vec<u8>>);
+
+impl wrpc_transport::Index<Self> for Buffer {
+    fn index(&self, path: &[usize]) -> anyhow::Result<Self> {
+        anyhow::bail!("nested value at path {path:?} is not supported")
+    }
+}
+
+impl tokio::io::AsyncRead for Buffer {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> core::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for Buffer {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &[u8],
+    ) -> core::task::Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[test]
+fn rust_round_trip() -> anyhow::Result<()> {
+    use anyhow::ensure;
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder as _, Encoder as _};
+    use wrpc_transport::{Decode, Encode};
+
+    wrpc::generate!({
+        world: "round-trip",
+        path: "tests/wit",
+    });
+    use wrpc_test::integration::round_trip::Values;
+
+    let (resolve, pkg) = test_helpers::round_trip::parse("tests/wit")?;
+    let ty = test_helpers::round_trip::interface_type(&resolve, pkg, "round-trip", "values")?;
+    test_helpers::round_trip::check(&resolve, &ty, |buf| {
+        let mut buf = BytesMut::from(buf);
+        let v = <Values as Decode<Buffer>>::Decoder::default()
+            .decode(&mut buf)
+            .context("failed to decode value")?
+            .context("value incomplete")?;
+        ensure!(buf.is_empty(), "value not fully consumed");
+        <Values as Encode<Buffer>>::Encoder::default()
+            .encode(v, &mut buf)
+            .context("failed to encode value")?;
+        Ok(buf.to_vec())
+    })
+}
+
+#[cfg(feature = "wasmtime")]
+#[test]
+fn wasmtime_round_trip() -> anyhow::Result<()> {
+    use core::pin::pin;
+
+    use anyhow::ensure;
+    use bytes::BytesMut;
+    use tokio_util::codec::Encoder as _;
+    use wasmtime::component::types::ComponentItem;
+    use wasmtime::component::{Component, Val};
+    use wasmtime::{AsContextMut as _, Engine, Store};
+    use wasmtime_wasi::{ResourceTable, WasiCtx, WasiCtxBuilder, WasiView};
+    use wrpc_runtime_wasmtime::{read_value, ValEncoder};
+
+    struct Ctx {
+        table: ResourceTable,
+        wasi: WasiCtx,
+    }
+
+    impl WasiView for Ctx {
+        fn ctx(&mut self) -> &mut WasiCtx {
+            &mut self.wasi
+        }
+        fn table(&mut self) -> &mut ResourceTable {
+            &mut self.table
+        }
+    }
+
+    let (resolve, pkg) = test_helpers::round_trip::parse("tests/wit")?;
+    let ty = test_helpers::round_trip::interface_type(&resolve, pkg, "round-trip", "values")?;
+
+    let world = resolve.select_world(pkg, Some("round-trip"))?;
+    let mut module = wit_component::dummy_module(&resolve, world);
+    wit_component::embed_component_metadata(
+        &mut module,
+        &resolve,
+        world,
+        wit_component::StringEncoding::UTF8,
+    )
+    .context("failed to embed component metadata")?;
+    let component = wit_component::ComponentEncoder::default()
+        .module(&module)
+        .context("failed to set core component module")?
+        .validate(true)
+        .encode()
+        .context("failed to encode component")?;
+    let engine = Engine::new(wasmtime::Config::new().wasm_component_model(true))
+        .context("failed to initialize engine")?;
+    let component = Component::new(&engine, component).context("failed to compile component")?;
+    let val_ty = component
+        .component_type()
+        .imports(&engine)
+        .find_map(|(name, item)| match item {
+            ComponentItem::ComponentInstance(instance)
+                if name == "wrpc-test:integration/round-trip" =>
+            {
+                instance
+                    .exports(&engine)
+                    .find_map(|(name, item)| match item {
+                        ComponentItem::ComponentFunc(func) if name == "get" => {
+                            func.results().next()
+                        }
+                        _ => None,
+                    })
+            }
+            _ => None,
+        })
+        .context("`wrpc-test:integration/round-trip.get` type not found")?;
+
+    test_helpers::round_trip::check(&resolve, &ty, |buf| {
+        let mut store = Store::new(
+            &engine,
+            Ctx {
+                table: ResourceTable::new(),
+                wasi: WasiCtxBuilder::new().build(),
+            },
+        );
+        let mut val = Val::Bool(false);
+        let mut r = pin!(Buffer(std::io::Cursor::new(buf.to_vec())));
+        futures::executor::block_on(read_value(&mut store, &mut r, &mut val, &val_ty, &[]))
+            .context("failed to read value")?;
+        ensure!(
+            r.0.position() == buf.len() as u64,
+            "value not fully consumed"
+        );
+        let mut buf = BytesMut::default();
+        ValEncoder::<_, Buffer>::new(store.as_context_mut(), &val_ty)
+            .encode(&val, &mut buf)
+            .context("failed to encode value")?;
+        Ok(buf.to_vec())
+    })
+}